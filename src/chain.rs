@@ -1,4 +1,4 @@
-//! Provides the `smtp_chain` macro and the `chain` function
+//! Provides the `smtp_chain` macro, the `chain` function and `Connection::run_script`
 //!
 //! see their respective documentation for more information.
 use std::io as std_io;
@@ -7,6 +7,7 @@ use futures::future::{self, Future, Loop, Either};
 
 use ::{command, Connection, BoxedCmd};
 use ::error::LogicError;
+use ::io::SmtpResult;
 
 /// creates a chain of commands and them to the given connection
 ///
@@ -155,4 +156,62 @@ impl HandleErrorInChain for OnError {
 
         Box::new(fut)
     }
-}
\ No newline at end of file
+}
+
+/// Decide if `Connection::run_script_with` stops at the first failing command
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum OnScriptError {
+    /// don't send any further commands
+    Stop,
+    /// continue with the next command
+    Continue
+}
+
+impl Connection {
+
+    /// send all commands one after another, collecting their results
+    ///
+    /// This stops at the first command failing with a `LogicError`,
+    /// see `run_script_with` for details.
+    pub fn run_script(self, cmds: Vec<BoxedCmd>)
+        -> impl Future<Item=(Connection, Vec<SmtpResult>), Error=std_io::Error> + Send
+    {
+        self.run_script_with(cmds, OnScriptError::Stop)
+    }
+
+    /// send all commands one after another, collecting their results
+    ///
+    /// Unlike `chain` the result of each command which was send is
+    /// returned (in the order the commands were send), i.e. if the script
+    /// stopped early there are less results than commands. Each command
+    /// is only send after the response to the previous one was received.
+    ///
+    /// I/O errors always end the script (and are returned as the error
+    /// of the future).
+    pub fn run_script_with(self, cmds: Vec<BoxedCmd>, on_error: OnScriptError)
+        -> impl Future<Item=(Connection, Vec<SmtpResult>), Error=std_io::Error> + Send
+    {
+        let results = Vec::with_capacity(cmds.len());
+        let cmds = cmds.into_iter();
+
+        future::loop_fn((self, cmds, results), move |(con, mut cmds, mut results)| {
+            match cmds.next() {
+                Some(cmd) => {
+                    let fut = con
+                        .send(cmd)
+                        .map(move |(con, result)| {
+                            let stop = result.is_err() && on_error == OnScriptError::Stop;
+                            results.push(result);
+                            if stop {
+                                Loop::Break((con, results))
+                            } else {
+                                Loop::Continue((con, cmds, results))
+                            }
+                        });
+                    Either::A(fut)
+                },
+                None => Either::B(future::ok(Loop::Break((con, results))))
+            }
+        })
+    }
+}
@@ -0,0 +1,117 @@
+use std::fmt::{self, Display};
+use std::error::{Error as ErrorTrait};
+
+use base64::encode;
+use futures::future::{self, Future};
+
+use ::future_ext::ResultWithContextExt;
+use ::{ExecFuture, Cmd, Io, EhloData};
+use ::error::{LogicError, MissingCapabilities};
+
+use super::validate_auth_capability;
+
+/// AUTH ANONYMOUS smtp authentication based on rfc4954/rfc4505
+///
+/// The optional trace token (e.g. a email address) is send to the
+/// server, which might use it for logging.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Anonymous(Option<String>);
+
+impl Anonymous {
+
+    /// Create a auth anonymous command without trace token.
+    pub fn new() -> Self {
+        Anonymous(None)
+    }
+
+    /// Create a auth anonymous command with given trace token.
+    pub fn with_trace<I>(trace: I) -> Result<Self, ControlCharError>
+        where I: Into<String>
+    {
+        let trace = trace.into();
+        if trace.chars().any(char::is_control) {
+            return Err(ControlCharError);
+        }
+        Ok(Anonymous(Some(trace)))
+    }
+
+    /// Returns the trace token which will be used (if any).
+    pub fn trace(&self) -> Option<&str> {
+        self.0.as_deref()
+    }
+}
+
+impl Default for Anonymous {
+    fn default() -> Self {
+        Anonymous::new()
+    }
+}
+
+impl Cmd for Anonymous {
+
+    fn check_cmd_availability(&self, caps: Option<&EhloData>)
+        -> Result<(), MissingCapabilities>
+    {
+        validate_auth_capability(caps, "ANONYMOUS")
+    }
+
+    fn exec(self, mut io: Io) -> ExecFuture {
+        // a empty initial response is send as "="
+        let initial_response = match self.0 {
+            Some(ref trace) if !trace.is_empty() => encode(trace),
+            _ => "=".to_owned()
+        };
+        io.write_line_from_parts(&["AUTH ANONYMOUS ", &initial_response]);
+
+        let fut = io
+            .flush()
+            .and_then(Io::parse_response)
+            .ctx_and_then(|io, response| -> ExecFuture {
+                if !response.code().is_intermediate() {
+                    return Box::new(future::ok((io, Ok(response))));
+                }
+
+                // we already send all we had, so cancel the exchange
+                let fut = io
+                    .flush_line_from_parts(&["*"])
+                    .and_then(Io::parse_response)
+                    .map(move |(io, _)| (io, Err(LogicError::UnexpectedCode(response))));
+
+                Box::new(fut)
+            });
+
+        Box::new(fut)
+    }
+
+    fn auth_identity(&self) -> Option<String> {
+        Some("ANONYMOUS".to_owned())
+    }
+}
+
+/// Error returned by `Anonymous::with_trace` if the trace token contains a control character.
+#[derive(Copy, Clone, Debug)]
+pub struct ControlCharError;
+
+impl Display for ControlCharError {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        fter.write_str(self.description())
+    }
+}
+
+impl ErrorTrait for ControlCharError {
+    fn description(&self) -> &str {
+        "trace token contained control character"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rejects_control_chars_in_trace() {
+        assert!(Anonymous::with_trace("sirhc\r\nQUIT").is_err());
+        assert!(Anonymous::with_trace("a\x7fb").is_err());
+        assert_eq!(Anonymous::with_trace("sirhc").unwrap().trace(), Some("sirhc"));
+    }
+}
@@ -4,13 +4,13 @@ use base64::encode;
 use ::future_ext::ResultWithContextExt;
 use ::{ExecFuture, Cmd, Io, EhloData};
 use ::error::{LogicError, MissingCapabilities};
-use super::validate_auth_capability;
+use super::{validate_auth_capability, exec_if_secure, Secret};
 
 /// Simple implementation of AUTH LOGIN for smtp.
 #[derive(Debug, Clone)]
 pub struct Login {
     username: String,
-    password: String
+    password: Secret
 }
 
 impl Login {
@@ -19,13 +19,13 @@ impl Login {
     pub fn new(username: &str, password: &str) -> Self {
         Login {
             username: encode(username),
-            password: encode(password),
+            password: Secret::from(encode(password)),
         }
     }
 
     /// Create a new auth login command based on base64 encoded username and password.
     pub fn from_base64(username: String, password: String) -> Self {
-        Login { username, password }
+        Login { username, password: Secret::from(password) }
     }
 
     /// Returns the username contained in the `Login` command.
@@ -46,7 +46,19 @@ impl Cmd for Login {
         validate_auth_capability(caps, "LOGIN")
     }
 
-    fn exec(self, mut io: Io) -> ExecFuture {
+    fn exec(self, io: Io) -> ExecFuture {
+        exec_if_secure(io, |io| self.send_credentials(io))
+    }
+
+    fn auth_identity(&self) -> Option<String> {
+        // the username is stored base64 encoded, which is fine for telling identities apart
+        Some(format!("LOGIN {}", self.username))
+    }
+}
+
+impl Login {
+
+    fn send_credentials(self, mut io: Io) -> ExecFuture {
         let Login { username, password } = self;
 
         io.write_line_from_parts(&["AUTH LOGIN", username.as_str()]);
@@ -59,7 +71,7 @@ impl Cmd for Login {
                     Either::A(future::ok((io, Err(LogicError::UnexpectedCode(response)))))
                 } else {
                     let fut = io
-                        .flush_line_from_parts(&[password.as_str()])
+                        .flush_line_from_parts(&[&*password])
                         .and_then(Io::parse_response);
 
                     Either::B(fut)
@@ -1,5 +1,14 @@
-use ::{EhloData, EsmtpKeyword, Capability};
-use ::error::MissingCapabilities;
+use std::fmt::{self, Debug};
+use std::ops::Deref;
+
+use base64::decode;
+use futures::future::{self, Future};
+#[cfg(feature="zeroize")]
+use zeroize::Zeroize;
+
+use ::{ExecFuture, EhloData, EsmtpKeyword, Capability, Io, Response};
+use ::error::{LogicError, MissingCapabilities};
+use ::response::codes;
 
 mod login;
 pub use self::login::*;
@@ -7,9 +16,33 @@ pub use self::login::*;
 mod plain;
 pub use self::plain::*;
 
+mod oauth_bearer;
+pub use self::oauth_bearer::*;
+
+mod anonymous;
+pub use self::anonymous::*;
+
+mod sasl;
+pub use self::sasl::*;
+
+#[cfg(feature="auth-scram")]
+mod scram;
+#[cfg(feature="auth-scram")]
+pub use self::scram::*;
+
 const CAP_AUTH: &str = "AUTH";
 
-fn validate_auth_capability(caps: Option<&EhloData>, auth_kind: &'static str)
+/// the maximal length of an `AUTH` command line including `"\r\n"` (rfc4954)
+///
+/// It's longer than `io::MAX_LINE_LENGTH` so that initial responses fit in.
+const MAX_AUTH_LINE_LENGTH: usize = 12288;
+
+/// sends the `AUTH` command line `parts`, see `Io::exec_simple_cmd`
+fn exec_auth_cmd(io: Io, parts: &[&str]) -> ExecFuture {
+    io.exec_cmd_with_max_length(parts, MAX_AUTH_LINE_LENGTH)
+}
+
+fn validate_auth_capability(caps: Option<&EhloData>, auth_kind: &str)
     -> Result<(), MissingCapabilities>
 {
     caps.and_then(|ehlo_data| {
@@ -22,4 +55,190 @@ fn validate_auth_capability(caps: Option<&EhloData>, auth_kind: &'static str)
         let mcap = Capability::from(EsmtpKeyword::from_unchecked(CAP_AUTH));
         MissingCapabilities::new(vec![mcap])
     })
-}
\ No newline at end of file
+}
+
+/// runs `exec` only if the connection is encrypted or insecure auth was allowed
+///
+/// Else `LogicError::InsecureAuth` is returned without sending anything. A
+/// `538` response (encryption required for the mechanism) is mapped to
+/// `LogicError::InsecureAuth`, too.
+fn exec_if_secure<F>(io: Io, exec: F) -> ExecFuture
+    where F: FnOnce(Io) -> ExecFuture
+{
+    if !io.is_secure() && !io.allows_insecure_auth() {
+        return Box::new(future::ok((io, Err(LogicError::InsecureAuth))));
+    }
+
+    let fut = exec(io)
+        .map(|(io, result)| {
+            let result = match result {
+                Err(LogicError::Code(ref response))
+                    if response.code() == codes::ENCRYPTION_REQUIRED =>
+                {
+                    Err(LogicError::InsecureAuth)
+                },
+                result => result
+            };
+            (io, result)
+        });
+
+    Box::new(fut)
+}
+
+/// strictly decodes the base64 challenge of a `334` response
+///
+/// The challenge is the text after the `"334 "` and has to be a single line
+/// of padded base64 (rfc4954), an empty challenge is valid. Trailing spaces
+/// and tabs are ignored, any other content (including leading or embedded
+/// whitespace) is returned as `LogicError::MalformedChallenge`, so that it
+/// never reaches the mechanism.
+fn decode_challenge(response: &Response) -> Result<Vec<u8>, LogicError> {
+    let malformed = || LogicError::MalformedChallenge {
+        raw: format!("{} {}", response.code(), response.msg().join(" "))
+    };
+
+    if response.msg().len() != 1 {
+        return Err(malformed());
+    }
+    let challenge = response.msg()[0].trim_end_matches(&[' ', '\t'][..]);
+    if !is_strict_base64(challenge) {
+        return Err(malformed());
+    }
+    decode(challenge).map_err(|_| malformed())
+}
+
+/// true if `data` consists of complete base64 quanta with padding only at the end
+fn is_strict_base64(data: &str) -> bool {
+    let quanta = data.as_bytes().chunks(4);
+    let last = quanta.len().saturating_sub(1);
+    quanta.enumerate().all(|(idx, quantum)| {
+        let padding = quantum.iter().rev().take_while(|&&byte| byte == b'=').count();
+        quantum.len() == 4
+            && (padding == 0 || (idx == last && padding <= 2))
+            && quantum[..4 - padding].iter()
+                .all(|&byte| byte.is_ascii_alphanumeric() || byte == b'+' || byte == b'/')
+    })
+}
+
+/// A string containing credential material (e.g. a password)
+///
+/// If the `zeroize` feature is enabled the string is zeroed
+/// on drop. The `Debug` impl does not show the content.
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) struct Secret(String);
+
+impl Secret {
+
+    /// creates an empty secret which can hold `capacity` bytes without reallocating
+    fn with_capacity(capacity: usize) -> Self {
+        Secret(String::with_capacity(capacity))
+    }
+
+    /// the buffer of the secret, e.g. to build it in place
+    ///
+    /// It must not grow beyond its capacity, as reallocating it leaves
+    /// a copy of the content in the old (not zeroed) allocation.
+    fn buffer_mut(&mut self) -> &mut String {
+        &mut self.0
+    }
+
+    /// zeros (if the `zeroize` feature is enabled) and clears the secret
+    fn wipe(&mut self) {
+        #[cfg(feature="zeroize")]
+        self.0.zeroize();
+        #[cfg(not(feature="zeroize"))]
+        self.0.clear();
+    }
+}
+
+impl From<String> for Secret {
+    fn from(secret: String) -> Self {
+        Secret(secret)
+    }
+}
+
+impl Deref for Secret {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        fter.write_str("Secret(..)")
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.wipe()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ::error::LogicError;
+    use ::response::{codes, Response};
+    use super::{Secret, decode_challenge};
+
+    fn challenge(lines: &[&str]) -> Response {
+        Response::new(codes::AUTH_CHALLENGE, lines.iter().map(|line| line.to_string()).collect())
+    }
+
+    fn assert_malformed(lines: &[&str]) {
+        match decode_challenge(&challenge(lines)) {
+            Err(LogicError::MalformedChallenge { .. }) => (),
+            other => panic!("unexpected result for {:?}: {:?}", lines, other)
+        }
+    }
+
+    #[test]
+    fn decodes_valid_challenges() {
+        assert_eq!(decode_challenge(&challenge(&["YWJj"])).unwrap(), b"abc");
+        assert_eq!(decode_challenge(&challenge(&["YWI="])).unwrap(), b"ab");
+        assert_eq!(decode_challenge(&challenge(&["YQ=="])).unwrap(), b"a");
+        assert_eq!(decode_challenge(&challenge(&["PDEyMz4rLw== \t"])).unwrap(), b"<123>+/");
+    }
+
+    #[test]
+    fn decodes_empty_challenges() {
+        assert_eq!(decode_challenge(&challenge(&[""])).unwrap(), b"");
+        assert_eq!(decode_challenge(&challenge(&["  "])).unwrap(), b"");
+    }
+
+    #[test]
+    fn rejects_invalid_challenges() {
+        assert_malformed(&["not base64!"]);
+        assert_malformed(&["YWJj$A=="]);
+        assert_malformed(&["YWJ"]);
+        assert_malformed(&["YW=j"]);
+        assert_malformed(&["Y==="]);
+        assert_malformed(&[" YWJj"]);
+        assert_malformed(&["YW Jj"]);
+        assert_malformed(&["YWJj\r"]);
+        assert_malformed(&["YWJj", "YWJj"]);
+    }
+
+    #[test]
+    fn debug_does_not_show_secret() {
+        let secret = Secret::from("hunter2".to_owned());
+        assert_eq!(format!("{:?}", secret), "Secret(..)");
+    }
+
+    #[cfg(feature="zeroize")]
+    #[test]
+    fn wipe_zeros_the_buffer() {
+        let mut secret = Secret::from("hunter2".to_owned());
+        let ptr = secret.0.as_ptr();
+        let len = secret.0.len();
+
+        secret.wipe();
+
+        assert!(secret.is_empty());
+        // the allocation is still owned by `secret` so reading it is fine
+        let buffer = unsafe { ::std::slice::from_raw_parts(ptr, len) };
+        assert!(buffer.iter().all(|bch| *bch == 0));
+    }
+}
@@ -0,0 +1,204 @@
+use std::fmt::{self, Display};
+use std::error::{Error as ErrorTrait};
+
+use base64::{encode, decode};
+use futures::future::{self, Future};
+#[cfg(feature="zeroize")]
+use zeroize::Zeroizing;
+
+use ::future_ext::ResultWithContextExt;
+use ::{ExecFuture, Cmd, Io, EhloData, Response};
+use ::error::{LogicError, MissingCapabilities};
+
+use super::{validate_auth_capability, exec_if_secure, exec_auth_cmd, Secret};
+
+/// base64 encoded `"\x01"`, the dummy client response after an error challenge
+const DUMMY_RESPONSE: &str = "AQ==";
+
+/// AUTH OAUTHBEARER smtp authentication based on rfc7628
+///
+/// If the server rejects the token it sends an error challenge
+/// (containing a json error description), in which case the exchange
+/// is completed as required by the rfc and a `LogicError::Custom` with
+/// a `OAuthBearerError` is returned.
+#[derive(Debug, Clone)]
+pub struct OAuthBearer {
+    user: String,
+    host: Option<String>,
+    port: Option<u16>,
+    token: Secret
+}
+
+impl OAuthBearer {
+
+    /// Create a new auth oauthbearer command based on user and (bearer) access token.
+    pub fn new<I1, I2>(user: I1, token: I2) -> Self
+        where I1: Into<String>, I2: Into<String>
+    {
+        OAuthBearer {
+            user: user.into(),
+            host: None,
+            port: None,
+            token: Secret::from(token.into())
+        }
+    }
+
+    /// Sets the host name of the server which is send to it.
+    pub fn with_host<I>(mut self, host: I) -> Self
+        where I: Into<String>
+    {
+        self.host = Some(host.into());
+        self
+    }
+
+    /// Sets the port of the server which is send to it.
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    /// Returns the user contained in the `OAuthBearer` command.
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    //intentionally no fn token(&self)!
+
+    fn initial_response(&self) -> Secret {
+        // the user is a saslname, so ',' and '=' have to be escaped
+        let user = self.user.replace('=', "=3D").replace(',', "=2C");
+        let host = self.host.as_ref().map(|host| format!("host={}\x01", host)).unwrap_or_default();
+        let port = self.port.map(|port| format!("port={}\x01", port)).unwrap_or_default();
+        let parts = ["n,a=", &user, ",\x01", &host, &port, "auth=Bearer ", &self.token, "\x01\x01"];
+
+        // allocate the buffer once, so that growing it leaves no copies of the token behind
+        let len = parts.iter().map(|part| part.len()).sum();
+        #[cfg(feature="zeroize")]
+        let mut raw = Zeroizing::new(String::with_capacity(len));
+        #[cfg(not(feature="zeroize"))]
+        let mut raw = String::with_capacity(len);
+        for part in &parts {
+            raw.push_str(part);
+        }
+        Secret::from(encode(&*raw))
+    }
+}
+
+impl Cmd for OAuthBearer {
+
+    fn check_cmd_availability(&self, caps: Option<&EhloData>)
+        -> Result<(), MissingCapabilities>
+    {
+        validate_auth_capability(caps, "OAUTHBEARER")
+    }
+
+    fn exec(self, io: Io) -> ExecFuture {
+        exec_if_secure(io, |io| self.send_credentials(io))
+    }
+
+    fn auth_identity(&self) -> Option<String> {
+        Some(format!("OAUTHBEARER {}", self.user))
+    }
+}
+
+impl OAuthBearer {
+
+    fn send_credentials(self, io: Io) -> ExecFuture {
+        let fut = exec_auth_cmd(io, &["AUTH OAUTHBEARER ", &*self.initial_response()])
+            .ctx_and_then(|io, response| -> ExecFuture {
+                if !response.code().is_intermediate() {
+                    return Box::new(future::ok((io, Ok(response))));
+                }
+
+                // error challenge, we have to send a dummy response and
+                // the server will then fail the authentication
+                let error_json = decode_error_challenge(&response);
+                let fut = io
+                    .flush_line_from_parts(&[DUMMY_RESPONSE])
+                    .and_then(Io::parse_response)
+                    .map(move |(io, result)| {
+                        let response = match result {
+                            Ok(response) | Err(LogicError::Code(response)) => response,
+                            Err(err) => return (io, Err(err))
+                        };
+                        let err = OAuthBearerError { error_json, response };
+                        (io, Err(LogicError::Custom(Box::new(err))))
+                    });
+
+                Box::new(fut)
+            });
+
+        Box::new(fut)
+    }
+}
+
+fn decode_error_challenge(response: &Response) -> String {
+    let first = response.msg().first().map(|line| line.trim()).unwrap_or("");
+    match decode(first) {
+        Ok(raw) => String::from_utf8_lossy(&raw).into_owned(),
+        // not valid base64, keep it as is so that it's still available
+        Err(_) => first.to_owned()
+    }
+}
+
+/// Error returned (as `LogicError::Custom`) if the server rejected the token
+#[derive(Debug, Clone)]
+pub struct OAuthBearerError {
+    error_json: String,
+    response: Response
+}
+
+impl OAuthBearerError {
+
+    /// Returns the (json) error description send by the server
+    ///
+    /// It's e.g. `{"status":"invalid_token","scope":"..."}`, but this
+    /// is returned as it was send by the server without validating it.
+    pub fn error_json(&self) -> &str {
+        &self.error_json
+    }
+
+    /// Returns the final response of the server (which is an error response).
+    pub fn response(&self) -> &Response {
+        &self.response
+    }
+}
+
+impl Display for OAuthBearerError {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        write!(fter, "server rejected oauth bearer token: {}", self.error_json)
+    }
+}
+
+impl ErrorTrait for OAuthBearerError {
+    fn description(&self) -> &str {
+        "oauthbearer authentication failed"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn initial_response_with_host_and_port() {
+        // example from rfc7628 section 4.1
+        let cmd = OAuthBearer::new("user@example.com", "vF9dft4qmTc2Nvb3RlckBhbHRhdmlzdGEuY29tCg==")
+            .with_host("server.example.com")
+            .with_port(587);
+
+        let raw = decode(&*cmd.initial_response()).unwrap();
+        assert_eq!(
+            String::from_utf8(raw).unwrap(),
+            "n,a=user@example.com,\x01host=server.example.com\x01port=587\x01\
+             auth=Bearer vF9dft4qmTc2Nvb3RlckBhbHRhdmlzdGEuY29tCg==\x01\x01"
+        );
+    }
+
+    #[test]
+    fn initial_response_escapes_user() {
+        let cmd = OAuthBearer::new("a=b,c", "token");
+        let raw = decode(&*cmd.initial_response()).unwrap();
+        assert_eq!(String::from_utf8(raw).unwrap(), "n,a=a=3Db=2Cc,\x01auth=Bearer token\x01\x01");
+    }
+}
@@ -2,19 +2,20 @@ use std::fmt::{self, Display};
 use std::sync::Arc;
 use std::error::{Error as ErrorTrait};
 
-use base64::encode;
+use base64::{encode_config_buf, STANDARD};
+use futures::Future;
 
 use ::{ExecFuture, Cmd, EhloData, Io};
 use ::error::MissingCapabilities;
 
-use super::validate_auth_capability;
+use super::{validate_auth_capability, exec_if_secure, Secret};
 
 /// AUTH PLAIN smtp authentication based on rfc4954/rfc4616
 #[derive(Debug, Clone)]
 pub struct Plain {
     authorization_identity: String,
     authentication_identity: String,
-    password: String
+    password: Secret
 }
 
 impl Plain {
@@ -30,7 +31,7 @@ impl Plain {
         Ok(Plain {
             authentication_identity: user.clone(),
             authorization_identity: user,
-            password: password.into()
+            password: Secret::from(password.into())
         })
     }
 
@@ -54,7 +55,7 @@ impl Plain {
         Ok(Plain {
             authentication_identity: authentication_identity.into(),
             authorization_identity: authorization_identity.into(),
-            password: password.into()
+            password: Secret::from(password.into())
         })
     }
 
@@ -70,13 +71,36 @@ impl Plain {
 
     //intentionally no fn password(&self)!
 
-    fn exec_ref(&self, io: Io) -> ExecFuture {
-        let auth_str = encode(&format!("{}\0{}\0{}",
-                               &self.authorization_identity,
-                               &self.authentication_identity,
-                               &self.password));
+    fn exec_ref(&self, mut io: Io) -> ExecFuture {
+        {
+            // both buffers are allocated with their final size, so that no
+            // (not zeroed) copy of the credentials is left behind when growing
+            let len = self.authorization_identity.len()
+                + self.authentication_identity.len()
+                + self.password.len() + 2;
+            let mut raw = Secret::with_capacity(len);
+            {
+                let buffer = raw.buffer_mut();
+                buffer.push_str(&self.authorization_identity);
+                buffer.push('\0');
+                buffer.push_str(&self.authentication_identity);
+                buffer.push('\0');
+                buffer.push_str(&self.password);
+            }
+
+            // usize::div_ceil is not available on older rust versions
+            #[allow(clippy::manual_div_ceil)]
+            let encoded_len = (len + 2) / 3 * 4;
+            let mut auth_str = Secret::with_capacity(encoded_len);
+            encode_config_buf(raw.as_bytes(), STANDARD, auth_str.buffer_mut());
+            io.write_line_from_parts(&["AUTH PLAIN ", &auth_str]);
+        }
+
+        let fut = io
+            .flush()
+            .and_then(Io::parse_response);
 
-        io.exec_simple_cmd(&["AUTH PLAIN ", auth_str.as_str()])
+        Box::new(fut)
     }
 }
 
@@ -89,7 +113,12 @@ impl Cmd for Plain {
     }
 
     fn exec(self, con: Io) -> ExecFuture {
-        self.exec_ref(con)
+        exec_if_secure(con, |io| self.exec_ref(io))
+    }
+
+    fn auth_identity(&self) -> Option<String> {
+        // neither identity can contain a '\0' so it's an unambiguous separator
+        Some(format!("PLAIN {}\0{}", self.authorization_identity, self.authentication_identity))
     }
 }
 
@@ -103,7 +132,12 @@ impl Cmd for Arc<Plain> {
     }
 
     fn exec(self, con: Io) -> ExecFuture {
-        self.exec_ref(con)
+        exec_if_secure(con, |io| self.exec_ref(io))
+    }
+
+    fn auth_identity(&self) -> Option<String> {
+        let me: &Plain = self;
+        me.auth_identity()
     }
 }
 
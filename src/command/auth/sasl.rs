@@ -0,0 +1,207 @@
+use std::{io as std_io};
+use std::error::Error;
+use std::fmt::{self, Display};
+
+use base64::encode;
+use futures::future::{self, Future, Loop};
+
+use ::{ExecFuture, Cmd, Io, EhloData};
+use ::io::SmtpResult;
+use ::error::{LogicError, MissingCapabilities};
+
+use super::{validate_auth_capability, exec_if_secure, exec_auth_cmd, decode_challenge};
+
+/// A SASL mechanism which can be used for authentication with the `Sasl` command
+///
+/// The challenges and responses passed to/returned from it are _not_
+/// base64 encoded, `Sasl` takes care of the encoding.
+/// Challenges which are not valid base64 are rejected with
+/// `LogicError::MalformedChallenge` before they reach the mechanism.
+pub trait SaslMechanism: Send + 'static {
+
+    /// the name of the mechanism, e.g. `"PLAIN"`
+    ///
+    /// The server has to advertise it as a parameter of the `AUTH` capability.
+    /// It has to be a valid SASL mechanism name (rfc4422), i.e. 1 to 20
+    /// upper case letters, digits, `'-'` or `'_'`, else `Sasl` fails with
+    /// a `InvalidMechanismName` (as `LogicError::Custom`) without sending
+    /// anything.
+    fn name(&self) -> &str;
+
+    /// returns the initial response send with the `AUTH` command
+    ///
+    /// If it is `None` no initial response is send, i.e. the exchange
+    /// starts with the first challenge of the server. If the `AUTH` line
+    /// would be longer than 12288 octets (rfc4954) nothing is send and
+    /// `LogicError::LineTooLong` is returned.
+    fn initial_response(&mut self) -> Option<Vec<u8>>;
+
+    /// computes the response to the given challenge of the server
+    ///
+    /// If an error is returned the exchange is canceled (by sending `"*"`)
+    /// and the error is returned as `LogicError::Custom`.
+    fn step(&mut self, challenge: &[u8]) -> Result<Vec<u8>, Box<Error + Send + Sync>>;
+
+    /// if true the mechanism is only used over encrypted connections (default: true)
+    ///
+    /// Mechanisms not sending any credentials (which could be reused by a
+    /// man in the middle) can return false, see `Connection::set_allow_insecure_auth`.
+    fn requires_encryption(&self) -> bool {
+        true
+    }
+
+    /// the identity authenticated as, e.g. the username (default: `None`)
+    ///
+    /// It must not contain any credentials, it's used by `Cmd::auth_identity`
+    /// to tell configs with different identities apart.
+    fn identity(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// AUTH command driving any `SaslMechanism` through the `334` challenge/response exchange
+#[derive(Debug, Clone)]
+pub struct Sasl<M> {
+    mechanism: M
+}
+
+impl<M> Sasl<M>
+    where M: SaslMechanism
+{
+    pub fn new(mechanism: M) -> Self {
+        Sasl { mechanism }
+    }
+
+    pub fn mechanism(&self) -> &M {
+        &self.mechanism
+    }
+
+    pub fn into_mechanism(self) -> M {
+        self.mechanism
+    }
+}
+
+impl<M> Cmd for Sasl<M>
+    where M: SaslMechanism
+{
+
+    fn check_cmd_availability(&self, caps: Option<&EhloData>)
+        -> Result<(), MissingCapabilities>
+    {
+        validate_auth_capability(caps, self.mechanism.name())
+    }
+
+    fn exec(self, io: Io) -> ExecFuture {
+        if self.mechanism.requires_encryption() {
+            exec_if_secure(io, |io| self.authenticate(io))
+        } else {
+            self.authenticate(io)
+        }
+    }
+
+    fn auth_identity(&self) -> Option<String> {
+        let identity = self.mechanism.identity().unwrap_or("");
+        Some(format!("{} {}", self.mechanism.name(), identity))
+    }
+}
+
+impl<M> Sasl<M>
+    where M: SaslMechanism
+{
+
+    fn authenticate(self, io: Io) -> ExecFuture {
+        let Sasl { mut mechanism } = self;
+
+        let name = mechanism.name().to_owned();
+        if !is_valid_mechanism_name(&name) {
+            let err = LogicError::Custom(Box::new(InvalidMechanismName(name)));
+            return Box::new(future::ok((io, Err(err))));
+        }
+
+        let initial_response = mechanism.initial_response().map(|initial| {
+            // an empty initial response is send as "=" (rfc4954)
+            if initial.is_empty() { "=".to_owned() } else { encode(&initial) }
+        });
+
+        // validates the line, e.g. a too long initial response isn't send
+        let fut = match initial_response {
+            Some(initial_response) => exec_auth_cmd(io, &["AUTH ", &name, " ", &initial_response]),
+            None => exec_auth_cmd(io, &["AUTH ", &name])
+        };
+
+        let fut = fut
+            .and_then(move |(io, result)| {
+                future::loop_fn((io, mechanism, result), |(io, mut mechanism, result)| {
+                    let challenge = match result {
+                        Ok(ref response) if response.code().is_intermediate() => decode_challenge(response),
+                        result => return Box::new(future::ok(Loop::Break((io, result)))) as StepFuture<M>
+                    };
+
+                    let response = challenge.and_then(|challenge| {
+                        mechanism.step(&challenge).map_err(LogicError::Custom)
+                    });
+                    match response {
+                        Ok(response) => {
+                            let fut = io
+                                .flush_line_from_parts(&[&encode(&response)])
+                                .and_then(Io::parse_response)
+                                .map(move |(io, result)| Loop::Continue((io, mechanism, result)));
+                            Box::new(fut) as StepFuture<M>
+                        },
+                        Err(err) => {
+                            let fut = cancel_auth(io, err).map(Loop::Break);
+                            Box::new(fut) as StepFuture<M>
+                        }
+                    }
+                })
+            });
+
+        Box::new(fut)
+    }
+}
+
+type StepFuture<M> = Box<Future<Item=Loop<(Io, SmtpResult), (Io, M, SmtpResult)>, Error=std_io::Error> + Send>;
+
+/// checks if `name` is a valid SASL mechanism name (rfc4422)
+fn is_valid_mechanism_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 20 && name.bytes().all(|bch| {
+        bch.is_ascii_uppercase() || bch.is_ascii_digit() || bch == b'-' || bch == b'_'
+    })
+}
+
+/// Error returned (as `LogicError::Custom`) by `Sasl` if the mechanism name is not valid
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvalidMechanismName(pub String);
+
+impl Display for InvalidMechanismName {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        write!(fter, "invalid sasl mechanism name: {:?}", self.0)
+    }
+}
+
+impl Error for InvalidMechanismName {}
+
+/// cancels the authentication exchange (by sending `"*"`) returning `err` as result
+fn cancel_auth(io: Io, err: LogicError)
+    -> impl Future<Item=(Io, SmtpResult), Error=std_io::Error> + Send
+{
+    io.flush_line_from_parts(&["*"])
+        .and_then(Io::parse_response)
+        .map(move |(io, _)| (io, Err(err)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn validates_mechanism_names() {
+        assert!(is_valid_mechanism_name("SCRAM-SHA-256"));
+        assert!(is_valid_mechanism_name("X_CUSTOM"));
+        assert!(!is_valid_mechanism_name(""));
+        assert!(!is_valid_mechanism_name("plain"));
+        assert!(!is_valid_mechanism_name("PLAIN\r\nQUIT"));
+        assert!(!is_valid_mechanism_name("PLAIN ="));
+        assert!(!is_valid_mechanism_name("A23456789012345678901"));
+    }
+}
@@ -0,0 +1,441 @@
+use std::{io as std_io};
+use std::fmt::{self, Display};
+use std::error::{Error as ErrorTrait};
+
+use base64::{encode, decode};
+use futures::future::{self, Future};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use sha2::{Sha256, Digest};
+#[cfg(feature="zeroize")]
+use zeroize::Zeroize;
+
+use ::future_ext::ResultWithContextExt;
+use ::{ExecFuture, Cmd, Io, EhloData, Response};
+use ::error::{LogicError, MissingCapabilities};
+
+use super::{validate_auth_capability, exec_if_secure, decode_challenge, Secret};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// gs2 header for "no channel binding, no authzid"
+const GS2_HEADER: &str = "n,,";
+/// base64 encoded `GS2_HEADER`
+const CHANNEL_BINDING: &str = "biws";
+/// length of the (raw) client nonce in bytes
+const NONCE_LEN: usize = 24;
+/// default for the maximal iteration count accepted from the server
+pub const DEFAULT_MAX_SCRAM_ITERATIONS: u32 = 100_000;
+
+/// AUTH SCRAM-SHA-256 smtp authentication based on rfc5802/rfc7677
+///
+/// This verifies the server signature, if it's missing or wrong
+/// a `LogicError::Custom` with a `ScramError` is returned.
+///
+/// Note that the password is used as is, i.e. it is not normalized
+/// using SASLprep.
+///
+/// The iteration count is chosen by the server and the key derivation
+/// runs synchronously while polling, so iteration counts above
+/// `max_iterations` (`DEFAULT_MAX_SCRAM_ITERATIONS` by default) are
+/// rejected with `ScramError::TooManyIterations`.
+#[derive(Debug, Clone)]
+pub struct Scram {
+    username: String,
+    password: Secret,
+    max_iterations: u32
+}
+
+impl Scram {
+
+    /// Create a new auth scram command based on username and password.
+    pub fn new<I1, I2>(username: I1, password: I2) -> Self
+        where I1: Into<String>, I2: Into<String>
+    {
+        Scram {
+            username: username.into(),
+            password: Secret::from(password.into()),
+            max_iterations: DEFAULT_MAX_SCRAM_ITERATIONS
+        }
+    }
+
+    /// Sets the maximal iteration count accepted from the server.
+    pub fn with_max_iterations(mut self, max_iterations: u32) -> Self {
+        self.max_iterations = max_iterations;
+        self
+    }
+
+    /// Returns the username contained in the `Scram` command.
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// Returns the maximal iteration count accepted from the server.
+    pub fn max_iterations(&self) -> u32 {
+        self.max_iterations
+    }
+
+    //intentionally no fn password(&self)!
+}
+
+impl Cmd for Scram {
+
+    fn check_cmd_availability(&self, caps: Option<&EhloData>)
+        -> Result<(), MissingCapabilities>
+    {
+        validate_auth_capability(caps, "SCRAM-SHA-256")
+    }
+
+    fn exec(self, io: Io) -> ExecFuture {
+        exec_if_secure(io, |io| self.send_credentials(io))
+    }
+
+    fn auth_identity(&self) -> Option<String> {
+        Some(format!("SCRAM-SHA-256 {}", self.username))
+    }
+}
+
+impl Scram {
+
+    fn send_credentials(self, mut io: Io) -> ExecFuture {
+        let Scram { username, password, max_iterations } = self;
+
+        let nonce = match new_nonce() {
+            Ok(nonce) => nonce,
+            Err(err) => return Box::new(future::err(err))
+        };
+
+        let client_first_bare = client_first_bare(&username, &nonce);
+        let client_first = encode(&format!("{}{}", GS2_HEADER, client_first_bare));
+        io.write_line_from_parts(&["AUTH SCRAM-SHA-256 ", &client_first]);
+
+        let fut = io
+            .flush()
+            .and_then(Io::parse_response)
+            .ctx_and_then(move |io, response| -> ExecFuture {
+                if !response.code().is_intermediate() {
+                    return Box::new(future::ok((io, Err(LogicError::UnexpectedCode(response)))));
+                }
+
+                let res = decode_scram_challenge(&response)
+                    .and_then(|server_first| {
+                        client_final(&password, &nonce, &client_first_bare, &server_first, max_iterations)
+                            .map_err(scram_error)
+                    });
+
+                let ClientFinal { message, server_signature } = match res {
+                    Ok(client_final) => client_final,
+                    Err(err) => return cancel_auth(io, err)
+                };
+
+                let fut = io
+                    .flush_line_from_parts(&[&encode(&message)])
+                    .and_then(Io::parse_response)
+                    .ctx_and_then(move |io, response| -> ExecFuture {
+                        let verified = decode_scram_challenge(&response)
+                            .and_then(|server_final| {
+                                verify_server_final(&server_final, &server_signature)
+                                    .map_err(scram_error)
+                            });
+
+                        if response.code().is_intermediate() {
+                            // server-final is send as challenge and we have to
+                            // answer with an empty line to complete the exchange
+                            if let Err(err) = verified {
+                                return cancel_auth(io, err);
+                            }
+                            let fut = io
+                                .flush_line_from_parts(&[])
+                                .and_then(Io::parse_response);
+                            Box::new(fut)
+                        } else if let Err(err) = verified {
+                            // server-final as additional data with the success response
+                            Box::new(future::ok((io, Err(err))))
+                        } else {
+                            Box::new(future::ok((io, Ok(response))))
+                        }
+                    });
+
+                Box::new(fut)
+            });
+
+        Box::new(fut)
+    }
+}
+
+/// cancels the authentication exchange (by sending `"*"`) returning `err` as result
+fn cancel_auth(io: Io, err: LogicError) -> ExecFuture {
+    let fut = io
+        .flush_line_from_parts(&["*"])
+        .and_then(Io::parse_response)
+        .map(move |(io, _)| (io, Err(err)));
+
+    Box::new(fut)
+}
+
+fn new_nonce() -> Result<String, std_io::Error> {
+    let mut rng = OsRng::new()
+        .map_err(|err| std_io::Error::new(std_io::ErrorKind::Other, err))?;
+    let mut raw = [0u8; NONCE_LEN];
+    rng.fill_bytes(&mut raw);
+    Ok(encode(&raw))
+}
+
+fn scram_error(err: ScramError) -> LogicError {
+    LogicError::Custom(Box::new(err))
+}
+
+/// decodes the challenge (see `auth::decode_challenge`) which has to be UTF-8
+fn decode_scram_challenge(response: &Response) -> Result<String, LogicError> {
+    let raw = decode_challenge(response)?;
+    String::from_utf8(raw).map_err(|_| scram_error(ScramError::MalformedServerMessage))
+}
+
+/// escapes `','` and `'='` in the username as required by rfc5802
+fn client_first_bare(username: &str, nonce: &str) -> String {
+    let username = username.replace('=', "=3D").replace(',', "=2C");
+    format!("n={},r={}", username, nonce)
+}
+
+struct ClientFinal {
+    message: String,
+    server_signature: Vec<u8>
+}
+
+fn client_final(
+    password: &str,
+    client_nonce: &str,
+    client_first_bare: &str,
+    server_first: &str,
+    max_iterations: u32
+) -> Result<ClientFinal, ScramError>
+{
+    let mut nonce = None;
+    let mut salt = None;
+    let mut iterations = None;
+    for (key, value) in server_first.split(',').map(split_attribute) {
+        match key {
+            "m" => return Err(ScramError::UnsupportedExtension),
+            "r" => nonce = Some(value),
+            "s" => salt = decode(value).ok(),
+            "i" => iterations = value.parse::<u32>().ok(),
+            _ => {}
+        }
+    }
+
+    let (nonce, salt, iterations) =
+        match (nonce, salt, iterations) {
+            (Some(nonce), Some(salt), Some(iterations)) if iterations > 0 => (nonce, salt, iterations),
+            _ => return Err(ScramError::MalformedServerMessage)
+        };
+
+    if !nonce.starts_with(client_nonce) || nonce.len() == client_nonce.len() {
+        return Err(ScramError::NonceMismatch);
+    }
+
+    if iterations > max_iterations {
+        return Err(ScramError::TooManyIterations(iterations));
+    }
+
+    let without_proof = format!("c={},r={}", CHANNEL_BINDING, nonce);
+    let auth_message = format!("{},{},{}", client_first_bare, server_first, without_proof);
+
+    let mut salted_password = hi(password.as_bytes(), &salt, iterations);
+    let mut client_key = hmac(&salted_password, b"Client Key");
+    let stored_key = Sha256::digest(&client_key);
+    let client_signature = hmac(&stored_key, auth_message.as_bytes());
+    let client_proof = client_key.iter()
+        .zip(client_signature.iter())
+        .map(|(key, sig)| key ^ sig)
+        .collect::<Vec<u8>>();
+
+    let server_key = hmac(&salted_password, b"Server Key");
+    let server_signature = hmac(&server_key, auth_message.as_bytes());
+
+    wipe(&mut salted_password);
+    wipe(&mut client_key);
+
+    Ok(ClientFinal {
+        message: format!("{},p={}", without_proof, encode(&client_proof)),
+        server_signature
+    })
+}
+
+fn verify_server_final(server_final: &str, expected_signature: &[u8]) -> Result<(), ScramError> {
+    let (key, value) = split_attribute(server_final.split(',').next().unwrap_or(""));
+    match key {
+        "v" => {},
+        "e" => return Err(ScramError::Server(value.to_owned())),
+        _ => return Err(ScramError::MalformedServerMessage)
+    }
+    let signature = decode(value).map_err(|_| ScramError::MalformedServerMessage)?;
+
+    let mut mac = HmacSha256::new_from_slice(b"")
+        .expect("hmac accepts keys of any length");
+    // constant time comparison, by comparing macs of both signatures
+    mac.update(expected_signature);
+    let expected = mac.finalize().into_bytes();
+    let mut mac = HmacSha256::new_from_slice(b"")
+        .expect("hmac accepts keys of any length");
+    mac.update(&signature);
+    if mac.verify_slice(&expected).is_ok() {
+        Ok(())
+    } else {
+        Err(ScramError::ServerSignatureMismatch)
+    }
+}
+
+/// splits a `"k=value"` attribute into `("k", "value")`
+fn split_attribute(attribute: &str) -> (&str, &str) {
+    match attribute.find('=') {
+        Some(idx) => (&attribute[..idx], &attribute[idx+1..]),
+        None => (attribute, "")
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key)
+        .expect("hmac accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// the `Hi` function from rfc5802 (i.e. PBKDF2 with HMAC-SHA-256)
+fn hi(password: &[u8], salt: &[u8], iterations: u32) -> Vec<u8> {
+    let mut first_input = salt.to_vec();
+    first_input.extend_from_slice(&[0, 0, 0, 1]);
+
+    let mut last = hmac(password, &first_input);
+    let mut result = last.clone();
+    for _ in 1..iterations {
+        last = hmac(password, &last);
+        for (res, bch) in result.iter_mut().zip(last.iter()) {
+            *res ^= *bch;
+        }
+    }
+    wipe(&mut last);
+    result
+}
+
+/// zeros (if the `zeroize` feature is enabled) and clears derived key material
+fn wipe(key: &mut Vec<u8>) {
+    #[cfg(feature="zeroize")]
+    key.zeroize();
+    #[cfg(not(feature="zeroize"))]
+    key.clear();
+}
+
+/// Error returned (as `LogicError::Custom`) if the scram exchange failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScramError {
+    /// a message from the server could not be parsed
+    MalformedServerMessage,
+    /// the server nonce doesn't extend the client nonce
+    NonceMismatch,
+    /// the server requested a mandatory extension we don't support
+    UnsupportedExtension,
+    /// the server reported a error in it's final message
+    Server(String),
+    /// the iteration count requested by the server is above the configured maximum
+    TooManyIterations(u32),
+    /// the server signature is missing or doesn't match, i.e. the server
+    /// could not proof that it knows the password
+    ServerSignatureMismatch
+}
+
+impl Display for ScramError {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        use self::ScramError::*;
+        match *self {
+            MalformedServerMessage => fter.write_str("malformed scram message from server"),
+            NonceMismatch => fter.write_str("server nonce does not extend client nonce"),
+            UnsupportedExtension => fter.write_str("server requires unsupported scram extension"),
+            Server(ref msg) => write!(fter, "server reported scram error: {}", msg),
+            TooManyIterations(count) =>
+                write!(fter, "server requested too many scram iterations: {}", count),
+            ServerSignatureMismatch => fter.write_str("server signature could not be verified"),
+        }
+    }
+}
+
+impl ErrorTrait for ScramError {
+    fn description(&self) -> &str {
+        "scram authentication failed"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // test vectors from RFC 7677 section 3
+    const NONCE: &str = "rOprNGfwEbeRWgbNEkqO";
+    const SERVER_FIRST: &str = concat!(
+        "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,",
+        "s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096"
+    );
+    const CLIENT_FINAL: &str = concat!(
+        "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,",
+        "p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+    );
+    const SERVER_FINAL: &str = "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=";
+
+    #[test]
+    fn client_first_matches_rfc_example() {
+        assert_eq!(client_first_bare("user", NONCE), "n=user,r=rOprNGfwEbeRWgbNEkqO");
+    }
+
+    #[test]
+    fn escapes_username() {
+        assert_eq!(client_first_bare("a=b,c", NONCE), "n=a=3Db=2Cc,r=rOprNGfwEbeRWgbNEkqO");
+    }
+
+    #[test]
+    fn computes_rfc_example_proof_and_signature() {
+        let client_first_bare = client_first_bare("user", NONCE);
+        let ClientFinal { message, server_signature } =
+            client_final("pencil", NONCE, &client_first_bare, SERVER_FIRST, DEFAULT_MAX_SCRAM_ITERATIONS).unwrap();
+
+        assert_eq!(message, CLIENT_FINAL);
+        assert_eq!(verify_server_final(SERVER_FINAL, &server_signature), Ok(()));
+    }
+
+    #[test]
+    fn rejects_wrong_server_signature() {
+        let client_first_bare = client_first_bare("user", NONCE);
+        let ClientFinal { server_signature, .. } =
+            client_final("not-pencil", NONCE, &client_first_bare, SERVER_FIRST, DEFAULT_MAX_SCRAM_ITERATIONS).unwrap();
+
+        assert_eq!(
+            verify_server_final(SERVER_FINAL, &server_signature),
+            Err(ScramError::ServerSignatureMismatch)
+        );
+    }
+
+    #[test]
+    fn rejects_nonce_not_extending_client_nonce() {
+        let client_first_bare = client_first_bare("user", "other");
+        let res = client_final("pencil", "other", &client_first_bare, SERVER_FIRST, DEFAULT_MAX_SCRAM_ITERATIONS);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn rejects_iteration_count_above_maximum() {
+        let client_first_bare = client_first_bare("user", NONCE);
+        let server_first = concat!(
+            "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,",
+            "s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4294967295"
+        );
+        let res = client_final("pencil", NONCE, &client_first_bare, server_first, DEFAULT_MAX_SCRAM_ITERATIONS);
+        assert_eq!(res.err(), Some(ScramError::TooManyIterations(4294967295)));
+
+        let res = client_final("pencil", NONCE, &client_first_bare, SERVER_FIRST, 4095);
+        assert_eq!(res.err(), Some(ScramError::TooManyIterations(4096)));
+    }
+
+    #[test]
+    fn nonce_is_random() {
+        assert_ne!(new_nonce().unwrap(), new_nonce().unwrap());
+    }
+}
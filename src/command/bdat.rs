@@ -0,0 +1,206 @@
+use std::error::Error;
+use std::fmt::{self, Display};
+
+use bytes::{BufMut, Bytes};
+use futures::future::{self, Future, Loop};
+
+use ::{ExecFuture, Cmd, Io, EhloData};
+use ::error::MissingCapabilities;
+
+/// Sends a mail body using `BDAT` (RFC 3030), requires `CHUNKING`
+///
+/// Unlike `Data` the body is send as is, i.e. without dot-stashing and
+/// without any line ending normalization, each chunk is prefixed by a
+/// `BDAT <size>` line and acknowledged by the server. The last chunk is
+/// send with `BDAT <size> LAST`, its response is the response of the command.
+///
+/// By default the whole body is send as a single chunk, but it can be
+/// split into fixed size chunks (`with_chunk_size`) or at explicit offsets
+/// (`with_split_points`), e.g. to align chunks with MIME part boundaries.
+#[derive(Debug, Clone)]
+pub struct Bdat {
+    body: Bytes,
+    split_points: Vec<usize>
+}
+
+impl Bdat {
+
+    pub fn new<B>(body: B) -> Self
+        where B: Into<Bytes>
+    {
+        Bdat { body: body.into(), split_points: Vec::new() }
+    }
+
+    /// splits the body into chunks of `chunk_size` bytes (the last chunk can be smaller)
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is 0.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "chunk size has to be greater than 0");
+        self.split_points = (1..)
+            .map(|idx| idx * chunk_size)
+            .take_while(|&offset| offset < self.body.len())
+            .collect();
+        self
+    }
+
+    /// splits the body at given byte offsets
+    ///
+    /// A chunk ends before each offset, i.e. the offsets `[10, 25]` send
+    /// the bytes `0..10`, `10..25` and `25..` as separate chunks. The offsets
+    /// have to be strictly increasing and within `1..body.len()`, so that
+    /// no empty chunk is send.
+    pub fn with_split_points(mut self, split_points: Vec<usize>) -> Result<Self, InvalidSplitPoint> {
+        let len = self.body.len();
+        let mut previous = 0;
+        for &offset in split_points.iter() {
+            if offset == 0 || offset >= len {
+                return Err(InvalidSplitPoint::OutOfRange { offset, len });
+            }
+            if offset <= previous {
+                return Err(InvalidSplitPoint::NotIncreasing { offset, previous });
+            }
+            previous = offset;
+        }
+        self.split_points = split_points;
+        Ok(self)
+    }
+
+    /// the sizes of the chunks which will be send
+    pub fn chunk_sizes(&self) -> Vec<usize> {
+        let ends = self.split_points.iter()
+            .cloned()
+            .chain(Some(self.body.len()));
+
+        let mut start = 0;
+        ends.map(|end| {
+            let size = end - start;
+            start = end;
+            size
+        }).collect()
+    }
+
+    fn into_chunks(self) -> Vec<Bytes> {
+        let sizes = self.chunk_sizes();
+        let mut body = self.body;
+        sizes.into_iter()
+            .map(|size| body.split_to(size))
+            .collect()
+    }
+}
+
+impl Cmd for Bdat {
+
+    fn check_cmd_availability(&self, caps: Option<&EhloData>)
+        -> Result<(), MissingCapabilities>
+    {
+        let supported = caps.map(|ehlo_data| ehlo_data.caps().has_chunking()).unwrap_or(false);
+        if supported {
+            Ok(())
+        } else {
+            Err(MissingCapabilities::new_from_unchecked("CHUNKING"))
+        }
+    }
+
+    fn exec(self, io: Io) -> ExecFuture {
+        let chunks = self.into_chunks().into_iter();
+
+        let fut = future::loop_fn((io, chunks), |(mut io, mut chunks)| {
+            let chunk = chunks.next().expect("[BUG] there is always at least one chunk");
+            let last = chunks.len() == 0;
+
+            let size = chunk.len().to_string();
+            if last {
+                io.write_line_from_parts(&["BDAT ", &size, " LAST"]);
+            } else {
+                io.write_line_from_parts(&["BDAT ", &size]);
+            }
+            io.out_buffer(chunk.len()).put(chunk);
+
+            io.flush()
+                .and_then(Io::parse_response)
+                .map(move |(io, result)| match result {
+                    Ok(_) if !last => Loop::Continue((io, chunks)),
+                    result => Loop::Break((io, result))
+                })
+        });
+
+        Box::new(fut)
+    }
+}
+
+/// Error returned by `Bdat::with_split_points` if a offset is invalid
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum InvalidSplitPoint {
+    /// the offset is 0 or not less than the length of the body
+    OutOfRange {
+        offset: usize,
+        len: usize
+    },
+    /// the offset is not greater than the previous one
+    NotIncreasing {
+        offset: usize,
+        previous: usize
+    }
+}
+
+impl Display for InvalidSplitPoint {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InvalidSplitPoint::OutOfRange { offset, len } =>
+                write!(fter, "split point {} is not in the range 1..{}", offset, len),
+            InvalidSplitPoint::NotIncreasing { offset, previous } =>
+                write!(fter, "split point {} is not greater than the previous one ({})", offset, previous)
+        }
+    }
+}
+
+impl Error for InvalidSplitPoint {
+    fn description(&self) -> &str {
+        match *self {
+            InvalidSplitPoint::OutOfRange { .. } => "split point is out of range",
+            InvalidSplitPoint::NotIncreasing { .. } => "split points are not strictly increasing"
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sends_single_chunk_by_default() {
+        assert_eq!(Bdat::new("0123456789").chunk_sizes(), vec![10]);
+        assert_eq!(Bdat::new("").chunk_sizes(), vec![0]);
+    }
+
+    #[test]
+    fn splits_into_fixed_size_chunks() {
+        assert_eq!(Bdat::new("0123456789").with_chunk_size(4).chunk_sizes(), vec![4, 4, 2]);
+        assert_eq!(Bdat::new("0123456789").with_chunk_size(5).chunk_sizes(), vec![5, 5]);
+        assert_eq!(Bdat::new("0123456789").with_chunk_size(20).chunk_sizes(), vec![10]);
+    }
+
+    #[test]
+    fn splits_at_given_offsets() {
+        let bdat = Bdat::new("0123456789").with_split_points(vec![3, 4, 8]).unwrap();
+        assert_eq!(bdat.chunk_sizes(), vec![3, 1, 4, 2]);
+        let chunks = bdat.into_chunks();
+        assert_eq!(chunks, vec![
+            Bytes::from("012"), Bytes::from("3"), Bytes::from("4567"), Bytes::from("89")
+        ]);
+    }
+
+    #[test]
+    fn rejects_invalid_split_points() {
+        let err = Bdat::new("0123456789").with_split_points(vec![0, 5]).unwrap_err();
+        assert_eq!(err, InvalidSplitPoint::OutOfRange { offset: 0, len: 10 });
+        let err = Bdat::new("0123456789").with_split_points(vec![5, 10]).unwrap_err();
+        assert_eq!(err, InvalidSplitPoint::OutOfRange { offset: 10, len: 10 });
+        let err = Bdat::new("0123456789").with_split_points(vec![5, 5]).unwrap_err();
+        assert_eq!(err, InvalidSplitPoint::NotIncreasing { offset: 5, previous: 5 });
+        let err = Bdat::new("0123456789").with_split_points(vec![6, 3]).unwrap_err();
+        assert_eq!(err, InvalidSplitPoint::NotIncreasing { offset: 3, previous: 6 });
+    }
+}
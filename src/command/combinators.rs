@@ -56,6 +56,12 @@ where
             EitherCmd::B(b) => b.exec(con),
         }
     }
+    fn auth_identity(&self) -> Option<String> {
+        match self {
+            EitherCmd::A(a) => a.auth_identity(),
+            EitherCmd::B(b) => b.auth_identity(),
+        }
+    }
 }
 
 /// An alternative of two commands
@@ -104,4 +110,11 @@ where
             Box::new(self.1.exec(con))
         }
     }
+    fn auth_identity(&self) -> Option<String> {
+        // which of both is used is only known once connected
+        match (self.0.auth_identity(), self.1.auth_identity()) {
+            (None, None) => None,
+            (a, b) => Some(format!("{} | {}", a.unwrap_or_default(), b.unwrap_or_default())),
+        }
+    }
 }
@@ -1,18 +1,31 @@
 use std::{io as std_io};
 
-use bytes::{Buf, IntoBuf};
-use futures::future::{self, Either, Future};
+use std::io::Cursor;
+use std::time::Duration;
+
+use bytes::{Buf, Bytes, IntoBuf};
+use futures::future::{Future, Either};
 use futures::stream::{self, Stream};
-use future_ext::ResultWithContextExt;
 
 use ::{ExecFuture, Cmd, Io, EhloData};
+use ::future_ext::ResultWithContextExt;
+use ::io::{
+    DataWriteOptions, LineLengthMode, BareLineEndingMode, ProgressFn,
+    DataAbortHandle, DataAbortSignal, DataWriteOutcome, data_abort_channel
+};
+use ::error::MissingCapabilities;
 use ::response::codes;
-use ::error::{LogicError, MissingCapabilities};
+use super::BodyMode;
 
 
 pub struct Data<S> {
     //TODO add parameter support
-    source: S
+    source: S,
+    options: DataWriteOptions,
+    body_mode: Option<BodyMode>,
+    progress: Option<ProgressFn>,
+    abort: Option<DataAbortSignal>,
+    liveness_check: Option<Duration>
 }
 
 impl<BF> Data<stream::Once<BF, std_io::Error>>
@@ -23,11 +36,90 @@ impl<BF> Data<stream::Once<BF, std_io::Error>>
     }
 }
 
+impl<BS> Data<stream::Map<BS, fn(Bytes) -> Cursor<Bytes>>>
+    where BS: Stream<Item=Bytes, Error=std_io::Error>
+{
+    /// creates a `Data` command sending the chunks of given stream
+    ///
+    /// Dot-stashing is applied across chunk boundaries, i.e. the data
+    /// can be split arbitrarily.
+    pub fn from_bytes_stream(source: BS) -> Self {
+        Data::new(source.map(Bytes::into_buf as fn(Bytes) -> Cursor<Bytes>))
+    }
+}
+
 impl<S> Data<S>
     where S: Stream<Error=std_io::Error>, S::Item: Buf
 {
     pub fn new(source: S) -> Self {
-        Data { source }
+        Data { source, options: DataWriteOptions::default(), body_mode: None, progress: None, abort: None, liveness_check: None }
+    }
+
+    /// sets how lines longer than `io::MAX_LINE_LENGTH` are handled
+    ///
+    /// The default is `LineLengthMode::Ignore`.
+    pub fn with_line_length_mode(mut self, mode: LineLengthMode) -> Self {
+        self.options.line_length = mode;
+        self
+    }
+
+    /// sets how a bare `'\r'` or `'\n'` is handled
+    ///
+    /// The default is `BareLineEndingMode::Normalize`.
+    pub fn with_bare_line_ending_mode(mut self, mode: BareLineEndingMode) -> Self {
+        self.options.bare_line_ending = mode;
+        self
+    }
+
+    /// sets the body mode the data is checked against
+    ///
+    /// With `BodyMode::SevenBit` the data must not contain any octet > 127,
+    /// `BodyMode::EightBitMime` allows them but requires the server to
+    /// support `8BITMIME`. Note that this has to match the body mode used
+    /// with the `MAIL` command. By default the data is not checked.
+    pub fn with_body_mode(mut self, mode: BodyMode) -> Self {
+        self.options.allow_8bit = mode == BodyMode::EightBitMime;
+        self.body_mode = Some(mode);
+        self
+    }
+
+    /// calls `progress` with the total number of data bytes send so far
+    ///
+    /// It's called each time a chunk of the source was flushed, see
+    /// `DotStashedWrite::with_progress` for details.
+    pub fn with_progress<F>(mut self, progress: F) -> Self
+        where F: FnMut(u64) + Send + 'static
+    {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// returns a handle which can be used to abort the data transfer
+    ///
+    /// If the transfer is aborted (e.g. because the source of a large
+    /// mail failed) no further data is send, especially not the end of
+    /// mail sequence, so that the server does not accept a truncated mail.
+    /// The command then fails with `LogicError::Aborted` and the connection
+    /// is poisoned, as the transaction can not be completed cleanly.
+    pub fn abortable(mut self) -> (Self, DataAbortHandle) {
+        let (handle, signal) = data_abort_channel();
+        self.abort = Some(signal);
+        (self, handle)
+    }
+
+    /// sends a `NOOP` before `DATA` to check that the connection is still alive
+    ///
+    /// As a failed data transfer can not be resumed this reduces the chance
+    /// of starting to upload a large mail into a dead connection. It's only
+    /// best-effort, the connection can still break during the transfer.
+    ///
+    /// If the last command completed less than `recent` ago the connection
+    /// is assumed to be alive and no `NOOP` is send (pass a zero duration to
+    /// always send it). If the `NOOP` fails `DATA` is not send, instead the
+    /// I/O-Error or the `LogicError` of the `NOOP` is returned.
+    pub fn with_liveness_check(mut self, recent: Duration) -> Self {
+        self.liveness_check = Some(recent);
+        self
     }
 }
 
@@ -35,31 +127,50 @@ impl<S: 'static> Cmd for Data<S>
     where S: Stream<Error=std_io::Error> + Send, S::Item: Buf
 {
 
-    fn check_cmd_availability(&self, _caps: Option<&EhloData>)
+    fn check_cmd_availability(&self, caps: Option<&EhloData>)
         -> Result<(), MissingCapabilities>
     {
-        Ok(())
+        match self.body_mode {
+            Some(mode) => mode.check_availability(caps),
+            None => Ok(())
+        }
     }
 
     fn exec(self, io: Io) -> ExecFuture {
-        let Data { source } = self;
+        match self.liveness_check {
+            Some(recent) if io.idle_duration() >= recent => {
+                let fut = io
+                    .exec_simple_cmd(&["NOOP"])
+                    .ctx_and_then(move |io, _response| self.send_data(io));
+                Box::new(fut)
+            },
+            _ => self.send_data(io)
+        }
+    }
 
-        let fut = io
-            .flush_line_from_parts(&["DATA"])
-            .and_then(Io::parse_response)
-            .ctx_and_then(move |io, response| {
-                if response.code() != codes::START_MAIL_DATA {
-                    return Either::A(future::ok((io, Err(LogicError::UnexpectedCode(response)))));
-                }
+}
 
-                let fut = io
-                    .write_dot_stashed(source)
-                    .and_then(Io::parse_response);
+impl<S: 'static> Data<S>
+    where S: Stream<Error=std_io::Error> + Send, S::Item: Buf
+{
+
+    fn send_data(self, io: Io) -> ExecFuture {
+        let Data { source, options, progress, abort, .. } = self;
 
-                Either::B(fut)
-            });
+        // only the code is relevant, the text (and number of lines) of the reply
+        // differs between servers, `parse_response` consumes all lines of it
+        io.send_expecting_intermediate(&["DATA"], codes::START_MAIL_DATA, move |io, _response| {
+            let write = io.write_dot_stashed_with_options(source, options);
+            let write = match progress {
+                Some(progress) => write.with_progress(progress),
+                None => write
+            };
 
-        Box::new(fut)
+            match abort {
+                Some(abort) => Either::A(write.abort_on(abort)),
+                None => Either::B(write.map(DataWriteOutcome::Completed))
+            }
+        })
     }
 
 }
\ No newline at end of file
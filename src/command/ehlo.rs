@@ -6,7 +6,7 @@ use futures::Future;
 
 use ::error::MissingCapabilities;
 use ::{
-    Domain, EhloData, SyntaxError, EhloParam,
+    Domain, EhloData, SyntaxError, EhloParam, HandshakeKind,
     Cmd, ExecFuture, Io, Response, ClientId
 };
 
@@ -48,18 +48,7 @@ impl Cmd for Ehlo {
     }
 
     fn exec(self, mut io: Io) -> ExecFuture {
-        let str_me = match *self.identity() {
-            ClientId::Domain(ref domain) => domain.as_str(),
-            ClientId::AddressLiteral(ref addr_lit) => addr_lit.as_str()
-        };
-
-        {
-            //7 == "EHLO ".len() + "\r\n".len()
-            let out = io.out_buffer(7 + str_me.len());
-            out.put("EHLO ");
-            out.put(str_me);
-            out.put("\r\n");
-        }
+        write_greeting(&mut io, "EHLO ", self.identity());
 
         let fut = io
             .flush()
@@ -72,6 +61,7 @@ impl Cmd for Ehlo {
                         .map_err(|err| std_io::Error::new(std_io::ErrorKind::Other, err))?;
 
                     io.set_ehlo_data(ehlo);
+                    io.set_handshake_kind(HandshakeKind::Ehlo);
                     Ok((io, Ok(response)))
                 }
             });
@@ -80,11 +70,93 @@ impl Cmd for Ehlo {
     }
 }
 
+/// Sends `HELO` instead of `EHLO`, i.e. uses basic smtp without extensions
+///
+/// As `HELO` doesn't list any capabilities empty `EhloData` is stored,
+/// so all capability checks fail after sending it. The domain of the
+/// stored `EhloData` is the first word of the reply _unchecked_, as it
+/// isn't always a valid domain (e.g. `"250 [192.0.2.1]"`).
+#[derive(Debug, Clone)]
+pub struct Helo {
+    identity: ClientId
+}
+
+impl Helo {
+
+    pub fn new(identity: ClientId) -> Self {
+        Helo { identity }
+    }
+
+    pub fn identity(&self) -> &ClientId {
+        &self.identity
+    }
+}
+
+impl From<ClientId> for Helo {
+    fn from(identity: ClientId) -> Self {
+        Helo { identity }
+    }
+}
+
+impl Into<ClientId> for Helo {
+    fn into(self) -> ClientId {
+        self.identity
+    }
+}
+
+impl Cmd for Helo {
+
+    fn check_cmd_availability(&self, _caps: Option<&EhloData>)
+        -> Result<(), MissingCapabilities>
+    {
+        Ok(())
+    }
+
+    fn exec(self, mut io: Io) -> ExecFuture {
+        write_greeting(&mut io, "HELO ", self.identity());
+
+        let fut = io
+            .flush()
+            .and_then(Io::parse_response)
+            .map(|(mut io, result)| match result {
+                Err(response) => (io, Err(response)),
+                Ok(response) => {
+                    let domain = Domain::from_unchecked(greeting_domain_str(&response));
+
+                    io.set_ehlo_data(EhloData::new(domain, HashMap::new()));
+                    io.set_handshake_kind(HandshakeKind::Helo);
+                    (io, Ok(response))
+                }
+            });
+
+        Box::new(fut)
+    }
+}
+
+/// writes e.g. `"EHLO <identity>\r\n"` to the output buffer
+fn write_greeting(io: &mut Io, cmd: &str, identity: &ClientId) {
+    let str_me = identity.as_str();
+    let out = io.out_buffer(cmd.len() + str_me.len() + 2);
+    out.put(cmd);
+    out.put(str_me);
+    out.put("\r\n");
+}
+
+/// returns the (unparsed) domain from the first line of a `EHLO`/`HELO` response
+fn greeting_domain_str(response: &Response) -> &str {
+    let first = response.msg().first().expect("response with 0 lines should not");
+    //UNWRAP_SAFE: Split has at last one entry
+    first.split(" ").next().unwrap()
+}
+
+/// parses the domain from the first line of a `EHLO` response
+fn parse_greeting_domain(response: &Response) -> Result<Domain, SyntaxError> {
+    greeting_domain_str(response).parse()
+}
+
 fn parse_ehlo_response(response: &Response) -> Result<EhloData, SyntaxError> {
     let lines = response.msg();
-    let first = lines.first().expect("response with 0 lines should not");
-    //UNWRAP_SAFE: Split has at last one entry
-    let domain: Domain = first.split(" ").next().unwrap().parse()?;
+    let domain = parse_greeting_domain(response)?;
     let mut caps = HashMap::new();
 
     for line in lines[1..].iter() {
@@ -156,5 +228,51 @@ mod test {
             assert_eq!(params.len(), 1);
             assert_eq!(params[0], "ENABLED");
         }
+
+        #[test]
+        fn caps_reflect_ehlo_response() {
+            let response = Response::new(OK, vec![
+                "1aim.test says hy".to_owned(),
+                "SIZE 35882577".to_owned(),
+                "8BITMIME".to_owned(),
+                "AUTH login PLAIN XOAUTH2".to_owned(),
+                "smtputf8".to_owned(),
+                "DSN".to_owned(),
+                "X-NOT-A-ROBOT".to_owned(),
+            ]);
+            let ehlo_data = parse_ehlo_response(&response).unwrap();
+            let caps = ehlo_data.caps();
+
+            assert!(caps.has_size());
+            assert_eq!(caps.max_message_size(), Some(35882577));
+            assert!(caps.has_auth());
+            assert_eq!(caps.auth_mechanisms(), &["LOGIN", "PLAIN", "XOAUTH2"]);
+            assert!(caps.supports_auth_mechanism("plain"));
+            assert!(!caps.supports_auth_mechanism("CRAM-MD5"));
+            assert!(caps.has_smtputf8());
+            assert!(caps.has_dsn());
+            assert!(caps.has_8bitmime());
+            assert!(!caps.has_pipelining());
+            assert!(!caps.has_starttls());
+            assert!(!caps.has_chunking());
+            assert!(!caps.has_enhanced_status_codes());
+        }
+
+        #[test]
+        fn parses_recipient_limit() {
+            let response = Response::new(OK, vec![
+                "1aim.test says hy".to_owned(),
+                "LIMITS MAILMAX=10 rcptmax=50".to_owned(),
+            ]);
+            let ehlo_data = parse_ehlo_response(&response).unwrap();
+            assert_eq!(ehlo_data.max_recipients(), Some(50));
+
+            let response = Response::new(OK, vec![
+                "1aim.test says hy".to_owned(),
+                "LIMITS MAILMAX=10".to_owned(),
+            ]);
+            let ehlo_data = parse_ehlo_response(&response).unwrap();
+            assert_eq!(ehlo_data.max_recipients(), None);
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,64 @@
+use ::{ExecFuture, Cmd, Io, EhloData};
+use ::error::MissingCapabilities;
+
+/// Trait for simple (single line) ESMTP extension commands
+///
+/// It allows adding commands not provided by this crate without having to
+/// implement `Cmd` by hand. `Cmd` is implemented for any `ExtensionCmd`:
+///
+/// - `check_cmd_availability` checks that the server advertised
+///   `required_capability` (if any)
+/// - `exec` sends `command_line` and reads the response, a line
+///   longer than `io::MAX_LINE_LENGTH` is not send (see `Io::exec_simple_cmd`)
+///
+/// # Example
+///
+/// ```
+/// use new_tokio_smtp::command::ExtensionCmd;
+///
+/// /// ETRN as specified in RFC 1985
+/// struct Etrn {
+///     node: String
+/// }
+///
+/// impl ExtensionCmd for Etrn {
+///     fn required_capability(&self) -> Option<&str> {
+///         Some("ETRN")
+///     }
+///
+///     fn command_line(&self) -> String {
+///         format!("ETRN {}", self.node)
+///     }
+/// }
+/// ```
+pub trait ExtensionCmd: Send + 'static {
+
+    /// the ehlo keyword the server has to advertise, `None` if there is no such keyword
+    fn required_capability(&self) -> Option<&str>;
+
+    /// the command line including all parameters but without the trailing `"\r\n"`
+    fn command_line(&self) -> String;
+}
+
+impl<C> Cmd for C
+    where C: ExtensionCmd
+{
+    fn check_cmd_availability(&self, caps: Option<&EhloData>)
+        -> Result<(), MissingCapabilities>
+    {
+        match self.required_capability() {
+            None => Ok(()),
+            Some(cap) => {
+                if caps.map(|ehlo_data| ehlo_data.has_capability(cap)).unwrap_or(false) {
+                    Ok(())
+                } else {
+                    Err(MissingCapabilities::new_from_unchecked(cap))
+                }
+            }
+        }
+    }
+
+    fn exec(self, io: Io) -> ExecFuture {
+        io.exec_simple_cmd(&[&self.command_line()])
+    }
+}
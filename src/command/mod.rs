@@ -1,6 +1,6 @@
 //! Module containing all commands already provided by this crate
 mod ehlo;
-pub use self::ehlo::Ehlo;
+pub use self::ehlo::{Ehlo, Helo};
 
 mod simple;
 pub use self::simple::*;
@@ -11,6 +11,9 @@ pub use self::starttls::*;
 mod data;
 pub use self::data::*;
 
+mod bdat;
+pub use self::bdat::*;
+
 pub mod auth;
 
 mod reset;
@@ -18,3 +21,6 @@ pub use self::reset::*;
 
 mod combinators;
 pub use self::combinators::*;
+
+mod extension;
+pub use self::extension::*;
@@ -1,8 +1,14 @@
 use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+use std::ops::RangeInclusive;
 
+use futures::{future, Future};
+
+use ::util::{xtext, date};
 use ::data_types::{ReversePath, ForwardPath, EsmtpKeyword, EsmtpValue};
 use ::common::EhloData;
-use ::error::MissingCapabilities;
+use ::error::{LogicError, MissingCapabilities};
+use ::response::Response;
 use ::{ExecFuture, Cmd, Io};
 
 /// Quit command, but as it makes the connection unusable we do
@@ -49,32 +55,442 @@ pub fn params_with_smtputf8(mut p: Params) -> Params {
     p
 }
 
+/// The `MAIL FROM:` command
+///
+/// It's created with `Mail::new` and the `with_*` methods, as new
+/// parameters are added over time it can't be created through a struct
+/// literal outside of this crate.
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub struct Mail {
     pub reverse_path: ReversePath,
-    pub params: Params
+    pub params: Params,
+    /// the `AUTH=` parameter (RFC 4954), only send if the server supports `AUTH`
+    pub auth: Option<MailAuth>,
+    /// the `RET=` parameter (RFC 3461), only send if the server supports `DSN`
+    pub dsn_return: Option<DsnReturn>,
+    /// the `ENVID=` parameter (RFC 3461), only send if the server supports `DSN`
+    ///
+    /// This is the unencoded envelope id, it is xtext encoded when send.
+    pub envelop_id: Option<String>,
+    /// the `BODY=` parameter (RFC 6152), `EightBitMime` requires `8BITMIME`
+    ///
+    /// `SevenBit` is only send if the server supports `8BITMIME`.
+    pub body: Option<BodyMode>,
+    /// the `HOLDFOR=`/`HOLDUNTIL=` parameter (RFC 4865), requires `FUTURERELEASE`
+    ///
+    /// If the hold time exceeds the maximal hold time advertised by the server
+    /// the command fails with `LogicError::HoldTooLong` without sending it.
+    pub future_release: Option<FutureRelease>,
+    /// the `REQUIRETLS` parameter (RFC 8689), requires `REQUIRETLS`
+    ///
+    /// If set the mail must only be relayed over TLS protected connections,
+    /// if the server does not support it the command fails without sending it.
+    /// It also fails with `LogicError::InsecureRequireTls` without sending it
+    /// if the connection itself is not encrypted.
+    pub require_tls: bool,
+    /// the `MT-PRIORITY=` parameter (RFC 6710), only send if the server supports `MT-PRIORITY`
+    ///
+    /// The priority has to be in the range `-9..=9`, else the command fails
+    /// with `LogicError::PriorityOutOfRange` without sending it.
+    pub mt_priority: Option<i8>,
+    /// the `BY=` parameter (RFC 2852), requires `DELIVERBY`
+    ///
+    /// If set a permanent failure with the enhanced status code `5.4.7`
+    /// (delivery time expired) is returned as `LogicError::DeliverByFailed`.
+    pub deliver_by: Option<DeliverBy>
 }
 
 impl Mail {
 
     pub fn new(reverse_path: ReversePath) -> Self {
-        Mail { reverse_path, params: Params::new() }
+        Mail {
+            reverse_path,
+            params: Params::new(),
+            auth: None,
+            dsn_return: None,
+            envelop_id: None,
+            body: None,
+            future_release: None,
+            require_tls: false,
+            mt_priority: None,
+            deliver_by: None
+        }
+    }
+
+    /// sets the `AUTH=` parameter
+    pub fn with_auth(mut self, auth: MailAuth) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// sets the `RET=` parameter
+    pub fn with_dsn_return(mut self, ret: DsnReturn) -> Self {
+        self.dsn_return = Some(ret);
+        self
+    }
+
+    /// sets the `ENVID=` parameter
+    pub fn with_envelop_id<I>(mut self, envelop_id: I) -> Self
+        where I: Into<String>
+    {
+        self.envelop_id = Some(envelop_id.into());
+        self
+    }
+
+    /// sets the `BODY=` parameter
+    pub fn with_body(mut self, body: BodyMode) -> Self {
+        self.body = Some(body);
+        self
+    }
+
+    /// sets the `HOLDFOR=`/`HOLDUNTIL=` parameter
+    pub fn with_future_release(mut self, release: FutureRelease) -> Self {
+        self.future_release = Some(release);
+        self
+    }
+
+    /// sets the `REQUIRETLS` parameter
+    pub fn with_require_tls(mut self) -> Self {
+        self.require_tls = true;
+        self
+    }
+
+    /// sets the `MT-PRIORITY=` parameter
+    pub fn with_mt_priority(mut self, priority: i8) -> Self {
+        self.mt_priority = Some(priority);
+        self
+    }
+
+    /// sets the `BY=` parameter
+    pub fn with_deliver_by(mut self, deliver_by: DeliverBy) -> Self {
+        self.deliver_by = Some(deliver_by);
+        self
     }
 }
 
 impl Cmd for Mail {
 
-    fn check_cmd_availability(&self, _caps: Option<&EhloData>)
+    fn check_cmd_availability(&self, caps: Option<&EhloData>)
         -> Result<(), MissingCapabilities>
     {
+        if let Some(body) = self.body {
+            body.check_availability(caps)?;
+        }
+        let supports_future_release = caps
+            .map(|ehlo_data| ehlo_data.caps().has_future_release())
+            .unwrap_or(false);
+        if self.future_release.is_some() && !supports_future_release {
+            return Err(MissingCapabilities::new_from_unchecked("FUTURERELEASE"));
+        }
+        let supports_requiretls = caps
+            .map(|ehlo_data| ehlo_data.supports_requiretls())
+            .unwrap_or(false);
+        if self.require_tls && !supports_requiretls {
+            return Err(MissingCapabilities::new_from_unchecked("REQUIRETLS"));
+        }
+        let supports_deliver_by = caps
+            .map(|ehlo_data| ehlo_data.caps().has_deliver_by())
+            .unwrap_or(false);
+        if self.deliver_by.is_some() && !supports_deliver_by {
+            return Err(MissingCapabilities::new_from_unchecked("DELIVERBY"));
+        }
         Ok(())
     }
 
     fn exec(self, con: Io) -> ExecFuture {
-        handle_pathy_cmd(con, "MAIL FROM:", self.reverse_path.as_str(), &self.params)
+        if self.require_tls && !con.is_secure() {
+            return Box::new(future::ok((con, Err(LogicError::InsecureRequireTls))));
+        }
+        let mut extra_params = Vec::new();
+        let caps = con.caps();
+        if let Some(priority) = self.mt_priority {
+            if !MT_PRIORITY_RANGE.contains(&priority) {
+                let err = LogicError::PriorityOutOfRange { priority };
+                return Box::new(future::ok((con, Err(err))));
+            }
+            if caps.has_mt_priority() {
+                extra_params.push(format!("MT-PRIORITY={}", priority));
+            }
+        }
+        if let Some(release) = self.future_release {
+            let requested = release.hold_time(SystemTime::now());
+            if let Some(limit) = caps.max_future_release_interval() {
+                if requested > limit {
+                    let err = LogicError::HoldTooLong { requested, limit };
+                    return Box::new(future::ok((con, Err(err))));
+                }
+            }
+            extra_params.push(release.to_param());
+        }
+        if caps.has_auth() {
+            if let Some(auth) = self.auth.as_ref() {
+                extra_params.push(format!("AUTH={}", auth.to_param_value()));
+            }
+        }
+        if caps.has_dsn() {
+            if let Some(ret) = self.dsn_return {
+                extra_params.push(format!("RET={}", ret.as_str()));
+            }
+            if let Some(envelop_id) = self.envelop_id.as_ref() {
+                extra_params.push(format!("ENVID={}", xtext::encode(envelop_id)));
+            }
+        }
+
+        if caps.has_8bitmime() {
+            if let Some(body) = self.body {
+                extra_params.push(format!("BODY={}", body.as_str()));
+            }
+        }
+
+        if self.require_tls {
+            extra_params.push("REQUIRETLS".to_owned());
+        }
+
+        if let Some(deliver_by) = self.deliver_by {
+            if !deliver_by.is_valid(caps.min_deliver_by_time()) {
+                let err = LogicError::InvalidDeliverBy { by_time: deliver_by.by_time };
+                return Box::new(future::ok((con, Err(err))));
+            }
+            extra_params.push(deliver_by.to_param());
+        }
+
+        let fut = handle_pathy_cmd(con, "MAIL FROM:", self.reverse_path.as_str(),
+            &self.params, &extra_params);
+
+        if self.deliver_by.is_none() {
+            return fut;
+        }
+
+        let fut = fut.map(|(con, result)| {
+            (con, result.map_err(DeliverBy::map_failure))
+        });
+        Box::new(fut)
+    }
+}
+
+/// the valid priorities of the `MT-PRIORITY=` parameter (RFC 6710)
+const MT_PRIORITY_RANGE: RangeInclusive<i8> = -9..=9;
+
+/// the valid by-times of the `BY=` parameter (RFC 2852)
+const BY_TIME_RANGE: RangeInclusive<i64> = -999_999_999..=999_999_999;
+
+/// The value of the `RET=` parameter of `MAIL` as specified in RFC 3461
+///
+/// It specifies if a delivery status notification should contain the
+/// full message or just it's headers.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum DsnReturn {
+    /// return the full message (`RET=FULL`)
+    Full,
+    /// return only the headers (`RET=HDRS`)
+    Headers
+}
+
+impl DsnReturn {
+
+    /// returns the parameter value, i.e. `"FULL"` or `"HDRS"`
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            DsnReturn::Full => "FULL",
+            DsnReturn::Headers => "HDRS"
+        }
+    }
+}
+
+/// The `HOLDFOR=` or `HOLDUNTIL=` parameter of `MAIL` as specified in RFC 4865
+///
+/// It asks the server to hold the mail and only deliver it later.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum FutureRelease {
+    /// hold the mail for given time (`HOLDFOR=`, send in seconds)
+    HoldFor(Duration),
+    /// hold the mail until given point in time (`HOLDUNTIL=`, send as RFC 3339 timestamp in UTC)
+    HoldUntil(SystemTime)
+}
+
+impl FutureRelease {
+
+    /// returns the parameter, e.g. `"HOLDFOR=3600"` or `"HOLDUNTIL=2018-06-14T11:22:18Z"`
+    pub fn to_param(&self) -> String {
+        match *self {
+            FutureRelease::HoldFor(duration) => format!("HOLDFOR={}", duration.as_secs()),
+            FutureRelease::HoldUntil(time) => format!("HOLDUNTIL={}", date::format_rfc3339(time))
+        }
+    }
+
+    /// returns the time the mail will be held if it is send at `now`
+    pub fn hold_time(&self, now: SystemTime) -> Duration {
+        match *self {
+            FutureRelease::HoldFor(duration) => duration,
+            FutureRelease::HoldUntil(time) => time.duration_since(now).unwrap_or_default()
+        }
+    }
+}
+
+/// The `BY=` parameter of `MAIL` as specified in RFC 2852
+///
+/// It asks the server to deliver the mail within given time, what happens
+/// if that isn't possible depends on the `mode`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct DeliverBy {
+    /// the by-time in seconds (relative to when the mail is received by the server)
+    ///
+    /// It has to be positive for `DeliverByMode::Return`, it can be zero
+    /// or negative for `DeliverByMode::Notify`. In both cases it has to
+    /// be in the range `-999999999..=999999999` (see `DeliverBy::is_valid`).
+    pub by_time: i64,
+    /// what to do if the mail can not be delivered in time
+    pub mode: DeliverByMode,
+    /// request tracing, i.e. a delivery status notification (the `T` modifier)
+    pub trace: bool
+}
+
+impl DeliverBy {
+
+    /// creates a new `BY=` parameter without the trace (`T`) modifier
+    ///
+    /// The `by_time` is not checked here, a invalid by-time makes the
+    /// `Mail` command fail with `LogicError::InvalidDeliverBy` without
+    /// sending it.
+    pub fn new(by_time: i64, mode: DeliverByMode) -> Self {
+        DeliverBy { by_time, mode, trace: false }
+    }
+
+    /// sets the trace (`T`) modifier
+    pub fn with_trace(mut self) -> Self {
+        self.trace = true;
+        self
+    }
+
+    /// returns the parameter, e.g. `"BY=120;R"` or `"BY=-60;NT"`
+    pub fn to_param(&self) -> String {
+        let trace = if self.trace { "T" } else { "" };
+        format!("BY={};{}{}", self.by_time, self.mode.as_str(), trace)
+    }
+
+    /// true if the response is a permanent failure because the mail could not be delivered in time
+    ///
+    /// I.e. it has the enhanced status code `5.4.7` (delivery time expired,
+    /// RFC 3463).
+    pub fn is_failure(response: &Response) -> bool {
+        response.code().is_permanent_failure()
+            && response.enhanced_status_code() == Some("5.4.7")
     }
+
+    /// true if the by-time is valid for the mode
+    ///
+    /// The by-time has to be in the range `-999999999..=999999999`, for
+    /// `DeliverByMode::Return` it also has to be positive and at least
+    /// `min_by_time` (i.e. the parameter of `DELIVERBY`, see
+    /// `EhloData::min_deliver_by_time`) if given.
+    pub fn is_valid(&self, min_by_time: Option<Duration>) -> bool {
+        if !BY_TIME_RANGE.contains(&self.by_time) {
+            return false;
+        }
+        match self.mode {
+            DeliverByMode::Notify => true,
+            DeliverByMode::Return => {
+                let min = min_by_time.map(|min| min.as_secs()).unwrap_or(0);
+                self.by_time > 0 && self.by_time as u64 >= min
+            }
+        }
+    }
+
+    /// maps a `5.4.7` error response to `LogicError::DeliverByFailed`
+    ///
+    /// Used for the responses to `MAIL`, `RCPT TO:` and `DATA` of a
+    /// transaction using `BY=`, as the server can refuse the mail on
+    /// any of them.
+    pub(crate) fn map_failure(err: LogicError) -> LogicError {
+        match err {
+            LogicError::Code(response) => {
+                if DeliverBy::is_failure(&response) {
+                    LogicError::DeliverByFailed(response)
+                } else {
+                    LogicError::Code(response)
+                }
+            },
+            other => other
+        }
+    }
+}
+
+/// The by-mode of the `BY=` parameter of `MAIL` as specified in RFC 2852
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum DeliverByMode {
+    /// return the mail as undeliverable if it can't be delivered in time (`R`)
+    Return,
+    /// only send a delay notification if it can't be delivered in time (`N`)
+    Notify
 }
 
+impl DeliverByMode {
+
+    /// returns the mode as used in the parameter, i.e. `"R"` or `"N"`
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            DeliverByMode::Return => "R",
+            DeliverByMode::Notify => "N"
+        }
+    }
+}
+
+/// The value of the `BODY=` parameter of `MAIL` as specified in RFC 6152
+///
+/// It's also used by `Data` to check the send data.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum BodyMode {
+    /// the body only contains 7bit octets (`BODY=7BIT`)
+    SevenBit,
+    /// the body contains 8bit octets (`BODY=8BITMIME`), requires `8BITMIME`
+    EightBitMime
+}
+
+impl BodyMode {
+
+    /// returns the parameter value, i.e. `"7BIT"` or `"8BITMIME"`
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            BodyMode::SevenBit => "7BIT",
+            BodyMode::EightBitMime => "8BITMIME"
+        }
+    }
+
+    pub(crate) fn check_availability(&self, caps: Option<&EhloData>)
+        -> Result<(), MissingCapabilities>
+    {
+        let supported = caps.map(|ehlo_data| ehlo_data.caps().has_8bitmime()).unwrap_or(false);
+        if *self == BodyMode::EightBitMime && !supported {
+            Err(MissingCapabilities::new_from_unchecked("8BITMIME"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// The value of the `AUTH=` parameter of `MAIL` as specified in RFC 4954
+///
+/// It is used by a MSA/relay to pass on the identity which (authenticated)
+/// submitted the mail.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum MailAuth {
+    /// the mail was submitted by given identity (normally a mail address)
+    Identity(String),
+    /// the identity of the submitter is unknown, i.e. `AUTH=<>`
+    Unknown
+}
+
+impl MailAuth {
+
+    /// returns the parameter value, i.e. the xtext encoded identity or `<>`
+    pub fn to_param_value(&self) -> String {
+        match *self {
+            MailAuth::Identity(ref identity) => xtext::encode(identity),
+            MailAuth::Unknown => "<>".to_owned()
+        }
+    }
+}
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Recipient {
@@ -100,13 +516,70 @@ impl Cmd for Recipient {
     }
 
     fn exec(self, con: Io) -> ExecFuture {
-        handle_pathy_cmd(con, "RCPT TO:", self.forward_path.as_str(), &self.params)
+        handle_pathy_cmd(con, "RCPT TO:", self.forward_path.as_str(), &self.params, &[])
     }
 }
 
-fn handle_pathy_cmd(io: Io, cmd: &str, path: &str, params: &Params) -> ExecFuture {
+macro_rules! legacy_mail_cmd {
+    ($(#[$attr:meta])* $name:ident, $cmd:expr) => {
+        $(#[$attr])*
+        ///
+        /// It's used like `Mail`, i.e. it starts a mail transaction which then
+        /// continues with `Recipient` and `Data`. Unlike `Mail` it has no fields
+        /// for specific ESMTP parameters (like `auth` or `body`), they can
+        /// still be passed through `params`. It is only needed for interop
+        /// with legacy systems, most servers don't implement it.
+        #[derive(Debug, Clone)]
+        pub struct $name {
+            pub reverse_path: ReversePath,
+            pub params: Params
+        }
+
+        impl $name {
+
+            pub fn new(reverse_path: ReversePath) -> Self {
+                $name { reverse_path, params: Params::new() }
+            }
+        }
+
+        impl Cmd for $name {
+
+            fn check_cmd_availability(&self, _caps: Option<&EhloData>)
+                -> Result<(), MissingCapabilities>
+            {
+                Ok(())
+            }
+
+            fn exec(self, con: Io) -> ExecFuture {
+                handle_pathy_cmd(con, $cmd, self.reverse_path.as_str(), &self.params, &[])
+            }
+        }
+    };
+}
+
+legacy_mail_cmd! {
+    /// The `SEND FROM:` command (RFC 821), delivers the mail to the terminal of the recipients
+    SendFrom, "SEND FROM:"
+}
+
+legacy_mail_cmd! {
+    /// The `SOML FROM:` command (RFC 821), delivers the mail to the terminal of the recipients
+    /// if they are active, to their mailbox otherwise ("send or mail")
+    Soml, "SOML FROM:"
+}
+
+legacy_mail_cmd! {
+    /// The `SAML FROM:` command (RFC 821), delivers the mail to the terminal of the recipients
+    /// if they are active and to their mailbox ("send and mail")
+    Saml, "SAML FROM:"
+}
+
+fn handle_pathy_cmd(
+    io: Io, cmd: &str, path: &str,
+    params: &Params, extra_params: &[String]
+) -> ExecFuture {
     //no additional heap alloc
-    if params.is_empty() {
+    if params.is_empty() && extra_params.is_empty() {
         io.exec_simple_cmd(&[cmd, "<", path, ">"])
     } else {
         let mut parts = vec![cmd, "<", path, ">" ];
@@ -118,6 +591,10 @@ fn handle_pathy_cmd(io: Io, cmd: &str, path: &str, params: &Params) -> ExecFutur
                 parts.push(v.as_str());
             }
         }
+        for extra_param in extra_params.iter() {
+            parts.push(" ");
+            parts.push(extra_param);
+        }
         io.exec_simple_cmd(parts.as_slice())
     }
 }
@@ -139,11 +616,21 @@ impl Cmd for Verify {
     }
 }
 
+/// The `HELP` command, optionally asking for help about a specific topic
+///
+/// See `Connection::help` for a way to get the help text lines.
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct Help {
     pub topic: Option<String>
 }
 
+impl Help {
+
+    pub fn new(topic: Option<String>) -> Self {
+        Help { topic }
+    }
+}
+
 impl Cmd for Help {
     fn check_cmd_availability(&self, _caps: Option<&EhloData>)
         -> Result<(), MissingCapabilities>
@@ -160,3 +647,38 @@ impl Cmd for Help {
     }
 }
 
+/// The `BURL` command (RFC 4468), requires `BURL`
+///
+/// Can be used instead of `DATA` to submit a message the server fetches
+/// itself from the given (IMAP URLAUTH) url. The url is always send with
+/// `LAST`, i.e. the fetched message is the complete mail body.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Burl {
+    pub url: String
+}
+
+impl Burl {
+
+    pub fn new<U>(url: U) -> Self
+        where U: Into<String>
+    {
+        Burl { url: url.into() }
+    }
+}
+
+impl Cmd for Burl {
+    fn check_cmd_availability(&self, caps: Option<&EhloData>)
+        -> Result<(), MissingCapabilities>
+    {
+        let supported = caps.map(|ehlo_data| ehlo_data.has_capability("BURL")).unwrap_or(false);
+        if supported {
+            Ok(())
+        } else {
+            Err(MissingCapabilities::new_from_unchecked("BURL"))
+        }
+    }
+
+    fn exec(self, io: Io) -> ExecFuture {
+        io.exec_simple_cmd(&["BURL ", self.url.as_str(), " LAST"])
+    }
+}
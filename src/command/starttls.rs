@@ -1,17 +1,17 @@
 use std::{io as std_io};
+use std::time::Duration;
+#[cfg(feature="metrics")]
+use tokio::clock;
 
 use futures::future::{self, Either, Future};
 
-use native_tls::TlsConnector as NativeTlsConnector;
-use tokio_tls::TlsConnector;
-
 use ::error::MissingCapabilities;
 use ::{
     ExecFuture, Cmd,
     Domain, Capability, EsmtpKeyword,
-    map_tls_err, SetupTls, DefaultTlsSetup, EhloData
+    SetupTls, DefaultTlsSetup, EhloData
 };
-use ::io::{Io, Socket};
+use ::io::{Io, Socket, connect_tls};
 use ::response::{Response, codes};
 
 
@@ -20,6 +20,10 @@ use ::response::{Response, codes};
 pub struct StartTls<S = DefaultTlsSetup> {
     pub setup_tls: S,
     pub sni_domain: Domain,
+    /// domain the certificate is verified against, if `None` `sni_domain` is used
+    pub verify_domain: Option<Domain>,
+    /// if set the tls handshake fails if it takes longer (see `with_handshake_timeout`)
+    pub handshake_timeout: Option<Duration>
 }
 
 impl StartTls<DefaultTlsSetup> {
@@ -28,7 +32,9 @@ impl StartTls<DefaultTlsSetup> {
     {
         StartTls {
             sni_domain: sni_domain.into(),
-            setup_tls: DefaultTlsSetup
+            verify_domain: None,
+            setup_tls: DefaultTlsSetup,
+            handshake_timeout: None
         }
     }
 }
@@ -43,8 +49,25 @@ impl<S> StartTls<S>
         StartTls {
             setup_tls,
             sni_domain: sni_domain.into(),
+            verify_domain: None,
+            handshake_timeout: None
         }
     }
+
+    /// sets the domain the certificate is verified against (instead of using `sni_domain`)
+    pub fn with_verify_domain(mut self, verify_domain: Domain) -> Self {
+        self.verify_domain = Some(verify_domain);
+        self
+    }
+
+    /// fail with an I/O-Error if the tls handshake takes longer than `timeout`
+    ///
+    /// The error is of kind `TimedOut` and wraps a `error::TlsHandshakeTimeout`.
+    /// This requires a running tokio timer (e.g. a tokio runtime).
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
 }
 
 /// STARTTLS is the only command which does not have a "final" response,
@@ -67,6 +90,14 @@ fn connection_already_secure_error_future() -> ExecFuture {
     return Box::new(fut);
 }
 
+fn custom_stream_error_future() -> ExecFuture {
+    let fut = future::err(std_io::Error::new(
+        std_io::ErrorKind::InvalidInput,
+        "STARTTLS can not upgrade a custom stream"
+    ));
+    Box::new(fut)
+}
+
 const STARTTLS: &str = "STARTTLS";
 
 impl<S> Cmd for StartTls<S>
@@ -89,13 +120,19 @@ impl<S> Cmd for StartTls<S>
     }
 
     fn exec(self, mut io: Io) -> ExecFuture {
-        let StartTls { sni_domain, setup_tls } = self;
+        let StartTls { sni_domain, verify_domain, setup_tls, handshake_timeout } = self;
 
         let was_mock =
             match *io.socket_mut() {
                 Socket::Insecure(_) => {
                     false
                 },
+                Socket::Custom(ref stream) => {
+                    if stream.is_secure() {
+                        return connection_already_secure_error_future();
+                    }
+                    return custom_stream_error_future();
+                },
                 #[cfg(feature="mock-support")]
                 Socket::Mock(ref mut socket_mock) if !socket_mock.is_secure() => {
                     socket_mock.set_is_secure(true);
@@ -112,6 +149,10 @@ impl<S> Cmd for StartTls<S>
             };
 
         if was_mock {
+            if io.keeps_cleartext_ehlo_data() {
+                let ehlo_data = io.ehlo_data().cloned();
+                io.set_cleartext_ehlo_data(ehlo_data);
+            }
             let fut = future::ok((io, Ok(tls_done_result())));
             return Box::new(fut);
         }
@@ -124,26 +165,21 @@ impl<S> Cmd for StartTls<S>
                     Either::A(future::ok((io, Err(response))))
                 },
                 Ok(_) => {
-                    let connector = alttry!(
-                        {
-                            let contor = setup_tls.setup(NativeTlsConnector::builder())?;
-                            Ok(TlsConnector::from(contor))
-                        } =>
-                        |err| Either::A(future::err(map_tls_err(err)))
-                    );
-
-                    let (socket, _buffer, _ehlo_data) = io.split();
+                    let (socket, state) = io.into_parts();
                     let stream = match socket {
                         Socket::Insecure(stream) => stream,
                         _ => unreachable!()
                     };
 
-                    let fut = connector
-                        .connect(sni_domain.as_str(), stream)
-                        .map_err(map_tls_err)
+                    #[cfg(feature="metrics")]
+                    let handshake_start = clock::now();
+                    let verify_domain = verify_domain.as_ref().unwrap_or(&sni_domain);
+                    let fut = connect_tls(setup_tls, &sni_domain, verify_domain, stream, handshake_timeout)
                         .map(move |stream| {
-                            let socket = Socket::Secure(stream);
-                            let io = Io::from(socket);
+                            #[allow(unused_mut)]
+                            let mut io = Io::from_upgraded_parts(stream, state);
+                            #[cfg(feature="metrics")]
+                            io.metrics_mut().record_tls_handshake(clock::now().duration_since(handshake_start));
                             (io, Ok(tls_done_result()))
                         });
 
@@ -2,7 +2,9 @@ use std::io as std_io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::fmt::Debug;
 use std::collections::HashMap;
+use std::time::Duration;
 
+use futures::Future;
 use native_tls::{
     self,
     TlsConnectorBuilder,
@@ -27,7 +29,7 @@ use ::data_types::{Domain, AddressLiteral, EhloParam, Capability};
 ///
 /// MX: Mail Exchanger
 ///
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum ClientId {
     /// a registered domain
     Domain(Domain),
@@ -72,6 +74,62 @@ impl ClientId {
                 ClientId::Domain(domain)
             })
     }
+
+    /// returns the identity as it's send with `EHLO`/`HELO`
+    pub fn as_str(&self) -> &str {
+        match *self {
+            ClientId::Domain(ref domain) => domain.as_str(),
+            ClientId::AddressLiteral(ref addr_lit) => addr_lit.as_str()
+        }
+    }
+}
+
+/// Resolves the domain name of an ip address (i.e. a reverse DNS lookup)
+///
+/// Used to determine the client identity from the local address a
+/// connection is bound to, see `ConnectionConfig::client_id_lookup`.
+///
+/// As this crate doesn't depend on a dns resolver no implementation
+/// doing an actual reverse DNS lookup is provided. The lookup is done
+/// while connecting, so it has to be asynchronous (like the lookups of
+/// `mx::MxResolver`) to not block the event loop.
+pub trait ReverseLookup: Debug + Send + Sync + 'static {
+    /// returns the domain name of `addr`, `None` if it can not be resolved
+    fn lookup(&self, addr: IpAddr) -> ReverseLookupFuture;
+}
+
+/// The future returned by `ReverseLookup::lookup`
+pub type ReverseLookupFuture = Box<Future<Item=Option<Domain>, Error=std_io::Error> + Send>;
+
+/// Notified each time the ehlo data of a connection is (re-)set
+///
+/// E.g. after `Connection::rehello` or the `EHLO` following `STARTTLS`,
+/// see `Connection::set_capabilities_observer`.
+pub trait CapabilitiesObserver: Debug + Send + Sync + 'static {
+    /// called with the capabilities before and after the ehlo data was set
+    ///
+    /// It's called for every `EHLO`/`HELO`, even if the capabilities did
+    /// not change. The `EHLO` after `STARTTLS` is called with the
+    /// capabilities from before `STARTTLS` as `old`.
+    fn capabilities_changed(&self, old: &Capabilities, new: &Capabilities);
+}
+
+impl ClientId {
+
+    /// creates a client identity for the given local address
+    ///
+    /// The domain name returned by `lookup` is used, if it returns
+    /// `None` (or fails) the address literal is used instead, i.e. the
+    /// returned future never fails.
+    pub fn from_local_addr(addr: IpAddr, lookup: &ReverseLookup)
+        -> impl Future<Item=Self, Error=std_io::Error> + Send
+    {
+        lookup.lookup(addr)
+            .then(move |res| match res {
+                Ok(Some(domain)) => Ok(ClientId::Domain(domain)),
+                Ok(None) | Err(_) => Ok(ClientId::from(addr))
+            })
+    }
 }
 
 impl From<Domain> for ClientId {
@@ -114,30 +172,93 @@ impl From<Ipv6Addr> for ClientId {
 /// which can be used to modify the tls setup e.g. to
 /// use a client certificate for authentication.
 ///
+/// The domain is used both for SNI and to verify the
+/// certificate of the server, except if a separate
+/// sni domain is set with `with_sni_domain` (e.g. for
+/// CDN-fronted or multi tenant endpoints). Using a
+/// separate sni domain requires a `SetupTls` which
+/// provides a `SetupTls::domain_verifier`.
+///
+/// Neither domain has to be related to the address
+/// connected to, e.g. the connection can be pinned to a
+/// known-good ip address (see `ConnectionBuilder::new_with_addr`).
+///
 /// The `SetupTls` default to `DefaultTlsSetup` which
 /// is enough for most use cases.
 #[derive(Debug, Clone, PartialEq)]
 pub struct TlsConfig<S = DefaultTlsSetup>
     where S: SetupTls
 {
-    /// domain of the server we connect to
+    /// domain of the server we connect to, the certificate is verified against it
     pub domain: Domain,
     /// setup allowing modifying TLS setup process
-    pub setup: S
+    pub setup: S,
+    /// domain send through SNI, if `None` `domain` is used
+    pub(crate) sni_domain: Option<Domain>
+}
+
+impl<S> TlsConfig<S>
+    where S: SetupTls
+{
+    /// create a new config using `domain` for SNI and certificate verification
+    pub fn new(domain: Domain, setup: S) -> Self {
+        TlsConfig { domain, setup, sni_domain: None }
+    }
+
+    /// sets the domain send through SNI (instead of using `domain`)
+    ///
+    /// Connecting fails if `setup` provides no `SetupTls::domain_verifier`,
+    /// as the certificate can then not be verified against `domain`.
+    pub fn with_sni_domain(mut self, sni_domain: Domain) -> Self {
+        self.sni_domain = Some(sni_domain);
+        self
+    }
+
+    /// returns the domain which is send through SNI
+    pub fn get_sni_domain(&self) -> &Domain {
+        self.sni_domain.as_ref().unwrap_or(&self.domain)
+    }
 }
 
 impl From<Domain> for TlsConfig {
     fn from(domain: Domain) -> Self {
-        TlsConfig { domain, setup: DefaultTlsSetup }
+        TlsConfig::new(domain, DefaultTlsSetup)
     }
 }
 
+/// Checks if a (DER encoded) certificate is valid for given domain, see `SetupTls::domain_verifier`
+pub type DomainVerifier = Box<Fn(&[u8], &Domain) -> bool + Send>;
+
 /// Trait used when setting up tls to modify the setup process
 pub trait SetupTls: Debug + Send + 'static {
 
     /// Accepts a connection builder and returns a connector if possible
     fn setup(self, builder: TlsConnectorBuilder)
         -> Result<NativeTlsConnector, native_tls::Error>;
+
+    /// Returns the SHA-256 hashes of the pinned public keys (SPKI)
+    ///
+    /// If not empty the handshake is rejected with a `CertificatePinMismatch`
+    /// error if the public key of the servers certificate doesn't match any
+    /// of the pins (even if the certificate chain is valid).
+    ///
+    /// This is called before `setup`, by default nothing is pinned.
+    fn spki_pins(&self) -> Vec<[u8; 32]> {
+        Vec::new()
+    }
+
+    /// Returns the check used if the SNI domain differs from the verification domain
+    ///
+    /// The tls implementation verifies the certificate against the domain
+    /// send through SNI. If a different one is set (`TlsConfig::with_sni_domain`)
+    /// only the certificate chain is verified by it and the returned check
+    /// is used to verify the servers certificate against the verification
+    /// domain. Without a check connecting with a different SNI domain fails.
+    ///
+    /// This is called before `setup`, by default there is no check.
+    fn domain_verifier(&self) -> Option<DomainVerifier> {
+        None
+    }
 }
 
 /// The default tls setup, which just calls `builder.build()`
@@ -152,6 +273,121 @@ impl SetupTls for DefaultTlsSetup {
     }
 }
 
+/// The minimal TLS protocol version which can be set with `TlsOptions`
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum TlsVersion {
+    /// TLS 1.0 (deprecated by RFC 8996)
+    Tls10,
+    /// TLS 1.1 (deprecated by RFC 8996)
+    Tls11,
+    /// TLS 1.2
+    Tls12,
+    /// TLS 1.3 (not supported on macOS/iOS)
+    Tls13
+}
+
+impl TlsVersion {
+    fn to_protocol(self) -> native_tls::Protocol {
+        match self {
+            TlsVersion::Tls10 => native_tls::Protocol::Tlsv10,
+            TlsVersion::Tls11 => native_tls::Protocol::Tlsv11,
+            TlsVersion::Tls12 => native_tls::Protocol::Tlsv12,
+            TlsVersion::Tls13 => native_tls::Protocol::Tlsv13
+        }
+    }
+}
+
+/// A tls setup allowing to set a minimal protocol version and ALPN protocols
+///
+/// If the server only supports protocol versions lower than the minimal
+/// version the tls handshake fails (with an I/O-Error). E.g.:
+///
+/// ```
+/// use new_tokio_smtp::{TlsOptions, TlsVersion};
+///
+/// let setup = TlsOptions::new()
+///     .min_protocol_version(TlsVersion::Tls12)
+///     .alpn_protocols(&["smtp"]);
+/// ```
+///
+/// Which can then be used with `ConnectionBuilder::use_tls_setup`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TlsOptions {
+    min_protocol_version: Option<TlsVersion>,
+    alpn_protocols: Vec<String>,
+    spki_pins: Vec<[u8; 32]>
+}
+
+impl TlsOptions {
+
+    /// create new options using the defaults of the tls implementation
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// sets the minimal protocol version the server has to support
+    pub fn min_protocol_version(mut self, version: TlsVersion) -> Self {
+        self.min_protocol_version = Some(version);
+        self
+    }
+
+    /// sets the protocols requested through ALPN (in order of preference)
+    pub fn alpn_protocols<I>(mut self, protocols: &[I]) -> Self
+        where I: AsRef<str>
+    {
+        self.alpn_protocols = protocols.iter()
+            .map(|protocol| protocol.as_ref().to_owned())
+            .collect();
+        self
+    }
+
+    /// pins the SHA-256 hash of the servers public key (SPKI)
+    ///
+    /// Can be called multiple times (e.g. to allow key rotation), in which
+    /// case the servers public key has to match any of the pins. Pinning
+    /// is currently only supported on platforms using openssl.
+    pub fn pin_spki_sha256(mut self, pin: [u8; 32]) -> Self {
+        self.spki_pins.push(pin);
+        self
+    }
+
+    /// returns the minimal protocol version (if set)
+    pub fn get_min_protocol_version(&self) -> Option<TlsVersion> {
+        self.min_protocol_version
+    }
+
+    /// returns the protocols requested through ALPN
+    pub fn get_alpn_protocols(&self) -> &[String] {
+        &self.alpn_protocols
+    }
+
+    /// returns the pinned SPKI hashes
+    pub fn get_spki_pins(&self) -> &[[u8; 32]] {
+        &self.spki_pins
+    }
+}
+
+impl SetupTls for TlsOptions {
+    fn setup(self, mut builder: TlsConnectorBuilder)
+        -> Result<NativeTlsConnector, native_tls::Error>
+    {
+        if let Some(version) = self.min_protocol_version {
+            builder.min_protocol_version(Some(version.to_protocol()));
+        }
+        if !self.alpn_protocols.is_empty() {
+            let protocols = self.alpn_protocols.iter()
+                .map(|protocol| protocol.as_str())
+                .collect::<Vec<_>>();
+            builder.request_alpns(&protocols);
+        }
+        builder.build()
+    }
+
+    fn spki_pins(&self) -> Vec<[u8; 32]> {
+        self.spki_pins.clone()
+    }
+}
+
 impl<F: 'static> SetupTls for F
     where F: Send + Debug + FnOnce(TlsConnectorBuilder)
     -> Result<NativeTlsConnector, native_tls::Error>
@@ -180,6 +416,15 @@ pub(crate) fn map_tls_err(err: native_tls::Error) -> std_io::Error {
     )
 }
 
+/// The command used for the (last) handshake with the server
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum HandshakeKind {
+    /// `EHLO` was used, i.e. the server supports smtp extensions (ESMTP)
+    Ehlo,
+    /// `HELO` was used, i.e. no smtp extensions can be used
+    Helo
+}
+
 /// A type representing the ehlo response of the last ehlo call
 ///
 /// This is mainly used to check if a certain capability/command
@@ -187,7 +432,8 @@ pub(crate) fn map_tls_err(err: native_tls::Error) -> std_io::Error {
 #[derive(Debug, Clone)]
 pub struct EhloData {
     domain: Domain,
-    data: HashMap<Capability, Vec<EhloParam>>
+    data: HashMap<Capability, Vec<EhloParam>>,
+    caps: Capabilities
 }
 
 impl EhloData {
@@ -195,7 +441,8 @@ impl EhloData {
     /// create a new Ehlo data from the domain with which the server responded and the
     /// ehlo parameters of the response
     pub fn new(domain: Domain, data: HashMap<Capability, Vec<EhloParam>>) -> Self {
-        EhloData { domain, data }
+        let caps = Capabilities::from_capability_map(&data);
+        EhloData { domain, data, caps }
     }
 
     /// check if a ehlo contained a specific capability e.g. `SMTPUTF8`
@@ -213,6 +460,60 @@ impl EhloData {
             .map(|vec| &**vec)
     }
 
+    /// returns the maximal message size advertised through the `SIZE` capability
+    ///
+    /// Returns `None` if `SIZE` is not advertised, has no (valid) parameter or if
+    /// the parameter is `0`, which means there is no fixed limit (RFC 1870).
+    pub fn max_message_size(&self) -> Option<u64> {
+        self.caps.max_message_size()
+    }
+
+    /// returns the maximal time a mail can be held, advertised through `FUTURERELEASE`
+    ///
+    /// This is the first parameter of `FUTURERELEASE` (RFC 4865), `None` is
+    /// returned if `FUTURERELEASE` is not advertised or has no (valid) parameter.
+    pub fn max_future_release_interval(&self) -> Option<Duration> {
+        self.caps.max_future_release_interval()
+    }
+
+    /// returns the deliver-by time advertised through `DELIVERBY`
+    ///
+    /// This is the parameter of `DELIVERBY` (RFC 2852), i.e. the minimal
+    /// by-time the server accepts for `DeliverByMode::Return`. `None` is
+    /// returned if `DELIVERBY` is not advertised or has no (valid) parameter.
+    pub fn min_deliver_by_time(&self) -> Option<Duration> {
+        self.caps.min_deliver_by_time()
+    }
+
+    /// returns the maximal number of recipients per transaction
+    ///
+    /// This is the `RCPTMAX=` parameter of `LIMITS` (RFC 9422), `None` is
+    /// returned if it's not advertised or has no (valid) value.
+    pub fn max_recipients(&self) -> Option<usize> {
+        self.caps.max_recipients()
+    }
+
+    /// check if `REQUIRETLS` (RFC 8689) was advertised
+    pub fn supports_requiretls(&self) -> bool {
+        self.caps.has_requiretls()
+    }
+
+    /// returns the priority assignment policy advertised through `MT-PRIORITY`
+    ///
+    /// This is the parameter of `MT-PRIORITY` (RFC 6710), e.g. `"MIXER"`,
+    /// `"STANAG4406"` or `"NSEP"`. `None` is returned if `MT-PRIORITY` is
+    /// not advertised or advertised without a policy.
+    pub fn mt_priority_profile(&self) -> Option<&str> {
+        self.get_capability_params("MT-PRIORITY")
+            .and_then(|params| params.first())
+            .map(|param| param.as_str())
+    }
+
+    /// returns the snapshot of the well known capabilities (computed once on creation)
+    pub fn caps(&self) -> &Capabilities {
+        &self.caps
+    }
+
     /// return a reference to the inner hash map
     pub fn capability_map(&self) -> &HashMap<Capability, Vec<EhloParam>> {
         &self.data
@@ -233,7 +534,212 @@ impl From<(Domain, HashMap<Capability, Vec<EhloParam>>)> for EhloData {
 
 impl Into<(Domain, HashMap<Capability, Vec<EhloParam>>)> for EhloData {
     fn into(self) -> (Domain, HashMap<Capability, Vec<EhloParam>>) {
-        let EhloData { domain, data } = self;
+        let EhloData { domain, data, .. } = self;
         (domain, data)
     }
 }
+
+const CAP_SIZE: u16 = 1;
+const CAP_AUTH: u16 = 1 << 1;
+const CAP_SMTPUTF8: u16 = 1 << 2;
+const CAP_8BITMIME: u16 = 1 << 3;
+const CAP_DSN: u16 = 1 << 4;
+const CAP_PIPELINING: u16 = 1 << 5;
+const CAP_STARTTLS: u16 = 1 << 6;
+const CAP_CHUNKING: u16 = 1 << 7;
+const CAP_ENHANCEDSTATUSCODES: u16 = 1 << 8;
+const CAP_FUTURERELEASE: u16 = 1 << 9;
+const CAP_REQUIRETLS: u16 = 1 << 10;
+const CAP_MTPRIORITY: u16 = 1 << 11;
+const CAP_DELIVERBY: u16 = 1 << 12;
+
+const KNOWN_CAPABILITIES: &[(&str, u16)] = &[
+    ("SIZE", CAP_SIZE),
+    ("AUTH", CAP_AUTH),
+    ("SMTPUTF8", CAP_SMTPUTF8),
+    ("8BITMIME", CAP_8BITMIME),
+    ("DSN", CAP_DSN),
+    ("PIPELINING", CAP_PIPELINING),
+    ("STARTTLS", CAP_STARTTLS),
+    ("CHUNKING", CAP_CHUNKING),
+    ("ENHANCEDSTATUSCODES", CAP_ENHANCEDSTATUSCODES),
+    ("FUTURERELEASE", CAP_FUTURERELEASE),
+    ("REQUIRETLS", CAP_REQUIRETLS),
+    ("MT-PRIORITY", CAP_MTPRIORITY),
+    ("DELIVERBY", CAP_DELIVERBY),
+];
+
+/// used if a connection has no ehlo data
+pub(crate) static NO_CAPABILITIES: Capabilities = Capabilities {
+    flags: 0,
+    max_message_size: None,
+    max_future_release_interval: None,
+    min_deliver_by_time: None,
+    max_recipients: None,
+    auth_mechanisms: Vec::new()
+};
+
+/// A snapshot of the well known capabilities of a `EhloData` instance
+///
+/// It is computed once when the `EhloData` is created so that checking
+/// for them does not require looking up (case insensitive) strings.
+/// Other capabilities still have to be checked through `EhloData`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    flags: u16,
+    max_message_size: Option<u64>,
+    max_future_release_interval: Option<Duration>,
+    min_deliver_by_time: Option<Duration>,
+    max_recipients: Option<usize>,
+    auth_mechanisms: Vec<String>
+}
+
+impl Capabilities {
+
+    fn from_capability_map(data: &HashMap<Capability, Vec<EhloParam>>) -> Self {
+        let mut flags = 0;
+        for &(name, flag) in KNOWN_CAPABILITIES {
+            if data.contains_key(<&IgnoreAsciiCaseStr>::from(name)) {
+                flags |= flag;
+            }
+        }
+
+        let params = |name: &str| data.get(<&IgnoreAsciiCaseStr>::from(name));
+
+        let max_message_size = params("SIZE")
+            .and_then(|params| params.first())
+            .and_then(|param| param.as_str().parse().ok())
+            .and_then(|size| if size == 0 { None } else { Some(size) });
+
+        let max_future_release_interval = params("FUTURERELEASE")
+            .and_then(|params| params.first())
+            .and_then(|param| param.as_str().parse().ok())
+            .map(Duration::from_secs);
+
+        let min_deliver_by_time = params("DELIVERBY")
+            .and_then(|params| params.first())
+            .and_then(|param| param.as_str().parse().ok())
+            .map(Duration::from_secs);
+
+        let max_recipients = params("LIMITS")
+            .and_then(|params| params.iter()
+                .filter_map(|param| {
+                    let param = param.as_str();
+                    let is_rcptmax = param.len() > 8 && param[..8].eq_ignore_ascii_case("RCPTMAX=");
+                    if is_rcptmax { param[8..].parse().ok() } else { None }
+                })
+                .next())
+            .and_then(|max| if max == 0 { None } else { Some(max) });
+
+        let auth_mechanisms = params("AUTH")
+            .map(|params| params.iter()
+                .map(|param| param.as_str().to_ascii_uppercase())
+                .collect())
+            .unwrap_or_default();
+
+        Capabilities {
+            flags, max_message_size, max_future_release_interval,
+            min_deliver_by_time, max_recipients, auth_mechanisms
+        }
+    }
+
+    fn has(&self, flag: u16) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// `SIZE` was advertised (RFC 1870)
+    pub fn has_size(&self) -> bool {
+        self.has(CAP_SIZE)
+    }
+
+    /// the maximal message size, see `EhloData::max_message_size`
+    pub fn max_message_size(&self) -> Option<u64> {
+        self.max_message_size
+    }
+
+    /// `AUTH` was advertised (RFC 4954)
+    pub fn has_auth(&self) -> bool {
+        self.has(CAP_AUTH)
+    }
+
+    /// the (upper cased) auth mechanisms advertised with `AUTH`
+    pub fn auth_mechanisms(&self) -> &[String] {
+        &self.auth_mechanisms
+    }
+
+    /// true if `AUTH` was advertised with given mechanism (ignoring case)
+    pub fn supports_auth_mechanism(&self, mechanism: &str) -> bool {
+        self.auth_mechanisms.iter()
+            .any(|known| known.eq_ignore_ascii_case(mechanism))
+    }
+
+    /// `SMTPUTF8` was advertised (RFC 6531)
+    pub fn has_smtputf8(&self) -> bool {
+        self.has(CAP_SMTPUTF8)
+    }
+
+    /// `8BITMIME` was advertised (RFC 6152)
+    pub fn has_8bitmime(&self) -> bool {
+        self.has(CAP_8BITMIME)
+    }
+
+    /// `DSN` was advertised (RFC 3461)
+    pub fn has_dsn(&self) -> bool {
+        self.has(CAP_DSN)
+    }
+
+    /// `PIPELINING` was advertised (RFC 2920)
+    pub fn has_pipelining(&self) -> bool {
+        self.has(CAP_PIPELINING)
+    }
+
+    /// `STARTTLS` was advertised (RFC 3207)
+    pub fn has_starttls(&self) -> bool {
+        self.has(CAP_STARTTLS)
+    }
+
+    /// `CHUNKING` was advertised (RFC 3030)
+    pub fn has_chunking(&self) -> bool {
+        self.has(CAP_CHUNKING)
+    }
+
+    /// `ENHANCEDSTATUSCODES` was advertised (RFC 2034)
+    pub fn has_enhanced_status_codes(&self) -> bool {
+        self.has(CAP_ENHANCEDSTATUSCODES)
+    }
+
+    /// `FUTURERELEASE` was advertised (RFC 4865)
+    pub fn has_future_release(&self) -> bool {
+        self.has(CAP_FUTURERELEASE)
+    }
+
+    /// the maximal hold time, see `EhloData::max_future_release_interval`
+    pub fn max_future_release_interval(&self) -> Option<Duration> {
+        self.max_future_release_interval
+    }
+
+    /// `REQUIRETLS` was advertised (RFC 8689)
+    pub fn has_requiretls(&self) -> bool {
+        self.has(CAP_REQUIRETLS)
+    }
+
+    /// `MT-PRIORITY` was advertised (RFC 6710)
+    pub fn has_mt_priority(&self) -> bool {
+        self.has(CAP_MTPRIORITY)
+    }
+
+    /// `DELIVERBY` was advertised (RFC 2852)
+    pub fn has_deliver_by(&self) -> bool {
+        self.has(CAP_DELIVERBY)
+    }
+
+    /// the minimal by-time, see `EhloData::min_deliver_by_time`
+    pub fn min_deliver_by_time(&self) -> Option<Duration> {
+        self.min_deliver_by_time
+    }
+
+    /// the maximal number of recipients per transaction, see `EhloData::max_recipients`
+    pub fn max_recipients(&self) -> Option<usize> {
+        self.max_recipients
+    }
+}
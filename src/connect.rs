@@ -1,6 +1,8 @@
 use std::net::{SocketAddr, ToSocketAddrs, Ipv4Addr};
 use std::{io as std_io};
 use std::fmt::Debug;
+use std::time::Duration;
+use std::sync::Arc;
 
 use futures::future::{self, Future, Either};
 
@@ -9,12 +11,14 @@ use ::error::{
     ConnectingFailed,
     LogicError
 };
-use ::data_types::Domain;
+use ::data_types::{Domain, Capability, EsmtpKeyword};
 use ::common::{
     TlsConfig, SetupTls,
-    ClientId, DefaultTlsSetup
+    ClientId, DefaultTlsSetup,
+    ReverseLookup
 };
-use ::io::{Io, SmtpResult};
+use ::io::{Io, ProxyHeader, RateLimit, SmtpResult};
+use ::response::{Response, codes};
 use ::connection::{
     Connection, Cmd
 };
@@ -24,8 +28,15 @@ use ::command::Noop;
 /// A future resolving to an `Connection` instance
 pub type ConnectingFuture = Box<Future<Item=Connection, Error=ConnectingFailed> + Send + 'static>;
 
-pub const DEFAULT_SMTP_MSA_PORT: u16 = 587;
-pub const DEFAULT_SMTP_MX_PORT: u16 = 25;
+/// the default port for smtp (e.g. for relaying between MX servers), RFC 5321
+pub const DEFAULT_SMTP_PORT: u16 = 25;
+/// the default port for mail submission using `STARTTLS`, RFC 6409
+pub const DEFAULT_SUBMISSION_PORT: u16 = 587;
+/// the default port for mail submission using direct tls, RFC 8314
+pub const DEFAULT_SMTPS_PORT: u16 = 465;
+
+pub const DEFAULT_SMTP_MSA_PORT: u16 = DEFAULT_SUBMISSION_PORT;
+pub const DEFAULT_SMTP_MX_PORT: u16 = DEFAULT_SMTP_PORT;
 
 fn cmd_future2connecting_future<LE: 'static, E>(
     res: Result<(Connection, SmtpResult), E>,
@@ -46,47 +57,119 @@ fn cmd_future2connecting_future<LE: 'static, E>(
     fut
 }
 
+/// true if the response to `EHLO` means it's not supported (i.e. `HELO` should be used)
+fn is_ehlo_unsupported(response: &Response) -> bool {
+    let code = response.code();
+    code == codes::SYNTAX_ERROR || code == codes::COMMAND_UNIMPLEMENTED
+}
 
 impl Connection {
 
     /// open a connection to an smtp server using given configuration
+    ///
+    /// Fails with `ConnectingFailed::InvalidConfig` without connecting if
+    /// `skip_auto_ehlo` is combined with authentication or required capabilities.
     pub fn connect<S, A>(config: ConnectionConfig<A, S>)
         -> impl Future<Item=Connection, Error=ConnectingFailed> + Send
         where S: SetupTls, A: Cmd + Send
     {
-        let ConnectionConfig { addr, security, client_id, auth_cmd } = config;
+        if config.skip_auto_ehlo {
+            let authenticates = config.auth_cmd.auth_identity().is_some();
+            if authenticates || !config.required_capabilities.is_empty() {
+                let err = ConnectingFailed::InvalidConfig(
+                    "skip_auto_ehlo can not be combined with auth_cmd or required_capabilities");
+                return Either::B(future::err(err));
+            }
+        }
+
+        let ConnectionConfig {
+            addr, security, client_id, auth_cmd,
+            idle_timeout, read_buffer_size, allow_insecure_auth,
+            required_capabilities, client_id_lookup, tls_handshake_timeout,
+            rate_limit, keep_cleartext_ehlo_data, skip_auto_ehlo, proxy_header,
+            direct_tls_fallback_port
+        } = config;
+        let lookup = client_id_lookup;
+        let timeout = tls_handshake_timeout;
+        let auto_ehlo = !skip_auto_ehlo;
 
         #[allow(deprecated)]
         let con_fut = match security {
+            Security::None if skip_auto_ehlo => {
+                Either::B(Either::A(Either::A(Connection::_connect_insecure_no_ehlo(&addr, proxy_header))))
+            },
             Security::None => {
-                Either::B(Either::A(Connection::_connect_insecure(&addr, client_id)))
+                Either::B(Either::A(Either::B(Connection::_connect_insecure(
+                    &addr, client_id, lookup, proxy_header))))
             },
+            Security::DirectTls(tls_config) if skip_auto_ehlo => {
+                Either::B(Either::B(Either::A(Connection::_connect_direct_tls_no_ehlo(
+                    &addr, tls_config, timeout, proxy_header))))
+            }
             Security::DirectTls(tls_config) => {
-                Either::B(Either::B(Connection::_connect_direct_tls(&addr, client_id, tls_config)))
+                Either::B(Either::B(Either::B(Connection::_connect_direct_tls(
+                    &addr, client_id, tls_config, timeout, lookup, proxy_header))))
             }
-            Security::StartTls(tls_config) => {
-                Either::A(Connection::_connect_starttls(&addr, client_id, tls_config))
+            Security::StartTls(tls_config) => match direct_tls_fallback_port {
+                None => Either::A(Either::A(Either::A(Connection::_connect_starttls(
+                    &addr, client_id, tls_config, timeout, lookup,
+                    keep_cleartext_ehlo_data, auto_ehlo, proxy_header)))),
+                Some(port) => Either::A(Either::A(Either::B(Connection::_connect_starttls_or_direct_tls(
+                    &addr, port, client_id, tls_config, timeout, lookup,
+                    keep_cleartext_ehlo_data, auto_ehlo, proxy_header))))
+            },
+            Security::Opportunistic(tls_config, fallback) => {
+                Either::A(Either::B(Connection::_connect_opportunistic(
+                    &addr, client_id, tls_config, fallback, timeout, lookup,
+                    keep_cleartext_ehlo_data, auto_ehlo, proxy_header)))
             }
         };
 
         let fut = con_fut
-            .and_then(|con| con
-                .send(auth_cmd)
-                .then(|res| cmd_future2connecting_future(res, ConnectingFailed::Auth))
-            );
+            .and_then(move |mut con| {
+                con.set_allow_insecure_auth(allow_insecure_auth);
+                if skip_auto_ehlo {
+                    // capabilities and auth both need the ehlo data,
+                    // (checked above) neither is used
+                    return Either::A(future::ok(con));
+                }
+
+                let missing = required_capabilities.into_iter()
+                    .find(|cap| !con.has_capability(cap.as_str()));
+                if let Some(cap) = missing {
+                    return Either::A(future::err(ConnectingFailed::MissingCapability(cap)));
+                }
+
+                let fut = con.send(auth_cmd)
+                    .then(|res| cmd_future2connecting_future(res, ConnectingFailed::Auth));
+                Either::B(fut)
+            })
+            .map(move |mut con| {
+                con.set_idle_timeout(idle_timeout);
+                con.set_rate_limit(rate_limit);
+                if let Some(size) = read_buffer_size {
+                    con.set_read_buffer_size(size);
+                }
+                con
+            });
 
-        fut
+        Either::A(fut)
     }
 
     #[doc(hidden)]
-    pub fn _connect_insecure_no_ehlo(addr: &SocketAddr)
+    pub fn _connect_insecure_no_ehlo(addr: &SocketAddr, proxy_header: Option<ProxyHeader>)
         -> impl Future<Item=Connection, Error=ConnectingFailed> + Send
     {
         let fut = Io
-            ::connect_insecure(addr)
+            ::connect_insecure_with_proxy_header(addr, proxy_header)
             .and_then(Io::parse_response)
             .then(|res| {
-                let res = res.map(|(io, res)| (Connection::from(io), res));
+                let res = res.map(|(mut io, res)| {
+                    if let Ok(ref greeting) = res {
+                        io.set_greeting(greeting.clone());
+                    }
+                    (Connection::from(io), res)
+                });
                 cmd_future2connecting_future(res, ConnectingFailed::Setup)
             });
 
@@ -94,15 +177,24 @@ impl Connection {
     }
 
     #[doc(hidden)]
-    pub fn _connect_direct_tls_no_ehlo<S>(addr: &SocketAddr, config: TlsConfig<S>)
-        -> impl Future<Item=Connection, Error=ConnectingFailed> + Send
+    pub fn _connect_direct_tls_no_ehlo<S>(
+        addr: &SocketAddr,
+        config: TlsConfig<S>,
+        handshake_timeout: Option<Duration>,
+        proxy_header: Option<ProxyHeader>
+    ) -> impl Future<Item=Connection, Error=ConnectingFailed> + Send
         where S: SetupTls
     {
         let fut = Io
-            ::connect_secure(addr, config)
+            ::connect_secure_with_proxy_header(addr, config, handshake_timeout, proxy_header)
             .and_then(Io::parse_response)
             .then(|res| {
-                let res = res.map(|(io, res)| (Connection::from(io), res));
+                let res = res.map(|(mut io, res)| {
+                    if let Ok(ref greeting) = res {
+                        io.set_greeting(greeting.clone());
+                    }
+                    (Connection::from(io), res)
+                });
                 cmd_future2connecting_future(res, ConnectingFailed::Setup)
             });
 
@@ -110,21 +202,17 @@ impl Connection {
     }
 
     #[doc(hidden)]
-    pub fn _connect_insecure(addr: &SocketAddr, clid: ClientId)
-        -> impl Future<Item=Connection, Error=ConnectingFailed> + Send
+    pub fn _connect_insecure(
+        addr: &SocketAddr,
+        clid: ClientId,
+        lookup: Option<Arc<ReverseLookup>>,
+        proxy_header: Option<ProxyHeader>
+    ) -> impl Future<Item=Connection, Error=ConnectingFailed> + Send
     {
-        //Note: this has a circular dependency between Connection <-> cmd Ehlo which
-        // could be resolved using a ext. trait, but it's more ergonomic this way
-        use command::Ehlo;
-        let fut = Connection
-            ::_connect_insecure_no_ehlo(addr)
-            .and_then(|con| con
-                .send(Ehlo::from(clid))
-                .then(|res| cmd_future2connecting_future(res, ConnectingFailed::Setup))
-            );
-
-
-        fut
+        Connection
+            ::_connect_insecure_no_ehlo(addr, proxy_header)
+            .and_then(|con| con.resolve_client_id_and_ehlo(clid, lookup))
+            .map(|(con, _clid)| con)
     }
 
     #[doc(hidden)]
@@ -132,52 +220,196 @@ impl Connection {
         addr: &SocketAddr,
         clid: ClientId,
         config: TlsConfig<S>,
+        handshake_timeout: Option<Duration>,
+        lookup: Option<Arc<ReverseLookup>>,
+        proxy_header: Option<ProxyHeader>
     ) -> impl Future<Item=Connection, Error=ConnectingFailed> + Send
         where S: SetupTls
     {
-        //Note: this has a circular dependency between Connection <-> cmd Ehlo which
-        // could be resolved using a ext. trait, but it's more ergonomic this way
-        use command::Ehlo;
-        let fut = Connection
-            ::_connect_direct_tls_no_ehlo(addr, config)
-            .and_then(|con| con
-                .send(Ehlo::from(clid))
-                .then(|res| cmd_future2connecting_future(res, ConnectingFailed::Setup))
-            );
-
-        fut
+        Connection
+            ::_connect_direct_tls_no_ehlo(addr, config, handshake_timeout, proxy_header)
+            .and_then(|con| con.resolve_client_id_and_ehlo(clid, lookup))
+            .map(|(con, _clid)| con)
     }
 
     #[doc(hidden)]
+    #[allow(clippy::too_many_arguments)]
     pub fn _connect_starttls<S>(
         addr: &SocketAddr,
         clid: ClientId,
-        config: TlsConfig<S>
+        config: TlsConfig<S>,
+        handshake_timeout: Option<Duration>,
+        lookup: Option<Arc<ReverseLookup>>,
+        keep_cleartext_ehlo_data: bool,
+        auto_ehlo: bool,
+        proxy_header: Option<ProxyHeader>
+    )
+        -> impl Future<Item=Connection, Error=ConnectingFailed> + Send
+        where S: SetupTls
+    {
+        Connection
+            ::_connect_insecure_no_ehlo(addr, proxy_header)
+            .and_then(|con| con.resolve_client_id_and_ehlo(clid, lookup))
+            .and_then(move |(con, clid)| {
+                con.upgrade_starttls(clid, config, handshake_timeout, keep_cleartext_ehlo_data, auto_ehlo)
+            })
+    }
+
+    /// like `_connect_starttls` but falls back to direct tls on `fallback_port`
+    ///
+    /// The fallback is only used (once) if the server does not advertise
+    /// `STARTTLS`, in which case the plain connection is closed (sending
+    /// `QUIT`) before connecting again.
+    #[doc(hidden)]
+    #[allow(clippy::too_many_arguments)]
+    pub fn _connect_starttls_or_direct_tls<S>(
+        addr: &SocketAddr,
+        fallback_port: u16,
+        clid: ClientId,
+        config: TlsConfig<S>,
+        handshake_timeout: Option<Duration>,
+        lookup: Option<Arc<ReverseLookup>>,
+        keep_cleartext_ehlo_data: bool,
+        auto_ehlo: bool,
+        proxy_header: Option<ProxyHeader>
+    )
+        -> impl Future<Item=Connection, Error=ConnectingFailed> + Send
+        where S: SetupTls
+    {
+        let mut fallback_addr = *addr;
+        fallback_addr.set_port(fallback_port);
+        let first_lookup = lookup.clone();
+
+        Connection
+            ::_connect_insecure_no_ehlo(addr, proxy_header)
+            .and_then(move |con| con.resolve_client_id_and_ehlo(clid, first_lookup))
+            .and_then(move |(con, clid)| {
+                if con.has_capability("STARTTLS") {
+                    let upgrade = con.upgrade_starttls(
+                        clid, config, handshake_timeout, keep_cleartext_ehlo_data, auto_ehlo);
+                    return Either::A(upgrade);
+                }
+
+                let fallback = con.quit().then(move |_| if auto_ehlo {
+                    Either::A(Connection::_connect_direct_tls(
+                        &fallback_addr, clid, config, handshake_timeout, lookup, proxy_header))
+                } else {
+                    Either::B(Connection::_connect_direct_tls_no_ehlo(
+                        &fallback_addr, config, handshake_timeout, proxy_header))
+                });
+                Either::B(fallback)
+            })
+    }
+
+    #[doc(hidden)]
+    #[allow(clippy::too_many_arguments)]
+    pub fn _connect_opportunistic<S>(
+        addr: &SocketAddr,
+        clid: ClientId,
+        config: TlsConfig<S>,
+        fallback: PlaintextFallback,
+        handshake_timeout: Option<Duration>,
+        lookup: Option<Arc<ReverseLookup>>,
+        keep_cleartext_ehlo_data: bool,
+        auto_ehlo: bool,
+        proxy_header: Option<ProxyHeader>
     )
         -> impl Future<Item=Connection, Error=ConnectingFailed> + Send
         where S: SetupTls
+    {
+        Connection
+            ::_connect_insecure_no_ehlo(addr, proxy_header)
+            .and_then(|con| con.resolve_client_id_and_ehlo(clid, lookup))
+            .and_then(move |(con, clid)| {
+                if con.has_capability("STARTTLS") {
+                    let upgrade = con.upgrade_starttls(
+                        clid, config, handshake_timeout, keep_cleartext_ehlo_data, auto_ehlo);
+                    Either::A(upgrade)
+                } else if fallback == PlaintextFallback::AllowPlaintext {
+                    Either::B(Either::A(future::ok(con)))
+                } else {
+                    let err = ConnectingFailed::MissingCapability(Capability::from(EsmtpKeyword::from_unchecked("STARTTLS")));
+                    Either::B(Either::B(con.quit().then(|_| Err(err))))
+                }
+            })
+    }
+
+    /// sends `EHLO`, if `lookup` is given the client identity is derived from the local address
+    ///
+    /// If the server doesn't know `EHLO` (responds with 500 or 502) `HELO`
+    /// is send instead (RFC 5321, 3.2).
+    ///
+    /// Returns the used client identity so that it can be reused after `STARTTLS`.
+    /// If the local address can not be determined `clid` is used.
+    fn resolve_client_id_and_ehlo(self, clid: ClientId, lookup: Option<Arc<ReverseLookup>>)
+        -> impl Future<Item=(Connection, ClientId), Error=ConnectingFailed> + Send
+    {
+        //Note: this has a circular dependency between Connection <-> cmd Ehlo/Helo which
+        // could be resolved using a ext. trait, but it's more ergonomic this way
+        use command::{Ehlo, Helo};
+        let clid_fut = match (lookup, self.local_addr()) {
+            (Some(lookup), Ok(local_addr)) => {
+                Either::A(ClientId::from_local_addr(local_addr.ip(), &*lookup))
+            },
+            _ => Either::B(future::ok(clid))
+        };
+
+        clid_fut
+            .map_err(ConnectingFailed::Io)
+            .and_then(move |clid| {
+                let helo_clid = clid.clone();
+                self
+                    .send(Ehlo::from(clid.clone()))
+                    .and_then(|(con, res)| match res {
+                        Err(LogicError::Code(ref response)) if is_ehlo_unsupported(response) => {
+                            Either::A(con.send(Helo::from(helo_clid)))
+                        },
+                        res => Either::B(future::ok((con, res)))
+                    })
+                    .then(|res| cmd_future2connecting_future(res, ConnectingFailed::Setup))
+                    .map(|con| (con, clid))
+            })
+    }
+
+    /// sends `STARTTLS` followed by a new `EHLO` (if `auto_ehlo` is true)
+    fn upgrade_starttls<S>(
+        mut self,
+        clid: ClientId,
+        config: TlsConfig<S>,
+        handshake_timeout: Option<Duration>,
+        keep_cleartext_ehlo_data: bool,
+        auto_ehlo: bool
+    ) -> impl Future<Item=Connection, Error=ConnectingFailed> + Send
+        where S: SetupTls
     {
         //Note: this has a circular dependency between Connection <-> cmd StartTls/Ehlo which
         // could be resolved using a ext. trait, but it's more ergonomic this way
         use command::{StartTls, Ehlo};
-        let TlsConfig { domain, setup } = config;
-
-        let fut = Connection
-            ::_connect_insecure(&addr, clid.clone())
-            .and_then(|con| con
-                .send(StartTls {
-                    setup_tls: setup,
-                    sni_domain: domain
-                })
-                .map_err(ConnectingFailed::Io)
-            )
-            .ctx_and_then(|con, _| con
-                .send(Ehlo::from(clid))
-                .map_err(ConnectingFailed::Io)
-            )
-            .then(|res| cmd_future2connecting_future(res, ConnectingFailed::Setup));
+        let TlsConfig { domain, sni_domain, setup } = config;
+        self.set_keep_cleartext_ehlo_data(keep_cleartext_ehlo_data);
 
-        fut
+        self
+            .send(StartTls {
+                setup_tls: setup,
+                sni_domain: sni_domain.unwrap_or_else(|| domain.clone()),
+                verify_domain: Some(domain),
+                handshake_timeout
+            })
+            .map_err(ConnectingFailed::from)
+            .and_then(|(con, result)| match result {
+                Err(LogicError::Code(response)) => Either::A(con
+                    .quit()
+                    .then(|_| Err(ConnectingFailed::StartTlsRejected(response)))),
+                result => Either::B(future::ok((con, result)))
+            })
+            .ctx_and_then(move |con, response| if auto_ehlo {
+                Either::A(con
+                    .send(Ehlo::from(clid))
+                    .map_err(ConnectingFailed::from))
+            } else {
+                Either::B(future::ok((con, Ok(response))))
+            })
+            .then(|res| cmd_future2connecting_future(res, ConnectingFailed::Setup))
     }
 }
 
@@ -187,6 +419,8 @@ pub enum Security<S>
     where S: SetupTls
 {
     /// use a plain non encrypted connection
+    ///
+    /// Use `Security::plaintext_insecure` to explicitly opt-in into it.
     #[deprecated(
         since="0.0",
         note="it's strongly discourage to use unencrypted connections for private information/auth etc.")]
@@ -194,15 +428,84 @@ pub enum Security<S>
     /// directly connect with TCP-TLS to smtp server
     DirectTls(TlsConfig<S>),
     /// connect with just TCP and then start TLS with the STARTTLS command
-    StartTls(TlsConfig<S>)
+    StartTls(TlsConfig<S>),
+    /// connect with just TCP and start TLS if the server advertises `STARTTLS`
+    ///
+    /// What happens if the server doesn't advertise `STARTTLS` is decided
+    /// by the `PlaintextFallback`, its default (and the recommended setting)
+    /// is to fail with `ConnectingFailed::MissingCapability`.
+    Opportunistic(TlsConfig<S>, PlaintextFallback)
+}
+
+/// what to do if `Security::Opportunistic` is used but the server doesn't support `STARTTLS`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub enum PlaintextFallback {
+    /// fail to connect, this prevents (silent) downgrade attacks (the default)
+    #[default]
+    FailClosed,
+    /// continue using the unencrypted connection
+    AllowPlaintext
+}
+
+/// Token required to create a `Security` which doesn't use any encryption
+///
+/// It can only be created through `PlaintextAcknowledged::insecure_no_encryption`,
+/// which makes using unencrypted connections an explicit (and greppable)
+/// decision instead of the silent use of the deprecated `Security::None`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct PlaintextAcknowledged(());
+
+impl PlaintextAcknowledged {
+
+    /// opts in into using unencrypted connections
+    ///
+    /// Everything send through such a connection (including mails and
+    /// credentials) can be read and modified by anyone on the network
+    /// path, so it should only be used for tests or local setups.
+    pub fn insecure_no_encryption() -> Self {
+        PlaintextAcknowledged(())
+    }
+}
+
+impl<S> Security<S>
+    where S: SetupTls
+{
+    /// creates a `Security` which uses a plain non encrypted connection
+    ///
+    /// This is the same as the deprecated `Security::None`, but requires
+    /// an explicit `PlaintextAcknowledged` token (and isn't deprecated).
+    pub fn plaintext_insecure(_opt_in: PlaintextAcknowledged) -> Self {
+        #[allow(deprecated)]
+        Security::None
+    }
+
+    /// returns the port conventionally used with this kind of security
+    ///
+    /// - `None` => `DEFAULT_SMTP_PORT` (25)
+    /// - `DirectTls` => `DEFAULT_SMTPS_PORT` (465)
+    /// - `StartTls` => `DEFAULT_SUBMISSION_PORT` (587)
+    /// - `Opportunistic` => `DEFAULT_SMTP_PORT` (25)
+    pub fn default_port(&self) -> u16 {
+        #[allow(deprecated)]
+        match *self {
+            Security::None => DEFAULT_SMTP_PORT,
+            Security::DirectTls(_) => DEFAULT_SMTPS_PORT,
+            Security::StartTls(_) => DEFAULT_SUBMISSION_PORT,
+            Security::Opportunistic(..) => DEFAULT_SMTP_PORT
+        }
+    }
 }
 
 /// Configuration specifing how to setup an SMTP connection.
 ///
 /// Use the `ConnectionBuilder` to crate it.
 /// (Expect if you need a unencrypted connection, in which
-///  case you have to crate it by hand. It's not recommended
-///  to use unencrypted connections for mail).
+///  case you have to crate it with `ConnectionConfig::new`.
+///  It's not recommended to use unencrypted connections for mail).
+///
+/// As new settings are added over time it can't be created through a
+/// struct literal outside of this crate, the fields can still be changed
+/// after creating it.
 ///
 /// # Example
 ///
@@ -223,6 +526,7 @@ pub enum Security<S>
 ///     .build();
 /// ```
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub struct ConnectionConfig<A, S = DefaultTlsSetup>
     where S: SetupTls, A: Cmd
 {
@@ -237,10 +541,102 @@ pub struct ConnectionConfig<A, S = DefaultTlsSetup>
     /// This is relevant for the communication between smtp server, through
     /// for connecting to an MSA (e.g. thunderbird connecting to gmail)
     /// using localhost (`[127.0.0.1]`) is enough
-    pub client_id: ClientId
+    pub client_id: ClientId,
+    /// if set the connection is closed when it is used after being idle for longer than this
+    ///
+    /// The timeout is checked lazily, i.e. an idle connection is not closed
+    /// before the next command is send through it. See
+    /// `Connection::set_idle_timeout`.
+    pub idle_timeout: Option<Duration>,
+    /// if set overrides the size by which the read buffer grows
+    ///
+    /// See `Io::set_read_buffer_size`.
+    pub read_buffer_size: Option<usize>,
+    /// if true `auth_cmd` may send credentials over an unencrypted connection
+    ///
+    /// See `Connection::set_allow_insecure_auth`.
+    pub allow_insecure_auth: bool,
+    /// capabilities the server has to advertise, checked before authentication
+    ///
+    /// If STARTTLS is used the EHLO response after STARTTLS is checked,
+    /// if any capability is missing `ConnectingFailed::MissingCapability`
+    /// is returned.
+    pub required_capabilities: Vec<Capability>,
+    /// if set the client identity is derived from the local address of the connection
+    ///
+    /// The domain name the lookup returns for the local address is used,
+    /// if it returns none the address literal is used. `client_id` is
+    /// only used if the local address can not be determined.
+    pub client_id_lookup: Option<Arc<ReverseLookup>>,
+    /// if set the tls handshake (direct tls or after `STARTTLS`) fails if it takes longer
+    ///
+    /// The error then is `ConnectingFailed::Timeout(TimeoutPhase::TlsHandshake)`.
+    /// This requires a running tokio timer (e.g. a tokio runtime).
+    pub tls_handshake_timeout: Option<Duration>,
+    /// if set commands send through the connection are delayed to not exceed this limit
+    ///
+    /// It is not applied to the commands send while connecting (e.g. `EHLO`
+    /// and `auth_cmd`). See `Connection::set_rate_limit`.
+    pub rate_limit: Option<RateLimit>,
+    /// if true the ehlo data from before `STARTTLS` is kept for diagnostics
+    ///
+    /// See `Connection::cleartext_ehlo_data`.
+    pub keep_cleartext_ehlo_data: bool,
+    /// if true the connection is returned without sending `EHLO`
+    ///
+    /// This allows driving the handshake manually, e.g. to send a custom
+    /// command before `EHLO`. The connection is returned directly after
+    /// the greeting (or for `STARTTLS` directly after the tls handshake,
+    /// as the `EHLO` before it can not be skipped), i.e. `ehlo_data` returns
+    /// `None` until `EHLO` is send by the caller. As both need the ehlo data
+    /// it can not be combined with an auth command (see `Cmd::auth_identity`) or
+    /// `required_capabilities`, connecting fails with `ConnectingFailed::InvalidConfig`
+    /// if it is. `allow_insecure_auth` is still applied.
+    pub skip_auto_ehlo: bool,
+    /// if set this PROXY protocol header is send directly after connecting
+    ///
+    /// I.e. before the greeting is read and (for direct tls) before the tls
+    /// handshake, as required by load balancers using the PROXY protocol.
+    pub proxy_header: Option<ProxyHeader>,
+    /// if set and `STARTTLS` is not advertised direct tls is used on this port instead
+    ///
+    /// This only applies to `Security::StartTls` and is tried only once,
+    /// e.g. to fall back to port 465 if a server doesn't support `STARTTLS`
+    /// on port 587. The same `TlsConfig` is used, the ip address stays the
+    /// same.
+    pub direct_tls_fallback_port: Option<u16>
 }
 
 
+impl<A, S> ConnectionConfig<A, S>
+    where S: SetupTls, A: Cmd
+{
+    /// creates a config from the required settings, all other settings use their defaults
+    ///
+    /// Normally `ConnectionBuilder` should be used, this is meant for the
+    /// setups it doesn't cover, e.g. an unencrypted connection to another
+    /// host (see `Security::plaintext_insecure`).
+    pub fn new(addr: SocketAddr, auth_cmd: A, security: Security<S>, client_id: ClientId) -> Self {
+        ConnectionConfig {
+            addr,
+            auth_cmd,
+            security,
+            client_id,
+            idle_timeout: None,
+            read_buffer_size: None,
+            allow_insecure_auth: false,
+            required_capabilities: Vec::new(),
+            client_id_lookup: None,
+            tls_handshake_timeout: None,
+            rate_limit: None,
+            keep_cleartext_ehlo_data: false,
+            skip_auto_ehlo: false,
+            proxy_header: None,
+            direct_tls_fallback_port: None
+        }
+    }
+}
+
 impl<A> ConnectionConfig<A, DefaultTlsSetup>
     where A: Cmd
 {
@@ -262,7 +658,15 @@ impl ConnectionConfig<Noop, DefaultTlsSetup> {
         LocalNonSecureBuilder {
             client_id: None,
             port: DEFAULT_SMTP_MSA_PORT,
-            auth_cmd: Noop
+            auth_cmd: Noop,
+            idle_timeout: None,
+            read_buffer_size: None,
+            allow_insecure_auth: false,
+            required_capabilities: Vec::new(),
+            client_id_lookup: None,
+            rate_limit: None,
+            skip_auto_ehlo: false,
+            proxy_header: None
         }
     }
 
@@ -294,7 +698,15 @@ pub struct LocalNonSecureBuilder<A>
 {
     client_id: Option<ClientId>,
     port: u16,
-    auth_cmd: A
+    auth_cmd: A,
+    idle_timeout: Option<Duration>,
+    read_buffer_size: Option<usize>,
+    allow_insecure_auth: bool,
+    required_capabilities: Vec<Capability>,
+    client_id_lookup: Option<Arc<ReverseLookup>>,
+    rate_limit: Option<RateLimit>,
+    skip_auto_ehlo: bool,
+    proxy_header: Option<ProxyHeader>
 }
 
 impl<A> LocalNonSecureBuilder<A>
@@ -312,23 +724,87 @@ impl<A> LocalNonSecureBuilder<A>
         self
     }
 
+    /// derives the client id from the local address (default: not done)
+    ///
+    /// See `ConnectionConfig::client_id_lookup`.
+    pub fn client_id_from_local_addr(mut self, lookup: Arc<ReverseLookup>) -> Self {
+        self.client_id_lookup = Some(lookup);
+        self
+    }
+
+    /// sets the idle timeout (default: none, see `Connection::set_idle_timeout`)
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// sets the rate limit for commands (default: none, see `Connection::set_rate_limit`)
+    pub fn rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limit = Some(limit);
+        self
+    }
+
+    /// don't send `EHLO` after the greeting, leaving it to the caller (default: false)
+    ///
+    /// See `ConnectionConfig::skip_auto_ehlo`.
+    pub fn skip_auto_ehlo(mut self) -> Self {
+        self.skip_auto_ehlo = true;
+        self
+    }
+
+    /// sends given PROXY protocol header directly after connecting (default: none)
+    ///
+    /// See `ConnectionConfig::proxy_header`.
+    pub fn proxy_header(mut self, header: ProxyHeader) -> Self {
+        self.proxy_header = Some(header);
+        self
+    }
+
+    /// sets the read buffer size (default: 256, see `Io::set_read_buffer_size`)
+    pub fn read_buffer_size(mut self, size: usize) -> Self {
+        self.read_buffer_size = Some(size);
+        self
+    }
+
+    /// allows the auth command to send credentials unencrypted (default: false)
+    ///
+    /// See `Connection::set_allow_insecure_auth`.
+    pub fn allow_insecure_auth(mut self) -> Self {
+        self.allow_insecure_auth = true;
+        self
+    }
+
+    /// requires the server to advertise given capability (default: none required)
+    ///
+    /// See `ConnectionConfig::required_capabilities`.
+    pub fn require_capability(mut self, cap: Capability) -> Self {
+        self.required_capabilities.push(cap);
+        self
+    }
+
     /// sets the auth command to use (default no authentication)
     pub fn auth<NA>(self, auth_cmd: NA) -> LocalNonSecureBuilder<NA>
         where NA: Cmd
     {
         let LocalNonSecureBuilder {
-            client_id, port, auth_cmd:_
+            client_id, port, auth_cmd:_, idle_timeout, read_buffer_size,
+            allow_insecure_auth, required_capabilities, client_id_lookup, rate_limit,
+            skip_auto_ehlo, proxy_header
         } = self;
 
         LocalNonSecureBuilder {
-            client_id, port, auth_cmd
+            client_id, port, auth_cmd, idle_timeout, read_buffer_size,
+            allow_insecure_auth, required_capabilities, client_id_lookup, rate_limit,
+            skip_auto_ehlo, proxy_header
         }
     }
 
     // builds the connection config
     pub fn build(self) -> ConnectionConfig<A, DefaultTlsSetup> {
         let LocalNonSecureBuilder {
-            client_id, port, auth_cmd
+            client_id, port, auth_cmd, idle_timeout, read_buffer_size,
+            allow_insecure_auth, required_capabilities, client_id_lookup, rate_limit,
+            skip_auto_ehlo, proxy_header
         } = self;
 
         let client_id = client_id
@@ -336,10 +812,15 @@ impl<A> LocalNonSecureBuilder<A>
 
         let addr = SocketAddr::new(Ipv4Addr::new(127,0,0,1).into(), port);
 
-        #[allow(deprecated)]
-        let security = Security::None;
+        let security = Security::plaintext_insecure(PlaintextAcknowledged::insecure_no_encryption());
 
-        ConnectionConfig { addr, client_id, auth_cmd, security }
+        ConnectionConfig {
+            addr, client_id, auth_cmd, security, idle_timeout, read_buffer_size,
+            allow_insecure_auth, required_capabilities, client_id_lookup,
+            tls_handshake_timeout: None, rate_limit,
+            keep_cleartext_ehlo_data: false, skip_auto_ehlo, proxy_header,
+            direct_tls_fallback_port: None
+        }
     }
 
     /// Calls `Connection::connect(self.build())`.
@@ -359,9 +840,20 @@ pub struct ConnectionBuilder<A, S = DefaultTlsSetup>
     client_id: Option<ClientId>,
     addr: SocketAddr,
     domain: Domain,
+    sni_domain: Option<Domain>,
     setup_tls: S,
     use_security: UseSecurity,
-    auth_cmd: A
+    auth_cmd: A,
+    idle_timeout: Option<Duration>,
+    read_buffer_size: Option<usize>,
+    required_capabilities: Vec<Capability>,
+    client_id_lookup: Option<Arc<ReverseLookup>>,
+    tls_handshake_timeout: Option<Duration>,
+    rate_limit: Option<RateLimit>,
+    keep_cleartext_ehlo_data: bool,
+    skip_auto_ehlo: bool,
+    proxy_header: Option<ProxyHeader>,
+    direct_tls_fallback_port: Option<u16>
 }
 
 impl ConnectionBuilder<Noop, DefaultTlsSetup> {
@@ -402,14 +894,31 @@ impl ConnectionBuilder<Noop, DefaultTlsSetup> {
     ///
     /// The domain name is used for Server Name Identification (SNI) and
     /// Tls hostname verification (hostname of the server).
+    ///
+    /// The domain is fully independent of the address, no (reverse) DNS
+    /// lookup is done and it does not have to resolve to `addr`. This allows
+    /// pinning the connection to a known-good ip address while still
+    /// verifying the certificate against the expected domain. Use
+    /// `sni_domain` to send a different domain through SNI.
     pub fn new_with_addr(addr: SocketAddr, domain: Domain) -> Self {
         ConnectionBuilder {
             addr,
             domain,
+            sni_domain: None,
             use_security: UseSecurity::StartTls,
             client_id: None,
             setup_tls: DefaultTlsSetup,
-            auth_cmd: Noop
+            auth_cmd: Noop,
+            idle_timeout: None,
+            read_buffer_size: None,
+            required_capabilities: Vec::new(),
+            client_id_lookup: None,
+            tls_handshake_timeout: None,
+            rate_limit: None,
+            keep_cleartext_ehlo_data: false,
+            skip_auto_ehlo: false,
+            proxy_header: None,
+            direct_tls_fallback_port: None
         }
     }
 
@@ -424,20 +933,26 @@ impl<A, S> ConnectionBuilder<A, S>
     /// e.g. if you want to:
     ///
     /// - use client certificate authentication
-    /// - change the min/max protocol version
+    /// - change the min/max protocol version (see `TlsOptions`)
     /// - add a root certificate
     /// - disable sni
     /// - and some crazy stuff like disable hostname verification, or certificate verification
     ///
     pub fn use_tls_setup<S2: SetupTls>(self, setup: S2) -> ConnectionBuilder<A, S2> {
         let ConnectionBuilder {
-            addr, domain, use_security,
-            client_id, setup_tls:_, auth_cmd
+            addr, domain, sni_domain, use_security,
+            client_id, setup_tls:_, auth_cmd,
+            idle_timeout, read_buffer_size, required_capabilities, client_id_lookup,
+            tls_handshake_timeout, rate_limit, keep_cleartext_ehlo_data, skip_auto_ehlo,
+            proxy_header, direct_tls_fallback_port
         } = self;
 
         ConnectionBuilder {
-            addr, domain, use_security,
-            client_id, setup_tls: setup, auth_cmd
+            addr, domain, sni_domain, use_security,
+            client_id, setup_tls: setup, auth_cmd,
+            idle_timeout, read_buffer_size, required_capabilities, client_id_lookup,
+            tls_handshake_timeout, rate_limit, keep_cleartext_ehlo_data, skip_auto_ehlo,
+            proxy_header, direct_tls_fallback_port
         }
     }
 
@@ -447,6 +962,14 @@ impl<A, S> ConnectionBuilder<A, S>
         self
     }
 
+    /// Make the builder use opportunistic `STARTTLS` security when building.
+    ///
+    /// See `Security::Opportunistic` for details.
+    pub fn use_opportunistic_tls(mut self, fallback: PlaintextFallback) -> Self {
+        self.use_security = UseSecurity::Opportunistic(fallback);
+        self
+    }
+
     /// Make the builder use direct tls security when building.
     ///
     /// This is sometimes known as "wrapped" mode, it used a
@@ -466,22 +989,109 @@ impl<A, S> ConnectionBuilder<A, S>
         self
     }
 
+    /// Send a different domain through SNI than the one the certificate is verified against.
+    ///
+    /// See `TlsConfig::with_sni_domain` for details, this requires a tls setup
+    /// providing a `SetupTls::domain_verifier` (see `use_tls_setup`).
+    pub fn sni_domain(mut self, sni_domain: Domain) -> Self {
+        self.sni_domain = Some(sni_domain);
+        self
+    }
+
+    /// Fall back to direct tls on `port` if the server doesn't advertise `STARTTLS`.
+    ///
+    /// See `ConnectionConfig::direct_tls_fallback_port` for details.
+    pub fn fallback_to_direct_tls(mut self, port: u16) -> Self {
+        self.direct_tls_fallback_port = Some(port);
+        self
+    }
+
     /// Set the command to use for authentication.
     ///
     /// If this function is not called `Noop` is used,
     /// i.e. no authentication is done.
     pub fn auth<NA: Cmd>(self, auth_cmd: NA) -> ConnectionBuilder<NA, S> {
         let ConnectionBuilder {
-            addr, domain, use_security,
-            client_id, setup_tls, auth_cmd:_
+            addr, domain, sni_domain, use_security,
+            client_id, setup_tls, auth_cmd:_,
+            idle_timeout, read_buffer_size, required_capabilities, client_id_lookup,
+            tls_handshake_timeout, rate_limit, keep_cleartext_ehlo_data, skip_auto_ehlo,
+            proxy_header, direct_tls_fallback_port
         } = self;
 
         ConnectionBuilder {
-            addr, domain, use_security,
-            client_id, setup_tls, auth_cmd: auth_cmd
+            addr, domain, sni_domain, use_security,
+            client_id, setup_tls, auth_cmd: auth_cmd,
+            idle_timeout, read_buffer_size, required_capabilities, client_id_lookup,
+            tls_handshake_timeout, rate_limit, keep_cleartext_ehlo_data, skip_auto_ehlo,
+            proxy_header, direct_tls_fallback_port
         }
     }
 
+    /// Close the connection once it was idle for longer than `timeout`.
+    ///
+    /// See `Connection::set_idle_timeout` for details.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail if the tls handshake takes longer than `timeout` (by default there is no timeout).
+    ///
+    /// See `ConnectionConfig::tls_handshake_timeout` for details.
+    pub fn tls_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.tls_handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// Delay commands so that they don't exceed `limit` (by default there is no limit).
+    ///
+    /// See `Connection::set_rate_limit` for details.
+    pub fn rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limit = Some(limit);
+        self
+    }
+
+    /// Keep the ehlo data from before `STARTTLS` (by default it is discarded).
+    ///
+    /// See `Connection::cleartext_ehlo_data` for details.
+    pub fn keep_cleartext_ehlo_data(mut self) -> Self {
+        self.keep_cleartext_ehlo_data = true;
+        self
+    }
+
+    /// Don't send `EHLO` when connecting, leaving it to the caller.
+    ///
+    /// See `ConnectionConfig::skip_auto_ehlo` for details.
+    pub fn skip_auto_ehlo(mut self) -> Self {
+        self.skip_auto_ehlo = true;
+        self
+    }
+
+    /// Send given PROXY protocol header directly after connecting (by default none is send).
+    ///
+    /// See `ConnectionConfig::proxy_header` for details.
+    pub fn proxy_header(mut self, header: ProxyHeader) -> Self {
+        self.proxy_header = Some(header);
+        self
+    }
+
+    /// Set the size by which the read buffer grows (the default is 256 bytes).
+    ///
+    /// See `Io::set_read_buffer_size` for details.
+    pub fn read_buffer_size(mut self, size: usize) -> Self {
+        self.read_buffer_size = Some(size);
+        self
+    }
+
+    /// Require the server to advertise given capability (by default none are required).
+    ///
+    /// See `ConnectionConfig::required_capabilities` for details.
+    pub fn require_capability(mut self, cap: Capability) -> Self {
+        self.required_capabilities.push(cap);
+        self
+    }
+
     /// Set's the client identity to the given identity.
     ///
     /// (The default is to use `ClientId::hostname()`)
@@ -490,6 +1100,14 @@ impl<A, S> ConnectionBuilder<A, S>
         self
     }
 
+    /// Derive the client identity from the local address of the connection.
+    ///
+    /// See `ConnectionConfig::client_id_lookup` for details.
+    pub fn client_id_from_local_addr(mut self, lookup: Arc<ReverseLookup>) -> Self {
+        self.client_id_lookup = Some(lookup);
+        self
+    }
+
 
     /// Creates a new connection config.
     ///
@@ -499,24 +1117,42 @@ impl<A, S> ConnectionBuilder<A, S>
     /// - `Noop` is used as authentication command, i.e. no auth is done
     /// - `StartTls` is used as security method
     /// - `DefaultTlsSetup` is used for setting up tls (i.e. no special options are set)
+    /// - the domain is used for SNI
+    /// - no idle timeout is used
+    /// - no tls handshake timeout is used
+    /// - no rate limit is used
+    /// - the ehlo data from before `STARTTLS` is not kept
+    /// - `EHLO` is send automatically
+    /// - no PROXY protocol header is send
+    /// - there is no fallback to direct tls if `STARTTLS` is not supported
+    /// - the default read buffer size is used
+    /// - no capabilities are required
+    /// - the client identity is not derived from the local address
     ///
     pub fn build(self) -> ConnectionConfig<A, S> {
         let ConnectionBuilder {
-            addr, domain, use_security,
-            client_id, setup_tls: setup, auth_cmd
+            addr, domain, sni_domain, use_security,
+            client_id, setup_tls: setup, auth_cmd,
+            idle_timeout, read_buffer_size, required_capabilities, client_id_lookup,
+            tls_handshake_timeout, rate_limit, keep_cleartext_ehlo_data, skip_auto_ehlo,
+            proxy_header, direct_tls_fallback_port
         } = self;
 
-        let tls_config = TlsConfig { domain, setup };
+        let tls_config = TlsConfig { domain, sni_domain, setup };
         let security =
             match use_security {
                 UseSecurity::StartTls => Security::StartTls(tls_config),
-                UseSecurity::DirectTls => Security::DirectTls(tls_config)
+                UseSecurity::DirectTls => Security::DirectTls(tls_config),
+                UseSecurity::Opportunistic(fallback) => Security::Opportunistic(tls_config, fallback)
             };
 
         let client_id = client_id.unwrap_or_else(|| ClientId::hostname());
 
         ConnectionConfig {
-            addr, security, auth_cmd, client_id
+            addr, security, auth_cmd, client_id, idle_timeout, read_buffer_size,
+            allow_insecure_auth: false, required_capabilities, client_id_lookup,
+            tls_handshake_timeout, rate_limit, keep_cleartext_ehlo_data, skip_auto_ehlo,
+            proxy_header, direct_tls_fallback_port
         }
     }
 
@@ -531,10 +1167,10 @@ impl<A, S> ConnectionBuilder<A, S>
 
 #[derive(Debug)]
 enum UseSecurity {
-    StartTls, DirectTls
+    StartTls, DirectTls, Opportunistic(PlaintextFallback)
 }
 
-fn get_addr(tsas: impl ToSocketAddrs + Copy + Debug) -> Result<SocketAddr, std_io::Error> {
+pub(crate) fn get_addr(tsas: impl ToSocketAddrs + Copy + Debug) -> Result<SocketAddr, std_io::Error> {
     if let Some(addr) = tsas.to_socket_addrs()?.next() {
         Ok(addr)
     } else {
@@ -546,6 +1182,8 @@ fn get_addr(tsas: impl ToSocketAddrs + Copy + Debug) -> Result<SocketAddr, std_i
 #[cfg(test)]
 mod testd {
     use hostname::get_hostname;
+    use ::common::{HandshakeKind, ReverseLookupFuture};
+    use ::stub_server::{self, default_response, serve_lines};
     use super::*;
 
     //this domain has to exist
@@ -558,7 +1196,10 @@ mod testd {
         let cb = ConnectionBuilder::new(host.clone()).unwrap();
 
         let ConnectionConfig {
-            addr, security, auth_cmd, client_id
+            addr, security, auth_cmd, client_id, idle_timeout, read_buffer_size,
+            allow_insecure_auth, required_capabilities, client_id_lookup,
+            tls_handshake_timeout, rate_limit, keep_cleartext_ehlo_data, skip_auto_ehlo,
+            proxy_header, direct_tls_fallback_port
         } = cb.build();
 
         assert!(
@@ -567,11 +1208,19 @@ mod testd {
             .unwrap()
             .any(|other_addr| other_addr == addr)
         );
-        assert_eq!(security, Security::StartTls(TlsConfig {
-            domain: host,
-            setup: DefaultTlsSetup
-        }));
+        assert_eq!(security, Security::StartTls(TlsConfig::new(host, DefaultTlsSetup)));
         let _type_check: Noop = auth_cmd;
+        assert_eq!(idle_timeout, None);
+        assert_eq!(read_buffer_size, None);
+        assert!(!allow_insecure_auth);
+        assert!(required_capabilities.is_empty());
+        assert!(client_id_lookup.is_none());
+        assert_eq!(tls_handshake_timeout, None);
+        assert_eq!(rate_limit, None);
+        assert!(!skip_auto_ehlo);
+        assert_eq!(proxy_header, None);
+        assert_eq!(direct_tls_fallback_port, None);
+        assert!(!keep_cleartext_ehlo_data);
         if let ClientId::Domain(domain) = client_id {
             let expected_client_id = get_hostname()
                 .unwrap_or_else(|| "localhost".to_owned());
@@ -580,4 +1229,586 @@ mod testd {
             panic!("unexpected client id: {:?}", client_id);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn default_ports() {
+        assert_eq!(DEFAULT_SMTP_PORT, 25);
+        assert_eq!(DEFAULT_SUBMISSION_PORT, 587);
+        assert_eq!(DEFAULT_SMTPS_PORT, 465);
+
+        let tls_config = TlsConfig::from(Domain::from_unchecked("smtp.test"));
+        #[allow(deprecated)]
+        let none = Security::<DefaultTlsSetup>::None;
+        assert_eq!(none.default_port(), 25);
+        assert_eq!(Security::DirectTls(tls_config.clone()).default_port(), 465);
+        assert_eq!(Security::StartTls(tls_config).default_port(), 587);
+    }
+
+    /// maps 127.0.0.1 to `client.example.test`
+    #[derive(Debug)]
+    struct StubLookup;
+
+    impl ReverseLookup for StubLookup {
+        fn lookup(&self, addr: ::std::net::IpAddr) -> ReverseLookupFuture {
+            let domain =
+                if addr == Ipv4Addr::new(127, 0, 0, 1) {
+                    Some(Domain::from_unchecked("client.example.test"))
+                } else {
+                    None
+                };
+            Box::new(future::ok(domain))
+        }
+    }
+
+    #[derive(Debug)]
+    struct FailingLookup;
+
+    impl ReverseLookup for FailingLookup {
+        fn lookup(&self, _addr: ::std::net::IpAddr) -> ReverseLookupFuture {
+            let err = ::std::io::Error::new(::std::io::ErrorKind::Other, "no dns server");
+            Box::new(future::err(err))
+        }
+    }
+
+    /// resolves every address to `None`
+    #[derive(Debug)]
+    struct UnknownLookup;
+
+    impl ReverseLookup for UnknownLookup {
+        fn lookup(&self, _addr: ::std::net::IpAddr) -> ReverseLookupFuture {
+            Box::new(future::ok(None))
+        }
+    }
+
+    /// runs a server answering `EHLO` (if `ehlo_supported`) or `HELO`, returns the received lines
+    fn stub_server(ehlo_supported: bool) -> (u16, ::std::thread::JoinHandle<Vec<String>>) {
+        let (addr, server) = stub_server::spawn_one(move |stream| {
+            serve_lines(stream, b"220 stub ready\r\n", |line| {
+                if line.starts_with("EHLO") && !ehlo_supported { Some(b"500 what?\r\n") }
+                else if line.starts_with("HELO") { Some(b"250 stub.test\r\n") }
+                else { default_response(line) }
+            })
+        });
+        (addr.port(), server)
+    }
+
+    fn local_builder(port: u16) -> LocalNonSecureBuilder<Noop> {
+        ConnectionConfig::builder_local_unencrypted()
+            .port(port)
+            .client_id(ClientId::Domain(Domain::from_unchecked("fixed.test")))
+    }
+
+    /// connects to a stub server returning the `EHLO` line it received
+    fn received_ehlo(lookup: Arc<ReverseLookup>) -> String {
+        let (port, server) = stub_server(true);
+
+        let config = local_builder(port)
+            .client_id_from_local_addr(lookup)
+            .build();
+        let con = Connection::connect(config).wait().unwrap();
+        con.quit().wait().unwrap();
+
+        server.join().unwrap().remove(0)
+    }
+
+    #[test]
+    fn client_id_is_resolved_from_local_addr() {
+        assert_eq!(received_ehlo(Arc::new(StubLookup)), "EHLO client.example.test\r\n");
+    }
+
+    #[test]
+    fn client_id_falls_back_to_address_literal() {
+        assert_eq!(received_ehlo(Arc::new(UnknownLookup)), "EHLO [127.0.0.1]\r\n");
+        assert_eq!(received_ehlo(Arc::new(FailingLookup)), "EHLO [127.0.0.1]\r\n");
+    }
+
+    #[test]
+    fn ehlo_handshake_is_reported() {
+        let (port, server) = stub_server(true);
+
+        let con = local_builder(port).connect().wait().unwrap();
+        assert_eq!(con.handshake_kind(), Some(HandshakeKind::Ehlo));
+        assert!(con.is_esmtp());
+        assert!(con.has_capability("SIZE"));
+        con.quit().wait().unwrap();
+
+        let lines = server.join().unwrap();
+        assert_eq!(lines[0], "EHLO fixed.test\r\n");
+    }
+
+    #[test]
+    fn falls_back_to_helo() {
+        let (port, server) = stub_server(false);
+
+        let con = local_builder(port).connect().wait().unwrap();
+        assert_eq!(con.handshake_kind(), Some(HandshakeKind::Helo));
+        assert!(!con.is_esmtp());
+        assert!(!con.has_capability("SIZE"));
+        con.quit().wait().unwrap();
+
+        let lines = server.join().unwrap();
+        assert_eq!(&lines[..2], &["EHLO fixed.test\r\n", "HELO fixed.test\r\n"]);
+    }
+
+    #[test]
+    fn reads_complete_multi_line_greeting_before_ehlo() {
+        use std::io::{Read, Write};
+        use std::time::Duration;
+
+        let (addr, server) = stub_server::spawn_one(|mut stream| {
+            stream.write_all(b"220-stub.test ESMTP\r\n").unwrap();
+            // the client must not send anything before the last greeting line
+            stream.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+            let sent_early = stream.read(&mut [0; 16]).is_ok();
+            stream.set_read_timeout(None).unwrap();
+
+            let lines = serve_lines(stream, b"220 no UCE\r\n", default_response);
+            (sent_early, lines)
+        });
+        let port = addr.port();
+
+        let con = local_builder(port).connect().wait().unwrap();
+        {
+            let greeting = con.greeting().unwrap();
+            assert_eq!(greeting.code(), ::response::codes::READY);
+            assert_eq!(greeting.msg(), &["stub.test ESMTP".to_owned(), "no UCE".to_owned()]);
+            let banner = con.banner().unwrap();
+            assert_eq!(banner.domain(), Some("stub.test"));
+            assert!(banner.announces_esmtp());
+            assert_eq!(banner.full_text(), "stub.test ESMTP\nno UCE");
+        }
+        assert!(con.has_capability("SIZE"));
+        con.quit().wait().unwrap();
+
+        let (sent_early, lines) = server.join().unwrap();
+        assert!(!sent_early);
+        assert_eq!(lines[0], "EHLO fixed.test\r\n");
+    }
+
+    #[test]
+    fn plaintext_works_with_explicit_opt_in() {
+        let (port, server) = stub_server(true);
+
+        let addr = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), port);
+        let mut config = ConnectionConfig::builder_with_addr(addr, Domain::from_unchecked("localhost"))
+            .client_id(ClientId::Domain(Domain::from_unchecked("fixed.test")))
+            .build();
+        config.security = Security::plaintext_insecure(PlaintextAcknowledged::insecure_no_encryption());
+        #[allow(deprecated)]
+        let expected = Security::None;
+        assert_eq!(config.security, expected);
+
+        let con = Connection::connect(config).wait().unwrap();
+        assert!(!con.is_secure());
+        assert!(con.has_capability("SIZE"));
+        con.quit().wait().unwrap();
+
+        let lines = server.join().unwrap();
+        assert_eq!(lines[0], "EHLO fixed.test\r\n");
+    }
+
+    #[test]
+    fn new_config_uses_defaults_for_optional_settings() {
+        let (port, server) = stub_server(true);
+
+        let addr = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), port);
+        let security = Security::plaintext_insecure(PlaintextAcknowledged::insecure_no_encryption());
+        let client_id = ClientId::Domain(Domain::from_unchecked("fixed.test"));
+        let config: ConnectionConfig<Noop> = ConnectionConfig::new(addr, Noop, security, client_id);
+        assert_eq!(config.idle_timeout, None);
+        assert!(config.required_capabilities.is_empty());
+        assert!(!config.allow_insecure_auth);
+        assert!(!config.skip_auto_ehlo);
+
+        let con = Connection::connect(config).wait().unwrap();
+        con.quit().wait().unwrap();
+
+        let lines = server.join().unwrap();
+        assert_eq!(lines, vec!["EHLO fixed.test\r\n", "NOOP\r\n", "QUIT\r\n"]);
+    }
+
+    #[test]
+    fn skip_auto_ehlo_leaves_ehlo_to_the_caller() {
+        use ::command::Ehlo;
+        let (port, server) = stub_server(true);
+
+        let con = local_builder(port).skip_auto_ehlo().connect().wait().unwrap();
+        assert!(con.ehlo_data().is_none());
+        assert_eq!(con.handshake_kind(), None);
+
+        let clid = ClientId::Domain(Domain::from_unchecked("manual.test"));
+        let (con, res) = con.send(Ehlo::from(clid)).wait().unwrap();
+        assert!(res.is_ok());
+        assert_eq!(con.ehlo_data().unwrap().domain(), "stub.test");
+        assert!(con.has_capability("SIZE"));
+        con.quit().wait().unwrap();
+
+        // neither `EHLO` nor the (default `NOOP`) auth command was send automatically
+        let lines = server.join().unwrap();
+        assert_eq!(lines, vec!["EHLO manual.test\r\n", "QUIT\r\n"]);
+    }
+
+    #[test]
+    fn skip_auto_ehlo_can_not_be_combined_with_auth() {
+        use ::command::auth::Plain;
+
+        let config = local_builder(1)
+            .skip_auto_ehlo()
+            .auth(Plain::from_username("user", "pass").unwrap())
+            .build();
+        match Connection::connect(config).wait() {
+            Err(ConnectingFailed::InvalidConfig(_)) => (),
+            Err(err) => panic!("unexpected error: {:?}", err),
+            Ok(_) => panic!("unexpectedly connected")
+        }
+
+        // also detected if the type of the auth command is erased
+        let config = local_builder(1)
+            .skip_auto_ehlo()
+            .auth(Plain::from_username("user", "pass").unwrap().boxed())
+            .build();
+        match Connection::connect(config).wait() {
+            Err(ConnectingFailed::InvalidConfig(_)) => (),
+            Err(err) => panic!("unexpected error: {:?}", err),
+            Ok(_) => panic!("unexpectedly connected")
+        }
+
+        let config = local_builder(1)
+            .skip_auto_ehlo()
+            .require_capability(Capability::from(EsmtpKeyword::from_unchecked("SIZE")))
+            .build();
+        match Connection::connect(config).wait() {
+            Err(ConnectingFailed::InvalidConfig(_)) => (),
+            Err(err) => panic!("unexpected error: {:?}", err),
+            Ok(_) => panic!("unexpectedly connected")
+        }
+    }
+}
+
+#[cfg(all(test, not(any(target_os = "windows", target_vendor = "apple"))))]
+mod tls_test {
+    use std::io::{BufReader, Write};
+    use std::net::SocketAddr;
+    use std::thread::JoinHandle;
+    use std::time::Duration;
+
+    use futures::Future;
+    use tokio::runtime::current_thread::Runtime;
+    use native_tls::{Identity, TlsAcceptor};
+
+    use ::common::{ClientId, TlsConfig, TlsOptions};
+    use ::data_types::{Domain, Capability};
+    use ::connection::Connection;
+    use ::command::Noop;
+    use ::error::{ConnectingFailed, TimeoutPhase};
+    use ::io::Io;
+    use ::stub_server::{self, default_response, respond, serve_lines, wait_for_close};
+    use ::stub_server::tls::{TrustTestCa, SERVER_CERT, SERVER_KEY, sni_recording_acceptor};
+    use super::{ConnectionConfig, PlaintextFallback, Security};
+
+    /// runs a server supporting STARTTLS, `ehlo_response` is the response to the EHLO after STARTTLS
+    ///
+    /// The server thread returns the server name (SNI) send by the client.
+    fn starttls_server(ehlo_response: &'static [u8]) -> (SocketAddr, JoinHandle<Option<String>>) {
+        stub_server::spawn_one(move |mut stream| {
+            stream.write_all(b"220 stub ready\r\n").unwrap();
+            let mut reader = BufReader::new(stream);
+            assert!(respond(&mut reader, b"250-stub.test\r\n250-AUTH PLAIN\r\n250 STARTTLS\r\n"));
+            assert!(respond(&mut reader, b"220 go ahead\r\n"));
+
+            let (acceptor, sni) = sni_recording_acceptor();
+            let stream = acceptor.accept(reader.into_inner()).unwrap();
+            serve_lines(stream, b"", |line| {
+                if line.starts_with("EHLO") { Some(ehlo_response) }
+                else { default_response(line) }
+            });
+
+            let sni = sni.lock().unwrap().take();
+            sni
+        })
+    }
+
+    fn starttls_config(addr: SocketAddr, required_capabilities: Vec<Capability>)
+        -> ConnectionConfig<Noop, TrustTestCa>
+    {
+        let security = Security::StartTls(TlsConfig::new(
+            Domain::from_unchecked("smtp.example.com"),
+            TrustTestCa(TlsOptions::new())
+        ));
+        let client_id = ClientId::Domain(Domain::from_unchecked("client.test"));
+        let mut config = ConnectionConfig::new(addr, Noop, security, client_id);
+        config.required_capabilities = required_capabilities;
+        config
+    }
+
+    #[test]
+    fn connection_is_secure_after_starttls() {
+        let (addr, server) = starttls_server(b"250 stub.test\r\n");
+
+        let con = Connection::connect(starttls_config(addr, Vec::new())).wait().unwrap();
+        assert!(con.is_secure());
+        let info = con.tls_info().unwrap();
+        assert_eq!(info.alpn_protocol(), None);
+
+        con.quit().wait().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn starttls_keeps_the_connection_settings() {
+        use ::command::StartTls;
+        use ::common::HandshakeKind;
+        use ::io::RateLimit;
+
+        let (addr, server) = starttls_server(b"250 stub.test\r\n");
+        let clid = ClientId::Domain(Domain::from_unchecked("client.test"));
+        let mut con = Connection::_connect_insecure(&addr, clid, None, None).wait().unwrap();
+        let rate_limit = RateLimit::new(100, Duration::from_secs(1));
+        con.set_idle_timeout(Some(Duration::from_secs(300)));
+        con.set_read_buffer_size(4096);
+        con.set_rate_limit(Some(rate_limit));
+        con.set_allow_insecure_auth(true);
+
+        let starttls = StartTls {
+            setup_tls: TrustTestCa(TlsOptions::new()),
+            sni_domain: Domain::from_unchecked("smtp.example.com"),
+            verify_domain: None,
+            handshake_timeout: None
+        };
+        let (con, result) = con.send(starttls).wait().unwrap();
+        result.unwrap();
+        assert!(con.is_secure());
+        // the server has to be greeted again
+        assert!(con.ehlo_data().is_none());
+
+        let (socket, state) = con.into_parts();
+        let io = Io::from_parts(socket, state);
+        assert_eq!(io.idle_timeout(), Some(Duration::from_secs(300)));
+        assert_eq!(io.read_buffer_size(), 4096);
+        assert_eq!(io.rate_limit(), Some(rate_limit));
+        assert!(io.allows_insecure_auth());
+        assert_eq!(io.handshake_kind(), Some(HandshakeKind::Ehlo));
+
+        Connection::from(io).quit().wait().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn connects_to_pinned_ip_verifying_a_separate_domain() {
+        let (addr, server) = starttls_server(b"250 stub.test\r\n");
+        assert!(addr.ip().is_loopback());
+
+        let config = ConnectionConfig::builder_with_addr(addr, Domain::from_unchecked("smtp.example.com"))
+            .use_tls_setup(TrustTestCa(TlsOptions::new()))
+            .sni_domain(Domain::from_unchecked("tenant.cdn.example.net"))
+            .client_id(ClientId::Domain(Domain::from_unchecked("client.test")))
+            .build();
+        match config.security {
+            Security::StartTls(ref tls_config) => {
+                assert_eq!(tls_config.domain.as_str(), "smtp.example.com");
+                assert_eq!(tls_config.get_sni_domain().as_str(), "tenant.cdn.example.net");
+            },
+            ref other => panic!("unexpected security: {:?}", other)
+        }
+
+        let con = Connection::connect(config).wait().unwrap();
+        assert!(con.is_secure());
+
+        con.quit().wait().unwrap();
+        let sni = server.join().unwrap();
+        assert_eq!(sni.as_ref().map(|sni| &**sni), Some("tenant.cdn.example.net"));
+    }
+
+    #[test]
+    fn starttls_rejection_carries_the_reply() {
+        let (addr, server) = stub_server::spawn_one(|stream| {
+            serve_lines(stream, b"220 stub ready\r\n", |line| Some(
+                if line.starts_with("EHLO") { b"250-stub.test\r\n250 STARTTLS\r\n" }
+                else if line.starts_with("STARTTLS") { b"454 4.7.0 TLS not available due to temporary reason\r\n" }
+                else { b"221 Bye\r\n" }
+            ))
+        });
+
+        let err = match Connection::connect(starttls_config(addr, Vec::new())).wait() {
+            Err(err) => err,
+            Ok(_) => panic!("unexpectedly connected")
+        };
+
+        match err {
+            ConnectingFailed::StartTlsRejected(ref response) => {
+                assert_eq!(response.code().as_byte_string(), *b"454");
+                assert_eq!(response.msg(), &["4.7.0 TLS not available due to temporary reason".to_owned()]);
+            },
+            ref other => panic!("unexpected error: {:?}", other)
+        }
+        assert!(err.is_transient());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn required_capabilities_are_checked_after_starttls() {
+        // AUTH is only advertised before STARTTLS
+        let (addr, server) = starttls_server(b"250-stub.test\r\n250 SIZE 1000\r\n");
+        let required = vec!["SIZE".parse().unwrap(), "AUTH".parse().unwrap()];
+
+        let res = Connection::connect(starttls_config(addr, required)).wait();
+
+        match res {
+            Err(ConnectingFailed::MissingCapability(cap)) => assert_eq!(cap.as_str(), "AUTH"),
+            Err(err) => panic!("unexpected error: {:?}", err),
+            Ok(_) => panic!("unexpectedly connected")
+        }
+        server.join().unwrap();
+    }
+
+    /// runs a server not supporting STARTTLS
+    fn plaintext_server() -> (SocketAddr, JoinHandle<Vec<String>>) {
+        stub_server::spawn_one(|stream| serve_lines(stream, b"220 stub ready\r\n", default_response))
+    }
+
+    fn opportunistic_config(addr: SocketAddr, fallback: PlaintextFallback)
+        -> ConnectionConfig<Noop, TrustTestCa>
+    {
+        let mut config = starttls_config(addr, Vec::new());
+        config.security = match config.security {
+            Security::StartTls(tls_config) => Security::Opportunistic(tls_config, fallback),
+            _ => unreachable!()
+        };
+        config
+    }
+
+    #[test]
+    fn opportunistic_tls_upgrades_if_available() {
+        let (addr, server) = starttls_server(b"250 stub.test\r\n");
+
+        let config = opportunistic_config(addr, PlaintextFallback::default());
+        let con = Connection::connect(config).wait().unwrap();
+        assert!(con.is_secure());
+
+        con.quit().wait().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn opportunistic_tls_fails_closed_by_default() {
+        let (addr, server) = plaintext_server();
+
+        let res = Connection::connect(opportunistic_config(addr, PlaintextFallback::default())).wait();
+
+        match res {
+            Err(ConnectingFailed::MissingCapability(cap)) => assert_eq!(cap.as_str(), "STARTTLS"),
+            Err(err) => panic!("unexpected error: {:?}", err),
+            Ok(_) => panic!("unexpectedly connected")
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn opportunistic_tls_can_allow_plaintext() {
+        let (addr, server) = plaintext_server();
+
+        let config = opportunistic_config(addr, PlaintextFallback::AllowPlaintext);
+        let con = Connection::connect(config).wait().unwrap();
+        assert!(!con.is_secure());
+        assert!(con.has_capability("SIZE"));
+
+        con.quit().wait().unwrap();
+        server.join().unwrap();
+    }
+
+    /// runs a server using direct tls (i.e. like on port 465)
+    fn direct_tls_server() -> (SocketAddr, JoinHandle<Vec<String>>) {
+        stub_server::spawn_one(|stream| {
+            let identity = Identity::from_pkcs8(SERVER_CERT, SERVER_KEY).unwrap();
+            let acceptor = TlsAcceptor::new(identity).unwrap();
+            let stream = acceptor.accept(stream).unwrap();
+            serve_lines(stream, b"220 stub ready\r\n", |line| {
+                if line.starts_with("EHLO") { Some(b"250-stub.test\r\n250 AUTH PLAIN\r\n") }
+                else { default_response(line) }
+            })
+        })
+    }
+
+    #[test]
+    fn starttls_can_fall_back_to_direct_tls() {
+        let (plain_addr, plain_server) = plaintext_server();
+        let (tls_addr, tls_server) = direct_tls_server();
+
+        let mut config = starttls_config(plain_addr, Vec::new());
+        config.direct_tls_fallback_port = Some(tls_addr.port());
+        let con = Connection::connect(config).wait().unwrap();
+        assert!(con.is_secure());
+        assert!(con.has_capability("AUTH"));
+
+        con.quit().wait().unwrap();
+        plain_server.join().unwrap();
+        tls_server.join().unwrap();
+    }
+
+    #[test]
+    fn direct_tls_fallback_is_not_used_if_starttls_is_available() {
+        let (addr, server) = starttls_server(b"250 stub.test\r\n");
+
+        let mut config = starttls_config(addr, Vec::new());
+        // nothing listens there, so connecting to it would fail
+        config.direct_tls_fallback_port = Some(1);
+        let con = Connection::connect(config).wait().unwrap();
+        assert!(con.is_secure());
+
+        con.quit().wait().unwrap();
+        server.join().unwrap();
+    }
+
+    /// runs a server which completes the tcp connect but never answers the tls handshake
+    ///
+    /// If `starttls` is true it first does the smtp part of `STARTTLS`.
+    fn stalling_tls_server(starttls: bool) -> (SocketAddr, JoinHandle<()>) {
+        stub_server::spawn_one(move |mut stream| {
+            if starttls {
+                stream.write_all(b"220 stub ready\r\n").unwrap();
+                let mut reader = BufReader::new(stream);
+                assert!(respond(&mut reader, b"250-stub.test\r\n250 STARTTLS\r\n"));
+                assert!(respond(&mut reader, b"220 go ahead\r\n"));
+                stream = reader.into_inner();
+            }
+            // read the ClientHello (and anything else) until the client hangs up
+            wait_for_close(stream);
+        })
+    }
+
+    fn assert_tls_handshake_timeout(res: Result<Connection, ConnectingFailed>) {
+        match res {
+            Err(ConnectingFailed::Timeout(TimeoutPhase::TlsHandshake)) => (),
+            Err(err) => panic!("unexpected error: {:?}", err),
+            Ok(_) => panic!("unexpectedly connected")
+        }
+    }
+
+    #[test]
+    fn starttls_handshake_timeout() {
+        let (addr, server) = stalling_tls_server(true);
+        let mut config = starttls_config(addr, Vec::new());
+        config.tls_handshake_timeout = Some(Duration::from_millis(200));
+
+        let res = Runtime::new().unwrap().block_on(Connection::connect(config));
+
+        assert_tls_handshake_timeout(res);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn direct_tls_handshake_timeout() {
+        let (addr, server) = stalling_tls_server(false);
+        let mut config = starttls_config(addr, Vec::new());
+        config.security = match config.security {
+            Security::StartTls(tls_config) => Security::DirectTls(tls_config),
+            _ => unreachable!()
+        };
+        config.tls_handshake_timeout = Some(Duration::from_millis(200));
+
+        let res = Runtime::new().unwrap().block_on(Connection::connect(config));
+
+        assert_tls_handshake_timeout(res);
+        server.join().unwrap();
+    }
+}
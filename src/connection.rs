@@ -1,13 +1,33 @@
 use std::{io as std_io};
+use std::time::{Duration, SystemTime};
+use std::net::SocketAddr;
+use std::sync::Arc;
 
-use futures::future::{self, Future, Either};
+use futures::future::{self, Future, Either, Loop};
 use tokio::io::{shutdown, Shutdown};
+use tokio::clock;
+use tokio::timer::Delay;
 
-use ::common::EhloData;
+use ::data_types::ForwardPath;
+use ::common::{EhloData, Capabilities, CapabilitiesObserver, HandshakeKind, ClientId, NO_CAPABILITIES};
 use ::error::{LogicError, MissingCapabilities};
-use ::io::{Io, SmtpResult, Socket};
+use ::io::{Io, IoState, RateLimit, ResponseInterceptor, SmtpResult, Socket, TlsInfo};
+#[cfg(feature="metrics")]
+use ::io::ConnectionMetrics;
+#[cfg(feature="transcript")]
+use ::io::{Transcript, ErrorLog};
+use ::response::{Response, Greeting, codes};
+use ::util::date::find_timestamp;
 
 /// future returned by `Cmd::exec`
+///
+/// `Connection::send` itself returns an unboxed `impl Future`, but the
+/// future returned by `exec` is boxed, which is needed for `Cmd` to be
+/// usable as `BoxedCmd`. Besides this box parsing the response allocates
+/// (its lines), while the I/O buffers are reused across commands. Commands
+/// might allocate further e.g. for their arguments (see `tests/allocations.rs`
+/// which counts the allocations of sending `NOOP`).
+//FIXME[rust/impl Trait for associated type]: use a associated future type in `Cmd`
 pub type ExecFuture = Box<Future<Item=(Io, SmtpResult), Error=std_io::Error> + Send + 'static>;
 
 /// The basic `Connection` type representing an (likely) open smtp connection
@@ -21,14 +41,73 @@ pub type ExecFuture = Box<Future<Item=(Io, SmtpResult), Error=std_io::Error> + S
 /// the `connect` method, call the `send` method or the `quit` method (
 /// or the `send_mail` cmd if the future is enabled). All other methods
 /// of it are mainly for implementor of the `Cmd` trait.
+///
+/// Dropping a `Connection` shuts down the underlying socket (see
+/// `Socket::shutdown_now`) _without_ sending `QUIT`, so calling
+/// `quit` explicitly is still preferred.
 #[derive(Debug)]
 pub struct Connection {
-    io: Io
+    // always `Some` except in drop after `into_inner` was called
+    io: Option<Io>
+}
+
+
+/// marks the command as in flight, executes it and updates the connection state from the result
+fn exec_cmd<C: Cmd>(mut io: Io, cmd: C)
+    -> impl Future<Item=(Connection, SmtpResult), Error=std_io::Error>
+{
+    io.set_cmd_in_flight(true);
+    #[cfg(feature="metrics")]
+    io.metrics_mut().record_command();
+    cmd
+        .exec(io)
+        .map(|(mut io, smtp_res)| {
+            io.set_cmd_in_flight(false);
+            io.reset_idle_timer();
+            #[cfg(feature="metrics")]
+            {
+                if let Err(LogicError::Code(ref response)) = smtp_res {
+                    io.metrics_mut().record_failure(response.code());
+                }
+            }
+            let smtp_res = match smtp_res {
+                Err(LogicError::Code(response)) => {
+                    if response.code() == codes::SERVICE_UNAVAILABLE {
+                        io.set_service_closed(true);
+                        Err(LogicError::ServiceClosing(response))
+                    } else {
+                        Err(LogicError::Code(response))
+                    }
+                },
+                Err(LogicError::Aborted) => {
+                    // the command did not complete, so the connection stays poisoned
+                    io.set_cmd_in_flight(true);
+                    Err(LogicError::Aborted)
+                },
+                Err(err @ LogicError::MalformedResponse { .. })
+                | Err(err @ LogicError::ResponseTooLarge { .. })
+                | Err(err @ LogicError::TooManyResponseLines { .. }) => {
+                    // the rest of the response can not be told apart from the next one
+                    io.set_cmd_in_flight(true);
+                    Err(err)
+                },
+                other => other
+            };
+            (Connection::from(io), smtp_res)
+        })
 }
 
 
 impl Connection {
 
+    fn io(&self) -> &Io {
+        self.io.as_ref().expect("[BUG] use of connection after into_inner")
+    }
+
+    fn io_mut(&mut self) -> &mut Io {
+        self.io.as_mut().expect("[BUG] use of connection after into_inner")
+    }
+
     /// send a command to the smtp server
     ///
     /// This consumes the connection (as it might be modified, recrated or
@@ -106,22 +185,193 @@ impl Connection {
     ///
     /// If the connection fails (e.g. the internet connection is interrupted)
     /// the future will resolve to an `io::Error` and the connection is gone.
+    /// This is also the case if the connection is poisoned (see `is_poisoned`).
+    ///
+    /// # Idle Timeout
+    ///
+    /// If the connection was idle for longer than the idle timeout (see
+    /// `set_idle_timeout`) `QUIT` is send and the socket is shut down instead
+    /// of sending the command. The future then resolves to an `io::Error` of
+    /// the kind `TimedOut`.
+    ///
+    /// # Rate Limit
+    ///
+    /// If a rate limit is set (see `set_rate_limit`) and sending the command
+    /// now would exceed it, the command is delayed until it no longer does.
+    /// This uses the tokio timer, so the future has to be run on a tokio runtime.
     ///
     pub fn send<C: Cmd>(self, cmd: C)
         -> impl Future<Item=(Connection, SmtpResult), Error=std_io::Error>
     {
         let fut =
-            if let Err(err) = cmd.check_cmd_availability(self.io.ehlo_data()) {
-                Either::B(future::ok((self, Err(LogicError::MissingCapabilities(err)))))
+            if self.is_poisoned() {
+                Either::B(Either::A(future::err(std_io::Error::new(
+                    std_io::ErrorKind::Other,
+                    "connection is poisoned, a previous command did not complete"
+                ))))
+            } else if self.is_service_closed() {
+                Either::B(Either::A(future::err(std_io::Error::new(
+                    std_io::ErrorKind::NotConnected,
+                    "connection was closed by the server (421)"
+                ))))
+            } else if self.is_idle_timed_out() {
+                Either::B(Either::B(self.close_idle()))
+            } else if let Err(err) = cmd.check_cmd_availability(self.io().ehlo_data()) {
+                Either::B(Either::A(future::ok((self, Err(LogicError::MissingCapabilities(err))))))
             } else {
-                Either::A(cmd
-                    .exec(self.into())
-                    .map(|(io, smtp_res)| (Connection::from(io), smtp_res)))
+                let mut io = self.into_inner();
+                match io.reserve_cmd_slot() {
+                    None => Either::A(Either::A(exec_cmd(io, cmd))),
+                    Some(start_at) => Either::A(Either::B(Delay::new(start_at)
+                        .map_err(|err| std_io::Error::new(std_io::ErrorKind::Other, err))
+                        .and_then(move |()| exec_cmd(io, cmd))))
+                }
             };
 
         fut
     }
 
+    /// sends `QUIT`, shuts down the socket and fails with a `TimedOut` error
+    fn close_idle(self) -> impl Future<Item=(Connection, SmtpResult), Error=std_io::Error> {
+        let timeout = self.io().idle_timeout().unwrap_or_default();
+        self.into_inner()
+            .exec_simple_cmd(&["QUIT"])
+            .and_then(|(io, _res)| {
+                let (socket, _, _) = io.split();
+                shutdown(socket)
+            })
+            .then(move |_| Err(std_io::Error::new(
+                std_io::ErrorKind::TimedOut,
+                format!("connection was idle for longer than {:?} and has been closed", timeout)
+            )))
+    }
+
+    /// sets the size by which the read buffer grows (see `Io::set_read_buffer_size`)
+    pub fn set_read_buffer_size(&mut self, size: usize) {
+        self.io_mut().set_read_buffer_size(size)
+    }
+
+    /// sets the maximal size of a response (see `Io::set_max_response_size`)
+    ///
+    /// This needs to be raised for commands with huge responses, e.g. `EXPN`
+    /// for a large mailing list.
+    pub fn set_max_response_size(&mut self, size: usize) {
+        self.io_mut().set_max_response_size(size)
+    }
+
+    /// returns the maximal size of a response, see `set_max_response_size`
+    pub fn max_response_size(&self) -> usize {
+        self.io().max_response_size()
+    }
+
+    /// sets the maximal number of lines of a response (see `Io::set_max_response_lines`)
+    pub fn set_max_response_lines(&mut self, lines: usize) {
+        self.io_mut().set_max_response_lines(lines)
+    }
+
+    /// returns the maximal number of lines of a response, see `set_max_response_lines`
+    pub fn max_response_lines(&self) -> usize {
+        self.io().max_response_lines()
+    }
+
+    /// sets the idle timeout of this connection, `None` disables it
+    ///
+    /// If no command was send through the connection for longer than the
+    /// timeout the connection is closed the next time it is used (see `send`).
+    /// Setting the timeout resets the idle timer.
+    ///
+    /// As a `Connection` is not driven by any background task this can not
+    /// happen earlier. Use `ConnectionConfig::idle_timeout` to set it up
+    /// when connecting.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.io_mut().set_idle_timeout(timeout)
+    }
+
+    /// true if an idle timeout is set and has elapsed
+    ///
+    /// In which case the connection will be closed instead of sending
+    /// the next command.
+    pub fn is_idle_timed_out(&self) -> bool {
+        self.io().is_idle_timed_out()
+    }
+
+    /// true if no command was send through the connection for at least `interval`
+    ///
+    /// Only such connections are pinged by `keep_alive`.
+    pub fn needs_keep_alive(&self, interval: Duration) -> bool {
+        self.io().idle_duration() >= interval
+    }
+
+    /// sets how long reading from the socket may make no progress, `None` disables it
+    ///
+    /// If the server doesn't send any data for longer than the timeout while
+    /// a response is read the command fails with an `io::Error` of the kind
+    /// `TimedOut` (and the connection is gone). This is a backstop independent
+    /// of any timeouts applied to the futures, e.g. `deadline::Deadline`.
+    ///
+    /// As the sockets are non-blocking this is not implemented through the
+    /// OS socket options but the tokio timer, so commands have to be run on
+    /// a tokio runtime if it is set.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.io_mut().set_read_timeout(timeout)
+    }
+
+    /// returns the read timeout, see `set_read_timeout`
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.io().read_timeout()
+    }
+
+    /// sets how long writing to the socket may make no progress, `None` disables it
+    ///
+    /// Like `set_read_timeout` but for writing (and flushing) commands and
+    /// mail data, e.g. if the server stops reading data.
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+        self.io_mut().set_write_timeout(timeout)
+    }
+
+    /// returns the write timeout, see `set_write_timeout`
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.io().write_timeout()
+    }
+
+    /// sets (or with `None` removes) a rate limit for commands send through this connection
+    ///
+    /// Commands exceeding the limit are not failed but delayed by `send`.
+    /// Use `ConnectionConfig::rate_limit` to set it up when connecting.
+    pub fn set_rate_limit(&mut self, limit: Option<RateLimit>) {
+        self.io_mut().set_rate_limit(limit)
+    }
+
+    /// returns the rate limit for commands, if one is set
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        self.io().rate_limit()
+    }
+
+    /// true if the server announced that it closes the connection
+    ///
+    /// If the server responds with `421` to any command `send` returns a
+    /// `LogicError::ServiceClosing` and marks the connection as closed,
+    /// any further `send` fails with a `NotConnected` I/O-Error.
+    pub fn is_service_closed(&self) -> bool {
+        self.io().is_service_closed()
+    }
+
+    /// true if the last command send through this connection did not complete
+    ///
+    /// Normally this can not happen as dropping the future returned by `send`
+    /// also drops the connection. But if the underlying `Io` was recovered
+    /// from a not completed command (e.g. by a custom `Cmd` implementation)
+    /// the smtp session is in an unknown state. In which case `send` will
+    /// fail with an I/O-Error and a new connection has to be created.
+    ///
+    /// This is also the case after a command was aborted (see `LogicError::Aborted`)
+    /// or the server send a malformed response (see `LogicError::MalformedResponse`)
+    /// or a too large one (see `LogicError::ResponseTooLarge` and
+    /// `LogicError::TooManyResponseLines`).
+    pub fn is_poisoned(&self) -> bool {
+        self.io().is_cmd_in_flight()
+    }
+
     /// returns true if the capability is known to be supported, false else wise
     ///
     /// The capability is know to be supported if the connection has EhloData and
@@ -133,20 +383,199 @@ impl Connection {
     pub fn has_capability<C>(&self, cap: C) -> bool
         where C: AsRef<str>
     {
-        self.io.has_capability(cap)
+        self.io().has_capability(cap)
+    }
+
+    /// returns the snapshot of well known capabilities from the last ehlo call
+    ///
+    /// This is cheaper than multiple `has_capability` calls, if the connection
+    /// has no ehlo data a snapshot without any capabilities is returned.
+    pub fn caps(&self) -> &Capabilities {
+        self.io().caps()
+    }
+
+    /// returns the metrics accumulated for this connection
+    ///
+    /// This is only available if the `metrics` feature is enabled.
+    #[cfg(feature="metrics")]
+    pub fn metrics(&self) -> &ConnectionMetrics {
+        self.io().metrics()
+    }
+
+    /// allows (or forbids) authentication commands to send credentials unencrypted
+    ///
+    /// By default authentication commands sending credentials (e.g. `auth::Plain`)
+    /// fail with `LogicError::InsecureAuth` if the connection is not encrypted.
+    /// Use `ConnectionConfig::allow_insecure_auth` to set it up when connecting.
+    pub fn set_allow_insecure_auth(&mut self, allow: bool) {
+        self.io_mut().set_allow_insecure_auth(allow)
+    }
+
+    /// true if the connection is encrypted (i.e. uses Tls)
+    ///
+    /// This can e.g. be used to refuse sending AUTH over plaintext.
+    pub fn is_secure(&self) -> bool {
+        self.io().is_secure()
+    }
+
+    /// returns information about the tls session, `None` for plain connections
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        self.io().tls_info()
+    }
+
+    /// returns the local address the connection is bound to
+    pub fn local_addr(&self) -> Result<SocketAddr, std_io::Error> {
+        self.io().socket().local_addr()
+    }
+
+    /// starts recording the lines send and received through this connection
+    ///
+    /// Only the last `capacity` lines are kept, credentials send as part
+    /// of an `AUTH` exchange are redacted. Calling it again replaces the
+    /// current transcript with a new (empty) one.
+    ///
+    /// This is only available if the `transcript` feature is enabled.
+    #[cfg(feature="transcript")]
+    pub fn enable_transcript(&mut self, capacity: usize) {
+        self.io_mut().set_transcript(Some(Transcript::new(capacity)))
+    }
+
+    /// returns the transcript, if it was enabled (see `enable_transcript`)
+    ///
+    /// This is only available if the `transcript` feature is enabled.
+    #[cfg(feature="transcript")]
+    pub fn transcript(&self) -> Option<&Transcript> {
+        self.io().transcript()
+    }
+
+    /// starts keeping the last error responses (4xx/5xx) received through this connection
+    ///
+    /// Only the last `capacity` error responses are kept, other responses
+    /// and the lines send are not recorded, which makes it more lightweight
+    /// then `enable_transcript`. Calling it again replaces the current log
+    /// with a new (empty) one.
+    ///
+    /// This is only available if the `transcript` feature is enabled.
+    #[cfg(feature="transcript")]
+    pub fn enable_error_log(&mut self, capacity: usize) {
+        self.io_mut().set_error_log(Some(ErrorLog::new(capacity)))
+    }
+
+    /// returns the error log, if it was enabled (see `enable_error_log`)
+    ///
+    /// This is only available if the `transcript` feature is enabled.
+    #[cfg(feature="transcript")]
+    pub fn error_log(&self) -> Option<&ErrorLog> {
+        self.io().error_log()
+    }
+
+    /// returns if `EHLO` or (as fallback) `HELO` was used for the handshake
+    ///
+    /// With `HandshakeKind::Helo` no smtp extensions (ESMTP) can be used.
+    /// This is `None` if no handshake was done (e.g. if the connection
+    /// was created directly from an `Io` instance).
+    pub fn handshake_kind(&self) -> Option<HandshakeKind> {
+        self.io().handshake_kind()
+    }
+
+    /// true if the server speaks ESMTP, i.e. the handshake was done using `EHLO`
+    ///
+    /// This is false after falling back to `HELO` and if no handshake was
+    /// done (see `handshake_kind`). On a non-ESMTP connection all commands
+    /// requiring a capability (e.g. `Bdat`, `Mail` with `SMTPUTF8`) are not
+    /// send to the server, instead `send` resolves to a
+    /// `LogicError::MissingCapabilities` naming the missing capabilities.
+    pub fn is_esmtp(&self) -> bool {
+        self.handshake_kind() == Some(HandshakeKind::Ehlo)
+    }
+
+    /// returns the greeting the server send when the connection was opened
+    ///
+    /// A multi-line greeting (using `220-` continuation lines) is read
+    /// completely before `EHLO` is send, `Response::msg` contains all lines.
+    ///
+    /// This is `None` if the connection wasn't created through `Connection::connect`
+    /// (e.g. directly from an `Io` instance).
+    pub fn greeting(&self) -> Option<&Response> {
+        self.io().greeting()
+    }
+
+    /// returns the greeting as `Greeting` (giving access to e.g. the domain in it)
+    ///
+    /// Like `greeting` this is `None` if the connection wasn't created
+    /// through `Connection::connect`.
+    pub fn banner(&self) -> Option<&Greeting> {
+        self.io().banner()
+    }
+
+    /// returns the server time as given in the greeting, if it contains one
+    ///
+    /// This is best-effort, many servers don't include a timestamp in their
+    /// greeting and the formats used differ (see `util::date::find_timestamp`).
+    /// It can be used to estimate the clock skew between client and server.
+    pub fn estimated_server_time(&self) -> Option<SystemTime> {
+        self.greeting()?
+            .msg()
+            .iter()
+            .filter_map(|line| find_timestamp(line))
+            .next()
     }
 
     /// returns a opt. reference to the ehlo data stored from the last ehlo call
     pub fn ehlo_data(&self) -> Option<&EhloData> {
-        self.io.ehlo_data()
+        self.io().ehlo_data()
+    }
+
+    /// returns a standalone snapshot of the capabilities (see `CapabilityView`)
+    ///
+    /// This clones the ehlo data but not the socket, so the view can be
+    /// held/send to other tasks independent of the connection.
+    pub fn capability_view(&self) -> CapabilityView {
+        CapabilityView {
+            ehlo_data: self.ehlo_data().cloned(),
+            handshake_kind: self.handshake_kind(),
+            is_secure: self.is_secure()
+        }
+    }
+
+    /// returns the ehlo data from before `STARTTLS` if it was kept
+    ///
+    /// It is only kept if `set_keep_cleartext_ehlo_data` (or
+    /// `ConnectionConfig::keep_cleartext_ehlo_data`) was set before
+    /// `STARTTLS` was send. It is meant for diagnostics only (e.g. comparing
+    /// it with `ehlo_data` to detect capability stripping middleboxes),
+    /// the authoritative ehlo data is always the one returned by `ehlo_data`.
+    pub fn cleartext_ehlo_data(&self) -> Option<&EhloData> {
+        self.io().cleartext_ehlo_data()
+    }
+
+    /// keep the ehlo data discarded by `STARTTLS`, see `cleartext_ehlo_data`
+    pub fn set_keep_cleartext_ehlo_data(&mut self, keep: bool) {
+        self.io_mut().set_keep_cleartext_ehlo_data(keep)
     }
 
     /// converts the `Connection` into an `Io` instance
     ///
     /// This is only need when implementing custom `Cmd`'s
-    pub fn into_inner(self) -> Io {
-        let Connection { io } = self;
-        io
+    pub fn into_inner(mut self) -> Io {
+        self.io.take().expect("[BUG] use of connection after into_inner")
+    }
+
+    /// splits the connection into the socket and the rest of its state
+    ///
+    /// This can be used to e.g. wrap the socket with custom middleware
+    /// (like throttling or logging). Use `Connection::from_parts` to get
+    /// back a usable connection, see `Io::into_parts` for details.
+    pub fn into_parts(self) -> (Socket, IoState) {
+        self.into_inner().into_parts()
+    }
+
+    /// reconstructs a connection from the parts returned by `into_parts`
+    ///
+    /// The ehlo data (and with it the capabilities) and all settings
+    /// are preserved.
+    pub fn from_parts(socket: Socket, state: IoState) -> Self {
+        Connection::from(Io::from_parts(socket, state))
     }
 
     /// shutdown the connection _without_ sending quit
@@ -166,7 +595,218 @@ impl Connection {
         // could be resolved using a ext. trait, but it's more ergonomic this way
         use command::Quit;
 
-        self.send(Quit).and_then(|(con, _res)| con.shutdown())
+        if self.is_service_closed() {
+            return Either::A(self.shutdown());
+        }
+
+        Either::B(self.send(Quit).and_then(|(con, _res)| con.shutdown()))
+    }
+
+    /// gracefully closes the connection, this should be used instead of dropping it
+    ///
+    /// As there is no async `Drop` dropping a connection just closes the
+    /// socket without telling the server. This does the full teardown
+    /// instead, i.e. it sends `QUIT` (if the service is not already closed)
+    /// and then shuts down the socket, like `quit` but without returning
+    /// the socket. Logic errors caused by `QUIT` are ignored.
+    pub fn close(self) -> impl Future<Item=(), Error=std_io::Error> {
+        self.quit().map(|_socket| ())
+    }
+
+    /// like `quit` but also returns the response to `QUIT`
+    ///
+    /// The socket is shut down independent of the response, a response
+    /// other than `221` is returned as `LogicError::UnexpectedCode` (or as
+    /// `LogicError::Code` if it's an error response). If the server already
+    /// closed the service (see `is_service_closed`) `QUIT` is not send and
+    /// `None` is returned instead of a result.
+    pub fn quit_with_response(self)
+        -> impl Future<Item=(Socket, Option<SmtpResult>), Error=std_io::Error>
+    {
+        //Note: this has a circular dependency between Connection <-> cmd Quit
+        use command::Quit;
+
+        if self.is_service_closed() {
+            return Either::A(self.shutdown().map(|socket| (socket, None)));
+        }
+
+        let fut = self.send(Quit).and_then(|(con, result)| {
+            let result = match result {
+                Ok(response) => {
+                    if response.code() == codes::CLOSING_CHANNEL {
+                        Ok(response)
+                    } else {
+                        Err(LogicError::UnexpectedCode(response))
+                    }
+                },
+                Err(err) => Err(err)
+            };
+            con.shutdown().map(move |socket| (socket, Some(result)))
+        });
+
+        Either::B(fut)
+    }
+
+    /// sends `RSET` to the server, resolving to the connection once it's reset
+    ///
+    /// This aborts any ongoing mail transaction, making the connection
+    /// ready to be reused e.g. for the next mail. If the server responds
+    /// with anything but `250` an I/O-Error (wrapping a `LogicError`) is
+    /// returned, as the state of the connection is unknown in that case.
+    pub fn reset(self) -> impl Future<Item=Connection, Error=std_io::Error> {
+        //Note: this has a circular dependency between Connection <-> cmd Reset
+        use command::Reset;
+
+        self.send(Reset).and_then(|(con, result)| match result {
+            Ok(ref response) if response.code() == codes::OK => Ok(con),
+            Ok(response) => {
+                let logic_err = LogicError::UnexpectedCode(response);
+                Err(std_io::Error::new(std_io::ErrorKind::Other, logic_err))
+            },
+            Err(logic_err) => Err(std_io::Error::new(std_io::ErrorKind::Other, logic_err))
+        })
+    }
+
+    /// sets an observer notified each time the ehlo data (and with it the capabilities) is set
+    ///
+    /// This happens e.g. on `rehello` or the `EHLO` after `STARTTLS`, the
+    /// observer gets the capabilities from before and after the change (see
+    /// `CapabilitiesObserver`). The capabilities of the connection at the
+    /// time the observer is set are passed as old capabilities on the first
+    /// notification. Pass `None` to remove the observer.
+    pub fn set_capabilities_observer(&mut self, observer: Option<Arc<CapabilitiesObserver>>) {
+        self.io_mut().set_capabilities_observer(observer)
+    }
+
+    /// sets a function called with every response read through this connection
+    ///
+    /// The function is called in the order the responses are received and
+    /// before the response is handed back to the command, it only gets a
+    /// shared reference, i.e. it can not change the response. This can be
+    /// used e.g. to cache the last reply or to update external metrics, see
+    /// `io::ResponseInterceptor` for details. Setting it replaces a
+    /// previously set interceptor.
+    pub fn with_response_interceptor<F>(mut self, func: F) -> Self
+        where F: Fn(&Response) + Send + Sync + 'static
+    {
+        self.io_mut().set_response_interceptor(Some(ResponseInterceptor::new(func)));
+        self
+    }
+
+    /// removes the function set by `with_response_interceptor`
+    pub fn remove_response_interceptor(&mut self) {
+        self.io_mut().set_response_interceptor(None)
+    }
+
+    /// re-issues `EHLO` with a new client identity on the existing connection
+    ///
+    /// On success the cached `EhloData` (and with it the capabilities) is
+    /// replaced by the data from the new response, if it fails the previous
+    /// `EhloData` is kept. Note that `EHLO` also resets any ongoing mail
+    /// transaction (RFC 5321, 4.1.4), so it should only be used between mails.
+    pub fn rehello(self, identity: ClientId)
+        -> impl Future<Item=(Connection, SmtpResult), Error=std_io::Error>
+    {
+        //Note: this has a circular dependency between Connection <-> cmd Ehlo
+        use command::Ehlo;
+
+        self.send(Ehlo::new(identity))
+    }
+
+    /// sends `NOOP` measuring the time until the reply was received
+    ///
+    /// This can be used as a health check/latency measurement e.g. for
+    /// pooled connections. As `NOOP` does not affect the state of a mail
+    /// transaction it can be used in the middle of one. An error reply is
+    /// returned as `LogicError`.
+    ///
+    /// The time is measured from the point the future is first polled, so
+    /// it includes any delay caused by a rate limit (see `set_rate_limit`).
+    pub fn ping(self)
+        -> impl Future<Item=(Connection, Result<Duration, LogicError>), Error=std_io::Error>
+    {
+        //Note: this has a circular dependency between Connection <-> cmd Noop
+        use command::Noop;
+
+        future::lazy(move || {
+            let start = clock::now();
+            self.send(Noop).map(move |(con, result)| {
+                let result = result.map(|_response| clock::now().duration_since(start));
+                (con, result)
+            })
+        })
+    }
+
+    /// sends `NOOP` if the connection was idle for at least `interval`
+    ///
+    /// If any command was send within the interval no `NOOP` is send and
+    /// `None` is returned, as the connection was recently used anyway. This
+    /// means consecutive keep-alives are coalesced with other traffic and only
+    /// genuinely idle connections get pinged. Else the result of the `NOOP`
+    /// is returned.
+    pub fn keep_alive(self, interval: Duration)
+        -> impl Future<Item=(Connection, Option<SmtpResult>), Error=std_io::Error>
+    {
+        //Note: this has a circular dependency between Connection <-> cmd Noop
+        use command::Noop;
+
+        if self.needs_keep_alive(interval) {
+            Either::A(self.send(Noop).map(|(con, result)| (con, Some(result))))
+        } else {
+            Either::B(future::ok((self, None)))
+        }
+    }
+
+    /// sends `HELP` (or `HELP <topic>`) resolving to the lines of the help text
+    ///
+    /// A `502` (command not implemented) response is treated as a successful
+    /// response without any help text, as `HELP` is optional for servers.
+    /// Other error responses are returned as `LogicError`.
+    pub fn help(self, topic: Option<String>)
+        -> impl Future<Item=(Connection, Result<Vec<String>, LogicError>), Error=std_io::Error>
+    {
+        //Note: this has a circular dependency between Connection <-> cmd Help
+        use command::Help;
+
+        self.send(Help::new(topic)).map(|(con, result)| {
+            let result = match result {
+                Ok(response) => Ok(response.msg().to_vec()),
+                Err(LogicError::Code(ref response))
+                    if response.code() == codes::COMMAND_UNIMPLEMENTED => Ok(Vec::new()),
+                Err(err) => Err(err)
+            };
+            (con, result)
+        })
+    }
+
+    /// sends one `RCPT TO` command for each address
+    ///
+    /// Returns each address paired with the result of it's `RCPT TO`
+    /// command, a rejected recipient does not stop the remaining ones
+    /// from being send. As pipelining is not supported the commands are
+    /// send one after another.
+    ///
+    /// An I/O-Error still fails the whole future (and drops the connection).
+    pub fn rcpt_many(self, addrs: &[ForwardPath])
+        -> impl Future<Item=(Connection, Vec<(ForwardPath, SmtpResult)>), Error=std_io::Error>
+    {
+        //Note: this has a circular dependency between Connection <-> cmd Recipient
+        use command::Recipient;
+
+        let results = Vec::with_capacity(addrs.len());
+        let pending = addrs.to_vec();
+
+        future::loop_fn((self, pending.into_iter(), results), |(con, mut pending, mut results)| {
+            match pending.next() {
+                None => Either::A(future::ok(Loop::Break((con, results)))),
+                Some(addr) => Either::B(con
+                    .send(Recipient::new(addr.clone()))
+                    .map(move |(con, res)| {
+                        results.push((addr, res));
+                        Loop::Continue((con, pending, results))
+                    }))
+            }
+        })
     }
 }
 
@@ -176,14 +816,13 @@ impl Connection {
 /// is still alive.
 impl From<Io> for Connection {
     fn from(io: Io) -> Self {
-        Connection { io }
+        Connection { io: Some(io) }
     }
 }
 
 impl From<Connection> for Io {
     fn from(con: Connection) -> Self {
-        let Connection { io } = con;
-        io
+        con.into_inner()
     }
 }
 
@@ -195,7 +834,69 @@ impl From<Connection> for Io {
 impl From<Socket> for Connection {
     fn from(socket: Socket) -> Self {
         let io = Io::from(socket);
-        Connection { io }
+        Connection::from(io)
+    }
+}
+
+/// best-effort shutdown of the socket, without sending `QUIT`
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Some(ref mut io) = self.io {
+            // there is nothing we could do about an error here
+            let _ = io.socket_mut().shutdown_now();
+        }
+    }
+}
+
+/// A read-only snapshot of the capabilities of a connection
+///
+/// It is created with `Connection::capability_view` and does not borrow
+/// the connection, so it can be used from other tasks/threads (it is
+/// `Send + Sync`). It is not updated if the capabilities of the connection
+/// change later on (e.g. through `rehello` or `STARTTLS`).
+#[derive(Debug, Clone)]
+pub struct CapabilityView {
+    ehlo_data: Option<EhloData>,
+    handshake_kind: Option<HandshakeKind>,
+    is_secure: bool
+}
+
+impl CapabilityView {
+
+    /// the ehlo data the connection had when the view was created
+    pub fn ehlo_data(&self) -> Option<&EhloData> {
+        self.ehlo_data.as_ref()
+    }
+
+    /// the snapshot of well known capabilities (see `Connection::caps`)
+    pub fn caps(&self) -> &Capabilities {
+        self.ehlo_data.as_ref()
+            .map(|ehlo| ehlo.caps())
+            .unwrap_or(&NO_CAPABILITIES)
+    }
+
+    /// see `Connection::has_capability`
+    pub fn has_capability<C>(&self, cap: C) -> bool
+        where C: AsRef<str>
+    {
+        self.ehlo_data.as_ref()
+            .map(|ehlo| ehlo.has_capability(cap))
+            .unwrap_or(false)
+    }
+
+    /// see `Connection::handshake_kind`
+    pub fn handshake_kind(&self) -> Option<HandshakeKind> {
+        self.handshake_kind
+    }
+
+    /// see `Connection::is_esmtp`
+    pub fn is_esmtp(&self) -> bool {
+        self.handshake_kind == Some(HandshakeKind::Ehlo)
+    }
+
+    /// true if the connection was encrypted when the view was created
+    pub fn is_secure(&self) -> bool {
+        self.is_secure
     }
 }
 
@@ -209,6 +910,10 @@ pub trait Cmd: Send + 'static {
 
     /// This method is used to verify if the command can be used
     /// for a given connection
+    ///
+    /// `caps` is `None` if the connection has no ehlo data and empty
+    /// ehlo data after a `HELO` handshake, in both cases commands
+    /// requiring a capability should return `MissingCapabilities`.
     fn check_cmd_availability(&self, caps: Option<&EhloData>)
         -> Result<(), MissingCapabilities>;
 
@@ -222,8 +927,23 @@ pub trait Cmd: Send + 'static {
     ///    not do so as it's unnecessary
     /// 2. send turns the `Io` instance the returned future resolves to
     ///    back into a `Connection` instance
+    ///
+    /// While the command is executed `io.is_cmd_in_flight()` is true,
+    /// so if a implementation wants to use `Connection::send` internally
+    /// it has to reset the flag using `io.set_cmd_in_flight(false)` first.
     fn exec(self, io: Io) -> ExecFuture;
 
+    /// The identity this command authenticates as, `None` if it isn't an auth command
+    ///
+    /// Auth commands return their mechanism together with the (user) identity
+    /// they authenticate as, but never any credentials. It is used to tell
+    /// otherwise equal `ConnectionConfig`s apart (see `pool::PoolKey`) and
+    /// to detect configs using `skip_auto_ehlo` with an auth command, so
+    /// custom auth commands should implement it.
+    fn auth_identity(&self) -> Option<String> {
+        None
+    }
+
     /// Turns the command into a `BoxedCmd`
     ///
     /// `BoxedCmd` isn't a trait object of `Cmd` but
@@ -266,6 +986,13 @@ pub trait TypeErasableCmd {
     /// as it requires object-safety)
     #[doc(hidden)]
     fn _only_once_exec(&mut self, io: Io) -> ExecFuture;
+
+    /// # Panics
+    ///
+    /// may panic if called after `_only_once_exec` was
+    /// called
+    #[doc(hidden)]
+    fn _auth_identity(&self) -> Option<String>;
 }
 
 #[doc(hidden)]
@@ -283,6 +1010,11 @@ impl<C> TypeErasableCmd for Option<C>
         let me = self.take().expect("_only_once_exec called a second time");
         me.exec(io)
     }
+
+    fn _auth_identity(&self) -> Option<String> {
+        let me = self.as_ref().expect("_auth_identity called after _only_onece_exec");
+        me.auth_identity()
+    }
 }
 
 impl Cmd for BoxedCmd {
@@ -296,6 +1028,10 @@ impl Cmd for BoxedCmd {
     fn exec(mut self, io: Io) -> ExecFuture {
         self._only_once_exec(io)
     }
+
+    fn auth_identity(&self) -> Option<String> {
+        self._auth_identity()
+    }
 }
 
 //FIXME[rustc/specialization]
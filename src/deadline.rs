@@ -0,0 +1,198 @@
+//! Provides `Deadline` and `Connection::connect_with_deadline`
+//!
+//! A deadline bounds the total (wall-clock) time of setting up a
+//! connection, i.e. connecting, EHLO, STARTTLS and AUTH, and optionally
+//! sending the first command (see `Connection::connect_send_with_deadline`).
+//!
+//! It composes with the tls handshake timeout (see
+//! `ConnectionConfig::tls_handshake_timeout`) in the way that whichever
+//! fires first wins. The idle timeout is not involved, it only applies to
+//! the established connection between commands.
+use std::{io as std_io};
+use std::time::{Duration, Instant};
+
+use futures::future::Future;
+use tokio::clock;
+use tokio::timer::Timeout;
+use tokio::timer::timeout;
+
+use ::error::{ConnectingFailed, GeneralError, LogicError, TimeoutPhase};
+use ::common::SetupTls;
+use ::io::SmtpResult;
+use ::connection::{Connection, Cmd};
+use ::connect::ConnectionConfig;
+
+/// A point in time until which a operation has to be completed
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Deadline {
+    instant: Instant
+}
+
+impl Deadline {
+
+    /// creates a deadline at the given point in time
+    pub fn at(instant: Instant) -> Self {
+        Deadline { instant }
+    }
+
+    /// creates a deadline `duration` from now
+    pub fn after(duration: Duration) -> Self {
+        Deadline::at(clock::now() + duration)
+    }
+
+    /// returns the point in time of the deadline
+    pub fn instant(&self) -> Instant {
+        self.instant
+    }
+
+    /// returns the time left until the deadline is reached (zero if it already passed)
+    pub fn remaining(&self) -> Duration {
+        let now = clock::now();
+        if now >= self.instant {
+            Duration::from_secs(0)
+        } else {
+            self.instant - now
+        }
+    }
+
+    /// true if the deadline already passed
+    pub fn has_passed(&self) -> bool {
+        clock::now() >= self.instant
+    }
+}
+
+/// maps a timeout error to the inner error, or `elapsed` if the deadline was exceeded
+fn map_timeout_err<E>(err: timeout::Error<E>, elapsed: E) -> E
+    where E: From<std_io::Error>
+{
+    if err.is_elapsed() {
+        elapsed
+    } else if err.is_timer() {
+        let timer_err = err.into_timer().expect("[BUG] is_timer but no timer error");
+        E::from(std_io::Error::new(std_io::ErrorKind::Other, timer_err))
+    } else {
+        err.into_inner().expect("[BUG] timeout error neither elapsed, timer nor inner")
+    }
+}
+
+impl Connection {
+
+    /// open a connection to an smtp server, failing if it takes longer than the deadline
+    ///
+    /// If the deadline is exceeded, at any stage of the setup,
+    /// `ConnectingFailed::Timeout(TimeoutPhase::Deadline)` is returned.
+    ///
+    /// This requires a running tokio timer (e.g. a tokio runtime).
+    pub fn connect_with_deadline<S, A>(config: ConnectionConfig<A, S>, deadline: Deadline)
+        -> impl Future<Item=Connection, Error=ConnectingFailed> + Send
+        where S: SetupTls, A: Cmd + Send
+    {
+        Timeout::new_at(Connection::connect(config), deadline.instant())
+            .map_err(|err| map_timeout_err(err, ConnectingFailed::Timeout(TimeoutPhase::Deadline)))
+    }
+
+    /// open a connection and send a first command, all of it bounded by the deadline
+    ///
+    /// If the deadline is exceeded while setting up the connection
+    /// `GeneralError::Connecting(ConnectingFailed::Timeout(TimeoutPhase::Deadline))` is returned,
+    /// if it's exceeded while sending the command it is
+    /// `GeneralError::Cmd(LogicError::Timeout)`.
+    ///
+    /// This requires a running tokio timer (e.g. a tokio runtime).
+    pub fn connect_send_with_deadline<S, A, C>(
+        config: ConnectionConfig<A, S>,
+        cmd: C,
+        deadline: Deadline
+    ) -> impl Future<Item=(Connection, SmtpResult), Error=GeneralError> + Send
+        where S: SetupTls, A: Cmd + Send, C: Cmd + Send
+    {
+        Connection::connect_with_deadline(config, deadline)
+            .map_err(GeneralError::from)
+            .and_then(move |con| {
+                let fut = con.send(cmd).map_err(GeneralError::from);
+                Timeout::new_at(fut, deadline.instant())
+                    .map_err(|err| map_timeout_err(err, GeneralError::Cmd(LogicError::Timeout)))
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread::JoinHandle;
+    use std::time::Duration;
+
+    use tokio::runtime::current_thread::Runtime;
+
+    use ::error::{ConnectingFailed, GeneralError, LogicError, TimeoutPhase};
+    use ::common::ClientId;
+    use ::data_types::Domain;
+    use ::command::Reset;
+    use ::command::auth::Plain;
+    use ::connect::ConnectionConfig;
+    use ::connection::Connection;
+    use ::stub_server::{self, serve_lines};
+    use super::Deadline;
+
+    /// runs a server answering the greeting and EHLO but stopping to respond
+    /// on the first line starting with `stall_on`
+    fn stalling_server(stall_on: &'static str) -> (u16, JoinHandle<Vec<String>>) {
+        let (addr, server) = stub_server::spawn_one(move |stream| {
+            serve_lines(stream, b"220 stub ready\r\n", |line| {
+                if line.starts_with(stall_on) { None }
+                else if line.starts_with("EHLO") { Some(b"250-stub.test\r\n250 AUTH PLAIN\r\n") }
+                else { Some(b"250 Ok\r\n") }
+            })
+        });
+        (addr.port(), server)
+    }
+
+    #[test]
+    fn deadline_trips_during_auth() {
+        let (port, server) = stalling_server("AUTH");
+        let config = ConnectionConfig::builder_local_unencrypted()
+            .port(port)
+            .client_id(ClientId::Domain(Domain::from_unchecked("client.test")))
+            .auth(Plain::from_username("user", "password").unwrap())
+            .allow_insecure_auth()
+            .build();
+
+        let fut = Connection::connect_with_deadline(config, Deadline::after(Duration::from_millis(200)));
+        let res = Runtime::new().unwrap().block_on(fut);
+
+        match res {
+            Err(ConnectingFailed::Timeout(TimeoutPhase::Deadline)) => (),
+            Err(err) => panic!("unexpected error: {:?}", err),
+            Ok(_) => panic!("unexpectedly connected")
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn deadline_trips_during_first_command() {
+        // the Noop "auth" command still gets a response
+        let (port, server) = stalling_server("RSET");
+        let config = ConnectionConfig::builder_local_unencrypted()
+            .port(port)
+            .client_id(ClientId::Domain(Domain::from_unchecked("client.test")))
+            .build();
+
+        let fut = Connection::connect_send_with_deadline(
+            config, Reset, Deadline::after(Duration::from_millis(200)));
+        let res = Runtime::new().unwrap().block_on(fut);
+
+        match res {
+            Err(GeneralError::Cmd(LogicError::Timeout)) => (),
+            Err(err) => panic!("unexpected error: {:?}", err),
+            Ok(_) => panic!("unexpectedly completed")
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn remaining_is_zero_after_deadline() {
+        let deadline = Deadline::after(Duration::from_secs(0));
+        assert!(deadline.has_passed());
+        assert_eq!(deadline.remaining(), Duration::from_secs(0));
+        assert!(Deadline::after(Duration::from_secs(60)).remaining() > Duration::from_secs(50));
+    }
+}
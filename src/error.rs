@@ -2,6 +2,7 @@
 use std::{io as std_io};
 use std::error::Error;
 use std::fmt::{self, Display, Debug};
+use std::time::Duration;
 use ::data_types::{Capability, EsmtpKeyword};
 use ::response::Response;
 
@@ -53,15 +54,151 @@ pub enum ConnectingFailed {
     Setup(LogicError),
 
     /// the authentication command failed
-    Auth(LogicError)
+    Auth(LogicError),
+
+    /// a timeout was exceeded before the connection was set up
+    ///
+    /// The `TimeoutPhase` tells which timeout was exceeded.
+    Timeout(TimeoutPhase),
+
+    /// the server didn't advertise a capability required by the `ConnectionConfig`
+    MissingCapability(Capability),
+
+    /// the server rejected `STARTTLS` with an error response
+    ///
+    /// E.g. `454 TLS not available due to temporary reason`, in which case
+    /// retrying later could succeed (see `is_transient`). The response
+    /// contains the code and text of the reply.
+    StartTlsRejected(Response),
+
+    /// the `ConnectionConfig` contains options which can not be combined
+    ///
+    /// E.g. `skip_auto_ehlo` with an auth command (see `Cmd::auth_identity`) or
+    /// `required_capabilities`, both need the ehlo data. This is detected
+    /// _before_ connecting.
+    InvalidConfig(&'static str)
+}
+
+impl ConnectingFailed {
+
+    /// true if retrying to connect could succeed
+    ///
+    /// Timeouts and setup errors with a transient (4xx) response code are
+    /// seen as transient, failed authentication never is. I/O-Errors are
+    /// seen as transient except if they are caused by invalid input or
+    /// missing permissions (see `IoFailure::is_transient`).
+    pub fn is_transient(&self) -> bool {
+        use self::ConnectingFailed::*;
+        match *self {
+            Io(ref err) => IoFailure::from_error(err).is_transient(),
+            Timeout(_) => true,
+            Setup(ref err) => err.is_transient(),
+            StartTlsRejected(ref response) => response.code().is_transient_failure(),
+            Auth(_) | MissingCapability(_) | InvalidConfig(_) => false
+        }
+    }
+
+    /// classifies the I/O-Error, returns `None` if this is not `ConnectingFailed::Io`
+    ///
+    /// The I/O-Error itself is still available through the `Io` variant
+    /// (and as the cause of this error).
+    pub fn io_failure(&self) -> Option<IoFailure> {
+        match *self {
+            ConnectingFailed::Io(ref err) => Some(IoFailure::from_error(err)),
+            _ => None
+        }
+    }
+}
+
+/// the kind of I/O-Error which made connecting fail (see `ConnectingFailed::io_failure`)
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum IoFailure {
+    /// the server (or a firewall) refused the connection
+    ConnectionRefused,
+    /// the connection attempt (or a read/write) timed out
+    TimedOut,
+    /// the host (or the network it's in) is not reachable
+    HostUnreachable,
+    /// the connection can't be opened from this host
+    ///
+    /// I.e. the I/O-Error kind is `InvalidInput`, `PermissionDenied`
+    /// or `AddrNotAvailable`.
+    InvalidSetup,
+    /// any other I/O-Error
+    Other
+}
+
+/// os error codes meaning the network or host is unreachable
+#[cfg(unix)]
+const UNREACHABLE_OS_ERRORS: &[i32] = &[libc::ENETUNREACH, libc::EHOSTUNREACH];
+/// os error codes meaning the network or host is unreachable (`WSAENETUNREACH`, `WSAEHOSTUNREACH`)
+#[cfg(windows)]
+const UNREACHABLE_OS_ERRORS: &[i32] = &[10051, 10065];
+#[cfg(not(any(unix, windows)))]
+const UNREACHABLE_OS_ERRORS: &[i32] = &[];
+
+impl IoFailure {
+
+    /// classifies the I/O-Error based on its kind and os error code
+    ///
+    /// Unreachable networks/hosts are detected through the os error code
+    /// (`ENETUNREACH`/`EHOSTUNREACH`) as the corresponding `io::ErrorKind`s
+    /// are not available on all supported rust versions.
+    pub fn from_error(err: &std_io::Error) -> Self {
+        use std::io::ErrorKind;
+        let is_unreachable = err.raw_os_error()
+            .map(|code| UNREACHABLE_OS_ERRORS.contains(&code))
+            .unwrap_or(false);
+
+        if is_unreachable {
+            return IoFailure::HostUnreachable;
+        }
+
+        match err.kind() {
+            ErrorKind::ConnectionRefused => IoFailure::ConnectionRefused,
+            ErrorKind::TimedOut => IoFailure::TimedOut,
+            ErrorKind::InvalidInput | ErrorKind::PermissionDenied | ErrorKind::AddrNotAvailable =>
+                IoFailure::InvalidSetup,
+            _ => IoFailure::Other
+        }
+    }
+
+    /// true if retrying could succeed
+    ///
+    /// All failures except `InvalidSetup` are transient (e.g. the server
+    /// is restarting or the network is temporary down).
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            IoFailure::ConnectionRefused | IoFailure::TimedOut
+                | IoFailure::HostUnreachable | IoFailure::Other => true,
+            IoFailure::InvalidSetup => false
+        }
+    }
 }
 
 impl From<std_io::Error> for ConnectingFailed {
     fn from(err: std_io::Error) -> Self {
-        ConnectingFailed::Io(err)
+        let is_handshake_timeout = err.get_ref()
+            .map(|inner| inner.is::<TlsHandshakeTimeout>())
+            .unwrap_or(false);
+
+        if is_handshake_timeout {
+            ConnectingFailed::Timeout(TimeoutPhase::TlsHandshake)
+        } else {
+            ConnectingFailed::Io(err)
+        }
     }
 }
 
+/// the phase of setting up a connection in which a timeout was exceeded
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum TimeoutPhase {
+    /// the deadline for the whole setup was exceeded (see `deadline::Deadline`)
+    Deadline,
+    /// the tls handshake took longer than `ConnectionConfig::tls_handshake_timeout`
+    TlsHandshake
+}
+
 impl Error for ConnectingFailed {
     fn description(&self) -> &str {
        "connecting with server failed"
@@ -72,7 +209,8 @@ impl Error for ConnectingFailed {
         match *self {
             Io(ref err) => Some(err),
             Setup(ref err) => Some(err),
-            Auth(ref err) => Some(err)
+            Auth(ref err) => Some(err),
+            Timeout(_) | MissingCapability(_) | StartTlsRejected(_) | InvalidConfig(_) => None
         }
     }
 }
@@ -83,7 +221,13 @@ impl Display for ConnectingFailed {
         match *self {
             Io(ref err) => write!(fter, "I/O-Error: {}", err),
             Setup(ref err) => write!(fter, "Setup-Error: {}", err),
-            Auth(ref err) => write!(fter, "Authentication-Error: {}", err)
+            Auth(ref err) => write!(fter, "Authentication-Error: {}", err),
+            Timeout(TimeoutPhase::Deadline) => write!(fter, "Timeout: deadline exceeded while connecting"),
+            Timeout(TimeoutPhase::TlsHandshake) => write!(fter, "Timeout: tls handshake took too long"),
+            MissingCapability(ref cap) => write!(fter, "Setup-Error: server doesn't support required {}", cap.as_str()),
+            StartTlsRejected(ref response) => write!(fter,
+                "Setup-Error: server rejected STARTTLS: {} {}", response.code(), response.msg().join(" ")),
+            InvalidConfig(msg) => write!(fter, "Config-Error: {}", msg)
         }
     }
 }
@@ -122,7 +266,177 @@ pub enum LogicError {
     Custom(Box<Error + 'static + Send + Sync>),
 
     /// command can not be used, as the server does not promotes the necessary capabilities
-    MissingCapabilities(MissingCapabilities)
+    MissingCapabilities(MissingCapabilities),
+
+    /// the message is larger than the maximal message size advertised by the server
+    ///
+    /// This is detected _before_ sending the message (see `EhloData::max_message_size`).
+    MessageTooLarge {
+        /// the size of the message in bytes
+        size: u64,
+        /// the limit advertised through `SIZE`
+        limit: u64
+    },
+
+    /// the server responded with `421`, i.e. it is closing the connection
+    ///
+    /// This can happen in response to any command. The connection can not be
+    /// used anymore, a new connection has to be created to continue.
+    ServiceClosing(Response),
+
+    /// a command line is longer than allowed by RFC 5321
+    ///
+    /// This is detected _before_ sending the command (see `io::MAX_LINE_LENGTH`).
+    LineTooLong {
+        /// the length of the line including `"\r\n"`
+        length: usize,
+        /// the maximal allowed length
+        limit: usize
+    },
+
+    /// the requested hold time of a mail exceeds the maximal hold time of the server
+    ///
+    /// This is detected _before_ sending the message (see `EhloData::max_future_release_interval`).
+    HoldTooLong {
+        /// the requested hold time
+        requested: Duration,
+        /// the limit advertised through `FUTURERELEASE`
+        limit: Duration
+    },
+
+    /// the `MT-PRIORITY=` priority of a mail is not in the valid range (`-9..=9`)
+    ///
+    /// This is detected _before_ sending the command (see `Mail::mt_priority`).
+    PriorityOutOfRange {
+        /// the requested priority
+        priority: i8
+    },
+
+    /// the mail could not be delivered within the `BY=` by-time
+    ///
+    /// The server responded with a permanent failure with the enhanced
+    /// status code `5.4.7` to a `MAIL`, `RCPT TO:` or `DATA` command of a
+    /// transaction using `BY=` (see `Mail::deliver_by`).
+    DeliverByFailed(Response),
+
+    /// the `BY=` by-time of a mail is not valid
+    ///
+    /// This is detected _before_ sending the command (see `DeliverBy::is_valid`).
+    InvalidDeliverBy {
+        /// the requested by-time
+        by_time: i64
+    },
+
+    /// a command line contains a `'\r'` or `'\n'`, i.e. it would be split into multiple lines
+    ///
+    /// This is detected _before_ sending the command (see `Io::exec_simple_cmd`).
+    LineBreakInCommand,
+
+    /// refused to authenticate over an unencrypted connection
+    ///
+    /// This is detected _before_ sending any credentials, except if
+    /// `Connection::set_allow_insecure_auth` was used to opt in. It is
+    /// also returned if the server responds with `538`, i.e. the server
+    /// requires encryption for the used authentication mechanism.
+    InsecureAuth,
+
+    /// refused to send a mail with `REQUIRETLS` over an unencrypted connection
+    ///
+    /// This is detected _before_ sending the `MAIL` command, as anyone able to
+    /// tamper with an unencrypted connection can also fake the capability.
+    InsecureRequireTls,
+
+    /// the deadline (see `deadline::Deadline`) was exceeded before the command completed
+    ///
+    /// The connection was dropped, as it's in an unknown state.
+    Timeout,
+
+    /// the command was aborted before it completed (see `command::Data::abortable`)
+    ///
+    /// The smtp session is in an unknown state, so the connection is
+    /// poisoned (see `Connection::is_poisoned`).
+    Aborted,
+
+    /// the server send a line which is not a valid response line
+    ///
+    /// E.g. a line with a non-numeric code, a missing `' '`/`'-'` separator or
+    /// a line of a multi-line response with a different code. As it is unknown
+    /// how many lines belong to the response the connection is poisoned (see
+    /// `Connection::is_poisoned`).
+    MalformedResponse {
+        /// the offending line (without `"\r\n"`, decoded lossily)
+        raw: String
+    },
+
+    /// the `334` challenge of an `AUTH` command is not strictly valid base64
+    ///
+    /// The authentication exchange was canceled (by sending `"*"`), so unlike
+    /// with `MalformedResponse` the connection can still be used.
+    MalformedChallenge {
+        /// the offending response (code and text)
+        raw: String
+    },
+
+    /// the response of the server exceeded the maximal response size
+    ///
+    /// See `Io::set_max_response_size`. As the rest of the response was not
+    /// read the connection is poisoned (see `Connection::is_poisoned`).
+    ResponseTooLarge {
+        /// the maximal response size in bytes
+        limit: usize
+    },
+
+    /// the response of the server has more lines than allowed
+    ///
+    /// See `Io::set_max_response_lines`. Like with `ResponseTooLarge` the
+    /// connection is poisoned.
+    TooManyResponseLines {
+        /// the maximal number of lines
+        limit: usize
+    }
+}
+
+impl LogicError {
+
+    /// true if the server responded with a transient failure code (4xx)
+    ///
+    /// `ServiceClosing` and `Timeout` are seen as transient, too.
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            LogicError::Code(ref response) => response.code().is_transient_failure(),
+            LogicError::ServiceClosing(_) | LogicError::Timeout => true,
+            _ => false
+        }
+    }
+
+    /// true if the server responded in a way which likely means the mail was greylisted
+    ///
+    /// This is a heuristic: the response has to be a `450` or `451` and either
+    /// have a `4.7.x` enhanced status code or mention greylisting in its text.
+    /// Greylisting is transient, i.e. retrying later (normally after some
+    /// minutes) is expected to succeed.
+    pub fn is_greylisted(&self) -> bool {
+        const MARKERS: &[&str] = &["greylist", "graylist", "grey-list", "gray-list", "grey list", "gray list"];
+
+        let response = match *self {
+            LogicError::Code(ref response) => response,
+            _ => return false
+        };
+
+        let code = response.code().as_u16();
+        if code != 450 && code != 451 {
+            return false;
+        }
+
+        let has_policy_code = response.enhanced_status_code()
+            .map(|enhanced| enhanced.starts_with("4.7."))
+            .unwrap_or(false);
+
+        has_policy_code || response.msg().iter().any(|line| {
+            let line = line.to_ascii_lowercase();
+            MARKERS.iter().any(|marker| line.contains(marker))
+        })
+    }
 }
 
 impl From<MissingCapabilities> for LogicError {
@@ -141,6 +455,22 @@ impl Error for LogicError {
             Code(_) => "server responded with error response code",
             UnexpectedCode(_) => "server responded with unexpected non-error response code",
             MissingCapabilities(ref err) => err.description(),
+            MessageTooLarge { .. } => "message exceeds the size limit of the server",
+            LineTooLong { .. } => "command line exceeds the maximal line length",
+            LineBreakInCommand => "command line contains a line break",
+            HoldTooLong { .. } => "requested hold time exceeds the maximal hold time of the server",
+            PriorityOutOfRange { .. } => "mail priority is out of the valid range",
+            DeliverByFailed(_) => "mail can not be delivered within the requested time",
+            InvalidDeliverBy { .. } => "deliver-by time is not valid",
+            ServiceClosing(_) => "server is closing the connection",
+            Timeout => "deadline exceeded before the command completed",
+            Aborted => "command was aborted before it completed",
+            MalformedResponse { .. } => "server send a malformed response",
+            MalformedChallenge { .. } => "server send a malformed auth challenge",
+            ResponseTooLarge { .. } => "server send a response exceeding the maximal response size",
+            TooManyResponseLines { .. } => "server send a response with too many lines",
+            InsecureAuth => "refused to authenticate over an unencrypted connection",
+            InsecureRequireTls => "refused to send a REQUIRETLS mail over an unencrypted connection",
             Custom(ref boxed) => boxed.description()
         }
     }
@@ -161,6 +491,34 @@ impl Display for LogicError {
 
         match *self {
             Custom(ref boxed) => Display::fmt(boxed, fter),
+            MessageTooLarge { size, limit } => write!(fter,
+                "message size ({} bytes) exceeds the size limit of the server ({} bytes)",
+                size, limit),
+            LineTooLong { length, limit } => write!(fter,
+                "command line length ({} bytes) exceeds the maximal line length ({} bytes)",
+                length, limit),
+            LineBreakInCommand => write!(fter, "command line contains a line break"),
+            HoldTooLong { requested, limit } => write!(fter,
+                "requested hold time ({}s) exceeds the maximal hold time of the server ({}s)",
+                requested.as_secs(), limit.as_secs()),
+            PriorityOutOfRange { priority } => write!(fter,
+                "mail priority ({}) is out of the valid range (-9 to 9)", priority),
+            DeliverByFailed(ref response) => write!(fter,
+                "mail can not be delivered within the requested time: {}", response.msg().join(" ")),
+            InvalidDeliverBy { by_time } => write!(fter,
+                "deliver-by time ({}s) is out of range or below the minimum of the server", by_time),
+            ServiceClosing(ref response) => write!(fter,
+                "server is closing the connection: {}", response.msg().join(" ")),
+            Timeout => write!(fter, "deadline exceeded before the command completed"),
+            Aborted => write!(fter, "command was aborted before it completed"),
+            MalformedResponse { ref raw } => write!(fter, "server send a malformed response: {:?}", raw),
+            MalformedChallenge { ref raw } => write!(fter, "server send a malformed auth challenge: {:?}", raw),
+            ResponseTooLarge { limit } => write!(fter,
+                "server send a response exceeding the maximal response size ({} bytes)", limit),
+            TooManyResponseLines { limit } => write!(fter,
+                "server send a response with more than {} lines", limit),
+            InsecureAuth => write!(fter, "refused to authenticate over an unencrypted connection"),
+            InsecureRequireTls => write!(fter, "refused to send a REQUIRETLS mail over an unencrypted connection"),
             //FIXME better display impl
             _ => Debug::fmt(self, fter),
         }
@@ -230,4 +588,167 @@ impl Display for MissingCapabilities {
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Error representing that the public key of the server didn't match any pinned key
+///
+/// This is returned (wrapped in a `std::io::Error`) by the tls handshake,
+/// see `SetupTls::spki_pins`.
+#[derive(Debug, Clone)]
+pub struct CertificatePinMismatch {
+    spki_sha256: [u8; 32]
+}
+
+impl CertificatePinMismatch {
+
+    pub fn new(spki_sha256: [u8; 32]) -> Self {
+        CertificatePinMismatch { spki_sha256 }
+    }
+
+    /// the SHA-256 hash of the public key (SPKI) presented by the server
+    pub fn spki_sha256(&self) -> &[u8; 32] {
+        &self.spki_sha256
+    }
+}
+
+impl Error for CertificatePinMismatch {
+    fn description(&self) -> &str {
+        "server public key does not match any pinned key"
+    }
+}
+
+impl Display for CertificatePinMismatch {
+
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        write!(fter, "server public key does not match any pinned key, its SPKI SHA-256 hash is: ")?;
+        for byte in self.spki_sha256.iter() {
+            write!(fter, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Error representing that the tls handshake took longer than allowed
+///
+/// This is returned (wrapped in a `std::io::Error` of kind `TimedOut`) by
+/// the tls handshake if a handshake timeout was set, when connecting
+/// it's turned into `ConnectingFailed::Timeout(TimeoutPhase::TlsHandshake)`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TlsHandshakeTimeout;
+
+impl Error for TlsHandshakeTimeout {
+    fn description(&self) -> &str {
+        "tls handshake took too long"
+    }
+}
+
+impl Display for TlsHandshakeTimeout {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        write!(fter, "{}", self.description())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io as std_io};
+    use ::response::{Response, ResponseCode};
+    use super::{ConnectingFailed, IoFailure, LogicError};
+
+    fn io_err(kind: std_io::ErrorKind) -> ConnectingFailed {
+        ConnectingFailed::from(std_io::Error::new(kind, "test error"))
+    }
+
+    #[test]
+    fn maps_io_error_kinds_to_failures() {
+        use std::io::ErrorKind::*;
+        let cases = [
+            (ConnectionRefused, IoFailure::ConnectionRefused),
+            (TimedOut, IoFailure::TimedOut),
+            (ConnectionReset, IoFailure::Other),
+            (PermissionDenied, IoFailure::InvalidSetup),
+            (InvalidInput, IoFailure::InvalidSetup),
+        ];
+
+        for &(kind, failure) in cases.iter() {
+            assert_eq!(io_err(kind).io_failure(), Some(failure), "kind: {:?}", kind);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn maps_unreachable_os_errors_to_host_unreachable() {
+        use libc::{ENETUNREACH, EHOSTUNREACH};
+        for &code in [ENETUNREACH, EHOSTUNREACH].iter() {
+            let err = ConnectingFailed::from(std_io::Error::from_raw_os_error(code));
+            assert_eq!(err.io_failure(), Some(IoFailure::HostUnreachable), "code: {}", code);
+            assert!(err.is_transient());
+        }
+    }
+
+    #[test]
+    fn keeps_io_error_as_cause() {
+        use std::error::Error;
+        let err = io_err(std_io::ErrorKind::ConnectionRefused);
+        match err {
+            ConnectingFailed::Io(ref io_err) => assert_eq!(io_err.kind(), std_io::ErrorKind::ConnectionRefused),
+            ref other => panic!("unexpected error: {:?}", other)
+        }
+        #[allow(deprecated)]
+        let cause = err.cause().unwrap().to_string();
+        assert_eq!(cause, "test error");
+    }
+
+    #[test]
+    fn classifies_transient_io_errors() {
+        use std::io::ErrorKind::*;
+        assert!(io_err(ConnectionRefused).is_transient());
+        assert!(io_err(TimedOut).is_transient());
+        assert!(io_err(ConnectionReset).is_transient());
+        assert!(!io_err(PermissionDenied).is_transient());
+        assert!(!io_err(InvalidInput).is_transient());
+    }
+
+    #[test]
+    fn non_io_errors_have_no_io_failure() {
+        let err = ConnectingFailed::Auth(LogicError::InsecureAuth);
+        assert_eq!(err.io_failure(), None);
+    }
+
+    fn code_error(code: u16, lines: &[&str]) -> LogicError {
+        let lines = lines.iter().map(|line| (*line).to_owned()).collect();
+        LogicError::Code(Response::new(ResponseCode::from_u16(code).unwrap(), lines))
+    }
+
+    #[test]
+    fn detects_greylisting_replies() {
+        let replies: &[(u16, &[&str])] = &[
+            (451, &["4.7.1 Greylisting in action, please come back later"]),
+            (450, &["4.7.1 <t@test.test>: Recipient address rejected: Try again later"]),
+            (451, &["4.7.0 Temporary server error. Please try again later"]),
+            (450, &["Greylisted, see http://postgrey.schweikert.ch/help/test.test.html"]),
+            (451, &["Temporarily rejected.", "You are being graylisted, try again in 5 minutes."]),
+        ];
+
+        for &(code, lines) in replies.iter() {
+            assert!(code_error(code, lines).is_greylisted(), "reply: {} {:?}", code, lines);
+        }
+    }
+
+    #[test]
+    fn does_not_classify_other_errors_as_greylisting() {
+        let replies: &[(u16, &[&str])] = &[
+            (450, &["4.2.0 Mailbox busy"]),
+            (451, &["4.3.0 Local error in processing"]),
+            (452, &["4.5.3 Too many recipients"]),
+            (452, &["4.7.1 Greylisted"]),
+            (421, &["4.7.0 Too many connections"]),
+            (550, &["5.7.1 Greylisted forever"]),
+        ];
+
+        for &(code, lines) in replies.iter() {
+            assert!(!code_error(code, lines).is_greylisted(), "reply: {} {:?}", code, lines);
+        }
+
+        assert!(!LogicError::Timeout.is_greylisted());
+    }
+}
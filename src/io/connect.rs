@@ -1,13 +1,15 @@
 use std::{io as std_io};
 use std::net::SocketAddr;
+use std::time::Duration;
+#[cfg(feature="metrics")]
+use tokio::clock;
 
-use futures::future::{self, Map, Either, Future};
+use futures::future::{Map, Future};
 use tokio::net::tcp::{TcpStream, ConnectFuture};
-use tokio_tls::TlsConnector;
-use native_tls::TlsConnector as NativeTlsConnector;
 
-use ::common::{map_tls_err, SetupTls, TlsConfig};
-use super::Io;
+use ::common::{SetupTls, TlsConfig};
+use super::{Io, ProxyHeader, connect_tls};
+use super::proxy::connect_tcp;
 
 
 impl Io {
@@ -21,30 +23,136 @@ impl Io {
         fut
     }
 
+    /// like `connect_insecure` but sending given PROXY protocol header (if any) directly after connecting
+    pub fn connect_insecure_with_proxy_header(addr: &SocketAddr, proxy_header: Option<ProxyHeader>)
+        -> impl Future<Item=Io, Error=std_io::Error> + Send
+    {
+        connect_tcp(addr, proxy_header).map(Io::from)
+    }
+
     /// create a new Tcp-Tls connection to the given address using the given tls config
     pub fn connect_secure<S>(addr: &SocketAddr, config: TlsConfig<S>)
         -> impl Future<Item=Io, Error=std_io::Error> + Send
         where S: SetupTls
     {
-        let TlsConfig { domain, setup } = config;
-        let connector = alttry!(
-            {
-                let contor = setup.setup(NativeTlsConnector::builder())?;
-                Ok(TlsConnector::from(contor))
-            } =>
-            |err| Either::B(future::err(map_tls_err(err)))
-        );
+        Io::connect_secure_with_handshake_timeout(addr, config, None)
+    }
 
-        let fut = TcpStream
-            ::connect(&addr)
-            .and_then(move |stream| connector
-                .connect(domain.as_str(), stream)
-                .map_err(map_tls_err)
-            )
-            .map(Io::from);
+    /// like `connect_secure` but failing if the tls handshake takes longer than `handshake_timeout`
+    ///
+    /// The timeout only applies to the tls handshake, not to the tcp connect.
+    /// If it's exceeded an I/O-Error of kind `TimedOut` wrapping a
+    /// `error::TlsHandshakeTimeout` is returned. This requires a running
+    /// tokio timer (e.g. a tokio runtime).
+    pub fn connect_secure_with_handshake_timeout<S>(
+        addr: &SocketAddr,
+        config: TlsConfig<S>,
+        handshake_timeout: Option<Duration>
+    ) -> impl Future<Item=Io, Error=std_io::Error> + Send
+        where S: SetupTls
+    {
+        Io::connect_secure_with_proxy_header(addr, config, handshake_timeout, None)
+    }
+
+    /// like `connect_secure_with_handshake_timeout` but sending given PROXY protocol header (if any)
+    ///
+    /// The header is send directly after the tcp connect, i.e. before the
+    /// tls handshake.
+    pub fn connect_secure_with_proxy_header<S>(
+        addr: &SocketAddr,
+        config: TlsConfig<S>,
+        handshake_timeout: Option<Duration>,
+        proxy_header: Option<ProxyHeader>
+    ) -> impl Future<Item=Io, Error=std_io::Error> + Send
+        where S: SetupTls
+    {
+        let TlsConfig { domain, sni_domain, setup } = config;
 
-        Either::A(fut)
+        let fut = connect_tcp(addr, proxy_header)
+            .and_then(move |stream| {
+                #[cfg(feature="metrics")]
+                let handshake_start = clock::now();
+                let sni_domain = sni_domain.as_ref().unwrap_or(&domain);
+                connect_tls(setup, sni_domain, &domain, stream, handshake_timeout)
+                    .map(move |stream| {
+                        #[allow(unused_mut)]
+                        let mut io = Io::from(stream);
+                        #[cfg(feature="metrics")]
+                        io.metrics_mut().record_tls_handshake(clock::now().duration_since(handshake_start));
+                        io
+                    })
+            });
+
+        fut
     }
 
 }
 
+
+#[cfg(test)]
+mod test {
+    use std::{io as std_io};
+    use std::io::{Read, Write};
+
+    use futures::Future;
+    use native_tls::{self, Certificate, Identity, Protocol, TlsAcceptor};
+    use native_tls::{TlsConnectorBuilder, TlsConnector as NativeTlsConnector};
+
+    use ::common::{SetupTls, TlsConfig, TlsOptions, TlsVersion};
+    use ::data_types::Domain;
+    use ::stub_server;
+    use super::super::Io;
+
+    static CA_CERT: &[u8] = include_bytes!("test_certs/ca.pem");
+    static SERVER_CERT: &[u8] = include_bytes!("test_certs/server.pem");
+    static SERVER_KEY: &[u8] = include_bytes!("test_certs/server.key");
+
+    /// trusts the test ca, applying the `TlsOptions` on top
+    #[derive(Debug)]
+    struct TrustTestCa(TlsOptions);
+
+    impl SetupTls for TrustTestCa {
+        fn setup(self, mut builder: TlsConnectorBuilder)
+            -> Result<NativeTlsConnector, native_tls::Error>
+        {
+            builder.add_root_certificate(Certificate::from_pem(CA_CERT)?);
+            self.0.setup(builder)
+        }
+    }
+
+    /// connects to a real tls server which speaks at most TLS 1.2
+    ///
+    /// (TLS 1.0/1.1 can't be used for this as current tls implementations
+    /// refuse them even if no minimal version is set)
+    fn connect_to_tls12_server(options: TlsOptions) -> Result<(), std_io::Error> {
+        let (addr, server) = stub_server::spawn_one(|stream| {
+            let identity = Identity::from_pkcs8(SERVER_CERT, SERVER_KEY).unwrap();
+            let acceptor = TlsAcceptor::builder(identity)
+                .max_protocol_version(Some(Protocol::Tlsv12))
+                .build()
+                .unwrap();
+            if let Ok(mut stream) = acceptor.accept(stream) {
+                let _ = stream.write_all(b"220 ready\r\n");
+                let mut buf = [0u8; 64];
+                let _ = stream.read(&mut buf);
+            }
+        });
+
+        let config = TlsConfig::new(Domain::from_unchecked("smtp.example.com"), TrustTestCa(options));
+        let res = Io::connect_secure(&addr, config).wait().map(|_| ());
+
+        server.join().unwrap();
+        res
+    }
+
+    #[test]
+    fn rejects_server_below_min_protocol_version() {
+        assert!(connect_to_tls12_server(TlsOptions::new()).is_ok());
+
+        let options = TlsOptions::new().min_protocol_version(TlsVersion::Tls12);
+        assert!(connect_to_tls12_server(options).is_ok());
+
+        let options = TlsOptions::new().min_protocol_version(TlsVersion::Tls13);
+        assert!(connect_to_tls12_server(options).is_err());
+    }
+}
@@ -2,9 +2,11 @@ use std::io as std_io;
 
 use futures::{Poll, Future, Async};
 use futures::stream::Stream;
+use futures::sync::oneshot;
+use bytes::BytesMut;
 use bytes::buf::{Buf, BufMut};
 
-use super::{Io, OUTPUT_BUFFER_INC_SIZE};
+use super::{Io, OUTPUT_BUFFER_INC_SIZE, MAX_LINE_LENGTH, CR_LF};
 
 impl Io {
 
@@ -14,27 +16,99 @@ impl Io {
     /// implementation makes sure not to add a additional "\r\n" to the end
     /// of the file if it isn't needed.
     ///
+    /// This uses the default `DataWriteOptions`, i.e. bare `'\r'`/`'\n'`
+    /// are normalized to `"\r\n"` and too long lines are send as they are.
     pub fn write_dot_stashed<S>(self, source: S) -> DotStashedWrite<S>
         where S: Stream<Error=std_io::Error>, S::Item: Buf
     {
-        DotStashedWrite::new(self, source)
+        self.write_dot_stashed_with_options(source, DataWriteOptions::default())
     }
+
+    /// like `write_dot_stashed` but using the given options
+    pub fn write_dot_stashed_with_options<S>(self, source: S, options: DataWriteOptions)
+        -> DotStashedWrite<S>
+        where S: Stream<Error=std_io::Error>, S::Item: Buf
+    {
+        DotStashedWrite::new(self, source, options)
+    }
+}
+
+/// options used when writing mail data (see `Io::write_dot_stashed_with_options`)
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct DataWriteOptions {
+    /// how lines longer than `MAX_LINE_LENGTH` are handled (default: `Ignore`)
+    pub line_length: LineLengthMode,
+    /// how a `'\r'` or `'\n'` not being part of `"\r\n"` is handled (default: `Normalize`)
+    pub bare_line_ending: BareLineEndingMode,
+    /// if false octets > 127 fail with an `InvalidData` I/O-Error (default: `true`)
+    pub allow_8bit: bool
 }
 
+impl Default for DataWriteOptions {
+    fn default() -> Self {
+        DataWriteOptions {
+            line_length: LineLengthMode::Ignore,
+            bare_line_ending: BareLineEndingMode::Normalize,
+            allow_8bit: true
+        }
+    }
+}
+
+/// specifies how lines longer than `MAX_LINE_LENGTH` are handled when writing mail data
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum LineLengthMode {
+    /// send them as they are
+    Ignore,
+    /// insert a `"\r\n"` when a line reaches the maximal length
+    ///
+    /// Note that this changes the content of the mail.
+    Fold,
+    /// fail with an `InvalidData` I/O-Error
+    ///
+    /// As part of the mail data might already have been send the
+    /// connection can not be used afterwards.
+    Reject
+}
+
+/// specifies how a bare `'\r'` or `'\n'` is handled when writing mail data
+///
+/// Smtp requires `"\r\n"` line endings, bare `'\r'`/`'\n'` can lead to
+/// corrupted mails or be abused for smtp smuggling.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum BareLineEndingMode {
+    /// replace them with `"\r\n"`
+    Normalize,
+    /// fail with an `InvalidData` I/O-Error
+    ///
+    /// As part of the mail data might already have been send the
+    /// connection can not be used afterwards.
+    Reject
+}
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 enum CrLf {
     None,
+    /// a `'\r'` was read but not yet written
     HitCr,
     HitLf
 }
 
+/// callback called with the total number of (unstashed) bytes send so far
+pub type ProgressFn = Box<FnMut(u64) + Send>;
+
 pub struct DotStashedWrite<S>
     where S: Stream, S::Item: Buf
 {
     io: Option<Io>,
+    progress: Option<ProgressFn>,
+    /// bytes taken from source and already written to the output buffer
+    bytes_written: u64,
+    bytes_reported: u64,
     source: S,
     stash_state: CrLf,
+    /// bytes written in the current line (excluding `"\r\n"`)
+    line_length: usize,
+    options: DataWriteOptions,
     /// end of mail sequence i.e. "\r\n.\r\n"
     write_eom_seq: bool
 }
@@ -42,23 +116,55 @@ pub struct DotStashedWrite<S>
 impl<S> DotStashedWrite<S>
     where S: Stream<Error=std_io::Error>, S::Item: Buf
 {
-    fn new(io: Io, source: S) -> Self {
+    fn new(io: Io, source: S, options: DataWriteOptions) -> Self {
         DotStashedWrite {
             source,
+            options,
             io: Some(io),
+            progress: None,
+            bytes_written: 0,
+            bytes_reported: 0,
             stash_state: CrLf::None,
+            line_length: 0,
             write_eom_seq: false
         }
     }
 
+    /// calls `progress` with the total number of bytes from source send so far
+    ///
+    /// The callback is called after each chunk of source data was flushed
+    /// (the dot-stashing/normalization is not included in the count). As
+    /// it's called while polling this future it must not block, the send
+    /// does not wait for anything the callback might start.
+    pub fn with_progress<F>(mut self, progress: F) -> Self
+        where F: FnMut(u64) + Send + 'static
+    {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
     fn io_mut(&mut self) -> &mut Io {
         self.io.as_mut().expect("poll after completion")
     }
 
+    fn report_progress(&mut self) {
+        if self.bytes_reported == self.bytes_written {
+            return;
+        }
+        self.bytes_reported = self.bytes_written;
+        if let Some(ref mut progress) = self.progress {
+            progress(self.bytes_written);
+        }
+    }
+
     fn poll_source(&mut self) -> Poll<Option<S::Item>, std_io::Error> {
         let next = try_ready!(self.source.poll());
 
         if next.is_none() {
+            if self.stash_state == CrLf::HitCr {
+                // the pending '\r' is a bare '\r'
+                check_bare_line_ending(self.options.bare_line_ending)?;
+            }
             self.write_eom_seq = true;
             let add_newline = self.stash_state != CrLf::HitLf;
             let need = 3 + if add_newline { 2 } else { 0 };
@@ -72,40 +178,149 @@ impl<S> DotStashedWrite<S>
         Ok(Async::Ready(next))
     }
 
-    fn write_dot_stashed_output(&mut self, unstashed: S::Item) {
+    fn write_dot_stashed_output(&mut self, unstashed: S::Item) -> Result<(), std_io::Error> {
+        // the max length of the line content, i.e. without "\r\n"
+        let max_content_length = MAX_LINE_LENGTH - CR_LF.len();
+        let DataWriteOptions { line_length: length_mode, bare_line_ending, allow_8bit } = self.options;
         let mut state = self.stash_state;
+        let mut line_length = self.line_length;
+        let chunk_len = unstashed.remaining() as u64;
         {
-            let raw_len = unstashed.remaining();
-            let out = self.io_mut().out_buffer(raw_len);
-            let mut over_capacity = out.remaining_mut() - raw_len;
+            let out = self.io_mut().out_buffer(unstashed.remaining());
             for bch in unstashed.iter() {
-                let (stash, new_state) = match (bch, state) {
-                    (b'\r', CrLf::None) => (false, CrLf::HitCr),
-                    (b'\n', CrLf::HitCr) => (false, CrLf::HitLf),
-                    (b'.', CrLf::HitLf) => (true, CrLf::None),
-                    (_, CrLf::None) => (false, CrLf::None),
-                    // this _could_ be invalid data but legacy systems _should_
-                    // be able to handle orphan '\r'/'\n' so treat it as ok
-                    (_, _) => (false, CrLf::None)
-                };
-                state = new_state;
-                if stash {
-                    if over_capacity == 0 {
-                        //increase buffer capacity
-                        let rem = out.remaining_mut();
-                        out.reserve(rem + OUTPUT_BUFFER_INC_SIZE);
-                        over_capacity += OUTPUT_BUFFER_INC_SIZE;
+                // 1. handle line endings, a '\r' is only written once we know
+                //    if it's followed by a '\n'
+                if state == CrLf::HitCr {
+                    if bch == b'\n' {
+                        put_reserved(out, b"\r\n");
+                        state = CrLf::HitLf;
+                        line_length = 0;
+                        continue;
                     }
-                    over_capacity -= 1;
-                    out.put_u8(b'.');
+                    check_bare_line_ending(bare_line_ending)?;
+                    put_reserved(out, b"\r\n");
+                    state = CrLf::HitLf;
+                    line_length = 0;
+                }
+
+                if bch == b'\r' {
+                    state = CrLf::HitCr;
+                    continue;
+                }
+
+                if bch == b'\n' {
+                    check_bare_line_ending(bare_line_ending)?;
+                    put_reserved(out, b"\r\n");
+                    state = CrLf::HitLf;
+                    line_length = 0;
+                    continue;
+                }
+
+                if !allow_8bit && bch > 0x7f {
+                    return Err(std_io::Error::new(
+                        std_io::ErrorKind::InvalidData,
+                        "mail data contains 8bit octet but 8bit data is not allowed"
+                    ));
+                }
+
+                // 2. handle too long lines
+                if line_length >= max_content_length {
+                    match length_mode {
+                        LineLengthMode::Ignore => (),
+                        LineLengthMode::Reject => {
+                            return Err(std_io::Error::new(
+                                std_io::ErrorKind::InvalidData,
+                                "mail data contains line exceeding the maximal line length"
+                            ));
+                        },
+                        LineLengthMode::Fold => {
+                            put_reserved(out, b"\r\n");
+                            state = CrLf::HitLf;
+                            line_length = 0;
+                        }
+                    }
+                }
+
+                // 3. dot-stashing (on the normalized data)
+                if bch == b'.' && state == CrLf::HitLf {
+                    put_reserved(out, b".");
+                    line_length += 1;
                 }
-                out.put_u8(bch);
+                put_reserved(out, &[bch]);
+                line_length += 1;
+                state = CrLf::None;
             }
         }
         self.stash_state = state;
+        self.line_length = line_length;
+        self.bytes_written += chunk_len;
+        Ok(())
     }
 }
 
+/// estimates the size of the mail data as it is send, e.g. for the `SIZE=` parameter of `MAIL`
+///
+/// This is the size after normalizing bare `'\r'`/`'\n'` to `"\r\n"`,
+/// dot-stashing lines (except the first one) starting with a `'.'` and
+/// adding a `"\r\n"` to the end if the body does not end with one (i.e.
+/// what is send with the default `DataWriteOptions` without the end of
+/// mail sequence `".\r\n"`).
+/// As the `SIZE=` parameter should not be smaller than the actual size
+/// this never underestimates, but it might be slightly larger than the
+/// size the server counts (which does not include the stashed dots).
+pub fn estimate_wire_size(body: &[u8]) -> usize {
+    let mut size = 0;
+    // like `DotStashedWrite` the first line is not stashed
+    let mut state = CrLf::None;
+    for &bch in body {
+        if state == CrLf::HitCr {
+            size += CR_LF.len();
+            state = CrLf::HitLf;
+            if bch == b'\n' {
+                continue;
+            }
+        }
+
+        match bch {
+            b'\r' => state = CrLf::HitCr,
+            b'\n' => {
+                size += CR_LF.len();
+                state = CrLf::HitLf;
+            },
+            _ => {
+                if bch == b'.' && state == CrLf::HitLf {
+                    size += 1;
+                }
+                size += 1;
+                state = CrLf::None;
+            }
+        }
+    }
+
+    if state != CrLf::HitLf {
+        size += CR_LF.len();
+    }
+    size
+}
+
+fn check_bare_line_ending(mode: BareLineEndingMode) -> Result<(), std_io::Error> {
+    match mode {
+        BareLineEndingMode::Normalize => Ok(()),
+        BareLineEndingMode::Reject => Err(std_io::Error::new(
+            std_io::ErrorKind::InvalidData,
+            "mail data contains a bare '\\r' or '\\n'"
+        ))
+    }
+}
+
+/// puts `data` into `out` increasing the capacity if needed
+fn put_reserved(out: &mut BytesMut, data: &[u8]) {
+    if out.remaining_mut() < data.len() {
+        out.reserve(data.len() + OUTPUT_BUFFER_INC_SIZE);
+    }
+    out.put_slice(data);
+}
+
 impl<S> Future for DotStashedWrite<S>
     where S: Stream<Error=std_io::Error>, S::Item: Buf
 {
@@ -120,6 +335,7 @@ impl<S> Future for DotStashedWrite<S>
             // e.g. while buffer has space write dot stashed bytes from self.pending into
             // out buffer while poll_flush is NotReady
             try_ready!(self.io_mut().poll_flush());
+            self.report_progress();
 
             if self.write_eom_seq {
                 return Ok(Async::Ready(self.io.take().expect("poll after completion")));
@@ -131,7 +347,138 @@ impl<S> Future for DotStashedWrite<S>
                     None => continue
                 };
 
-            self.write_dot_stashed_output(pending);
+            self.write_dot_stashed_output(pending)?;
+        }
+    }
+}
+
+/// creates a connected pair of a handle to abort a data transfer and the signal it triggers
+///
+/// See `DotStashedWrite::abort_on` and `command::Data::abortable`.
+pub fn data_abort_channel() -> (DataAbortHandle, DataAbortSignal) {
+    let (tx, rx) = oneshot::channel();
+    (DataAbortHandle(tx), DataAbortSignal(rx))
+}
+
+/// handle to abort a data transfer in flight (see `data_abort_channel`)
+///
+/// Dropping the handle without calling `abort` does not abort the transfer.
+#[derive(Debug)]
+pub struct DataAbortHandle(oneshot::Sender<()>);
+
+impl DataAbortHandle {
+
+    /// aborts the transfer, this does nothing if it already completed
+    pub fn abort(self) {
+        let _ = self.0.send(());
+    }
+}
+
+/// the signal triggered by `DataAbortHandle::abort`
+#[derive(Debug)]
+pub struct DataAbortSignal(oneshot::Receiver<()>);
+
+/// the result of a `AbortableDotStashedWrite`
+#[derive(Debug)]
+pub enum DataWriteOutcome {
+    /// all data including the end of mail sequence was written
+    Completed(Io),
+    /// the transfer was aborted, the end of mail sequence was _not_ written
+    ///
+    /// The smtp session is in the middle of the mail data, so the
+    /// connection can not be used for further commands.
+    Aborted(Io)
+}
+
+impl<S> DotStashedWrite<S>
+    where S: Stream<Error=std_io::Error>, S::Item: Buf
+{
+    /// stops writing the data once `signal` is triggered
+    ///
+    /// On abort no further data is send (including already buffered but
+    /// not yet flushed data) and the end of mail sequence is not written,
+    /// so that the server does not accept a truncated mail.
+    pub fn abort_on(self, signal: DataAbortSignal) -> AbortableDotStashedWrite<S> {
+        AbortableDotStashedWrite { write: self, signal: Some(signal) }
+    }
+}
+
+/// future returned by `DotStashedWrite::abort_on`
+pub struct AbortableDotStashedWrite<S>
+    where S: Stream, S::Item: Buf
+{
+    write: DotStashedWrite<S>,
+    /// `None` once the abort handle was dropped
+    signal: Option<DataAbortSignal>
+}
+
+impl<S> Future for AbortableDotStashedWrite<S>
+    where S: Stream<Error=std_io::Error>, S::Item: Buf
+{
+    type Item = DataWriteOutcome;
+    type Error = std_io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let aborted = match self.signal.as_mut().map(|signal| signal.0.poll()) {
+            Some(Ok(Async::Ready(()))) => true,
+            Some(Err(_)) => {
+                // the handle was dropped without aborting
+                self.signal = None;
+                false
+            },
+            _ => false
+        };
+
+        if aborted {
+            let mut io = self.write.io.take().expect("poll after completion");
+            io.out_buffer(0).clear();
+            return Ok(Async::Ready(DataWriteOutcome::Aborted(io)));
         }
+
+        let io = try_ready!(self.write.poll());
+        Ok(Async::Ready(DataWriteOutcome::Completed(io)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::estimate_wire_size;
+
+    #[test]
+    fn crlf_terminated_body_without_dots_is_unchanged() {
+        let body = b"Subject: hy\r\n\r\nthe body\r\n";
+        assert_eq!(estimate_wire_size(body), body.len());
+    }
+
+    #[test]
+    fn counts_stashed_dots() {
+        let body = b"first\r\n.leading\r\n..two\r\nmid.dle\r\n.\r\n";
+        assert_eq!(estimate_wire_size(body), body.len() + 3);
+    }
+
+    #[test]
+    fn does_not_count_a_dot_on_the_first_line() {
+        let body = b".first\r\n";
+        assert_eq!(estimate_wire_size(body), body.len());
+    }
+
+    #[test]
+    fn counts_normalized_bare_line_endings() {
+        // two bare '\n', one bare '\r'
+        let body = b"line1\nline2\rline3\n";
+        assert_eq!(estimate_wire_size(body), body.len() + 3);
+    }
+
+    #[test]
+    fn dots_after_bare_line_endings_are_stashed() {
+        let body = b"line1\n.dot\r\n";
+        assert_eq!(estimate_wire_size(body), body.len() + 2);
+    }
+
+    #[test]
+    fn counts_added_final_line_ending() {
+        assert_eq!(estimate_wire_size(b"no newline"), 12);
+        assert_eq!(estimate_wire_size(b"bare cr\r"), 9);
+        assert_eq!(estimate_wire_size(b""), 2);
     }
 }
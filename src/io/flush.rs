@@ -2,6 +2,8 @@ use std::io as std_io;
 
 use futures::{Poll, Future, Async};
 use tokio::io::AsyncWrite;
+#[cfg(feature="zeroize")]
+use zeroize::Zeroize;
 
 use super::Io;
 
@@ -23,20 +25,52 @@ impl Io {
     ///
     /// This first poll the writing of data from output to socket until
     /// output is empty, then it will start polling flush on the socket.
+    ///
+    /// If the `zeroize` feature is enabled written data is zeroed before
+    /// it's removed from the output buffer.
     pub fn poll_flush(&mut self) -> Poll<(), std_io::Error> {
-        let output = &mut self.buffer.output;
+        let output = &mut self.state.buffer.output;
+        let written = &mut self.state.output_written;
         let socket = &mut self.socket;
-        while !output.is_empty() {
-            let n = try_ready!(socket.poll_write(output));
+        while *written < output.len() {
+            let n = match socket.poll_write(&output[*written..])? {
+                Async::Ready(n) => n,
+                Async::NotReady => {
+                    self.state.stall.poll_write_stalled()?;
+                    return Ok(Async::NotReady);
+                }
+            };
+            self.state.stall.reset();
 
             // as long as output is not empty a it should never write 0 bytes
             assert!(n > 0);
 
-            // remove the bytes written from the buffer
-            output.advance(n);
+            #[cfg(feature="metrics")]
+            self.state.metrics.record_written(n);
+
+            #[cfg(feature="transcript")]
+            {
+                if let Some(ref mut transcript) = self.state.transcript {
+                    transcript.record_sent(&output[*written..*written + n]);
+                }
+            }
+
+            // don't leave written data (e.g. credentials) in the buffer
+            #[cfg(feature="zeroize")]
+            output[*written..*written + n].zeroize();
+
+            // written bytes are only skipped, the buffer is cleared once all
+            // of it was written, which keeps the allocation (at the start of
+            // it) for all following commands
+            *written += n;
         }
+        output.clear();
+        *written = 0;
 
-        try_ready!(socket.poll_flush());
+        if socket.poll_flush()?.is_not_ready() {
+            self.state.stall.poll_write_stalled()?;
+            return Ok(Async::NotReady);
+        }
 
         Ok(Async::Ready(()))
     }
@@ -47,7 +81,8 @@ pub struct Flushing {
 }
 
 impl Flushing {
-    pub(crate) fn new(inner: Io) -> Self {
+    pub(crate) fn new(mut inner: Io) -> Self {
+        inner.state.stall.reset();
         Flushing { inner: Some(inner) }
     }
 }
@@ -0,0 +1,74 @@
+use std::time::Duration;
+
+use ::response::ResponseCode;
+
+/// Counters accumulated over the lifetime of a connection
+///
+/// Only available if the `metrics` feature is enabled, see
+/// `Connection::metrics`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionMetrics {
+    commands_sent: u64,
+    bytes_written: u64,
+    bytes_read: u64,
+    tls_handshake_duration: Option<Duration>,
+    transient_failures: u64,
+    permanent_failures: u64
+}
+
+impl ConnectionMetrics {
+
+    /// the number of commands send through `Connection::send`
+    pub fn commands_sent(&self) -> u64 {
+        self.commands_sent
+    }
+
+    /// the number of bytes written to the socket
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// the number of bytes read from the socket
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// how long the tls handshake took, `None` if no handshake was done
+    pub fn tls_handshake_duration(&self) -> Option<Duration> {
+        self.tls_handshake_duration
+    }
+
+    /// the number of commands failing with a transient (4xx) error code
+    pub fn transient_failures(&self) -> u64 {
+        self.transient_failures
+    }
+
+    /// the number of commands failing with a permanent (5xx) error code
+    pub fn permanent_failures(&self) -> u64 {
+        self.permanent_failures
+    }
+
+    pub(crate) fn record_command(&mut self) {
+        self.commands_sent += 1;
+    }
+
+    pub(crate) fn record_written(&mut self, bytes: usize) {
+        self.bytes_written += bytes as u64;
+    }
+
+    pub(crate) fn record_read(&mut self, bytes: usize) {
+        self.bytes_read += bytes as u64;
+    }
+
+    pub(crate) fn record_tls_handshake(&mut self, duration: Duration) {
+        self.tls_handshake_duration = Some(duration);
+    }
+
+    pub(crate) fn record_failure(&mut self, code: ResponseCode) {
+        if code.is_transient_failure() {
+            self.transient_failures += 1;
+        } else if code.is_permanent_failure() {
+            self.permanent_failures += 1;
+        }
+    }
+}
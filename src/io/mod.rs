@@ -1,13 +1,23 @@
 //! This modules contains all the `Io` type related parts (for implementing `Cmd`)
 //!
+use std::{io as std_io};
+use std::cmp::max;
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use bytes::BytesMut;
 use bytes::buf::BufMut;
-use futures::Future;
+use futures::future::{self, Either, Future};
 use tokio_tls::TlsStream;
+use tokio::clock;
 use tokio::net::TcpStream;
+#[cfg(feature="zeroize")]
+use zeroize::Zeroize;
 
-use ::common::EhloData;
-use ::response::Response;
+use ::future_ext::ResultWithContextExt;
+use ::common::{EhloData, Capabilities, CapabilitiesObserver, HandshakeKind, NO_CAPABILITIES};
+use ::response::{Response, ResponseCode, Greeting};
 use ::error::LogicError;
 use super::ExecFuture;
 
@@ -27,8 +37,44 @@ pub use self::dot_stashing::*;
 mod connect;
 pub use self::connect::*;
 
+mod tls;
+pub(crate) use self::tls::connect_tls;
+
+mod proxy;
+pub use self::proxy::{ProxyHeader, ProxyVersion};
+
+mod throttle;
+pub use self::throttle::RateLimit;
+use self::throttle::Throttle;
+
+mod stall;
+use self::stall::StallTimeouts;
+
+#[cfg(feature="metrics")]
+mod metrics;
+#[cfg(feature="metrics")]
+pub use self::metrics::*;
+
+#[cfg(feature="transcript")]
+mod transcript;
+#[cfg(feature="transcript")]
+pub use self::transcript::*;
+
+/// the line terminator used for all lines send to the server
+///
+/// Smtp requires `"\r\n"` (RFC 5321), `exec_simple_cmd` makes sure it
+/// only appears once at the end of a command line.
 pub const CR_LF: &str = "\r\n";
 
+/// the maximal length of a command line or text line including `"\r\n"` (RFC 5321)
+pub const MAX_LINE_LENGTH: usize = 1000;
+
+/// the default maximal size of a response (see `Io::set_max_response_size`)
+pub const DEFAULT_MAX_RESPONSE_SIZE: usize = 64 * 1024;
+
+/// the default maximal number of lines of a response (see `Io::set_max_response_lines`)
+pub const DEFAULT_MAX_RESPONSE_LINES: usize = 1000;
+
 // most responses should fit in 256 bytes
 const INPUT_BUFFER_INC_SIZE: usize = 256;
 // most commands should fit in 1024 bytes (except e.g. DATA/BDAT)
@@ -41,8 +87,7 @@ pub type SmtpResult = Result<Response, LogicError>;
 #[derive(Debug)]
 pub struct Io {
     socket: Socket,
-    buffer: Buffers,
-    ehlo_data: Option<EhloData>,
+    state: IoState
 }
 
 impl Io {
@@ -54,9 +99,264 @@ impl Io {
     */
 
     /// split this instance into it's parts
+    ///
+    /// Note that all other state (e.g. the greeting or the settings) is
+    /// lost, use `into_parts` if the instance should be reconstructed.
     pub fn split(self) -> (Socket, Buffers, Option<EhloData>) {
-        let Io { socket, buffer, ehlo_data } = self;
-        (socket, buffer, ehlo_data)
+        let Io { socket, mut state } = self;
+        state.compact_output();
+        (socket, state.buffer, state.ehlo_data)
+    }
+
+    /// split this instance into the socket and all other state
+    ///
+    /// The socket (which implements `AsyncRead`/`AsyncWrite`) can be used
+    /// directly, e.g. by custom middleware, and then be put back using
+    /// `Io::from_parts`, which preserves the ehlo data/capabilities, the
+    /// buffered data and all settings.
+    pub fn into_parts(self) -> (Socket, IoState) {
+        let Io { socket, mut state } = self;
+        state.compact_output();
+        (socket, state)
+    }
+
+    /// reconstructs a instance from a socket and the state returned by `into_parts`
+    pub fn from_parts(socket: Socket, state: IoState) -> Self {
+        Io { socket, state }
+    }
+
+    /// reconstructs a instance using the tls stream a `STARTTLS` upgraded the socket to
+    ///
+    /// All settings are kept, but the buffers are reset (data read before
+    /// the tls handshake must not be used after it) and the ehlo data is
+    /// dropped (or kept as `cleartext_ehlo_data`), as the server has to be
+    /// greeted again.
+    pub(crate) fn from_upgraded_parts(stream: TlsStream<TcpStream>, mut state: IoState) -> Self {
+        state.buffer = Buffers::new();
+        state.output_written = 0;
+        let ehlo_data = state.ehlo_data.take();
+        if state.keep_cleartext_ehlo_data {
+            state.cleartext_ehlo_data = ehlo_data;
+        }
+        Io { socket: Socket::Secure(stream), state }
+    }
+
+    /// true if a command was started on this instance but did not complete
+    ///
+    /// This is set by `Connection::send` before executing a command and
+    /// cleared once the command completed. If it is still set the state of
+    /// the smtp session is unknown (e.g. the command might be half written or
+    /// it's response not yet read) and the connection should not be reused.
+    pub fn is_cmd_in_flight(&self) -> bool {
+        self.state.cmd_in_flight
+    }
+
+    /// sets the flag returned by `is_cmd_in_flight`
+    pub fn set_cmd_in_flight(&mut self, in_flight: bool) {
+        self.state.cmd_in_flight = in_flight;
+    }
+
+    /// true if the server announced that it closes the connection (`421`)
+    ///
+    /// This is set by `Connection::send` (see `LogicError::ServiceClosing`).
+    pub fn is_service_closed(&self) -> bool {
+        self.state.service_closed
+    }
+
+    /// sets the flag returned by `is_service_closed`
+    pub fn set_service_closed(&mut self, closed: bool) {
+        self.state.service_closed = closed;
+    }
+
+    /// true if authentication commands may send credentials over an unencrypted connection
+    pub fn allows_insecure_auth(&self) -> bool {
+        self.state.allow_insecure_auth
+    }
+
+    /// sets the flag returned by `allows_insecure_auth` (default: false)
+    pub fn set_allow_insecure_auth(&mut self, allow: bool) {
+        self.state.allow_insecure_auth = allow;
+    }
+
+    /// returns the metrics accumulated for this connection
+    #[cfg(feature="metrics")]
+    pub fn metrics(&self) -> &ConnectionMetrics {
+        &self.state.metrics
+    }
+
+    #[cfg(feature="metrics")]
+    pub(crate) fn metrics_mut(&mut self) -> &mut ConnectionMetrics {
+        &mut self.state.metrics
+    }
+
+    /// returns the transcript of this connection, if recording it was enabled
+    #[cfg(feature="transcript")]
+    pub fn transcript(&self) -> Option<&Transcript> {
+        self.state.transcript.as_ref()
+    }
+
+    /// sets (or with `None` removes) the transcript recording the lines send and received
+    #[cfg(feature="transcript")]
+    pub fn set_transcript(&mut self, transcript: Option<Transcript>) {
+        self.state.transcript = transcript;
+    }
+
+    /// removes the transcript from this instance and returns it
+    #[cfg(feature="transcript")]
+    pub fn take_transcript(&mut self) -> Option<Transcript> {
+        self.state.transcript.take()
+    }
+
+    /// returns the log of the last error responses, if keeping it was enabled
+    #[cfg(feature="transcript")]
+    pub fn error_log(&self) -> Option<&ErrorLog> {
+        self.state.error_log.as_ref()
+    }
+
+    /// sets (or with `None` removes) the log keeping the last error responses
+    #[cfg(feature="transcript")]
+    pub fn set_error_log(&mut self, error_log: Option<ErrorLog>) {
+        self.state.error_log = error_log;
+    }
+
+    /// removes the error log from this instance and returns it
+    #[cfg(feature="transcript")]
+    pub fn take_error_log(&mut self) -> Option<ErrorLog> {
+        self.state.error_log.take()
+    }
+
+    /// returns the size by which the input buffer grows when reading
+    pub fn read_buffer_size(&self) -> usize {
+        self.state.read_buffer_size
+    }
+
+    /// sets the size by which the input buffer grows when reading (default: 256)
+    ///
+    /// If the input buffer has less free capacity it is increased to it
+    /// directly. A larger size reduces the number of reallocations and reads
+    /// for large responses. It only affects the allocation strategy, not which
+    /// responses can be read. A size of `0` is treated as `1`.
+    pub fn set_read_buffer_size(&mut self, size: usize) {
+        let size = max(size, 1);
+        self.state.read_buffer_size = size;
+        let input = &mut self.state.buffer.input;
+        if input.remaining_mut() < size {
+            input.reserve(size);
+        }
+    }
+
+    /// returns the maximal size of a response, see `set_max_response_size`
+    pub fn max_response_size(&self) -> usize {
+        self.state.max_response_size
+    }
+
+    /// sets the maximal size of a response in bytes (default: `DEFAULT_MAX_RESPONSE_SIZE`)
+    ///
+    /// If the lines of a response (including `"\r\n"`) exceed it, parsing
+    /// fails with `LogicError::ResponseTooLarge` instead of buffering more of
+    /// it, which protects against servers sending endless responses. Commands
+    /// with large responses (e.g. `EXPN` or `HELP`) might need a larger limit.
+    /// As `parse_response_lines` does not collect the lines it only limits
+    /// the size of a single line there. No more data is read from the socket
+    /// while the input buffer exceeds the limit.
+    pub fn set_max_response_size(&mut self, size: usize) {
+        self.state.max_response_size = size;
+    }
+
+    /// returns the maximal number of lines of a response, see `set_max_response_lines`
+    pub fn max_response_lines(&self) -> usize {
+        self.state.max_response_lines
+    }
+
+    /// sets the maximal number of lines of a response (default: `DEFAULT_MAX_RESPONSE_LINES`)
+    ///
+    /// If a response has more lines parsing fails with
+    /// `LogicError::TooManyResponseLines`. This complements the maximal
+    /// response size (see `set_max_response_size`) as many tiny lines cause
+    /// more work than their size suggests. It does not apply to
+    /// `parse_response_lines`.
+    pub fn set_max_response_lines(&mut self, lines: usize) {
+        self.state.max_response_lines = lines;
+    }
+
+    /// returns the idle timeout (see `Connection::set_idle_timeout`)
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.state.idle_timeout
+    }
+
+    /// sets the idle timeout and resets the idle timer
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.state.idle_timeout = timeout;
+        self.reset_idle_timer();
+    }
+
+    /// resets the idle timer, this is done by `Connection::send` after each command
+    ///
+    /// The time is taken from `tokio::clock`, so a mocked clock is respected.
+    pub fn reset_idle_timer(&mut self) {
+        self.state.last_activity = clock::now();
+    }
+
+    /// returns when the last command completed (see `reset_idle_timer`)
+    pub fn last_activity(&self) -> Instant {
+        self.state.last_activity
+    }
+
+    /// returns for how long no command completed (see `reset_idle_timer`)
+    pub fn idle_duration(&self) -> Duration {
+        clock::now().duration_since(self.state.last_activity)
+    }
+
+    /// true if a idle timeout is set and no command completed for longer than it
+    ///
+    /// This is only checked when asked, nothing happens on its own once
+    /// the timeout elapsed.
+    pub fn is_idle_timed_out(&self) -> bool {
+        self.state.idle_timeout
+            .map(|timeout| self.idle_duration() >= timeout)
+            .unwrap_or(false)
+    }
+
+    /// returns the read timeout (see `Connection::set_read_timeout`)
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.state.stall.read_timeout()
+    }
+
+    /// sets how long reading may make no progress before failing with `TimedOut`
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.state.stall.set_read_timeout(timeout)
+    }
+
+    /// returns the write timeout (see `Connection::set_write_timeout`)
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.state.stall.write_timeout()
+    }
+
+    /// sets how long writing may make no progress before failing with `TimedOut`
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+        self.state.stall.set_write_timeout(timeout)
+    }
+
+    /// returns the rate limit for commands (see `Connection::set_rate_limit`)
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        self.state.throttle.as_ref().map(Throttle::limit)
+    }
+
+    /// sets (or with `None` removes) the rate limit for commands
+    ///
+    /// Commands started before setting it are not counted.
+    pub fn set_rate_limit(&mut self, limit: Option<RateLimit>) {
+        self.state.throttle = limit.map(Throttle::new);
+    }
+
+    /// reserves a slot for the next command, returning when it may be started
+    ///
+    /// Returns `None` if it can be started immediately (including if no rate limit is set).
+    pub(crate) fn reserve_cmd_slot(&mut self) -> Option<Instant> {
+        let throttle = self.state.throttle.as_mut()?;
+        let now = clock::now();
+        let start = throttle.reserve(now);
+        if start > now { Some(start) } else { None }
     }
 
     /// writes all strings in `parts` to the output buffer followed by `"\r\n"`
@@ -89,26 +389,121 @@ impl Io {
         self.socket.is_secure()
     }
 
-    /// returns a `&mut` to a (the) output buffer having at last `need_rem` bytes free capacity
+    /// returns information about the tls session, `None` if the socket doesn't use Tls
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        self.socket.tls_info()
+    }
+
+    /// returns a `&mut` to a (the) output buffer having at least `need_rem` bytes free capacity
     pub fn out_buffer(&mut self, need_rem: usize) -> &mut BytesMut {
-        let buf = &mut self.buffer.output;
+        self.state.compact_output();
+        let buf = &mut self.state.buffer.output;
         reverse_buffer_cap(buf, need_rem, OUTPUT_BUFFER_INC_SIZE);
         buf
     }
 
     /// returns a `&mut` to the input buffer
     pub fn in_buffer(&mut self) -> &mut BytesMut {
-        &mut self.buffer.input
+        &mut self.state.buffer.input
     }
 
     /// access the stored ehlo data
     pub fn ehlo_data(&self) -> Option<&EhloData> {
-        self.ehlo_data.as_ref()
+        self.state.ehlo_data.as_ref()
     }
 
     /// store different helo data
+    ///
+    /// This notifies the capabilities observer, if one is set.
     pub fn set_ehlo_data(&mut self, data: EhloData) {
-        self.ehlo_data = Some(data);
+        if let Some(ref mut state) = self.state.caps_observer {
+            state.observer.capabilities_changed(&state.last, data.caps());
+            state.last = data.caps().clone();
+        }
+        self.state.ehlo_data = Some(data);
+    }
+
+    /// sets the observer notified each time the ehlo data is set (see `set_ehlo_data`)
+    ///
+    /// The first notification passes the current capabilities as old
+    /// capabilities. Pass `None` to remove the observer.
+    pub fn set_capabilities_observer(&mut self, observer: Option<Arc<CapabilitiesObserver>>) {
+        let last = self.caps().clone();
+        self.state.caps_observer = observer.map(|observer| ObserverState { observer, last });
+    }
+
+    /// the observer set by `set_capabilities_observer`
+    pub fn capabilities_observer(&self) -> Option<&Arc<CapabilitiesObserver>> {
+        self.state.caps_observer.as_ref().map(|state| &state.observer)
+    }
+
+    /// sets (or with `None` removes) the interceptor called with every parsed response
+    pub fn set_response_interceptor(&mut self, interceptor: Option<ResponseInterceptor>) {
+        self.state.response_interceptor = interceptor;
+    }
+
+    /// the interceptor set by `set_response_interceptor`
+    pub fn response_interceptor(&self) -> Option<&ResponseInterceptor> {
+        self.state.response_interceptor.as_ref()
+    }
+
+    /// the ehlo data from before `STARTTLS`, if `keeps_cleartext_ehlo_data` was set
+    ///
+    /// This is _not_ authoritative (a man in the middle could have changed
+    /// it) and is only meant for diagnostics, e.g. to detect capabilities
+    /// stripped from the unencrypted `EHLO` response. Use `ehlo_data` for
+    /// everything else.
+    pub fn cleartext_ehlo_data(&self) -> Option<&EhloData> {
+        self.state.cleartext_ehlo_data.as_ref()
+    }
+
+    /// store different pre-`STARTTLS` ehlo data
+    pub fn set_cleartext_ehlo_data(&mut self, data: Option<EhloData>) {
+        self.state.cleartext_ehlo_data = data;
+    }
+
+    /// true if `STARTTLS` keeps the ehlo data it discards (see `cleartext_ehlo_data`)
+    pub fn keeps_cleartext_ehlo_data(&self) -> bool {
+        self.state.keep_cleartext_ehlo_data
+    }
+
+    /// sets the flag returned by `keeps_cleartext_ehlo_data` (default: false)
+    pub fn set_keep_cleartext_ehlo_data(&mut self, keep: bool) {
+        self.state.keep_cleartext_ehlo_data = keep;
+    }
+
+    /// returns if the ehlo data was set through `EHLO` or `HELO`
+    pub fn handshake_kind(&self) -> Option<HandshakeKind> {
+        self.state.handshake_kind
+    }
+
+    /// store which command was used for the handshake
+    pub fn set_handshake_kind(&mut self, kind: HandshakeKind) {
+        self.state.handshake_kind = Some(kind);
+    }
+
+    /// returns the greeting the server send when the connection was opened
+    pub fn greeting(&self) -> Option<&Response> {
+        self.state.greeting.as_ref().map(Greeting::response)
+    }
+
+    /// returns the greeting as `Greeting`, see `greeting`
+    pub fn banner(&self) -> Option<&Greeting> {
+        self.state.greeting.as_ref()
+    }
+
+    /// store the greeting of the server
+    pub fn set_greeting(&mut self, greeting: Response) {
+        self.state.greeting = Some(Greeting::new(greeting));
+    }
+
+    /// returns the snapshot of well known capabilities from the last Ehlo response
+    ///
+    /// If there is no ehlo data a snapshot without any capabilities is returned.
+    pub fn caps(&self) -> &Capabilities {
+        self.ehlo_data()
+            .map(|ehlo| ehlo.caps())
+            .unwrap_or(&NO_CAPABILITIES)
     }
 
     /// checks if a specific `EsmtpKeyword` had been in the last
@@ -122,7 +517,35 @@ impl Io {
     }
 
     /// used to impl. simple commands e.g. `con.send_simple_cmd(&["NOOP"])`
-    pub fn exec_simple_cmd(mut self, parts: &[&str]) -> ExecFuture {
+    ///
+    /// If the line (including `"\r\n"`) would be longer then `MAX_LINE_LENGTH`
+    /// nothing is send and a `LogicError::LineTooLong` is returned.
+    ///
+    /// If any part contains a `'\r'` or `'\n'` nothing is send and a
+    /// `LogicError::LineBreakInCommand` is returned, as it would split
+    /// the command into multiple lines (e.g. injecting another command).
+    pub fn exec_simple_cmd(self, parts: &[&str]) -> ExecFuture {
+        self.exec_cmd_with_max_length(parts, MAX_LINE_LENGTH)
+    }
+
+    /// like `exec_simple_cmd` but with another maximal line length, e.g. for `AUTH`
+    pub(crate) fn exec_cmd_with_max_length(mut self, parts: &[&str], max_length: usize) -> ExecFuture {
+        let has_line_break = parts.iter()
+            .any(|part| part.bytes().any(|bch| bch == b'\r' || bch == b'\n'));
+
+        if has_line_break {
+            return Box::new(future::ok((self, Err(LogicError::LineBreakInCommand))));
+        }
+
+        let length = parts
+            .iter()
+            .fold(CR_LF.len(), |sum, item| sum + item.len());
+
+        if length > max_length {
+            let err = LogicError::LineTooLong { length, limit: max_length };
+            return Box::new(future::ok((self, Err(err))));
+        }
+
         self.write_line_from_parts(parts);
 
         let fut = self
@@ -132,29 +555,194 @@ impl Io {
         Box::new(fut)
     }
 
+    /// used to impl. commands with an intermediate reply, e.g. `DATA`
+    ///
+    /// Sends `parts` as a command line and reads the (complete) reply, if
+    /// it has the `expected` intermediate code `payload` is called with the
+    /// `Io` and the reply, only the code is checked, not the text. Once the
+    /// future returned by it resolved (i.e. the payload was written and
+    /// flushed) the final reply is read. If the payload was aborted
+    /// (`DataWriteOutcome::Aborted`) no reply is read, as the server still
+    /// waits for the rest of the payload, and `LogicError::Aborted` is
+    /// returned instead.
+    ///
+    /// If the first reply has another code `payload` is not called and
+    /// the reply is returned as `LogicError::UnexpectedCode` (or as
+    /// `LogicError::Code` if it is an error reply).
+    pub fn send_expecting_intermediate<F, FUT>(self, parts: &[&str], expected: ResponseCode, payload: F)
+        -> ExecFuture
+        where F: FnOnce(Io, Response) -> FUT + Send + 'static,
+              FUT: Future<Item=DataWriteOutcome, Error=std_io::Error> + Send + 'static
+    {
+        let fut = self
+            .flush_line_from_parts(parts)
+            .and_then(Io::parse_response)
+            .ctx_and_then(move |io, response| {
+                if response.code() != expected {
+                    return Either::A(future::ok((io, Err(LogicError::UnexpectedCode(response)))));
+                }
+
+                let fut = payload(io, response)
+                    .and_then(|outcome| match outcome {
+                        DataWriteOutcome::Completed(io) => Either::A(io.parse_response()),
+                        DataWriteOutcome::Aborted(io) => Either::B(future::ok((io, Err(LogicError::Aborted))))
+                    });
+
+                Either::B(fut)
+            });
+
+        Box::new(fut)
+    }
+
+}
+
+/// A read-only hook called with every response parsed by `Io::parse_response`
+///
+/// It's called before the response is checked (i.e. turned into a `LogicError`
+/// for error codes) and handed back to the command, with every response
+/// including e.g. the `354` reply to `DATA` and the replies during `STARTTLS`.
+/// Responses read line by line (`Io::parse_response_lines`) and malformed
+/// responses are not passed to it. See `Connection::with_response_interceptor`.
+#[derive(Clone)]
+pub struct ResponseInterceptor(Arc<Fn(&Response) + Send + Sync>);
+
+impl ResponseInterceptor {
+
+    pub fn new<F>(func: F) -> Self
+        where F: Fn(&Response) + Send + Sync + 'static
+    {
+        ResponseInterceptor(Arc::new(func))
+    }
+
+    pub(crate) fn intercept(&self, response: &Response) {
+        (self.0)(response)
+    }
+}
+
+impl Debug for ResponseInterceptor {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        write!(fter, "ResponseInterceptor(..)")
+    }
+}
+
+/// a capabilities observer and the capabilities it was last notified with
+#[derive(Debug)]
+pub(crate) struct ObserverState {
+    observer: Arc<CapabilitiesObserver>,
+    last: Capabilities
+}
+
+/// The state of an `Io` instance except it's socket (see `Io::into_parts`)
+#[derive(Debug)]
+pub struct IoState {
+    buffer: Buffers,
+    /// number of bytes at the start of the output buffer which were already written
+    output_written: usize,
+    ehlo_data: Option<EhloData>,
+    cleartext_ehlo_data: Option<EhloData>,
+    keep_cleartext_ehlo_data: bool,
+    handshake_kind: Option<HandshakeKind>,
+    greeting: Option<Greeting>,
+    cmd_in_flight: bool,
+    service_closed: bool,
+    allow_insecure_auth: bool,
+    read_buffer_size: usize,
+    max_response_size: usize,
+    max_response_lines: usize,
+    #[cfg(feature="metrics")]
+    metrics: ConnectionMetrics,
+    #[cfg(feature="transcript")]
+    transcript: Option<Transcript>,
+    #[cfg(feature="transcript")]
+    error_log: Option<ErrorLog>,
+    idle_timeout: Option<Duration>,
+    last_activity: Instant,
+    throttle: Option<Throttle>,
+    stall: StallTimeouts,
+    caps_observer: Option<ObserverState>,
+    response_interceptor: Option<ResponseInterceptor>,
+}
+
+impl IoState {
+
+    /// the stored ehlo data
+    pub fn ehlo_data(&self) -> Option<&EhloData> {
+        self.ehlo_data.as_ref()
+    }
+
+    /// the buffers, the input buffer might contain data which was read but not yet parsed
+    pub fn buffers(&self) -> &Buffers {
+        &self.buffer
+    }
+
+    /// removes the already written bytes from the start of the output buffer
+    ///
+    /// Partial writes only move `output_written` forward, so that flushing
+    /// a large output buffer (e.g. a mail body) over a slow socket doesn't
+    /// move the remaining data after every write.
+    fn compact_output(&mut self) {
+        let written = self.output_written;
+        if written == 0 {
+            return;
+        }
+        self.output_written = 0;
+        let output = &mut self.buffer.output;
+        let rest = output.len() - written;
+        output.copy_within(written.., 0);
+        // the moved bytes are still in the buffer (behind rest)
+        #[cfg(feature="zeroize")]
+        output[rest..].zeroize();
+        output.truncate(rest);
+    }
 }
 
 impl From<(Socket, Buffers, Option<EhloData>)> for Io {
     fn from((socket, buffer, ehlo_data): (Socket, Buffers, Option<EhloData>)) -> Self {
-        Io { socket, buffer, ehlo_data }
+        let state = IoState {
+            buffer, ehlo_data,
+            output_written: 0,
+            cleartext_ehlo_data: None,
+            keep_cleartext_ehlo_data: false,
+            handshake_kind: None,
+            greeting: None,
+            cmd_in_flight: false,
+            service_closed: false,
+            allow_insecure_auth: false,
+            read_buffer_size: INPUT_BUFFER_INC_SIZE,
+            max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+            max_response_lines: DEFAULT_MAX_RESPONSE_LINES,
+            #[cfg(feature="metrics")]
+            metrics: ConnectionMetrics::default(),
+            #[cfg(feature="transcript")]
+            transcript: None,
+            #[cfg(feature="transcript")]
+            error_log: None,
+            idle_timeout: None,
+            last_activity: clock::now(),
+            throttle: None,
+            stall: StallTimeouts::default(),
+            caps_observer: None,
+            response_interceptor: None
+        };
+        Io { socket, state }
     }
 }
 
 impl From<(Socket, Buffers, EhloData)> for Io {
     fn from((socket, buffer, ehlo_data): (Socket, Buffers, EhloData)) -> Self {
-        Io { socket, buffer, ehlo_data: Some(ehlo_data) }
+        Io::from((socket, buffer, Some(ehlo_data)))
     }
 }
 
 impl From<(Socket, Buffers)> for Io {
     fn from((socket, buffer): (Socket, Buffers)) -> Self {
-        Io { socket, buffer, ehlo_data: None }
+        Io::from((socket, buffer, None))
     }
 }
 
 impl From<Socket> for Io {
     fn from(socket: Socket) -> Self {
-        Io { socket, buffer: Buffers::new(), ehlo_data: None }
+        Io::from((socket, Buffers::new(), None))
     }
 }
 
@@ -203,7 +791,7 @@ fn reverse_buffer_cap(buf: &mut BytesMut, need_rem: usize, increase: usize) {
             reserve += increase;
         }
         // this will keep the capacity a multiple of increase,
-        // at last as long as everyone keeps to this schema
+        // at least as long as everyone keeps to this schema
         buf.reserve(reserve)
     }
 }
\ No newline at end of file
@@ -2,46 +2,102 @@ use std::io as std_io;
 use std::mem;
 
 use bytes::BufMut;
-use futures::{Poll, Future, Async};
+use futures::{Poll, Future, Async, Stream};
+use futures::future::{self, Loop};
 use tokio::io::AsyncRead;
 
-use ::response::parser;
-use ::error::check_response;
+use ::response::{parser, ResponseCode};
+use ::error::{check_response, LogicError};
 
-use super::{Io, SmtpResult, INPUT_BUFFER_INC_SIZE};
+use super::{Io, SmtpResult};
 
 impl Io {
     /// parse a "normal" smtp response
     ///
+    /// If the server sends a line which is not a valid response line (or
+    /// a line of a multi-line response has a different code) the future
+    /// resolves to `LogicError::MalformedResponse`. The offending line is
+    /// left in the input buffer, the smtp session is in an unknown state
+    /// after this.
+    ///
     /// # Panics
     ///
     /// Panics if the write buffer is not empty
     pub fn parse_response(self) -> Parsing {
-        if !self.buffer.output.is_empty() {
+        if !self.state.buffer.output.is_empty() {
             panic!("parsing input before writing all output")
         }
         Parsing::new(self)
     }
 
+    /// parse a smtp response line by line
+    ///
+    /// Instead of collecting all lines into a `Response` the returned
+    /// `ResponseLines` stream yields each line once it was received, which
+    /// allows processing huge responses (e.g. to `EXPN`) incrementally.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the write buffer is not empty
+    pub fn parse_response_lines(self) -> ResponseLines {
+        if !self.state.buffer.output.is_empty() {
+            panic!("parsing input before writing all output")
+        }
+        ResponseLines::new(self)
+    }
+
 
     /// read data from the socket to buffer.input until it would block or the socket closed
     ///
-    /// The input buffer is increased in increments of 256 bytes (`INPUT_BUFFER_INC_SIZE`)
+    /// The input buffer is increased in increments of `read_buffer_size` bytes
+    /// (by default 256 bytes, see `set_read_buffer_size`).
+    ///
+    /// If a read timeout is set (see `set_read_timeout`) and no data could be
+    /// read for longer than it this fails with an error of kind `TimedOut`.
+    ///
+    /// Once the input buffer contains more than `max_response_size` bytes no
+    /// more data is read and `ReadState::BufferFull` is returned, so that a
+    /// server can not make the buffer grow without bound.
     pub fn read_from_socket(&mut self) -> Result<ReadState, std_io::Error> {
-        let input = &mut self.buffer.input;
+        let state = self.read_available()?;
+        if state == ReadState::NotReady {
+            self.state.stall.poll_read_stalled()?;
+        }
+        Ok(state)
+    }
+
+    fn read_available(&mut self) -> Result<ReadState, std_io::Error> {
+        let read_buffer_size = self.state.read_buffer_size;
+        let limit = self.state.max_response_size;
+        let input = &mut self.state.buffer.input;
         let socket = &mut self.socket;
 
-        //TODO limit the buffer size (configurable) to limit smtp response line size
         loop {
+            if input.len() > limit {
+                return Ok(ReadState::BufferFull);
+            }
             if input.remaining_mut() == 0 {
-                input.reserve(INPUT_BUFFER_INC_SIZE);
+                input.reserve(read_buffer_size);
             }
 
-            match socket.read_buf(input) {
+            #[allow(unused_variables)]
+            let n = match socket.read_buf(input) {
                 Ok(Async::NotReady) => return Ok(ReadState::NotReady),
                 Ok(Async::Ready(0)) => return Ok(ReadState::SocketClosed),
-                Ok(Async::Ready(_)) => (),
+                Ok(Async::Ready(n)) => n,
                 Err(err) => return Err(err)
+            };
+            self.state.stall.reset();
+
+            #[cfg(feature="metrics")]
+            self.state.metrics.record_read(n);
+
+            #[cfg(feature="transcript")]
+            {
+                if let Some(ref mut transcript) = self.state.transcript {
+                    let len = input.len();
+                    transcript.record_received(&input[len - n..]);
+                }
             }
         }
     }
@@ -71,6 +127,25 @@ impl Io {
             Ok(None)
         }
     }
+
+    /// removes all complete lines from the input buffer and returns them
+    ///
+    /// Lines which are in the buffer while no command is in flight, e.g.
+    /// unsolicited informational lines or the remainder of a reply which
+    /// was abandoned because of an error, would otherwise be misread as
+    /// (part of) the response to the next command.
+    ///
+    /// This doesn't read from the socket (and therefore never blocks), an
+    /// incomplete line at the end of the buffer is kept.
+    pub fn drain_pending(&mut self) -> Vec<String> {
+        let mut lines = Vec::new();
+        while let Ok(Some(line)) = self.try_pop_line(|line| {
+            Ok::<_, ()>(String::from_utf8_lossy(line).into_owned())
+        }) {
+            lines.push(line);
+        }
+        lines
+    }
 }
 
 /// Used to hint if a socket was closed
@@ -80,16 +155,12 @@ pub enum ReadState {
     SocketClosed,
     /// the socket is not ready
     NotReady,
-    // Buffer full is in between read and not ready, and super annoying to
-    // handle (e.g. the edge case where the buffer is full and does not contain
-    // at last one complete line, and the part that you can not "just" return
-    // Ready as well it's just partially ready and you can also not return
-    // NotReady as there is no Wakup registered)
-    // For now this will not be handle, maybe a max sized buffer + error if more requested
-    // is enough, I mean it's a smtp _Client_ it mainly gets back status messages etc. just
-    // some comands like list all users could actually fill the buffer (if decent sized),
-    // but then this commands do exists...
-    //BufferFull,
+    /// the input buffer exceeds the maximal response size, nothing more was read
+    ///
+    /// No wakeup is registered in this case, so the caller has to consume
+    /// data from the buffer or fail (e.g. with `LogicError::ResponseTooLarge`)
+    /// instead of returning `NotReady`.
+    BufferFull,
 }
 
 impl ReadState {
@@ -102,14 +173,18 @@ impl ReadState {
 /// future returned by `Connection.parse_result`
 pub struct Parsing {
     inner: Option<Io>,
-    lines: Vec<parser::ResponseLine>
+    lines: Vec<parser::ResponseLine>,
+    /// the size of the already parsed lines (including `"\r\n"`)
+    size: usize
 }
 
 impl Parsing {
-    pub(crate) fn new(inner: Io) -> Self {
+    pub(crate) fn new(mut inner: Io) -> Self {
+        inner.state.stall.reset();
         Parsing {
             inner: Some(inner),
-            lines: Vec::new()
+            lines: Vec::new(),
+            size: 0
         }
     }
 
@@ -117,32 +192,84 @@ impl Parsing {
         self.inner.as_mut().expect("[BUG] poll after completion")
     }
 
-    fn read_result(&mut self) -> Result<Option<(Io, SmtpResult)>, parser::ParseError> {
+    fn read_result(&mut self) -> Option<(Io, SmtpResult)> {
+        let limit = self.io_mut().max_response_size();
+        let line_limit = self.io_mut().max_response_lines();
         loop {
+            let expected_code = self.lines.first().map(|line| line.code);
+            let mut line_size = 0;
             let opt_line = self
                 .io_mut()
-                .try_pop_line(|line| parser::parse_line(line) )?;
+                .try_pop_line(|line| {
+                    line_size = line.len() + 2;
+                    match parser::parse_line(line) {
+                        // all lines of a multi-line response have to have the same code
+                        Ok(ref parsed) if expected_code.map(|code| code != parsed.code).unwrap_or(false)
+                            => Err(String::from_utf8_lossy(line).into_owned()),
+                        Ok(parsed) => Ok(parsed),
+                        Err(_) => Err(String::from_utf8_lossy(line).into_owned())
+                    }
+                });
 
-            if let Some(line) = opt_line {
-                let last = line.last_line;
-                self.lines.push(line);
-
-                if !last {
-                    continue;
+            let line = match opt_line {
+                Ok(Some(line)) => line,
+                Ok(None) => {
+                    // the rest of the input is a incomplete line of this response
+                    let pending = self.io_mut().in_buffer().len();
+                    if self.size + pending > limit {
+                        return Some(self.too_large(limit));
+                    }
+                    return None;
+                },
+                Err(raw) => {
+                    let io = self.inner.take().expect("[BUG] poll after completion");
+                    return Some((io, Err(LogicError::MalformedResponse { raw })));
                 }
+            };
 
-                let lines = mem::replace(&mut self.lines, Vec::new());
-                let response = parser::response_from_parsed_lines(lines.into_iter())?;
+            self.size += line_size;
+            if self.size > limit {
+                return Some(self.too_large(limit));
+            }
 
+            let last = line.last_line;
+            self.lines.push(line);
+            if self.lines.len() > line_limit {
                 let io = self.inner.take().expect("[BUG] poll after completion");
-                //FIXME[buf_management]: maybe normalize output bufer to have at most cap of 1024
-                return Ok(Some((io, check_response(response))));
+                return Some((io, Err(LogicError::TooManyResponseLines { limit: line_limit })));
+            }
+
+            if !last {
+                continue;
+            }
 
-            } else {
-                return Ok(None);
+            let lines = mem::replace(&mut self.lines, Vec::new());
+            let response = parser::response_from_parsed_lines(lines.into_iter())
+                .expect("[BUG] codes of the lines already checked");
+
+            #[allow(unused_mut)]
+            let mut io = self.inner.take().expect("[BUG] poll after completion");
+
+            if let Some(ref interceptor) = io.state.response_interceptor {
+                interceptor.intercept(&response);
+            }
+
+            #[cfg(feature="transcript")]
+            {
+                if let Some(ref mut error_log) = io.state.error_log {
+                    error_log.record(&response);
+                }
             }
+
+            //FIXME[buf_management]: maybe normalize output bufer to have at most cap of 1024
+            return Some((io, check_response(response)));
         }
     }
+
+    fn too_large(&mut self, limit: usize) -> (Io, SmtpResult) {
+        let io = self.inner.take().expect("[BUG] poll after completion");
+        (io, Err(LogicError::ResponseTooLarge { limit }))
+    }
 }
 
 impl Future for Parsing {
@@ -153,18 +280,18 @@ impl Future for Parsing {
         //1. parse more data
         let state = self.io_mut().read_from_socket()?;
 
-        //2. see if we have a full response now
-        match self.read_result() {
-            Ok(Some(result)) => return Ok(Async::Ready(result)),
-            Ok(None) => (),
-            Err(err) => return Err(std_io::Error::new(
-                std_io::ErrorKind::InvalidData, err
-            ))
+        //2. see if we have a full (or malformed) response now
+        if let Some(result) = self.read_result() {
+            return Ok(Async::Ready(result));
         }
 
         //3. if not see if the socked was closed
         match state {
             ReadState::NotReady => return Ok(Async::NotReady),
+            ReadState::BufferFull => {
+                let limit = self.io_mut().max_response_size();
+                return Ok(Async::Ready(self.too_large(limit)));
+            },
             ReadState::SocketClosed => {
                 return Err(std_io::Error::new(
                     std_io::ErrorKind::ConnectionAborted,
@@ -175,3 +302,126 @@ impl Future for Parsing {
     }
 }
 
+
+/// stream returned by `Io::parse_response_lines`
+///
+/// It ends after the last line of the response (i.e. the one using `' '`
+/// instead of `'-'` as separator), all lines have to have the same response
+/// code or the stream fails with an `InvalidData` I/O-Error. The same error
+/// (wrapping `LogicError::ResponseTooLarge`) is returned if a single line
+/// exceeds the maximal response size.
+pub struct ResponseLines {
+    inner: Option<Io>,
+    code: Option<ResponseCode>,
+    done: bool
+}
+
+impl ResponseLines {
+    pub(crate) fn new(mut inner: Io) -> Self {
+        inner.state.stall.reset();
+        ResponseLines {
+            inner: Some(inner),
+            code: None,
+            done: false
+        }
+    }
+
+    /// returns the `Io` instance if the stream completed, i.e. all lines were read
+    pub fn into_io(self) -> Option<Io> {
+        if self.done {
+            self.inner
+        } else {
+            None
+        }
+    }
+
+    /// folds all lines of the response into an accumulator
+    ///
+    /// Like `Stream::fold` but returns the `Io` instance together with the
+    /// accumulated value.
+    pub fn fold_lines<T, F>(self, init: T, func: F)
+        -> impl Future<Item=(Io, T), Error=std_io::Error>
+        where F: FnMut(T, parser::ResponseLine) -> T
+    {
+        future::loop_fn((self, init, func), |(lines, acc, mut func)| {
+            lines
+                .into_future()
+                .map_err(|(err, _lines)| err)
+                .map(|(opt_line, lines)| match opt_line {
+                    Some(line) => {
+                        let acc = func(acc, line);
+                        Loop::Continue((lines, acc, func))
+                    },
+                    None => {
+                        let io = lines.into_io().expect("[BUG] stream ended but is not done");
+                        Loop::Break((io, acc))
+                    }
+                })
+        })
+    }
+
+    fn pop_line(&mut self) -> Result<Option<parser::ResponseLine>, parser::ParseError> {
+        let opt_line = self
+            .inner.as_mut().expect("[BUG] poll after completion")
+            .try_pop_line(parser::parse_line)?;
+
+        if let Some(ref line) = opt_line {
+            match self.code {
+                Some(code) if code != line.code => {
+                    return Err(parser::ParseError::Code { expected: code, got: line.code });
+                },
+                Some(_) => (),
+                None => self.code = Some(line.code)
+            }
+            self.done = line.last_line;
+        }
+
+        Ok(opt_line)
+    }
+}
+
+impl Stream for ResponseLines {
+    type Item = parser::ResponseLine;
+    type Error = std_io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.done {
+            return Ok(Async::Ready(None));
+        }
+
+        let invalid_data = |err| std_io::Error::new(std_io::ErrorKind::InvalidData, err);
+
+        //1. only read more data if there is no complete line
+        if let Some(line) = self.pop_line().map_err(invalid_data)? {
+            return Ok(Async::Ready(Some(line)));
+        }
+
+        let state = self.inner.as_mut()
+            .expect("[BUG] poll after completion")
+            .read_from_socket()?;
+
+        if let Some(line) = self.pop_line().map_err(invalid_data)? {
+            return Ok(Async::Ready(Some(line)));
+        }
+
+        match state {
+            ReadState::NotReady => Ok(Async::NotReady),
+            ReadState::BufferFull => {
+                // the incomplete line in the buffer exceeds the limit
+                let limit = self.inner.as_ref()
+                    .expect("[BUG] poll after completion")
+                    .max_response_size();
+                Err(std_io::Error::new(
+                    std_io::ErrorKind::InvalidData,
+                    LogicError::ResponseTooLarge { limit }
+                ))
+            },
+            ReadState::SocketClosed => {
+                Err(std_io::Error::new(
+                    std_io::ErrorKind::ConnectionAborted,
+                    "socked closed before getting full smtp response",
+                ))
+            }
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use std::{io as std_io};
+use std::net::{IpAddr, SocketAddr};
+
+use futures::future::{self, Either, Future};
+use tokio::io::write_all;
+use tokio::net::TcpStream;
+
+/// the version of the PROXY protocol used for a `ProxyHeader`
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum ProxyVersion {
+    /// the human readable (text) format
+    V1,
+    /// the binary format
+    V2
+}
+
+/// A PROXY protocol header send directly after connecting
+///
+/// Load balancers like HAProxy use the PROXY protocol to pass on the
+/// address of the "real" client. If the smtp server sits behind such a
+/// balancer the header has to be send before the greeting is read (and
+/// for direct tls before the tls handshake).
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct ProxyHeader {
+    version: ProxyVersion,
+    source: SocketAddr,
+    destination: SocketAddr
+}
+
+//MAGIC_NUM: the signature of a PROXY protocol v2 header
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+//MAGIC_NUM: version 2 (high nibble) and the PROXY command (low nibble)
+const V2_VERSION_PROXY: u8 = 0x21;
+//MAGIC_NUM: AF_INET/AF_INET6 (high nibble) and STREAM (low nibble)
+const V2_TCP_OVER_IPV4: u8 = 0x11;
+const V2_TCP_OVER_IPV6: u8 = 0x21;
+
+impl ProxyHeader {
+
+    /// creates a header for a connection from `source` (the client) to `destination`
+    ///
+    /// If one address is a IPv4 and the other a IPv6 address the IPv4
+    /// address is send as IPv4-mapped IPv6 address, as the PROXY
+    /// protocol requires both to be of the same family.
+    pub fn new(version: ProxyVersion, source: SocketAddr, destination: SocketAddr) -> Self {
+        ProxyHeader { version, source, destination }
+    }
+
+    /// creates a PROXY protocol v1 (text) header
+    pub fn v1(source: SocketAddr, destination: SocketAddr) -> Self {
+        ProxyHeader::new(ProxyVersion::V1, source, destination)
+    }
+
+    /// creates a PROXY protocol v2 (binary) header
+    pub fn v2(source: SocketAddr, destination: SocketAddr) -> Self {
+        ProxyHeader::new(ProxyVersion::V2, source, destination)
+    }
+
+    pub fn version(&self) -> ProxyVersion {
+        self.version
+    }
+
+    /// the address of the client
+    pub fn source(&self) -> SocketAddr {
+        self.source
+    }
+
+    /// the address the client connected to
+    pub fn destination(&self) -> SocketAddr {
+        self.destination
+    }
+
+    /// returns the bytes of the header
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (source, destination) = same_family(self.source.ip(), self.destination.ip());
+        let (source_port, destination_port) = (self.source.port(), self.destination.port());
+
+        match self.version {
+            ProxyVersion::V1 => {
+                let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+                format!("PROXY {} {} {} {} {}\r\n",
+                    family, source, destination, source_port, destination_port)
+                    .into_bytes()
+            },
+            ProxyVersion::V2 => {
+                let mut out = Vec::with_capacity(16 + 36);
+                out.extend_from_slice(V2_SIGNATURE);
+                out.push(V2_VERSION_PROXY);
+                match (source, destination) {
+                    (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                        out.push(V2_TCP_OVER_IPV4);
+                        push_u16(&mut out, 12);
+                        out.extend_from_slice(&source.octets());
+                        out.extend_from_slice(&destination.octets());
+                    },
+                    (IpAddr::V6(source), IpAddr::V6(destination)) => {
+                        out.push(V2_TCP_OVER_IPV6);
+                        push_u16(&mut out, 36);
+                        out.extend_from_slice(&source.octets());
+                        out.extend_from_slice(&destination.octets());
+                    },
+                    _ => unreachable!("[BUG] addresses of different families")
+                }
+                push_u16(&mut out, source_port);
+                push_u16(&mut out, destination_port);
+                out
+            }
+        }
+    }
+}
+
+fn same_family(source: IpAddr, destination: IpAddr) -> (IpAddr, IpAddr) {
+    match (source, destination) {
+        (IpAddr::V4(source), IpAddr::V6(destination)) =>
+            (IpAddr::V6(source.to_ipv6_mapped()), IpAddr::V6(destination)),
+        (IpAddr::V6(source), IpAddr::V4(destination)) =>
+            (IpAddr::V6(source), IpAddr::V6(destination.to_ipv6_mapped())),
+        other => other
+    }
+}
+
+fn push_u16(out: &mut Vec<u8>, val: u16) {
+    out.push((val >> 8) as u8);
+    out.push(val as u8);
+}
+
+/// connects to `addr` and sends the proxy header (if there is one)
+pub(crate) fn connect_tcp(addr: &SocketAddr, proxy_header: Option<ProxyHeader>)
+    -> impl Future<Item=TcpStream, Error=std_io::Error> + Send
+{
+    TcpStream::connect(addr)
+        .and_then(move |stream| match proxy_header {
+            Some(header) => {
+                let fut = write_all(stream, header.to_bytes())
+                    .map(|(stream, _header)| stream);
+                Either::A(fut)
+            },
+            None => Either::B(future::ok(stream))
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use std::net::SocketAddr;
+
+    use futures::Future;
+
+    use ::{Connection, ConnectionConfig};
+    use ::stub_server::{self, serve_lines};
+    use super::*;
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn v1_header_for_ipv4() {
+        let header = ProxyHeader::v1(addr("192.0.2.1:56324"), addr("198.51.100.1:25"));
+        assert_eq!(header.to_bytes(), b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 25\r\n".to_vec());
+    }
+
+    #[test]
+    fn v1_header_for_ipv6() {
+        let header = ProxyHeader::v1(addr("[2001:db8::1]:56324"), addr("[2001:db8::2]:25"));
+        assert_eq!(header.to_bytes(), b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 25\r\n".to_vec());
+    }
+
+    #[test]
+    fn mixed_families_use_ipv4_mapped_addresses() {
+        let header = ProxyHeader::v1(addr("192.0.2.1:56324"), addr("[2001:db8::2]:25"));
+        assert_eq!(header.to_bytes(), b"PROXY TCP6 ::ffff:192.0.2.1 2001:db8::2 56324 25\r\n".to_vec());
+    }
+
+    #[test]
+    fn v2_header_for_ipv4() {
+        let header = ProxyHeader::v2(addr("192.0.2.1:56324"), addr("198.51.100.1:25"));
+        let mut expected = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        expected.extend_from_slice(&[
+            0x21, 0x11, 0, 12,
+            192, 0, 2, 1,
+            198, 51, 100, 1,
+            0xdc, 0x04, 0, 25
+        ]);
+        assert_eq!(header.to_bytes(), expected);
+    }
+
+    #[test]
+    fn v2_header_for_ipv6() {
+        let header = ProxyHeader::v2(addr("[2001:db8::1]:1"), addr("[2001:db8::2]:25"));
+        let bytes = header.to_bytes();
+        assert_eq!(bytes.len(), 16 + 36);
+        assert_eq!(&bytes[12..16], &[0x21, 0x21, 0, 36]);
+        assert_eq!(&bytes[16..18], &[0x20, 0x01]);
+        assert_eq!(&bytes[47], &2);
+        assert_eq!(&bytes[48..], &[0, 1, 0, 25]);
+    }
+
+    #[test]
+    fn header_is_send_before_the_greeting_is_read() {
+        let header = ProxyHeader::v1(addr("192.0.2.1:56324"), addr("198.51.100.1:25"));
+        let expected = header.to_bytes();
+
+        let (addr, server) = stub_server::spawn_one(move |mut stream| {
+            // the header has to arrive before the server sends anything
+            let mut received = vec![0; expected.len()];
+            stream.read_exact(&mut received).unwrap();
+            serve_lines(stream, b"220 stub ready\r\n", |line| Some(
+                if line.starts_with("QUIT") { b"221 Bye\r\n" }
+                else { b"250 stub.test\r\n" }
+            ));
+            (received, expected)
+        });
+
+        let config = ConnectionConfig::builder_local_unencrypted()
+            .port(addr.port())
+            .proxy_header(header)
+            .build();
+        let con = Connection::connect(config).wait().unwrap();
+        con.quit().wait().unwrap();
+
+        let (received, expected) = server.join().unwrap();
+        assert_eq!(received, expected);
+    }
+}
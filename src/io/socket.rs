@@ -1,16 +1,20 @@
 use std::io as std_io;
 use std::fmt::Debug;
+use std::net::{Shutdown, SocketAddr};
 
 use futures::Poll;
 use bytes::buf::{Buf, BufMut};
 use tokio::net::TcpStream;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_tls::TlsStream;
+use native_tls;
 
-/// Abstraction over Tcp, TcpTls (and Mock)
+/// Abstraction over Tcp, TcpTls, custom streams (and Mock)
 ///
 /// Allows treating both `TcpStream` and
-/// `TlsStream<TcpStream>` the same.
+/// `TlsStream<TcpStream>` the same. A `Custom` stream
+/// can be used e.g. to wrap a socket with middleware
+/// (see `Io::into_parts`).
 ///
 /// # Features
 /// ## `mock_support`
@@ -22,6 +26,7 @@ use tokio_tls::TlsStream;
 pub enum Socket {
     Secure(TlsStream<TcpStream>),
     Insecure(TcpStream),
+    Custom(Box<CustomStream + Send>),
     #[cfg(feature="mock-support")]
     Mock(Box<MockStream + Send>)
 }
@@ -33,10 +38,59 @@ impl Socket {
         match *self {
             Socket::Secure(_) => true,
             Socket::Insecure(_) => false,
+            Socket::Custom(ref stream) => stream.is_secure(),
             #[cfg(feature="mock-support")]
             Socket::Mock(ref mock) => mock.is_secure()
         }
     }
+
+    /// returns information about the tls session, `None` if the socket is not secure
+    ///
+    /// For a (secure) mock socket a `TlsInfo` without any information is returned.
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        match *self {
+            Socket::Secure(ref stream) => Some(TlsInfo::from_stream(stream.get_ref())),
+            Socket::Insecure(_) => None,
+            Socket::Custom(ref stream) => stream.tls_info(),
+            #[cfg(feature="mock-support")]
+            Socket::Mock(ref mock) => {
+                if mock.is_secure() {
+                    Some(TlsInfo::default())
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// returns the local address the underlying tcp connection is bound to
+    ///
+    /// Fails with `ErrorKind::Other` for a mock socket.
+    pub fn local_addr(&self) -> Result<SocketAddr, std_io::Error> {
+        match *self {
+            Socket::Secure(ref stream) => stream.get_ref().get_ref().local_addr(),
+            Socket::Insecure(ref stream) => stream.local_addr(),
+            Socket::Custom(ref stream) => stream.local_addr(),
+            #[cfg(feature="mock-support")]
+            Socket::Mock(_) => Err(std_io::Error::new(
+                std_io::ErrorKind::Other, "mock socket has no local address"))
+        }
+    }
+
+    /// shuts down the underlying tcp connection without blocking
+    ///
+    /// In difference to `AsyncWrite::shutdown` this doesn't need to be
+    /// polled and can be called outside of a task, but it also does
+    /// not send a tls close notify nor flushes any buffered data.
+    pub fn shutdown_now(&mut self) -> Result<(), std_io::Error> {
+        match *self {
+            Socket::Secure(ref stream) => stream.get_ref().get_ref().shutdown(Shutdown::Both),
+            Socket::Insecure(ref stream) => stream.shutdown(Shutdown::Both),
+            Socket::Custom(ref mut stream) => stream.shutdown_now(),
+            #[cfg(feature="mock-support")]
+            Socket::Mock(ref mut mock) => mock.shutdown_now()
+        }
+    }
 }
 
 macro_rules! socket_mux {
@@ -44,6 +98,7 @@ macro_rules! socket_mux {
         match *$self {
             Socket::Secure(ref mut $socket) => $block,
             Socket::Insecure(ref mut $socket) => $block,
+            Socket::Custom(ref mut $socket) => $block,
             #[cfg(feature="mock-support")]
             Socket::Mock(ref mut $socket) => $block
         }
@@ -78,6 +133,7 @@ impl AsyncRead for Socket {
         match *self {
             Socket::Secure(ref socket) => socket.prepare_uninitialized_buffer(buf),
             Socket::Insecure(ref socket) => socket.prepare_uninitialized_buffer(buf),
+            Socket::Custom(ref socket) => socket.prepare_uninitialized_buffer(buf),
             #[cfg(feature="mock-support")]
             Socket::Mock(ref socket) => socket.prepare_uninitialized_buffer(buf)
         }
@@ -128,10 +184,83 @@ impl AsyncWrite for Socket {
     }
 }
 
+/// Information about a negotiated tls session
+///
+/// Only contains what `native-tls` exposes, e.g. it doesn't expose the
+/// negotiated protocol version or cipher suite.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsInfo {
+    alpn_protocol: Option<Vec<u8>>
+}
+
+impl TlsInfo {
+
+    fn from_stream<S>(stream: &native_tls::TlsStream<S>) -> Self
+        where S: std_io::Read + std_io::Write
+    {
+        TlsInfo {
+            alpn_protocol: stream.negotiated_alpn().ok().and_then(|alpn| alpn)
+        }
+    }
+
+    /// the protocol negotiated through ALPN (if any)
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.alpn_protocol.as_deref()
+    }
+}
+
+/// A stream which can be used as `Socket::Custom`
+///
+/// E.g. a `Socket` wrapped by middleware (throttling, logging, etc.), for
+/// which `Socket` implements this trait, so a wrapper can forward to it.
+pub trait CustomStream: Debug + AsyncRead + AsyncWrite + 'static {
+
+    /// true if the stream is encrypted
+    fn is_secure(&self) -> bool;
+
+    /// returns information about the tls session, see `Socket::tls_info` (default: `None`)
+    fn tls_info(&self) -> Option<TlsInfo> {
+        None
+    }
+
+    /// returns the local address, see `Socket::local_addr` (default: fails with `ErrorKind::Other`)
+    fn local_addr(&self) -> Result<SocketAddr, std_io::Error> {
+        Err(std_io::Error::new(std_io::ErrorKind::Other, "custom stream has no local address"))
+    }
+
+    /// called by `Socket::shutdown_now` (default: does nothing)
+    fn shutdown_now(&mut self) -> Result<(), std_io::Error> {
+        Ok(())
+    }
+}
+
+impl CustomStream for Socket {
+    fn is_secure(&self) -> bool {
+        Socket::is_secure(self)
+    }
+
+    fn tls_info(&self) -> Option<TlsInfo> {
+        Socket::tls_info(self)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, std_io::Error> {
+        Socket::local_addr(self)
+    }
+
+    fn shutdown_now(&mut self) -> Result<(), std_io::Error> {
+        Socket::shutdown_now(self)
+    }
+}
+
 /// trait representing a mock stream
 pub trait MockStream: Debug + AsyncRead + AsyncWrite + 'static {
     fn is_secure(&self) -> bool {
         false
     }
     fn set_is_secure(&mut self, secure: bool);
+
+    /// called by `Socket::shutdown_now`
+    fn shutdown_now(&mut self) -> Result<(), std_io::Error> {
+        Ok(())
+    }
 }
\ No newline at end of file
@@ -0,0 +1,79 @@
+use std::{io as std_io};
+use std::time::Duration;
+
+use futures::{Async, Future};
+use tokio::clock;
+use tokio::timer::Delay;
+
+/// Read/write timeouts, i.e. how long the socket may not make any progress
+///
+/// The sockets are non-blocking, so OS level timeouts (e.g. `SO_RCVTIMEO`)
+/// would have no effect, instead the timer is started once the socket is
+/// not ready and reset whenever data is read or written.
+#[derive(Debug, Default)]
+pub(crate) struct StallTimeouts {
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    timer: Option<Delay>
+}
+
+impl StallTimeouts {
+
+    pub(crate) fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout
+    }
+
+    pub(crate) fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+        self.reset();
+    }
+
+    pub(crate) fn write_timeout(&self) -> Option<Duration> {
+        self.write_timeout
+    }
+
+    pub(crate) fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+        self.write_timeout = timeout;
+        self.reset();
+    }
+
+    /// resets the timer, done when starting to read/write and on progress
+    pub(crate) fn reset(&mut self) {
+        self.timer = None;
+    }
+
+    /// to be called if reading would block, fails if the read timeout elapsed
+    pub(crate) fn poll_read_stalled(&mut self) -> Result<(), std_io::Error> {
+        let timeout = self.read_timeout;
+        self.poll_stalled(timeout, "reading from")
+    }
+
+    /// to be called if writing would block, fails if the write timeout elapsed
+    pub(crate) fn poll_write_stalled(&mut self) -> Result<(), std_io::Error> {
+        let timeout = self.write_timeout;
+        self.poll_stalled(timeout, "writing to")
+    }
+
+    fn poll_stalled(&mut self, timeout: Option<Duration>, action: &str) -> Result<(), std_io::Error> {
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return Ok(())
+        };
+
+        let poll = self.timer
+            .get_or_insert_with(|| Delay::new(clock::now() + timeout))
+            .poll()
+            .map_err(|err| std_io::Error::new(std_io::ErrorKind::Other, err))?;
+
+        match poll {
+            Async::NotReady => Ok(()),
+            Async::Ready(()) => {
+                self.reset();
+                Err(std_io::Error::new(
+                    std_io::ErrorKind::TimedOut,
+                    format!("{} the socket made no progress for {:?}", action, timeout)
+                ))
+            }
+        }
+    }
+}
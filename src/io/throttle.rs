@@ -0,0 +1,107 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Limits how many commands can be started within a period of time
+///
+/// Commands exceeding the limit are delayed (not failed) until they
+/// can be sent without exceeding it, see `Connection::set_rate_limit`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct RateLimit {
+    max_commands: usize,
+    per: Duration
+}
+
+impl RateLimit {
+
+    /// allow at most `max_commands` commands within any time span of length `per`
+    ///
+    /// # Panics
+    ///
+    /// if `max_commands` is 0
+    pub fn new(max_commands: usize, per: Duration) -> Self {
+        assert!(max_commands > 0, "rate limit has to allow at least one command");
+        RateLimit { max_commands, per }
+    }
+
+    /// allow at most `max_commands` commands per second
+    pub fn per_second(max_commands: usize) -> Self {
+        RateLimit::new(max_commands, Duration::from_secs(1))
+    }
+
+    /// the maximal number of commands within `per()`
+    pub fn max_commands(&self) -> usize {
+        self.max_commands
+    }
+
+    /// the length of the time span
+    pub fn per(&self) -> Duration {
+        self.per
+    }
+}
+
+/// the state needed to enforce a `RateLimit` (a sliding window of start times)
+#[derive(Debug, Clone)]
+pub(crate) struct Throttle {
+    limit: RateLimit,
+    /// the start times of the last `max_commands` commands
+    starts: VecDeque<Instant>
+}
+
+impl Throttle {
+
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        Throttle { limit, starts: VecDeque::with_capacity(limit.max_commands) }
+    }
+
+    pub(crate) fn limit(&self) -> RateLimit {
+        self.limit
+    }
+
+    /// reserves the earliest point in time (not before `now`) a command can be started
+    pub(crate) fn reserve(&mut self, now: Instant) -> Instant {
+        let start =
+            if self.starts.len() < self.limit.max_commands {
+                now
+            } else {
+                //UNWRAP_SAFE: max_commands is at least 1
+                let window_start = *self.starts.front().unwrap() + self.limit.per;
+                if window_start > now { window_start } else { now }
+            };
+
+        if self.starts.len() >= self.limit.max_commands {
+            self.starts.pop_front();
+        }
+        self.starts.push_back(start);
+        start
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+    use super::{RateLimit, Throttle};
+
+    #[test]
+    fn delays_commands_exceeding_the_limit() {
+        let per = Duration::from_millis(100);
+        let mut throttle = Throttle::new(RateLimit::new(2, per));
+        let now = Instant::now();
+
+        assert_eq!(throttle.reserve(now), now);
+        assert_eq!(throttle.reserve(now), now);
+        assert_eq!(throttle.reserve(now), now + per);
+        assert_eq!(throttle.reserve(now), now + per);
+        assert_eq!(throttle.reserve(now), now + per * 2);
+    }
+
+    #[test]
+    fn does_not_delay_after_the_window_passed() {
+        let per = Duration::from_millis(100);
+        let mut throttle = Throttle::new(RateLimit::new(1, per));
+        let now = Instant::now();
+
+        assert_eq!(throttle.reserve(now), now);
+        let later = now + per * 3;
+        assert_eq!(throttle.reserve(later), later);
+    }
+}
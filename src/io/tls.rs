@@ -0,0 +1,287 @@
+use std::{io as std_io};
+use std::time::Duration;
+
+use futures::future::{self, Either, Future};
+use sha2::{Digest, Sha256};
+use tokio::net::TcpStream;
+use tokio::timer::Timeout;
+use tokio_tls::{TlsConnector, TlsStream};
+use native_tls::{self, TlsConnector as NativeTlsConnector};
+
+use ::common::{map_tls_err, SetupTls};
+use ::data_types::Domain;
+use ::error::{CertificatePinMismatch, TlsHandshakeTimeout};
+
+/// does the tls handshake on the given stream
+///
+/// `sni_domain` is send to the server (SNI) and the certificate is verified
+/// against `verify_domain`. If both are the same this is left to the tls
+/// implementation, else its hostname verification is disabled and the
+/// certificate is verified against `verify_domain` using the
+/// `SetupTls::domain_verifier` after the handshake (without one connecting
+/// fails before the handshake).
+///
+/// If the setup has pinned public keys (`SetupTls::spki_pins`) the servers
+/// public key is checked against them after the handshake.
+///
+/// If `handshake_timeout` is given and the handshake takes longer, it fails
+/// with an I/O-Error of kind `TimedOut` wrapping `TlsHandshakeTimeout`
+/// (this requires a running tokio timer).
+pub(crate) fn connect_tls<S>(
+    setup: S,
+    sni_domain: &Domain,
+    verify_domain: &Domain,
+    stream: TcpStream,
+    handshake_timeout: Option<Duration>
+) -> impl Future<Item=TlsStream<TcpStream>, Error=std_io::Error> + Send
+    where S: SetupTls
+{
+    let domain_verifier = if sni_domain != verify_domain {
+        match setup.domain_verifier() {
+            Some(verifier) => Some(verifier),
+            None => return Either::A(future::err(std_io::Error::new(
+                std_io::ErrorKind::InvalidInput,
+                "a sni domain different from the verification domain requires a SetupTls::domain_verifier"
+            )))
+        }
+    } else {
+        None
+    };
+    let separate_verification = domain_verifier.is_some();
+    let spki_pins = setup.spki_pins();
+    let connector = alttry!(
+        {
+            let mut builder = NativeTlsConnector::builder();
+            if separate_verification {
+                // the certificate is verified against the right domain after the handshake
+                builder.danger_accept_invalid_hostnames(true);
+            }
+            let contor = setup.setup(builder)?;
+            Ok(TlsConnector::from(contor))
+        } =>
+        |err| Either::A(future::err(map_tls_err(err)))
+    );
+
+    let handshake = connector
+        .connect(sni_domain.as_str(), stream)
+        .map_err(map_tls_err);
+
+    let handshake = match handshake_timeout {
+        None => Either::A(handshake),
+        Some(timeout) => {
+            let fut = Timeout::new(handshake, timeout)
+                .map_err(|err| {
+                    if err.is_elapsed() {
+                        std_io::Error::new(std_io::ErrorKind::TimedOut, TlsHandshakeTimeout)
+                    } else if err.is_timer() {
+                        let timer_err = err.into_timer().expect("[BUG] is_timer but no timer error");
+                        std_io::Error::new(std_io::ErrorKind::Other, timer_err)
+                    } else {
+                        err.into_inner().expect("[BUG] timeout error neither elapsed, timer nor inner")
+                    }
+                });
+            Either::B(fut)
+        }
+    };
+
+    let verify_domain = verify_domain.clone();
+    let fut = handshake
+        .and_then(move |stream| {
+            if let Some(verifier) = domain_verifier {
+                let der = peer_certificate_der(stream.get_ref())?;
+                if !verifier(&der, &verify_domain) {
+                    return Err(verification_error(
+                        "server certificate does not match the verification domain"));
+                }
+            }
+            if !spki_pins.is_empty() {
+                verify_spki_pins(stream.get_ref(), &spki_pins)?;
+            }
+            Ok(stream)
+        });
+
+    Either::B(fut)
+}
+
+fn peer_certificate_der<S>(stream: &native_tls::TlsStream<S>) -> Result<Vec<u8>, std_io::Error>
+    where S: std_io::Read + std_io::Write
+{
+    let cert = stream.peer_certificate()
+        .map_err(map_tls_err)?
+        .ok_or_else(|| verification_error("server did not send a certificate"))?;
+    cert.to_der().map_err(map_tls_err)
+}
+
+fn verify_spki_pins<S>(stream: &native_tls::TlsStream<S>, pins: &[[u8; 32]])
+    -> Result<(), std_io::Error>
+    where S: std_io::Read + std_io::Write
+{
+    let der = peer_certificate_der(stream)?;
+    let spki = certificate_spki(&der)
+        .ok_or_else(|| verification_error("server certificate is malformed"))?;
+
+    let mut spki_sha256 = [0u8; 32];
+    spki_sha256.copy_from_slice(&Sha256::digest(spki));
+
+    if pins.contains(&spki_sha256) {
+        Ok(())
+    } else {
+        Err(std_io::Error::new(
+            std_io::ErrorKind::InvalidData,
+            CertificatePinMismatch::new(spki_sha256)
+        ))
+    }
+}
+
+/// returns the (DER encoded) SubjectPublicKeyInfo of a DER encoded X.509 certificate
+fn certificate_spki(der: &[u8]) -> Option<&[u8]> {
+    let (_, _, certificate, _) = split_der(der)?;
+    let (_, _, tbs_certificate, _) = split_der(certificate)?;
+
+    // the version is optional (tagged `[0]`)
+    let (tag, _, _, mut rest) = split_der(tbs_certificate)?;
+    if tag != 0xa0 {
+        rest = tbs_certificate;
+    }
+    // serialNumber, signature, issuer, validity and subject
+    for _ in 0..5 {
+        rest = split_der(rest)?.3;
+    }
+    split_der(rest).map(|(_, spki, _, _)| spki)
+}
+
+/// `(tag, element, content, rest)` as returned by `split_der`
+type DerSplit<'a> = (u8, &'a [u8], &'a [u8], &'a [u8]);
+
+/// splits the first DER element from `data` into `(tag, element, content, rest)`
+fn split_der(data: &[u8]) -> Option<DerSplit<'_>> {
+    let tag = *data.first()?;
+    let first_len_byte = *data.get(1)?;
+    let (header_len, content_len) =
+        if first_len_byte < 0x80 {
+            (2, first_len_byte as usize)
+        } else {
+            let len_bytes = (first_len_byte & 0x7f) as usize;
+            if len_bytes == 0 || len_bytes > 4 {
+                return None;
+            }
+            let raw_len = data.get(2..2 + len_bytes)?;
+            let len = raw_len.iter().fold(0usize, |len, &byte| (len << 8) | byte as usize);
+            (2 + len_bytes, len)
+        };
+
+    let end = header_len.checked_add(content_len)?;
+    let element = data.get(..end)?;
+    Some((tag, element, &element[header_len..], &data[end..]))
+}
+
+fn verification_error(msg: &'static str) -> std_io::Error {
+    std_io::Error::new(std_io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(all(test, not(any(target_os = "windows", target_vendor = "apple"))))]
+mod test {
+    use std::{io as std_io};
+    use std::io::{Read, Write};
+
+    use futures::Future;
+    use openssl::sha::sha256;
+    use openssl::x509::X509;
+    use native_tls::{
+        self, Identity,
+        TlsAcceptor, TlsConnectorBuilder,
+        TlsConnector as NativeTlsConnector
+    };
+
+    use ::common::{SetupTls, TlsConfig, TlsOptions};
+    use ::data_types::Domain;
+    use ::error::CertificatePinMismatch;
+    use ::stub_server::{self, wait_for_close};
+    use ::stub_server::tls::{TrustTestCa, SERVER_CERT, SERVER_KEY};
+    use super::super::Io;
+
+    fn server_spki_sha256() -> [u8; 32] {
+        let cert = X509::from_pem(SERVER_CERT).unwrap();
+        sha256(&cert.public_key().unwrap().public_key_to_der().unwrap())
+    }
+
+    fn connect_to_test_server(config: TlsConfig<TrustTestCa>) -> Result<(), std_io::Error> {
+        let (addr, server) = stub_server::spawn_one(|stream| {
+            let identity = Identity::from_pkcs8(SERVER_CERT, SERVER_KEY).unwrap();
+            let acceptor = TlsAcceptor::new(identity).unwrap();
+            if let Ok(mut stream) = acceptor.accept(stream) {
+                let _ = stream.write_all(b"220 ready\r\n");
+                let mut buf = [0u8; 64];
+                let _ = stream.read(&mut buf);
+            }
+        });
+
+        let res = Io::connect_secure(&addr, config).wait().map(|_| ());
+
+        server.join().unwrap();
+        res
+    }
+
+    #[test]
+    fn sni_domain_can_differ_from_verification_domain() {
+        let config = TlsConfig::new(Domain::from_unchecked("smtp.example.com"), TrustTestCa(TlsOptions::new()))
+            .with_sni_domain(Domain::from_unchecked("tenant.cdn.example.net"));
+        assert!(connect_to_test_server(config).is_ok());
+    }
+
+    #[test]
+    fn verification_domain_is_still_checked() {
+        let config = TlsConfig::new(Domain::from_unchecked("other.example.com"), TrustTestCa(TlsOptions::new()))
+            .with_sni_domain(Domain::from_unchecked("smtp.example.com"));
+        assert!(connect_to_test_server(config).is_err());
+    }
+
+    #[test]
+    fn separate_sni_domain_requires_domain_verifier() {
+        /// trusts the test ca, but has no domain verifier
+        #[derive(Debug)]
+        struct NoVerifier;
+
+        impl SetupTls for NoVerifier {
+            fn setup(self, builder: TlsConnectorBuilder)
+                -> Result<NativeTlsConnector, native_tls::Error>
+            {
+                TrustTestCa(TlsOptions::new()).setup(builder)
+            }
+        }
+
+        let (addr, server) = stub_server::spawn_one(wait_for_close);
+        let config = TlsConfig::new(Domain::from_unchecked("smtp.example.com"), NoVerifier)
+            .with_sni_domain(Domain::from_unchecked("tenant.cdn.example.net"));
+
+        let err = Io::connect_secure(&addr, config).wait().unwrap_err();
+        assert_eq!(err.kind(), std_io::ErrorKind::InvalidInput);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn domain_is_used_for_sni_and_verification_by_default() {
+        let config = TlsConfig::new(Domain::from_unchecked("smtp.example.com"), TrustTestCa(TlsOptions::new()));
+        assert!(connect_to_test_server(config).is_ok());
+    }
+
+    #[test]
+    fn matching_spki_pin_is_accepted() {
+        let setup = TlsOptions::new()
+            .pin_spki_sha256([0; 32])
+            .pin_spki_sha256(server_spki_sha256());
+        let config = TlsConfig::new(Domain::from_unchecked("smtp.example.com"), TrustTestCa(setup));
+        assert!(connect_to_test_server(config).is_ok());
+    }
+
+    #[test]
+    fn wrong_spki_pin_is_rejected() {
+        let setup = TrustTestCa(TlsOptions::new().pin_spki_sha256([0; 32]));
+        let config = TlsConfig::new(Domain::from_unchecked("smtp.example.com"), setup);
+        let err = connect_to_test_server(config).unwrap_err();
+        let mismatch = err.get_ref()
+            .and_then(|err| err.downcast_ref::<CertificatePinMismatch>())
+            .expect("expected a CertificatePinMismatch error");
+        assert_eq!(mismatch.spki_sha256(), &server_spki_sha256());
+    }
+}
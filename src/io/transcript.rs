@@ -0,0 +1,329 @@
+use std::collections::VecDeque;
+
+use ::response::Response;
+use super::MAX_LINE_LENGTH;
+
+/// replaces credentials in the transcript
+const REDACTED: &str = "[redacted]";
+
+/// The direction in which a line of the transcript was transmitted
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Direction {
+    /// the line was send by the client (i.e. us)
+    Sent,
+    /// the line was received from the server
+    Received
+}
+
+/// A line of the transcript (without the trailing `"\r\n"`)
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct TranscriptEntry {
+    direction: Direction,
+    line: String
+}
+
+impl TranscriptEntry {
+
+    pub fn new<I>(direction: Direction, line: I) -> Self
+        where I: Into<String>
+    {
+        TranscriptEntry { direction, line: line.into() }
+    }
+
+    /// the direction in which the line was transmitted
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// the line, non utf-8 data is replaced by the replacement character
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+}
+
+/// Records the lines send and received through a connection
+///
+/// Only the last `capacity` lines are kept. Credentials send as part of
+/// an `AUTH` exchange are redacted (the mechanism is still recorded), they
+/// are dropped before being buffered, i.e. never copied into the transcript.
+///
+/// Only available if the `transcript` feature is enabled, see
+/// `Connection::enable_transcript`.
+#[derive(Debug, Clone)]
+pub struct Transcript {
+    capacity: usize,
+    entries: VecDeque<TranscriptEntry>,
+    partial_sent: Vec<u8>,
+    partial_received: Vec<u8>,
+    in_auth: bool,
+    /// the rest of the current sent line contains credentials
+    skip_sent: bool
+}
+
+impl Transcript {
+
+    /// creates a new transcript keeping up to `capacity` lines
+    pub fn new(capacity: usize) -> Self {
+        Transcript {
+            capacity,
+            entries: VecDeque::new(),
+            partial_sent: Vec::new(),
+            partial_received: Vec::new(),
+            in_auth: false,
+            skip_sent: false
+        }
+    }
+
+    /// the maximal number of lines kept
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// returns the recorded lines (oldest first)
+    pub fn entries(&self) -> Vec<TranscriptEntry> {
+        self.entries.iter().cloned().collect()
+    }
+
+    pub(crate) fn record_sent(&mut self, data: &[u8]) {
+        self.record(Direction::Sent, data)
+    }
+
+    pub(crate) fn record_received(&mut self, data: &[u8]) {
+        self.record(Direction::Received, data)
+    }
+
+    fn record(&mut self, direction: Direction, mut data: &[u8]) {
+        while !data.is_empty() {
+            let (chunk, complete) = match data.iter().position(|bch| *bch == b'\n') {
+                Some(idx) => (&data[..idx + 1], true),
+                None => (data, false)
+            };
+            data = &data[chunk.len()..];
+
+            let keep = match direction {
+                Direction::Sent => self.sent_keep_len(chunk),
+                Direction::Received => chunk.len()
+            };
+            let partial = match direction {
+                Direction::Sent => &mut self.partial_sent,
+                Direction::Received => &mut self.partial_received
+            };
+            // bound overlong lines, the rest of the line is dropped
+            let free = MAX_LINE_LENGTH.saturating_sub(partial.len());
+            partial.extend_from_slice(&chunk[..keep.min(free)]);
+
+            if complete {
+                let raw = ::std::mem::replace(partial, Vec::new());
+                let line = String::from_utf8_lossy(&raw)
+                    .trim_end_matches(&['\r', '\n'][..])
+                    .to_owned();
+                self.push_line(direction, line);
+                if direction == Direction::Sent {
+                    self.skip_sent = false;
+                }
+            }
+        }
+    }
+
+    /// how many bytes of `chunk` can be buffered without buffering credentials
+    ///
+    /// Lines send during an auth exchange and everything after the
+    /// mechanism of the `AUTH` command is dropped, if something was
+    /// dropped `redact_sent` adds the placeholder once the line is complete.
+    fn sent_keep_len(&mut self, chunk: &[u8]) -> usize {
+        if self.in_auth || self.skip_sent {
+            return 0;
+        }
+
+        let start = self.partial_sent.len();
+        let partial = &self.partial_sent;
+        let byte_at = |idx: usize| if idx < start {
+            partial.get(idx).cloned()
+        } else {
+            chunk.get(idx - start).cloned()
+        };
+
+        let is_auth = b"AUTH ".iter().enumerate()
+            .all(|(idx, expected)| byte_at(idx).map(|bch| bch.to_ascii_uppercase()) == Some(*expected));
+        if !is_auth {
+            return chunk.len();
+        }
+
+        // the initial response (if any) follows the space after the mechanism
+        match (5..start + chunk.len()).find(|idx| byte_at(*idx) == Some(b' ')) {
+            Some(idx) => {
+                self.skip_sent = true;
+                idx.saturating_sub(start)
+            },
+            None => chunk.len()
+        }
+    }
+
+    fn push_line(&mut self, direction: Direction, line: String) {
+        let line = match direction {
+            Direction::Sent => self.redact_sent(line),
+            Direction::Received => {
+                // a non challenge response ends the auth exchange
+                if self.in_auth && !line.starts_with("334") {
+                    self.in_auth = false;
+                }
+                line
+            }
+        };
+
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(TranscriptEntry { direction, line });
+    }
+
+    fn redact_sent(&mut self, line: String) -> String {
+        if self.in_auth {
+            return REDACTED.to_owned();
+        }
+
+        let is_auth = line.get(..5)
+            .map(|start| start.eq_ignore_ascii_case("AUTH "))
+            .unwrap_or(false);
+
+        if is_auth {
+            self.in_auth = true;
+        }
+
+        // `skip_sent` is only set if the line had an initial response
+        if is_auth && self.skip_sent {
+            let mechanism = line[5..].split(' ').next().unwrap_or("");
+            format!("AUTH {} {}", mechanism, REDACTED)
+        } else {
+            line
+        }
+    }
+}
+
+/// Keeps the last error responses (4xx/5xx) received through a connection
+///
+/// Only the last `capacity` error responses are kept, all other responses
+/// are ignored.
+///
+/// Only available if the `transcript` feature is enabled, see
+/// `Connection::enable_error_log`.
+#[derive(Debug, Clone)]
+pub struct ErrorLog {
+    capacity: usize,
+    responses: VecDeque<Response>
+}
+
+impl ErrorLog {
+
+    /// creates a new error log keeping up to `capacity` error responses
+    pub fn new(capacity: usize) -> Self {
+        ErrorLog {
+            capacity,
+            responses: VecDeque::new()
+        }
+    }
+
+    /// the maximal number of error responses kept
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// returns the kept error responses (oldest first)
+    pub fn entries(&self) -> Vec<Response> {
+        self.responses.iter().cloned().collect()
+    }
+
+    pub(crate) fn record(&mut self, response: &Response) {
+        if !response.is_erroneous() || self.capacity == 0 {
+            return;
+        }
+        if self.responses.len() >= self.capacity {
+            self.responses.pop_front();
+        }
+        self.responses.push_back(response.clone());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sent(line: &str) -> TranscriptEntry {
+        TranscriptEntry::new(Direction::Sent, line)
+    }
+
+    fn received(line: &str) -> TranscriptEntry {
+        TranscriptEntry::new(Direction::Received, line)
+    }
+
+    #[test]
+    fn joins_lines_split_across_chunks() {
+        let mut transcript = Transcript::new(10);
+        transcript.record_received(b"250-smtp.test\r\n250 SI");
+        transcript.record_received(b"ZE 1000\r\n");
+        transcript.record_sent(b"NOOP\r");
+        transcript.record_sent(b"\n");
+
+        assert_eq!(transcript.entries(), vec![
+            received("250-smtp.test"),
+            received("250 SIZE 1000"),
+            sent("NOOP")
+        ]);
+    }
+
+    #[test]
+    fn keeps_only_the_last_capacity_lines() {
+        let mut transcript = Transcript::new(2);
+        transcript.record_sent(b"NOOP\r\nRSET\r\nQUIT\r\n");
+
+        assert_eq!(transcript.entries(), vec![sent("RSET"), sent("QUIT")]);
+    }
+
+    #[test]
+    fn redacts_auth_exchange() {
+        let mut transcript = Transcript::new(10);
+        transcript.record_sent(b"AUTH LOGIN\r\n");
+        transcript.record_received(b"334 VXNlcm5hbWU6\r\n");
+        transcript.record_sent(b"dXNlcg==\r\n");
+        transcript.record_received(b"334 UGFzc3dvcmQ6\r\n");
+        transcript.record_sent(b"cGFzc3dvcmQ=\r\n");
+        transcript.record_received(b"235 Ok\r\n");
+        transcript.record_sent(b"NOOP\r\n");
+
+        assert_eq!(transcript.entries(), vec![
+            sent("AUTH LOGIN"),
+            received("334 VXNlcm5hbWU6"),
+            sent("[redacted]"),
+            received("334 UGFzc3dvcmQ6"),
+            sent("[redacted]"),
+            received("235 Ok"),
+            sent("NOOP")
+        ]);
+    }
+
+    #[test]
+    fn does_not_buffer_credentials() {
+        let mut transcript = Transcript::new(10);
+        transcript.record_sent(b"AU");
+        transcript.record_sent(b"TH PLAIN AHVzZX");
+        assert_eq!(transcript.partial_sent, b"AUTH PLAIN");
+        transcript.record_sent(b"IAcGFzc3dvcmQ=\r\n");
+        assert!(transcript.partial_sent.is_empty());
+
+        transcript.record_received(b"334 \r\n");
+        transcript.record_sent(b"cGFzc3");
+        assert!(transcript.partial_sent.is_empty());
+        transcript.record_sent(b"dvcmQ=\r\n");
+        transcript.record_received(b"235 Ok\r\n");
+
+        assert_eq!(transcript.entries(), vec![
+            sent("AUTH PLAIN [redacted]"),
+            received("334 "),
+            sent("[redacted]"),
+            received("235 Ok")
+        ]);
+    }
+}
@@ -20,11 +20,42 @@
 //! enabled a `send_mail` command is added `Connection` which combines the steps
 //! of sending the `MAIL` command, the `RCPT` command and the `DATA` command.
 //!
+//! ## `mail`
+//!
+//! Adds `MailEnvelop::from_message` (see the `message` module) which derives the
+//! envelop and encoding requirement from an already encoded mail message, e.g.
+//! one created with the `lettre` or `mail` crate, so that it can be sent with
+//! `send_mail`.
+//!
 //! ## `mock-support`, `mock-impl`
 //!
 //! Extend the `Socket` abstraction to include a mock socket additional to `Tcp`, `TcpTls`.
 //! Also provides a mock socket implementation for simply testing commands. Custom implementations
 //! can be provided too if needed for testing
+//!
+//! ## `auth-scram`
+//!
+//! Adds the `command::auth::Scram` command implementing `AUTH SCRAM-SHA-256`.
+//!
+//! ## `transcript`
+//!
+//! Adds `Connection::enable_transcript` which records the lines send and received
+//! through the connection (with credentials redacted), e.g. for debugging.
+//! It also adds `Connection::enable_error_log` which only keeps the last error
+//! responses.
+//!
+//! ## `resolver`
+//!
+//! Adds the `mx` module with `mx::resolve_mx` which returns the mail exchangers
+//! of a domain sorted by priority, e.g. for direct delivery. The dns lookups are
+//! done through the `mx::MxResolver` trait. Together with `send-mail` it also adds
+//! `send_mail::deliver_direct` which delivers a mail to the first mail exchanger
+//! accepting it.
+//!
+//! ## `zeroize`
+//!
+//! Zeros credential material (e.g. the password of `auth::Plain`) when it's
+//! dropped and zeros all data written to the socket once it was written.
 
 #[macro_use]
 extern crate futures;
@@ -34,15 +65,25 @@ extern crate tokio_tls;
 extern crate native_tls;
 extern crate base64;
 extern crate hostname;
-#[cfg(feature="mock-impl")]
+#[cfg(any(feature="mock-impl", feature="auth-scram"))]
 extern crate rand;
 #[cfg(feature="send-mail")]
 extern crate vec1;
+#[cfg(feature="zeroize")]
+extern crate zeroize;
+extern crate sha2;
+#[cfg(feature="auth-scram")]
+extern crate hmac;
+#[cfg(unix)]
+extern crate libc;
+#[cfg(all(test, not(any(target_os = "windows", target_vendor = "apple"))))]
+extern crate openssl;
 // order of modules is also "order" in dependency-tree
 // i.e. module should only import from modules hither
 // up in the list
 pub mod future_ext;
 mod ascii;
+pub mod util;
 mod data_types;
 #[macro_use]
 mod common;
@@ -51,18 +92,30 @@ pub mod error;
 pub mod io;
 mod connection;
 mod connect;
+pub mod retry;
+pub mod reconnect;
+pub mod pool;
+pub mod deadline;
 pub mod command;
+mod url;
+#[cfg(feature="resolver")]
+pub mod mx;
 pub mod chain;
 #[cfg(feature="mock-impl")]
 pub mod mock;
 #[cfg(feature="send-mail")]
 pub mod send_mail;
+#[cfg(feature="mail")]
+pub mod message;
+#[cfg(test)]
+mod stub_server;
 
 pub use self::data_types::*;
 pub use self::common::*;
-pub use self::response::Response;
+pub use self::response::{Response, Greeting};
 pub use self::io::Io;
 pub use self::connection::*;
 pub use self::connect::*;
+pub use self::url::*;
 
 
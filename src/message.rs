@@ -0,0 +1,596 @@
+//! [feature: `mail`] adapter for sending already encoded mail messages
+//!
+//! Mail creation crates (e.g. `lettre` with `Message::formatted` or `mail`
+//! with its encoding functionality) produce the complete RFC 5322 message,
+//! but sending it with `send_mail` also requires the smtp envelop and the
+//! `EncodingRequirement`. This module derives both from the message:
+//!
+//! - the reverse path is the address from the `Sender` header or,
+//!   if there is none, the (first) address of the `From` header
+//! - the recipients are all addresses of the `To`, `Cc` and `Bcc` headers,
+//!   the `Bcc` header itself is removed from the send message
+//! - the address lists are parsed following RFC 5322, if any address in
+//!   them is invalid `MessageError::InvalidAddress` is returned
+//! - non us-ascii data in the header requires `SMTPUTF8`, non us-ascii
+//!   data only in the body requires `8BITMIME`
+//!
+//! # Example
+//!
+//! ```no_run
+//! # extern crate futures;
+//! # extern crate new_tokio_smtp;
+//! # use futures::Future;
+//! # use new_tokio_smtp::Connection;
+//! use new_tokio_smtp::send_mail::MailEnvelop;
+//!
+//! # let con: Connection = unimplemented!();
+//! // e.g. the output of lettre's `Message::formatted`
+//! let message = concat!(
+//!     "From: Sender <sender@test.test>\r\n",
+//!     "To: receiver@test.test\r\n",
+//!     "Subject: Hy\r\n",
+//!     "\r\n",
+//!     "...\r\n"
+//! );
+//!
+//! let envelop = MailEnvelop::from_message(message).unwrap();
+//! let fut = con.send_mail(envelop);
+//! # let _ = fut;
+//! ```
+use std::error::Error;
+use std::fmt::{self, Display};
+
+use bytes::Bytes;
+use vec1::Vec1;
+
+use ::send_mail::{EncodingRequirement, Mail, MailAddress, MailEnvelop, header_section_len};
+
+/// Error returned if the envelop can not be derived from a message
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MessageError {
+    /// the header section is not terminated by an empty line
+    MissingHeaderEnd,
+    /// neither a `Sender` nor a `From` header with a mail address exist
+    NoSender,
+    /// no `To`, `Cc` or `Bcc` header with a mail address exist
+    NoRecipients,
+    /// a `Sender`, `From`, `To`, `Cc` or `Bcc` header contains a invalid address,
+    /// the body of the header is included
+    InvalidAddress(String)
+}
+
+impl Error for MessageError {
+    fn description(&self) -> &str {
+        match *self {
+            MessageError::MissingHeaderEnd => "message has no end of header section",
+            MessageError::NoSender => "message has no sender",
+            MessageError::NoRecipients => "message has no recipients",
+            MessageError::InvalidAddress(_) => "message has invalid address(es)"
+        }
+    }
+}
+
+impl Display for MessageError {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MessageError::MissingHeaderEnd => fter.write_str("message has no end of header section"),
+            MessageError::NoSender => fter.write_str("message has no sender"),
+            MessageError::NoRecipients => fter.write_str("message has no recipients"),
+            MessageError::InvalidAddress(ref body) =>
+                write!(fter, "message has invalid address(es) in header: {}", body)
+        }
+    }
+}
+
+impl MailEnvelop {
+
+    /// creates a envelop from a complete (encoded) RFC 5322 message
+    ///
+    /// See the `message` module documentation for how the envelop
+    /// and the encoding requirement are derived from the message.
+    pub fn from_message(message: impl Into<Bytes>) -> Result<Self, MessageError> {
+        let message = message.into();
+        let header_len = header_section_len(&message)
+            .ok_or(MessageError::MissingHeaderEnd)?;
+
+        let mut sender = None;
+        let mut from = None;
+        let mut recipients: Vec<MailAddress> = Vec::new();
+        let mut bcc_ranges = Vec::new();
+
+        for field in HeaderFields::new(&message[..header_len]) {
+            let name = field.name();
+            let is_sender = name.eq_ignore_ascii_case("Sender");
+            let is_from = name.eq_ignore_ascii_case("From");
+            let is_recipient = name.eq_ignore_ascii_case("To")
+                || name.eq_ignore_ascii_case("Cc")
+                || name.eq_ignore_ascii_case("Bcc");
+            if !(is_sender || is_from || is_recipient) {
+                continue;
+            }
+
+            let body = field.body();
+            let addresses = parse_address_list(&body)
+                .ok_or_else(|| MessageError::InvalidAddress(body.trim().to_owned()))?;
+
+            if is_sender {
+                sender = sender.or_else(|| addresses.into_iter().next());
+            } else if is_from {
+                from = from.or_else(|| addresses.into_iter().next());
+            } else {
+                for address in addresses {
+                    if !recipients.iter().any(|known| known.as_str() == address.as_str()) {
+                        recipients.push(address);
+                    }
+                }
+                if name.eq_ignore_ascii_case("Bcc") {
+                    bcc_ranges.push((field.start, field.end));
+                }
+            }
+        }
+
+        let from = sender.or(from).ok_or(MessageError::NoSender)?;
+        let mut recipients = recipients.into_iter();
+        let mut to = Vec1::new(recipients.next().ok_or(MessageError::NoRecipients)?);
+        to.extend(recipients);
+
+        let encoding_requirement = EncodingRequirement::detect(&message);
+
+        let message = if bcc_ranges.is_empty() {
+            message
+        } else {
+            Bytes::from(remove_ranges(&message, &bcc_ranges))
+        };
+
+        let mail = Mail::new(encoding_requirement, message);
+        Ok(MailEnvelop::new(from, to, mail))
+    }
+}
+
+fn remove_ranges(message: &[u8], ranges: &[(usize, usize)]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(message.len());
+    let mut last = 0;
+    for &(start, end) in ranges {
+        out.extend_from_slice(&message[last..start]);
+        last = end;
+    }
+    out.extend_from_slice(&message[last..]);
+    out
+}
+
+/// a (unfolded) header field and its position in the message
+struct HeaderField<'a> {
+    raw: &'a [u8],
+    start: usize,
+    end: usize
+}
+
+impl<'a> HeaderField<'a> {
+
+    fn name(&self) -> &'a str {
+        let raw = self.raw;
+        let end = raw.iter().position(|bch| *bch == b':').unwrap_or(raw.len());
+        ::std::str::from_utf8(&raw[..end]).unwrap_or("").trim()
+    }
+
+    fn body(&self) -> String {
+        let raw = self.raw;
+        let start = raw.iter().position(|bch| *bch == b':').map(|idx| idx + 1).unwrap_or(raw.len());
+        String::from_utf8_lossy(&raw[start..])
+            .replace("\r\n", "")
+            .replace('\n', "")
+    }
+}
+
+/// iterates over the header fields of a header section
+struct HeaderFields<'a> {
+    header: &'a [u8],
+    idx: usize
+}
+
+impl<'a> HeaderFields<'a> {
+    fn new(header: &'a [u8]) -> Self {
+        HeaderFields { header, idx: 0 }
+    }
+}
+
+impl<'a> Iterator for HeaderFields<'a> {
+    type Item = HeaderField<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let header = self.header;
+        let start = self.idx;
+        let mut end = start;
+        loop {
+            let line_end = match header[end..].iter().position(|bch| *bch == b'\n') {
+                Some(pos) => end + pos + 1,
+                None => header.len()
+            };
+            end = line_end;
+            // continuation lines start with white space (folding)
+            match header.get(end) {
+                Some(b' ') | Some(b'\t') => continue,
+                _ => break
+            }
+        }
+        self.idx = end;
+
+        let raw = &header[start..end];
+        if raw.iter().all(|bch| *bch == b'\r' || *bch == b'\n') {
+            None
+        } else {
+            Some(HeaderField { raw, start, end })
+        }
+    }
+}
+
+/// parses the mail addresses from a address list (e.g. the body of a `To` header)
+///
+/// This follows the `address-list` grammar of RFC 5322 (including RFC 6532
+/// non us-ascii text). Display names, comments and group syntax are skipped,
+/// quoted local parts and domain literals are kept as they are (both are valid
+/// in a smtp path). If any entry is not a valid address `None` is returned, so
+/// that a mangled address never reaches `RCPT TO`.
+fn parse_address_list(body: &str) -> Option<Vec<MailAddress>> {
+    let mut parser = AddressParser { tokens: tokenize(body)?, idx: 0 };
+    let mut addresses = Vec::new();
+    parser.parse_list(&mut addresses, false)?;
+    if parser.idx < parser.tokens.len() {
+        return None;
+    }
+    Some(addresses)
+}
+
+/// a lexical token of a structured header body, comments and white space are dropped
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// a atom (including non us-ascii text)
+    Atom(String),
+    /// a quoted string including the quotes and escapes
+    Quoted(String),
+    /// a domain literal including the brackets
+    DomainLiteral(String),
+    /// one of `<>:;@,.`
+    Special(char)
+}
+
+fn is_atext(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || !ch.is_ascii() || "!#$%&'*+-/=?^_`{|}~".contains(ch)
+}
+
+fn tokenize(body: &str) -> Option<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = body.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            ' ' | '\t' => (),
+            '(' => {
+                let mut depth = 1usize;
+                while depth > 0 {
+                    match chars.next()? {
+                        '\\' => { chars.next()?; },
+                        '(' => depth += 1,
+                        ')' => depth -= 1,
+                        _ => ()
+                    }
+                }
+            },
+            '"' => {
+                let mut quoted = String::from("\"");
+                loop {
+                    let ch = chars.next()?;
+                    quoted.push(ch);
+                    match ch {
+                        '\\' => quoted.push(chars.next()?),
+                        '"' => break,
+                        _ => ()
+                    }
+                }
+                tokens.push(Token::Quoted(quoted));
+            },
+            '[' => {
+                let mut literal = String::from("[");
+                loop {
+                    match chars.next()? {
+                        '[' | '\\' => return None,
+                        ' ' | '\t' => (),
+                        ']' => break,
+                        ch => literal.push(ch)
+                    }
+                }
+                literal.push(']');
+                tokens.push(Token::DomainLiteral(literal));
+            },
+            '<' | '>' | ':' | ';' | '@' | ',' | '.' => tokens.push(Token::Special(ch)),
+            ch if is_atext(ch) => {
+                let mut atom = ch.to_string();
+                while let Some(&ch) = chars.peek() {
+                    if !is_atext(ch) {
+                        break;
+                    }
+                    atom.push(ch);
+                    chars.next();
+                }
+                tokens.push(Token::Atom(atom));
+            },
+            _ => return None
+        }
+    }
+    Some(tokens)
+}
+
+struct AddressParser {
+    tokens: Vec<Token>,
+    idx: usize
+}
+
+impl AddressParser {
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.idx)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.idx).cloned();
+        self.idx += 1;
+        token
+    }
+
+    fn peek_special(&self, special: char) -> bool {
+        self.peek() == Some(&Token::Special(special))
+    }
+
+    fn expect_special(&mut self, special: char) -> Option<()> {
+        if self.peek_special(special) {
+            self.idx += 1;
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    /// parses `address *("," address)` allowing empty entries, stops at the end or
+    /// (if `in_group`) at a `";"`
+    fn parse_list(&mut self, addresses: &mut Vec<MailAddress>, in_group: bool) -> Option<()> {
+        loop {
+            match self.peek() {
+                None => return Some(()),
+                Some(&Token::Special(';')) if in_group => return Some(()),
+                Some(&Token::Special(',')) => { self.idx += 1; },
+                Some(_) => {
+                    self.parse_address(addresses, in_group)?;
+                    match self.peek() {
+                        None | Some(&Token::Special(',')) => (),
+                        Some(&Token::Special(';')) if in_group => (),
+                        Some(_) => return None
+                    }
+                }
+            }
+        }
+    }
+
+    /// parses a mailbox or (if not `in_group`) a group
+    fn parse_address(&mut self, addresses: &mut Vec<MailAddress>, in_group: bool) -> Option<()> {
+        let next_special = self.tokens[self.idx..].iter()
+            .filter_map(|token| match *token {
+                Token::Special(ch) if ch != '.' => Some(ch),
+                _ => None
+            })
+            .next();
+
+        match next_special {
+            Some(':') if !in_group => {
+                self.skip_phrase()?;
+                self.expect_special(':')?;
+                self.parse_list(addresses, true)?;
+                // be lenient about a missing group end at the end of the field
+                if self.peek().is_some() {
+                    self.expect_special(';')?;
+                }
+            },
+            Some('<') => {
+                self.skip_phrase_if_any();
+                self.expect_special('<')?;
+                addresses.push(self.parse_addr_spec()?);
+                self.expect_special('>')?;
+            },
+            _ => addresses.push(self.parse_addr_spec()?)
+        }
+        Some(())
+    }
+
+    /// skips a (non empty) display name, `"."` is allowed in it (obs-phrase)
+    fn skip_phrase(&mut self) -> Option<()> {
+        match self.peek() {
+            Some(&Token::Atom(_)) | Some(&Token::Quoted(_)) => (),
+            _ => return None
+        }
+        self.skip_phrase_if_any();
+        Some(())
+    }
+
+    fn skip_phrase_if_any(&mut self) {
+        while let Some(token) = self.peek() {
+            match *token {
+                Token::Atom(_) | Token::Quoted(_) | Token::Special('.') => self.idx += 1,
+                _ => break
+            }
+        }
+    }
+
+    /// parses `local-part "@" domain`
+    fn parse_addr_spec(&mut self) -> Option<MailAddress> {
+        let mut raw = String::new();
+        loop {
+            match self.next()? {
+                Token::Atom(word) | Token::Quoted(word) => raw.push_str(&word),
+                _ => return None
+            }
+            if self.expect_special('.').is_none() {
+                break;
+            }
+            raw.push('.');
+        }
+
+        self.expect_special('@')?;
+        raw.push('@');
+
+        if let Some(Token::DomainLiteral(literal)) = self.peek() {
+            raw.push_str(literal);
+            self.idx += 1;
+            return Some(MailAddress::from_unchecked(raw));
+        }
+        loop {
+            match self.next()? {
+                Token::Atom(label) => raw.push_str(&label),
+                _ => return None
+            }
+            if self.expect_special('.').is_none() {
+                break;
+            }
+            raw.push('.');
+        }
+
+        Some(MailAddress::from_unchecked(raw))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn addresses(body: &str) -> Vec<String> {
+        parse_address_list(body)
+            .unwrap_or_else(|| panic!("failed to parse: {:?}", body))
+            .into_iter()
+            .map(Into::into)
+            .collect()
+    }
+
+    #[test]
+    fn parses_address_lists() {
+        assert_eq!(
+            addresses(r#" "Doe, John" <j.doe@test.test>, x@test.test (X), Group: a@test.test, <b@test.test>;"#),
+            vec!["j.doe@test.test", "x@test.test", "a@test.test", "b@test.test"]
+        );
+        assert_eq!(addresses(" undisclosed-recipients:;"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn keeps_quoted_local_parts() {
+        assert_eq!(addresses(r#""john doe"@example.com"#), vec![r#""john doe"@example.com"#]);
+        assert_eq!(addresses(r#"<"john doe"@example.com>"#), vec![r#""john doe"@example.com"#]);
+        assert_eq!(
+            addresses(r#""Doe" <"john \"jd\" doe"@example.com>, a."b,c"@example.com"#),
+            vec![r#""john \"jd\" doe"@example.com"#, r#"a."b,c"@example.com"#]
+        );
+    }
+
+    #[test]
+    fn parses_groups() {
+        assert_eq!(
+            addresses("Team: a@test.test, B <b@test.test>;, c@test.test, Empty:;, Last: d@test.test"),
+            vec!["a@test.test", "b@test.test", "c@test.test", "d@test.test"]
+        );
+        assert_eq!(addresses(r#""Team, A": a@test.test;"#), vec!["a@test.test"]);
+    }
+
+    #[test]
+    fn skips_comments() {
+        assert_eq!(
+            addresses("(a (nested) comment) a@test.test (x), b(c)@(d)test.test, C (e) <c@[127.0.0.1]>"),
+            vec!["a@test.test", "b@test.test", "c@[127.0.0.1]"]
+        );
+        assert_eq!(addresses(r#"a@test.test (with \) escaped)"#), vec!["a@test.test"]);
+    }
+
+    #[test]
+    fn rejects_invalid_addresses() {
+        for body in &[
+            "john doe@example.com",
+            "@example.com",
+            "a@",
+            "a@test.test b@test.test",
+            "<a@test.test",
+            r#""unterminated@test.test"#,
+            "a@test.test (unterminated",
+            "Group: a@test.test, Inner: b@test.test;;",
+            "a@test..test",
+            "a\\b@test.test",
+            "local-only"
+        ] {
+            assert!(parse_address_list(body).is_none(), "{:?}", body);
+        }
+    }
+
+    #[test]
+    fn fails_for_invalid_recipient() {
+        let message = "From: a@test.test\r\nTo: \"john doe\"@example.com, john doe@example.com\r\n\r\n";
+        assert_eq!(
+            MailEnvelop::from_message(message).unwrap_err(),
+            MessageError::InvalidAddress(r#""john doe"@example.com, john doe@example.com"#.to_owned())
+        );
+    }
+
+    #[test]
+    fn derives_envelop_and_removes_bcc() {
+        let message = concat!(
+            "From: Sender <sender@test.test>\r\n",
+            "To: r1@test.test,\r\n",
+            "  r2@test.test\r\n",
+            "Bcc: hidden@test.test\r\n",
+            "Subject: Hy\r\n",
+            "\r\n",
+            "Bcc: not a header\r\n"
+        );
+
+        let envelop = MailEnvelop::from_message(message).unwrap();
+
+        assert_eq!(envelop.from_address().unwrap().as_str(), "sender@test.test");
+        let to: Vec<&str> = envelop.to_address().iter().map(|addr| addr.as_str()).collect();
+        assert_eq!(to, vec!["r1@test.test", "r2@test.test", "hidden@test.test"]);
+        assert_eq!(envelop.mail().encoding_requirement(), EncodingRequirement::None);
+        assert_eq!(envelop.mail().raw_data(), concat!(
+            "From: Sender <sender@test.test>\r\n",
+            "To: r1@test.test,\r\n",
+            "  r2@test.test\r\n",
+            "Subject: Hy\r\n",
+            "\r\n",
+            "Bcc: not a header\r\n"
+        ).as_bytes());
+    }
+
+    #[test]
+    fn sender_header_is_preferred() {
+        let message = "From: a@test.test\r\nSender: b@test.test\r\nTo: c@test.test\r\n\r\n";
+        let envelop = MailEnvelop::from_message(message).unwrap();
+        assert_eq!(envelop.from_address().unwrap().as_str(), "b@test.test");
+    }
+
+    #[test]
+    fn picks_encoding_requirement_from_content() {
+        let message = "From: a@test.test\r\nTo: c@test.test\r\n\r\nGrüße\r\n";
+        let envelop = MailEnvelop::from_message(message).unwrap();
+        assert_eq!(envelop.mail().encoding_requirement(), EncodingRequirement::Mime8bit);
+
+        let message = "From: a@test.test\r\nTo: c@test.test\r\nSubject: Grüße\r\n\r\n.\r\n";
+        let envelop = MailEnvelop::from_message(message).unwrap();
+        assert_eq!(envelop.mail().encoding_requirement(), EncodingRequirement::Smtputf8);
+    }
+
+    #[test]
+    fn fails_without_sender_or_recipients() {
+        assert_eq!(
+            MailEnvelop::from_message("To: c@test.test\r\n\r\n").unwrap_err(),
+            MessageError::NoSender
+        );
+        assert_eq!(
+            MailEnvelop::from_message("From: a@test.test\r\n\r\n").unwrap_err(),
+            MessageError::NoRecipients
+        );
+        assert_eq!(
+            MailEnvelop::from_message("From: a@test.test\r\n").unwrap_err(),
+            MessageError::MissingHeaderEnd
+        );
+    }
+}
@@ -1,7 +1,7 @@
 //! provides a `MockStream` implementations
 use std::io::{self as std_io, Read, Write};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::mem;
 use std::cmp::min;
 
@@ -139,7 +139,9 @@ pub struct MockSocket {
     conversation: Vec<(Actor, ActionData)>,
     fake_secure: bool,
     state: State,
-    check_shutdown: bool
+    check_shutdown: bool,
+    reply_delay: Option<Duration>,
+    reply_not_before: Option<Instant>
 }
 
 /// MockSocket going through a pre-coded interlocked client-server conversation
@@ -180,6 +182,8 @@ impl MockSocket {
             conversation,
             check_shutdown,
             fake_secure: false,
+            reply_delay: None,
+            reply_not_before: None,
             state: State::NeedNewAction {
                 buffer: BytesMut::new(),
                 waker: delayed_waker()
@@ -187,6 +191,14 @@ impl MockSocket {
         }
     }
 
+    /// delays each `Server` action by (at least) given duration
+    ///
+    /// The delay starts once the client starts reading the action, this
+    /// can be used to emulate a slow server.
+    pub fn set_reply_delay(&mut self, delay: Option<Duration>) {
+        self.reply_delay = delay;
+    }
+
     /// sets the state to `ShutdownOrPoison` and clears the conversation
     pub fn clear(&mut self) {
         self.conversation.clear();
@@ -215,6 +227,12 @@ impl MockSocket {
         }
     }
 
+    fn is_reply_delayed(&self) -> bool {
+        self.reply_not_before
+            .map(|not_before| Instant::now() < not_before)
+            .unwrap_or(false)
+    }
+
     /// creates the next state for given `waker` and `buffer`
     ///
     /// pop's the next action in the conversation if it's
@@ -237,6 +255,7 @@ impl MockSocket {
 
         match actor {
             Actor::Server => {
+                self.reply_not_before = self.reply_delay.map(|delay| Instant::now() + delay);
                 // 1. data into() buffer
                 assert!(buffer.is_empty(), "buffer had remaining input: {:?}",
                    String::from_utf8_lossy(buffer.as_ref()));
@@ -313,6 +332,23 @@ impl MockStream for MockSocket {
     fn set_is_secure(&mut self, secure: bool) {
         self.fake_secure = secure;
     }
+
+    /// marks the socket as shutdown if there is no pending action
+    fn shutdown_now(&mut self) -> Result<(), std_io::Error> {
+        let can_shutdown =
+            match self.state {
+                State::ShutdownOrPoison => true,
+                State::NeedNewAction { ref buffer, .. } => buffer.is_empty(),
+                _ => false
+            };
+
+        if can_shutdown {
+            self.state = State::ShutdownOrPoison;
+            Ok(())
+        } else {
+            Err(std_io::Error::new(std_io::ErrorKind::Other, "shutdown while in action"))
+        }
+    }
 }
 
 macro_rules! try_ready_or_would_block {
@@ -419,6 +455,11 @@ impl AsyncRead for MockSocket {
                 self.schedule_delayed_wake();
                 Ok(Async::NotReady)
             }
+            State::ServerIsWorking { waker, to_be_read } if self.is_reply_delayed() => {
+                self.state = State::ServerIsWorking { waker, to_be_read };
+                self.schedule_delayed_wake();
+                Ok(Async::NotReady)
+            },
             State::ServerIsWorking { waker, mut to_be_read } => {
                 let rem = to_be_read.len();
                 let can_write = buf.len();
@@ -0,0 +1,196 @@
+//! Provides `resolve_mx` to find the mail exchangers of a domain
+//!
+//! This is used for direct delivery (i.e. MTA to MTA), the returned
+//! hosts can be connected to e.g. with `ConnectionConfig::builder`.
+//!
+//! As this crate doesn't depend on a dns resolver the lookups are
+//! done through the `MxResolver` trait, which has to be implemented
+//! on top of the resolver used by the application.
+use std::{io as std_io};
+use std::fmt::Debug;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use futures::future::{self, Future, Either};
+
+use ::data_types::Domain;
+use ::util::random::random_u64;
+
+/// The future returned by the lookups of a `MxResolver`
+pub type LookupFuture<T> = Box<Future<Item=T, Error=std_io::Error> + Send>;
+
+/// A dns resolver used by `resolve_mx`
+pub trait MxResolver: Debug + Send + Sync + 'static {
+    /// returns the `MX` records of `domain` as `(preference, exchange)`
+    ///
+    /// If the domain exists but has no `MX` records an empty `Vec` has to
+    /// be returned (instead of failing), so that the implicit MX fallback
+    /// can be used.
+    fn lookup_mx(&self, domain: &Domain) -> LookupFuture<Vec<(u16, Domain)>>;
+
+    /// returns the addresses of the `A`/`AAAA` records of `domain`
+    ///
+    /// This is only called if there are no `MX` records.
+    fn lookup_ip(&self, domain: &Domain) -> LookupFuture<Vec<IpAddr>>;
+}
+
+/// returns the mail exchangers of `domain` sorted by priority (lowest preference first)
+///
+/// Mail exchangers with the same preference are in a random order, as
+/// required by RFC 5321 (section 5.1) to distribute the load.
+///
+/// If the domain has no `MX` records but has an `A` or `AAAA` record the
+/// domain itself is returned with a preference of `0` (the implicit MX).
+/// An empty `Vec` is returned if the domain doesn't accept mail, i.e. if
+/// it has neither or if it has a null MX record (RFC 7505).
+pub fn resolve_mx<R>(resolver: Arc<R>, domain: Domain)
+    -> impl Future<Item=Vec<(u16, Domain)>, Error=std_io::Error> + Send
+    where R: MxResolver + ?Sized
+{
+    resolver.lookup_mx(&domain)
+        .and_then(move |records| {
+            if records.is_empty() {
+                let fut = resolver.lookup_ip(&domain).map(move |addrs| {
+                    if addrs.is_empty() { Vec::new() } else { vec![(0, domain)] }
+                });
+                return Either::A(fut);
+            }
+
+            if is_null_mx(&records) {
+                return Either::B(future::ok(Vec::new()));
+            }
+
+            Either::B(future::ok(sort_by_priority(records)))
+        })
+}
+
+/// true if the records are a null MX record, i.e. a single record with the exchange `"."`
+fn is_null_mx(records: &[(u16, Domain)]) -> bool {
+    records.len() == 1 && {
+        let host = records[0].1.as_str();
+        host == "." || host.is_empty()
+    }
+}
+
+/// sorts the records by their preference, randomizing the order of records with the same preference
+fn sort_by_priority(records: Vec<(u16, Domain)>) -> Vec<(u16, Domain)> {
+    let mut keyed = records.into_iter()
+        .map(|record| ((record.0, random_u64()), record))
+        .collect::<Vec<_>>();
+
+    keyed.sort_by_key(|&(key, _)| key);
+    keyed.into_iter().map(|(_, record)| record).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use futures::future;
+
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct StubResolver {
+        mx: HashMap<&'static str, Vec<(u16, &'static str)>>,
+        ip: HashMap<&'static str, Vec<IpAddr>>,
+        ip_lookups: AtomicUsize
+    }
+
+    impl MxResolver for StubResolver {
+        fn lookup_mx(&self, domain: &Domain) -> LookupFuture<Vec<(u16, Domain)>> {
+            let records = self.mx.get(domain.as_str())
+                .map(|records| records.iter()
+                    .map(|&(pref, host)| (pref, Domain::from_unchecked(host)))
+                    .collect())
+                .unwrap_or_default();
+            Box::new(future::ok(records))
+        }
+
+        fn lookup_ip(&self, domain: &Domain) -> LookupFuture<Vec<IpAddr>> {
+            self.ip_lookups.fetch_add(1, Ordering::SeqCst);
+            let addrs = self.ip.get(domain.as_str()).cloned().unwrap_or_default();
+            Box::new(future::ok(addrs))
+        }
+    }
+
+    fn resolve(resolver: &Arc<StubResolver>, domain: &str) -> Vec<(u16, String)> {
+        resolve_mx(resolver.clone(), Domain::from_unchecked(domain))
+            .wait()
+            .unwrap()
+            .into_iter()
+            .map(|(pref, host)| (pref, host.as_str().to_owned()))
+            .collect()
+    }
+
+    fn localhost() -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))
+    }
+
+    #[test]
+    fn mx_records_are_sorted_by_priority() {
+        let mut resolver = StubResolver::default();
+        resolver.mx.insert("example.com", vec![
+            (20, "mx3.example.com"), (5, "mx1.example.com"), (10, "mx2.example.com")
+        ]);
+        resolver.ip.insert("example.com", vec![localhost()]);
+        let resolver = Arc::new(resolver);
+
+        assert_eq!(resolve(&resolver, "example.com"), vec![
+            (5, "mx1.example.com".to_owned()),
+            (10, "mx2.example.com".to_owned()),
+            (20, "mx3.example.com".to_owned())
+        ]);
+        assert_eq!(resolver.ip_lookups.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn ties_are_in_random_order() {
+        let mut resolver = StubResolver::default();
+        resolver.mx.insert("example.com", vec![
+            (10, "a.example.com"), (10, "b.example.com"), (20, "backup.example.com")
+        ]);
+        let resolver = Arc::new(resolver);
+
+        let mut seen_a_first = false;
+        let mut seen_b_first = false;
+        for _ in 0..100 {
+            let hosts = resolve(&resolver, "example.com");
+            assert_eq!(hosts.len(), 3);
+            assert_eq!(hosts[2], (20, "backup.example.com".to_owned()));
+            match hosts[0].1.as_str() {
+                "a.example.com" => seen_a_first = true,
+                "b.example.com" => seen_b_first = true,
+                other => panic!("unexpected first host: {}", other)
+            }
+        }
+        assert!(seen_a_first && seen_b_first);
+    }
+
+    #[test]
+    fn falls_back_to_implicit_mx() {
+        let mut resolver = StubResolver::default();
+        resolver.ip.insert("example.com", vec![localhost()]);
+        let resolver = Arc::new(resolver);
+
+        assert_eq!(resolve(&resolver, "example.com"), vec![(0, "example.com".to_owned())]);
+        assert_eq!(resolver.ip_lookups.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn no_mx_and_no_address_means_no_mail_host() {
+        let resolver = Arc::new(StubResolver::default());
+        assert_eq!(resolve(&resolver, "example.com"), vec![]);
+    }
+
+    #[test]
+    fn null_mx_means_no_mail_host() {
+        let mut resolver = StubResolver::default();
+        resolver.mx.insert("example.com", vec![(0, ".")]);
+        resolver.ip.insert("example.com", vec![localhost()]);
+        let resolver = Arc::new(resolver);
+
+        assert_eq!(resolve(&resolver, "example.com"), vec![]);
+    }
+}
@@ -0,0 +1,389 @@
+//! Provides a simple `Pool` of (already open) connections with fair acquisition
+//!
+//! With `Pool::acquire` the pool does not open connections itself,
+//! connections are added through `Pool::release` (e.g. after creating
+//! them with `Connection::connect`). Tasks waiting for a connection are
+//! served in the order in which they called `acquire`.
+//!
+//! Alternatively connections can be acquired for a `ConnectionConfig` using
+//! `Pool::acquire_for`, which opens a new connection with the given config
+//! if there is no idle one for it. Connections are kept apart by a `PoolKey`
+//! derived from the config, this allows using one pool for multiple
+//! destinations.
+use std::{io as std_io};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt::{self, Debug, Display};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::{future, Async, Future, Poll};
+use futures::future::Either;
+use futures::sync::oneshot;
+use tokio::clock;
+use tokio::timer::Delay;
+
+use ::connection::{Connection, Cmd};
+use ::connect::{ConnectionConfig, PlaintextFallback, Security};
+use ::error::ConnectingFailed;
+use ::common::{ClientId, SetupTls, TlsConfig};
+use ::data_types::Domain;
+
+/// A pool of connections shared by multiple tasks
+///
+/// Cloning the pool creates a new handle to the same pool.
+#[derive(Debug, Clone, Default)]
+pub struct Pool {
+    inner: Arc<Mutex<PoolState>>
+}
+
+#[derive(Debug, Default)]
+struct PoolState {
+    idle: VecDeque<Connection>,
+    waiters: VecDeque<oneshot::Sender<Connection>>,
+    idle_by_key: HashMap<PoolKey, VecDeque<Connection>>
+}
+
+impl Pool {
+
+    /// creates a pool without any connections
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// creates a pool containing given (idle) connections
+    pub fn with_connections<I>(connections: I) -> Self
+        where I: IntoIterator<Item=Connection>
+    {
+        let pool = Pool::new();
+        for con in connections {
+            pool.release(con);
+        }
+        pool
+    }
+
+    /// acquire a connection from the pool
+    ///
+    /// If no connection is idle the returned future waits until one is
+    /// released. Waiting futures are served in FIFO order (in the order in
+    /// which `acquire` was called), an acquired connection has to be put
+    /// back using `release`.
+    pub fn acquire(&self) -> Acquire {
+        self.acquire_with_deadline(None)
+    }
+
+    /// like `acquire` but fails with `PoolError::Timeout` if no connection is acquired in time
+    ///
+    /// This requires a running tokio timer (e.g. a tokio runtime).
+    pub fn acquire_timeout(&self, timeout: Duration) -> Acquire {
+        self.acquire_with_deadline(Some(Delay::new(clock::now() + timeout)))
+    }
+
+    fn acquire_with_deadline(&self, deadline: Option<Delay>) -> Acquire {
+        let mut state = self.lock();
+        let waiting =
+            if state.waiters.is_empty() {
+                state.idle.pop_front().map(Ok)
+            } else {
+                None
+            };
+
+        let waiting = waiting.unwrap_or_else(|| {
+            let (tx, rx) = oneshot::channel();
+            state.waiters.push_back(tx);
+            Err(rx)
+        });
+
+        Acquire { pool: self.clone(), waiting: Some(waiting), deadline }
+    }
+
+    /// puts a connection (back) into the pool
+    ///
+    /// It's directly handed to the longest waiting `acquire` future, if there is any.
+    pub fn release(&self, con: Connection) {
+        let mut state = self.lock();
+        let mut con = con;
+        while let Some(waiter) = state.waiters.pop_front() {
+            match waiter.send(con) {
+                Ok(()) => return,
+                // the waiter was dropped (e.g. it timed out)
+                Err(returned) => con = returned
+            }
+        }
+        state.idle.push_back(con);
+    }
+
+    /// sends `NOOP` through all idle connections which need it (see `Connection::keep_alive`)
+    ///
+    /// Only connections which were idle for at least `interval` are taken out
+    /// of the pool and pinged, the others are not touched. Pinged connections
+    /// are put back using `release`, except if the `NOOP` failed in which
+    /// case they are dropped. Resolves to the number of pinged connections.
+    ///
+    /// This does not include connections released through `release_for`.
+    pub fn keep_alive(&self, interval: Duration) -> impl Future<Item=usize, Error=()> + Send {
+        let stale = {
+            let mut state = self.lock();
+            let (stale, fresh): (VecDeque<_>, VecDeque<_>) = state.idle.drain(..)
+                .partition(|con| con.needs_keep_alive(interval));
+            state.idle = fresh;
+            stale
+        };
+
+        let count = stale.len();
+        let pool = self.clone();
+        let pings = stale.into_iter().map(move |con| {
+            let pool = pool.clone();
+            con.keep_alive(interval).then(move |result| {
+                if let Ok((con, Some(Ok(_)))) = result {
+                    pool.release(con);
+                }
+                Ok(())
+            })
+        });
+        future::join_all(pings).map(move |_| count)
+    }
+
+    /// acquire a connection for given config, opening a new one using `config` if there is no idle one
+    ///
+    /// Only connections released for a config with the same `PoolKey` are
+    /// returned. Connections acquired this way have to be put back using
+    /// `release_for` (with the same config), they are not shared with
+    /// `acquire`.
+    pub fn acquire_for<A, S>(&self, config: &ConnectionConfig<A, S>)
+        -> impl Future<Item=Connection, Error=ConnectingFailed> + Send
+        where A: Cmd + Clone + Send, S: SetupTls + Clone
+    {
+        let key = PoolKey::from_config(config);
+        let idle = self.lock().idle_by_key
+            .get_mut(&key)
+            .and_then(|idle| idle.pop_front());
+
+        match idle {
+            Some(con) => Either::A(future::ok(con)),
+            None => Either::B(Connection::connect(config.clone()))
+        }
+    }
+
+    /// puts a connection acquired through `acquire_for` (back) into the pool
+    ///
+    /// `config` has to be the config the connection was acquired with.
+    pub fn release_for<A, S>(&self, config: &ConnectionConfig<A, S>, con: Connection)
+        where A: Cmd, S: SetupTls
+    {
+        self.lock().idle_by_key
+            .entry(PoolKey::from_config(config))
+            .or_default()
+            .push_back(con);
+    }
+
+    /// the number of idle connections
+    ///
+    /// This does not include connections released through `release_for`.
+    pub fn idle_count(&self) -> usize {
+        self.lock().idle.len()
+    }
+
+    /// the number of idle connections for given config
+    pub fn idle_count_for<A, S>(&self, config: &ConnectionConfig<A, S>) -> usize
+        where A: Cmd, S: SetupTls
+    {
+        self.lock().idle_by_key
+            .get(&PoolKey::from_config(config))
+            .map(|idle| idle.len())
+            .unwrap_or(0)
+    }
+
+    /// the number of `acquire` futures waiting for a connection
+    ///
+    /// This can include futures which were dropped (e.g. timed out)
+    /// but not yet skipped by `release`.
+    pub fn waiting_count(&self) -> usize {
+        self.lock().waiters.len()
+    }
+
+    fn lock(&self) -> ::std::sync::MutexGuard<'_, PoolState> {
+        self.inner.lock().expect("[BUG] pool mutex poisoned")
+    }
+}
+
+/// The key by which `Pool::acquire_for` tells connections for different configs apart
+///
+/// It is derived from the `ConnectionConfig` and consists of the address
+/// of the server, the security mode (including the TLS domains), the
+/// client id and the identity the auth command authenticates as (see
+/// `Cmd::auth_identity`). The `SetupTls` instance can't be compared, so
+/// configs only differing in it share connections.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PoolKey {
+    addr: SocketAddr,
+    security: SecurityKey,
+    client_id: ClientId,
+    auth_identity: Option<String>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum SecurityKey {
+    None,
+    DirectTls(TlsKey),
+    StartTls(TlsKey),
+    Opportunistic(TlsKey, PlaintextFallback)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct TlsKey {
+    domain: Domain,
+    sni_domain: Domain
+}
+
+impl TlsKey {
+    fn new<S>(config: &TlsConfig<S>) -> Self
+        where S: SetupTls
+    {
+        TlsKey {
+            domain: config.domain.clone(),
+            sni_domain: config.get_sni_domain().clone()
+        }
+    }
+}
+
+impl PoolKey {
+
+    /// creates the key for connections opened with given config
+    pub fn from_config<A, S>(config: &ConnectionConfig<A, S>) -> Self
+        where A: Cmd, S: SetupTls
+    {
+        #[allow(deprecated)]
+        let security = match config.security {
+            Security::None => SecurityKey::None,
+            Security::DirectTls(ref tls) => SecurityKey::DirectTls(TlsKey::new(tls)),
+            Security::StartTls(ref tls) => SecurityKey::StartTls(TlsKey::new(tls)),
+            Security::Opportunistic(ref tls, fallback) =>
+                SecurityKey::Opportunistic(TlsKey::new(tls), fallback)
+        };
+
+        PoolKey {
+            addr: config.addr,
+            security,
+            client_id: config.client_id.clone(),
+            auth_identity: config.auth_cmd.auth_identity()
+        }
+    }
+
+    /// the address of the server
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// the client id used for `EHLO`
+    pub fn client_id(&self) -> &ClientId {
+        &self.client_id
+    }
+
+    /// the identity the connection is authenticated as, see `Cmd::auth_identity`
+    pub fn auth_identity(&self) -> Option<&str> {
+        self.auth_identity.as_deref()
+    }
+}
+
+/// Future returned by `Pool::acquire`
+#[derive(Debug)]
+pub struct Acquire {
+    pool: Pool,
+    /// `Ok` if a idle connection was available, `None` after completion
+    waiting: Option<Result<Connection, oneshot::Receiver<Connection>>>,
+    deadline: Option<Delay>
+}
+
+impl Acquire {
+
+    /// stops waiting, returning a connection if one was send in between
+    fn cancel(&mut self) -> Option<Connection> {
+        match self.waiting.take() {
+            Some(Ok(con)) => Some(con),
+            Some(Err(mut rx)) => {
+                rx.close();
+                rx.try_recv().ok().and_then(|opt_con| opt_con)
+            },
+            None => None
+        }
+    }
+}
+
+impl Future for Acquire {
+    type Item = Connection;
+    type Error = PoolError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.waiting.take() {
+            Some(Ok(con)) => return Ok(Async::Ready(con)),
+            Some(Err(mut rx)) => match rx.poll() {
+                Ok(Async::Ready(con)) => return Ok(Async::Ready(con)),
+                Ok(Async::NotReady) => self.waiting = Some(Err(rx)),
+                Err(_) => unreachable!("[BUG] pool dropped a waiting sender")
+            },
+            None => panic!("poll after completion")
+        }
+
+        let timed_out = match self.deadline {
+            Some(ref mut deadline) => deadline.poll()
+                .map_err(|err| PoolError::Timer(std_io::Error::new(std_io::ErrorKind::Other, err)))?
+                .is_ready(),
+            None => false
+        };
+
+        if timed_out {
+            match self.cancel() {
+                // released just before the timeout
+                Some(con) => Ok(Async::Ready(con)),
+                None => Err(PoolError::Timeout)
+            }
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+impl Drop for Acquire {
+    fn drop(&mut self) {
+        // don't lose a connection which was handed to this future but not yet received
+        if let Some(con) = self.cancel() {
+            self.pool.release(con);
+        }
+    }
+}
+
+/// error returned by `Acquire`
+#[derive(Debug)]
+pub enum PoolError {
+    /// no connection was acquired before the timeout (see `Pool::acquire_timeout`)
+    Timeout,
+    /// the timer failed, e.g. because there is no running tokio timer
+    Timer(std_io::Error)
+}
+
+impl Error for PoolError {
+    fn description(&self) -> &str {
+        match *self {
+            PoolError::Timeout => "acquiring a connection from the pool timed out",
+            PoolError::Timer(_) => "the timer used for the acquire timeout failed"
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            PoolError::Timer(ref err) => Some(err),
+            PoolError::Timeout => None
+        }
+    }
+}
+
+impl Display for PoolError {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PoolError::Timeout => write!(fter, "acquiring a connection from the pool timed out"),
+            PoolError::Timer(ref err) => write!(fter, "acquire timeout failed: {}", err)
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! Provides `ReconnectableConnection`, a connection which keeps the config it was created with
+//!
+//! This allows a (e.g. broken) connection to be replaced by a new one
+//! without having to pass the config to all places which might need to
+//! do so.
+use std::{io as std_io};
+
+use futures::future::{self, Future, Either};
+
+use ::error::ConnectingFailed;
+use ::common::{SetupTls, DefaultTlsSetup};
+use ::connection::{Connection, Cmd};
+use ::connect::ConnectionConfig;
+
+/// A connection bundled with the `ConnectionConfig` used to create it
+///
+/// `reconnect` re-runs the whole connection setup (including the
+/// tls handshake and the authentication) with a clone of the config.
+/// For this the auth command has to implement `Clone`, which all
+/// auth commands provided by this crate do.
+///
+/// Note that the config (and with it the credentials in the auth
+/// command) is kept in memory as long as this instance exists.
+#[derive(Debug)]
+pub struct ReconnectableConnection<A, S = DefaultTlsSetup>
+    where S: SetupTls + Clone, A: Cmd + Clone
+{
+    config: ConnectionConfig<A, S>,
+    connection: Option<Connection>
+}
+
+impl<A, S> ReconnectableConnection<A, S>
+    where S: SetupTls + Clone, A: Cmd + Clone + Send
+{
+
+    /// open a connection using a clone of given config and keeps the config
+    pub fn connect(config: ConnectionConfig<A, S>)
+        -> impl Future<Item=Self, Error=ConnectingFailed> + Send
+    {
+        Connection::connect(config.clone())
+            .map(move |con| ReconnectableConnection::new(config, Some(con)))
+    }
+
+    /// create a instance from a config and a connection created with it (if there is one)
+    ///
+    /// If `connection` is `None` the first use of it has to be `reconnect`.
+    pub fn new(config: ConnectionConfig<A, S>, connection: Option<Connection>) -> Self {
+        ReconnectableConnection { config, connection }
+    }
+
+    /// drops the current connection (if any) and returns a future resolving to a new one
+    ///
+    /// The old connection is dropped without sending `QUIT` (see the
+    /// `Drop` impl. of `Connection`). The returned future does not borrow
+    /// this instance, the new connection can be put into it using
+    /// `set_connection`. If connecting fails `reconnect` can just be
+    /// called again, e.g. by using `retry::retry_with_backoff`.
+    pub fn reconnect(&mut self) -> impl Future<Item=Connection, Error=ConnectingFailed> + Send {
+        self.connection = None;
+        Connection::connect(self.config.clone())
+    }
+
+    /// returns the connection, `None` if it was taken or connecting failed
+    pub fn connection(&self) -> Option<&Connection> {
+        self.connection.as_ref()
+    }
+
+    /// returns a mutable reference to the connection (see `connection`)
+    pub fn connection_mut(&mut self) -> Option<&mut Connection> {
+        self.connection.as_mut()
+    }
+
+    /// takes the connection out of this instance, e.g. to send a command with it
+    pub fn take_connection(&mut self) -> Option<Connection> {
+        self.connection.take()
+    }
+
+    /// puts a connection (back) into this instance
+    pub fn set_connection(&mut self, connection: Connection) {
+        self.connection = Some(connection);
+    }
+
+    /// sends `QUIT` if there is a connection and returns the config
+    ///
+    /// Like `Connection::quit` this fails with the I/O-Error if sending
+    /// `QUIT` or shutting down the socket failed.
+    pub fn quit(self) -> impl Future<Item=ConnectionConfig<A, S>, Error=std_io::Error> + Send {
+        let ReconnectableConnection { config, connection } = self;
+        match connection {
+            Some(con) => Either::A(con.quit().map(move |_| config)),
+            None => Either::B(future::ok(config))
+        }
+    }
+
+    /// the config used for connecting
+    pub fn config(&self) -> &ConnectionConfig<A, S> {
+        &self.config
+    }
+
+    /// splits this instance into the config and the connection
+    pub fn into_parts(self) -> (ConnectionConfig<A, S>, Option<Connection>) {
+        (self.config, self.connection)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread::JoinHandle;
+
+    use futures::Future;
+
+    use ::command::Noop;
+    use ::common::ClientId;
+    use ::data_types::Domain;
+    use ::stub_server::{self, default_response, serve_lines};
+    use super::*;
+
+    /// stub server accepting `connections` connections one after another
+    fn stub_server(connections: usize) -> (u16, JoinHandle<Vec<Vec<String>>>) {
+        let (addr, server) = stub_server::spawn(connections, |stream| {
+            serve_lines(stream, b"220 stub ready\r\n", default_response)
+        });
+        (addr.port(), server)
+    }
+
+    #[test]
+    fn reconnect_replaces_dropped_connection() {
+        let (port, server) = stub_server(2);
+        let config = ConnectionConfig::builder_local_unencrypted()
+            .port(port)
+            .client_id(ClientId::Domain(Domain::from_unchecked("fixed.test")))
+            .build();
+
+        let mut recon = ReconnectableConnection::connect(config).wait().unwrap();
+        // simulate the connection breaking
+        drop(recon.take_connection().unwrap());
+        assert!(recon.connection().is_none());
+
+        let con = recon.reconnect().wait().unwrap();
+        assert!(con.has_capability("SIZE"));
+        let (con, res) = con.send(Noop).wait().unwrap();
+        assert!(res.is_ok());
+        recon.set_connection(con);
+        recon.quit().wait().unwrap();
+
+        let lines = server.join().unwrap();
+        // the first `NOOP` of each connection is the (default) auth command
+        assert_eq!(lines, vec![
+            vec!["EHLO fixed.test\r\n", "NOOP\r\n"],
+            vec!["EHLO fixed.test\r\n", "NOOP\r\n", "NOOP\r\n", "QUIT\r\n"]
+        ]);
+    }
+
+    #[test]
+    fn failed_reconnect_keeps_config() {
+        let (port, server) = stub_server(1);
+        let config = ConnectionConfig::builder_local_unencrypted()
+            .port(port)
+            .client_id(ClientId::Domain(Domain::from_unchecked("fixed.test")))
+            .build();
+
+        let mut recon = ReconnectableConnection::connect(config).wait().unwrap();
+        // the server only accepts one connection
+        drop(recon.take_connection());
+        server.join().unwrap();
+
+        let err = recon.reconnect().wait().err().unwrap();
+        match err {
+            ConnectingFailed::Io(_) => (),
+            other => panic!("unexpected error: {:?}", other)
+        }
+        assert!(recon.connection().is_none());
+        assert_eq!(recon.config().addr.port(), port);
+    }
+}
@@ -1,4 +1,6 @@
 //! Provides access to `Response`, `ResponseCode` and parsing parts (form impl `Cmd`'s)
+use std::fmt::{self, Display};
+
 /// response of a smtp server
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct Response {
@@ -36,6 +38,107 @@ impl Response {
     pub fn msg(&self) -> &[String] {
         &self.lines
     }
+
+    /// returns the enhanced status code (RFC 3463) of the response, e.g. `"4.7.1"`
+    ///
+    /// This is the leading part of the first line if it has the form of a
+    /// enhanced status code, independent of `ENHANCEDSTATUSCODES` being advertised.
+    pub fn enhanced_status_code(&self) -> Option<&str> {
+        self.lines.first()
+            .and_then(|line| split_enhanced_code(line).0)
+    }
+}
+
+/// The greeting (banner) a server sends when a connection is opened
+///
+/// It gives access to the facts commonly contained in it, as the text
+/// is free form (RFC 5321 only requires it to start with the domain of
+/// the server) all of them are best-effort.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Greeting {
+    response: Response,
+    full_text: String
+}
+
+impl Greeting {
+
+    /// creates a new instance from the (`220`) response the server greeted with
+    pub fn new(response: Response) -> Self {
+        let full_text = response.msg().join("\n");
+        Greeting { response, full_text }
+    }
+
+    /// the response the server greeted with
+    pub fn response(&self) -> &Response {
+        &self.response
+    }
+
+    /// the text of all lines of the greeting joined with `'\n'`
+    pub fn full_text(&self) -> &str {
+        &self.full_text
+    }
+
+    /// the domain (or address literal) the greeting starts with, if it does
+    ///
+    /// Only a leading token which contains a `'.'`, is a address literal
+    /// (e.g. `"[127.0.0.1]"`) or is `"localhost"` is seen as domain, as
+    /// some servers leave it out (e.g. `"220 ESMTP ready"`).
+    pub fn domain(&self) -> Option<&str> {
+        let first = self.response.msg().first()?
+            .split_whitespace()
+            .next()?;
+
+        let is_address_literal = first.starts_with('[') && first.ends_with(']');
+        let is_domain = first.contains('.') && first.chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || ch == '.' || ch == '-');
+
+        if is_address_literal || is_domain || first.eq_ignore_ascii_case("localhost") {
+            Some(first)
+        } else {
+            None
+        }
+    }
+
+    /// true if the greeting contains the word `"ESMTP"` (ignoring case)
+    ///
+    /// Servers supporting smtp extensions normally announce it this way,
+    /// but `EHLO` can be tried independent of it.
+    pub fn announces_esmtp(&self) -> bool {
+        self.full_text
+            .split(|ch: char| !ch.is_ascii_alphanumeric())
+            .any(|word| word.eq_ignore_ascii_case("ESMTP"))
+    }
+}
+
+impl From<Response> for Greeting {
+    fn from(response: Response) -> Self {
+        Greeting::new(response)
+    }
+}
+
+/// splits of a leading enhanced status code (e.g. `"5.1.1 "`) from a response line
+pub(crate) fn split_enhanced_code(line: &str) -> (Option<&str>, &str) {
+    let (first, rest) = match line.find(' ') {
+        Some(idx) => (&line[..idx], &line[idx+1..]),
+        None => (line, "")
+    };
+
+    let mut parts = first.split('.');
+    let is_enhanced_code =
+        parts.next().map(|class| class == "2" || class == "4" || class == "5").unwrap_or(false)
+        && parts.next().map(is_status_number).unwrap_or(false)
+        && parts.next().map(is_status_number).unwrap_or(false)
+        && parts.next().is_none();
+
+    if is_enhanced_code {
+        (Some(first), rest)
+    } else {
+        (None, line)
+    }
+}
+
+fn is_status_number(part: &str) -> bool {
+    !part.is_empty() && part.len() <= 3 && part.bytes().all(|bch| bch.is_ascii_digit())
 }
 
 /// the response code of used by smtp server
@@ -73,12 +176,50 @@ impl ResponseCode {
     pub fn as_byte_string(&self) -> [u8; 3] {
         self.0
     }
+
+    /// the response code as number, e.g. `250`
+    pub fn as_u16(&self) -> u16 {
+        self.0.iter().fold(0, |num, digit| num * 10 + u16::from(digit - b'0'))
+    }
+
+    /// creates a response code from a number
+    ///
+    /// Returns `None` if the number has not exactly three digits.
+    pub fn from_u16(code: u16) -> Option<Self> {
+        if !(100..=999).contains(&code) {
+            return None;
+        }
+        let digit = |div: u16| b'0' + ((code / div) % 10) as u8;
+        Some(ResponseCode([digit(100), digit(10), digit(1)]))
+    }
+
+    /// returns the name of the constant in `codes` for well known codes
+    ///
+    /// E.g. `"OK"` for `250` or `"START_MAIL_DATA"` for `354`,
+    /// `None` is returned for codes not in `codes`.
+    pub fn name(&self) -> Option<&'static str> {
+        codes::KNOWN_CODES.iter()
+            .find(|&&(code, _)| code == *self)
+            .map(|&(_, name)| name)
+    }
+}
+
+impl From<ResponseCode> for u16 {
+    fn from(code: ResponseCode) -> u16 {
+        code.as_u16()
+    }
+}
+
+impl Display for ResponseCode {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        write!(fter, "{}", self.as_u16())
+    }
 }
 
 pub mod parser {
     use super::{Response, ResponseCode};
 
-    use std::str::{self, Utf8Error};
+    use std::str::Utf8Error;
     use std::fmt::{self, Display};
     use std::error::Error;
 
@@ -87,6 +228,7 @@ pub mod parser {
     pub enum ParseError {
         LineLength,
         CodeMsgSeparator,
+        /// not produced by `parse_line` anymore, as the message is decoded lossily
         Utf8(Utf8Error),
         CodeFormat {
             kind: u8,
@@ -124,7 +266,7 @@ pub mod parser {
 
         let code = parse_code(code[0], code[1], code[2])?;
         let last_line = parse_separator(sep[0])?;
-        let msg = parse_msg(msg)?.to_owned();
+        let msg = parse_msg(msg);
 
         Ok(ResponseLine { code, last_line, msg })
     }
@@ -165,8 +307,14 @@ pub mod parser {
         Ok(last_line)
     }
 
-    fn parse_msg(msg: &[u8]) -> Result<&str, ParseError> {
-        str::from_utf8(msg).map_err(ParseError::Utf8)
+    /// decodes the message as UTF-8 replacing invalid sequences with `'\u{FFFD}'`
+    ///
+    /// The text of a response is normally ASCII, but some servers include
+    /// UTF-8 (or other encodings) in it, which is harmless as long as it is
+    /// only used as text. Parts which are interpreted (e.g. ehlo keywords)
+    /// are still checked strictly when they are parsed.
+    fn parse_msg(msg: &[u8]) -> String {
+        String::from_utf8_lossy(msg).into_owned()
     }
 
 
@@ -227,6 +375,9 @@ pub mod codes {
     pub static CLOSING_CHANNEL: ResponseCode = ResponseCode(*b"221");
 
 
+    /// RFC 4954: Authentication Succeeded
+    pub static AUTH_SUCCEEDED: ResponseCode = ResponseCode(*b"235");
+
     /// RFC 5321: Requested mail action okay, completed
     pub static OK: ResponseCode = ResponseCode(*b"250");
 
@@ -237,6 +388,9 @@ pub mod codes {
     /// (See Section 3.5.3)
     pub static OK_UNVERIFIED: ResponseCode = ResponseCode(*b"252");
 
+    /// RFC 4954: server challenge (as part of the authentication exchange)
+    pub static AUTH_CHALLENGE: ResponseCode = ResponseCode(*b"334");
+
     /// RFC 5321: Start mail input; end with <CRLF>.<CRLF>
     pub static START_MAIL_DATA: ResponseCode = ResponseCode(*b"354");
 
@@ -277,6 +431,9 @@ pub mod codes {
     /// RFC 7504: Server does not accept mail
     pub static SERVER_DOES_NOT_ACCEPT_MAIL: ResponseCode = ResponseCode(*b"521");
 
+    /// RFC 4954: Encryption required for requested authentication mechanism
+    pub static ENCRYPTION_REQUIRED: ResponseCode = ResponseCode(*b"538");
+
     /// RFC 5321: Requested action not taken: mailbox unavailable (e.g., mailbox
     /// not found, no access, or command rejected for policy reasons)
     pub static MAILBOX_UNAVAILABLE: ResponseCode = ResponseCode(*b"550");
@@ -302,4 +459,152 @@ pub mod codes {
     ///  will return 521 when connecting, and therefore does decide not to connect
     ///  with it at all
     pub static TARGET_DOES_NOT_ACCEPT_MAIL: ResponseCode = ResponseCode(*b"556");
-}
\ No newline at end of file
+
+    /// all codes in this module with the name of their constant
+    pub(crate) static KNOWN_CODES: &[(ResponseCode, &str)] = &[
+        (ResponseCode(*b"211"), "STATUS_RESPONSE"),
+        (ResponseCode(*b"214"), "HELP_RESPONSE"),
+        (ResponseCode(*b"220"), "READY"),
+        (ResponseCode(*b"221"), "CLOSING_CHANNEL"),
+        (ResponseCode(*b"235"), "AUTH_SUCCEEDED"),
+        (ResponseCode(*b"250"), "OK"),
+        (ResponseCode(*b"251"), "OK_NOT_LOCAL"),
+        (ResponseCode(*b"252"), "OK_UNVERIFIED"),
+        (ResponseCode(*b"334"), "AUTH_CHALLENGE"),
+        (ResponseCode(*b"354"), "START_MAIL_DATA"),
+        (ResponseCode(*b"421"), "SERVICE_UNAVAILABLE"),
+        (ResponseCode(*b"450"), "MAILBOX_TEMP_UNAVAILABLE"),
+        (ResponseCode(*b"451"), "LOCAL_ERROR"),
+        (ResponseCode(*b"452"), "INSUFFICIENT_SYSTEM"),
+        (ResponseCode(*b"455"), "UNABLE_TO_ACCOMMODATE_PARAMETERS"),
+        (ResponseCode(*b"500"), "SYNTAX_ERROR"),
+        (ResponseCode(*b"501"), "PARAM_SYNTAX_ERROR"),
+        (ResponseCode(*b"502"), "COMMAND_UNIMPLEMENTED"),
+        (ResponseCode(*b"503"), "BAD_COMMAND_SEQUENCE"),
+        (ResponseCode(*b"504"), "PARAMETER_NOT_IMPLEMENTED"),
+        (ResponseCode(*b"521"), "SERVER_DOES_NOT_ACCEPT_MAIL"),
+        (ResponseCode(*b"538"), "ENCRYPTION_REQUIRED"),
+        (ResponseCode(*b"550"), "MAILBOX_UNAVAILABLE"),
+        (ResponseCode(*b"551"), "USER_NOT_LOCAL"),
+        (ResponseCode(*b"552"), "EXCEEDED_STORAGE_ALLOCATION"),
+        (ResponseCode(*b"553"), "BAD_MAILBOX_NAME"),
+        (ResponseCode(*b"554"), "TRANSACTION_FAILED"),
+        (ResponseCode(*b"555"), "PARAM_NOT_RECOGNIZED"),
+        (ResponseCode(*b"556"), "TARGET_DOES_NOT_ACCEPT_MAIL"),
+    ];
+}
+
+#[cfg(test)]
+mod test {
+    use super::{codes, split_enhanced_code, Greeting, Response, ResponseCode};
+
+    fn banner(lines: &[&str]) -> Greeting {
+        Greeting::new(Response::new(codes::READY, lines.iter().map(|line| line.to_string()).collect()))
+    }
+
+    #[test]
+    fn greeting_with_hostname_and_esmtp() {
+        let greeting = banner(&["mx.example.com ESMTP Postfix (Debian)"]);
+        assert_eq!(greeting.domain(), Some("mx.example.com"));
+        assert!(greeting.announces_esmtp());
+        assert_eq!(greeting.full_text(), "mx.example.com ESMTP Postfix (Debian)");
+    }
+
+    #[test]
+    fn greeting_with_hostname_without_esmtp() {
+        let greeting = banner(&["[192.0.2.1] Simple Mail Transfer Service Ready"]);
+        assert_eq!(greeting.domain(), Some("[192.0.2.1]"));
+        assert!(!greeting.announces_esmtp());
+
+        let greeting = banner(&["localhost NOESMTP-like service"]);
+        assert_eq!(greeting.domain(), Some("localhost"));
+        assert!(!greeting.announces_esmtp());
+    }
+
+    #[test]
+    fn greeting_without_hostname() {
+        let greeting = banner(&["ESMTP ready"]);
+        assert_eq!(greeting.domain(), None);
+        assert!(greeting.announces_esmtp());
+
+        let greeting = banner(&["Welcome, mail service ready"]);
+        assert_eq!(greeting.domain(), None);
+        assert!(!greeting.announces_esmtp());
+
+        let greeting = banner(&[""]);
+        assert_eq!(greeting.domain(), None);
+        assert_eq!(greeting.full_text(), "");
+    }
+
+    #[test]
+    fn greeting_esmtp_on_a_continuation_line() {
+        let greeting = banner(&["mail.example.org", "esmtp; no UCE"]);
+        assert_eq!(greeting.domain(), Some("mail.example.org"));
+        assert!(greeting.announces_esmtp());
+        assert_eq!(greeting.full_text(), "mail.example.org\nesmtp; no UCE");
+    }
+
+    #[test]
+    fn maps_numbers_to_known_codes_and_back() {
+        let known = [
+            (220, codes::READY, "READY"),
+            (235, codes::AUTH_SUCCEEDED, "AUTH_SUCCEEDED"),
+            (250, codes::OK, "OK"),
+            (354, codes::START_MAIL_DATA, "START_MAIL_DATA"),
+            (421, codes::SERVICE_UNAVAILABLE, "SERVICE_UNAVAILABLE"),
+            (552, codes::EXCEEDED_STORAGE_ALLOCATION, "EXCEEDED_STORAGE_ALLOCATION"),
+        ];
+
+        for &(num, known_code, name) in known.iter() {
+            let code = ResponseCode::from_u16(num).unwrap();
+            assert_eq!(code, known_code);
+            assert_eq!(code.name(), Some(name));
+            assert_eq!(code.as_u16(), num);
+            assert_eq!(u16::from(code), num);
+            assert_eq!(code.to_string(), num.to_string());
+        }
+    }
+
+    #[test]
+    fn unknown_codes_round_trip() {
+        let code = ResponseCode::from_u16(299).unwrap();
+        assert_eq!(code.name(), None);
+        assert_eq!(code.as_u16(), 299);
+        assert_eq!(code.as_byte_string(), *b"299");
+        assert!(code.is_positive());
+    }
+
+    #[test]
+    fn rejects_numbers_without_three_digits() {
+        assert_eq!(ResponseCode::from_u16(99), None);
+        assert_eq!(ResponseCode::from_u16(1000), None);
+    }
+
+    #[test]
+    fn decodes_invalid_utf8_in_message_lossily() {
+        use super::parser::parse_line;
+
+        let line = parse_line(b"250 gr\xfc\xdf dich, gr\xc3\xbc\xc3\x9fe").ok().unwrap();
+        assert_eq!(line.code, codes::OK);
+        assert_eq!(line.msg, "gr\u{fffd}\u{fffd} dich, gr\u{fc}\u{df}e");
+    }
+
+    #[test]
+    fn splits_enhanced_status_codes() {
+        assert_eq!(split_enhanced_code("5.1.1 User unknown"), (Some("5.1.1"), "User unknown"));
+        assert_eq!(split_enhanced_code("4.7.100 Try later"), (Some("4.7.100"), "Try later"));
+        assert_eq!(split_enhanced_code("User unknown"), (None, "User unknown"));
+        assert_eq!(split_enhanced_code("3.1.1 Not a class"), (None, "3.1.1 Not a class"));
+        assert_eq!(split_enhanced_code("5.1 Too short"), (None, "5.1 Too short"));
+    }
+
+    #[test]
+    fn exposes_enhanced_status_code() {
+        let code = ResponseCode::from_u16(451).unwrap();
+        let response = Response::new(code, vec!["4.7.1 Greylisted, try again later".to_owned()]);
+        assert_eq!(response.enhanced_status_code(), Some("4.7.1"));
+
+        let response = Response::new(code, vec!["Try again later".to_owned()]);
+        assert_eq!(response.enhanced_status_code(), None);
+    }
+}
@@ -0,0 +1,270 @@
+//! Provides `RetryPolicy` and `Connection::connect_with_retry`
+//!
+//! Only the setup of a connection is retried, retrying to send
+//! a mail is (still) out of scope for this crate.
+use std::{io as std_io};
+use std::time::Duration;
+
+use futures::future::{self, Future, Either, Loop};
+use tokio::clock;
+use tokio::timer::Delay;
+
+use ::error::ConnectingFailed;
+use ::common::SetupTls;
+use ::connection::{Connection, Cmd};
+use ::connect::ConnectionConfig;
+use ::util::random::random_u64;
+
+/// Specifies how often and with which delays connecting is retried
+///
+/// The delay before the `n`-th retry is `base_delay * backoff_factor^(n-1)`
+/// capped at `max_delay`. If `jitter` is enabled a random part of up to
+/// half of the delay is subtracted from it, so that multiple clients do
+/// not retry in lock-step.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// the maximal number of attempts, including the first one
+    pub max_attempts: u32,
+    /// the delay before the first retry
+    pub base_delay: Duration,
+    /// the factor by which the delay is increased after each retry
+    pub backoff_factor: u32,
+    /// the upper bound for the delay between two attempts
+    pub max_delay: Duration,
+    /// if the delay should be randomized
+    pub jitter: bool
+}
+
+impl RetryPolicy {
+
+    /// creates a policy doing up to `max_attempts` attempts with default delays
+    pub fn new(max_attempts: u32) -> Self {
+        RetryPolicy { max_attempts, ..Default::default() }
+    }
+
+    /// creates a policy which does only one attempt, i.e. never retries
+    pub fn no_retry() -> Self {
+        RetryPolicy::new(1)
+    }
+
+    /// returns the delay to wait before the `retry`-th retry (starting with 1)
+    pub fn delay_for_retry(&self, retry: u32) -> Duration {
+        let mut delay = self.base_delay;
+        for _ in 1..retry {
+            delay = delay.checked_mul(self.backoff_factor)
+                .unwrap_or(self.max_delay);
+            if delay >= self.max_delay {
+                break;
+            }
+        }
+        if delay > self.max_delay {
+            delay = self.max_delay;
+        }
+
+        if self.jitter {
+            let reduce_by = random_fraction(delay / 2);
+            delay - reduce_by
+        } else {
+            delay
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    /// 3 attempts, starting with a 1s delay doubling up to 60s, with jitter
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_secs(1),
+            backoff_factor: 2,
+            max_delay: Duration::from_secs(60),
+            jitter: true
+        }
+    }
+}
+
+/// returns a random duration in the range `[0; max]`
+fn random_fraction(max: Duration) -> Duration {
+    let random = random_u64();
+    let nanos = max.as_secs() * 1_000_000_000 + max.subsec_nanos() as u64;
+    if nanos == 0 {
+        Duration::from_secs(0)
+    } else {
+        let nanos = random % (nanos + 1);
+        Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+    }
+}
+
+/// repeatedly calls `attempt` until it succeeds or a non transient error occurs
+///
+/// If the future returned by `attempt` fails with a transient error
+/// (see `ConnectingFailed::is_transient`) it is retried after a delay
+/// specified through the `policy`, until `policy.max_attempts` attempts
+/// have been made. In which case the error of the last attempt is returned.
+///
+/// This requires a running tokio timer (e.g. a tokio runtime).
+pub fn retry_with_backoff<F, FUT, T>(policy: RetryPolicy, mut attempt: F)
+    -> impl Future<Item=T, Error=ConnectingFailed> + Send
+    where F: FnMut() -> FUT + Send + 'static,
+          FUT: Future<Item=T, Error=ConnectingFailed> + Send + 'static,
+          T: Send + 'static
+{
+    let fut = future
+        ::loop_fn(1, move |attempt_nr| {
+            let policy = policy.clone();
+            attempt().then(move |res| match res {
+                Ok(item) => Either::A(future::ok(Loop::Break(item))),
+                Err(err) => {
+                    if attempt_nr >= policy.max_attempts || !err.is_transient() {
+                        return Either::A(future::err(err));
+                    }
+
+                    let delay = policy.delay_for_retry(attempt_nr);
+                    let fut = Delay::new(clock::now() + delay)
+                        .map_err(|err| ConnectingFailed::Io(
+                            std_io::Error::new(std_io::ErrorKind::Other, err)
+                        ))
+                        .map(move |()| Loop::Continue(attempt_nr + 1));
+
+                    Either::B(fut)
+                }
+            })
+        });
+
+    fut
+}
+
+impl Connection {
+
+    /// open a connection to an smtp server retrying on transient failures
+    ///
+    /// This calls `Connection::connect` with a clone of the config
+    /// for each attempt, see `retry_with_backoff` for details when
+    /// a attempt is retried.
+    pub fn connect_with_retry<S, A>(config: ConnectionConfig<A, S>, policy: RetryPolicy)
+        -> impl Future<Item=Connection, Error=ConnectingFailed> + Send
+        where S: SetupTls + Clone, A: Cmd + Clone
+    {
+        retry_with_backoff(policy, move || Connection::connect(config.clone()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use futures::future;
+    use tokio::runtime::current_thread::Runtime;
+
+    use ::error::{ConnectingFailed, LogicError};
+    use ::response::{Response, codes};
+    use super::*;
+
+    fn quick_policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_delay: Duration::from_millis(1),
+            jitter: false,
+            ..Default::default()
+        }
+    }
+
+    fn error_with_code(code: ::response::ResponseCode) -> LogicError {
+        LogicError::Code(Response::new(code, vec![]))
+    }
+
+    #[test]
+    fn delay_grows_exponentially_up_to_max() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_secs(1),
+            backoff_factor: 2,
+            max_delay: Duration::from_secs(5),
+            jitter: false
+        };
+
+        assert_eq!(policy.delay_for_retry(1), Duration::from_secs(1));
+        assert_eq!(policy.delay_for_retry(2), Duration::from_secs(2));
+        assert_eq!(policy.delay_for_retry(3), Duration::from_secs(4));
+        assert_eq!(policy.delay_for_retry(4), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn jitter_stays_in_bounds() {
+        let policy = RetryPolicy { jitter: true, ..Default::default() };
+        for _ in 0..20 {
+            let delay = policy.delay_for_retry(2);
+            assert!(delay <= Duration::from_secs(2));
+            assert!(delay >= Duration::from_secs(1));
+        }
+    }
+
+    #[test]
+    fn retries_transient_failures() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let counter2 = counter.clone();
+        let fut = retry_with_backoff(quick_policy(3), move || {
+            let attempt = counter2.fetch_add(1, Ordering::SeqCst);
+            match attempt {
+                0 => future::err(ConnectingFailed::Io(
+                    std_io::Error::new(std_io::ErrorKind::ConnectionRefused, "refused"))),
+                1 => future::err(ConnectingFailed::Setup(
+                    error_with_code(codes::SERVICE_UNAVAILABLE))),
+                _ => future::ok(attempt)
+            }
+        });
+
+        let res = Runtime::new().unwrap().block_on(fut).unwrap();
+        assert_eq!(res, 2);
+        assert_eq!(counter.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn does_not_retry_auth_failures() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let counter2 = counter.clone();
+        let fut = retry_with_backoff(quick_policy(3), move || {
+            counter2.fetch_add(1, Ordering::SeqCst);
+            future::err::<(), _>(ConnectingFailed::Auth(
+                error_with_code(codes::SERVICE_UNAVAILABLE)))
+        });
+
+        let res = Runtime::new().unwrap().block_on(fut);
+        match res {
+            Err(ConnectingFailed::Auth(_)) => (),
+            other => panic!("unexpected result: {:?}", other)
+        }
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn does_not_retry_permanent_setup_failures() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let counter2 = counter.clone();
+        let fut = retry_with_backoff(quick_policy(3), move || {
+            counter2.fetch_add(1, Ordering::SeqCst);
+            future::err::<(), _>(ConnectingFailed::Setup(
+                error_with_code(codes::TRANSACTION_FAILED)))
+        });
+
+        let res = Runtime::new().unwrap().block_on(fut);
+        assert!(res.is_err());
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let counter2 = counter.clone();
+        let fut = retry_with_backoff(quick_policy(2), move || {
+            counter2.fetch_add(1, Ordering::SeqCst);
+            future::err::<(), _>(ConnectingFailed::Io(
+                std_io::Error::new(std_io::ErrorKind::ConnectionRefused, "refused")))
+        });
+
+        let res = Runtime::new().unwrap().block_on(fut);
+        assert!(res.is_err());
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+    }
+}
@@ -88,14 +88,19 @@
 //!
 use std::{io as std_io};
 use std::mem::replace;
+#[cfg(feature="resolver")]
+use std::net::IpAddr;
+#[cfg(feature="resolver")]
+use std::sync::Arc;
 
 use bytes::Bytes;
 use futures::{Poll, Async, IntoFuture};
-use futures::future::{self, Either, Future};
+use futures::future::{self, Either, Future, Loop};
 use futures::stream::Stream;
 use vec1::Vec1;
 
-use ::{Cmd, Connection};
+use ::{Cmd, Connection, Capabilities, Response};
+use ::response::{ResponseCode, split_enhanced_code};
 use ::error::{
     LogicError, MissingCapabilities,
     GeneralError
@@ -103,8 +108,13 @@ use ::error::{
 use ::common::SetupTls;
 use ::chain::{chain, OnError, HandleErrorInChain};
 use ::data_types::{ReversePath, ForwardPath};
-use ::command::{self, params_with_smtputf8};
+use ::command::{self, params_with_smtputf8, BodyMode, DeliverBy};
 use ::connect::ConnectionConfig;
+use ::io::estimate_wire_size;
+#[cfg(feature="resolver")]
+use ::data_types::Domain;
+#[cfg(feature="resolver")]
+use ::mx::{MxResolver, resolve_mx};
 
 /// Specifies if the mail requires SMTPUTF8 (or Mime8bit)
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
@@ -114,6 +124,48 @@ pub enum EncodingRequirement {
     Mime8bit
 }
 
+impl EncodingRequirement {
+
+    /// detects the encoding requirement of the mail data by scanning it
+    ///
+    /// Non us-ascii data in the header section requires `SMTPUTF8`,
+    /// non us-ascii data only in the body requires `8BITMIME` and pure
+    /// us-ascii data has no requirement. If there is no empty line ending
+    /// the header section all of the data is treated as header.
+    pub fn detect(data: &[u8]) -> Self {
+        let header_len = header_section_len(data).unwrap_or(data.len());
+        if !data[..header_len].is_ascii() {
+            EncodingRequirement::Smtputf8
+        } else if !data[header_len..].is_ascii() {
+            EncodingRequirement::Mime8bit
+        } else {
+            EncodingRequirement::None
+        }
+    }
+}
+
+/// returns the length of the header section including the terminating empty line
+pub(crate) fn header_section_len(message: &[u8]) -> Option<usize> {
+    if message.starts_with(b"\r\n") {
+        return Some(2);
+    }
+    if message.starts_with(b"\n") {
+        return Some(1);
+    }
+    let mut idx = 0;
+    while let Some(pos) = message[idx..].iter().position(|bch| *bch == b'\n') {
+        let line_end = idx + pos + 1;
+        let rest = &message[line_end..];
+        if rest.starts_with(b"\r\n") {
+            return Some(line_end + 2);
+        } else if rest.starts_with(b"\n") {
+            return Some(line_end + 1);
+        }
+        idx = line_end;
+    }
+    None
+}
+
 /// A simplified representation of a mail consisting of an `EncodingRequirement` and a buffer
 ///
 /// Note that the mail data will be placed internally inside a Bytes instance.
@@ -181,7 +233,8 @@ impl EnvelopData {
 #[derive(Debug, Clone)]
 pub struct MailEnvelop {
     envelop_data: EnvelopData,
-    mail: Mail
+    mail: Mail,
+    deliver_by: Option<DeliverBy>
 }
 
 impl MailEnvelop {
@@ -190,7 +243,8 @@ impl MailEnvelop {
     pub fn new(from: MailAddress, to: Vec1<MailAddress>, mail: Mail) -> Self {
         MailEnvelop {
             envelop_data: EnvelopData { from: Some(from), to },
-            mail
+            mail,
+            deliver_by: None
         }
     }
 
@@ -198,7 +252,8 @@ impl MailEnvelop {
     pub fn without_reverse_path(to: Vec1<MailAddress>, mail: Mail) -> Self {
         MailEnvelop {
             envelop_data: EnvelopData { from: None, to },
-            mail
+            mail,
+            deliver_by: None
         }
     }
 
@@ -214,6 +269,20 @@ impl MailEnvelop {
         &self.mail
     }
 
+    /// sends the mail with the `BY=` parameter (RFC 2852)
+    ///
+    /// If the server refuses the mail with the enhanced status code `5.4.7`
+    /// in response to `MAIL`, `RCPT TO:` or `DATA` the failure is returned
+    /// as `LogicError::DeliverByFailed`.
+    pub fn with_deliver_by(mut self, deliver_by: DeliverBy) -> Self {
+        self.deliver_by = Some(deliver_by);
+        self
+    }
+
+    pub fn deliver_by(&self) -> Option<DeliverBy> {
+        self.deliver_by
+    }
+
     /// true if any mail address is internationalized or the mail body needs it
     pub fn needs_smtputf8(&self) -> bool {
         self.envelop_data.needs_smtputf8() || self.mail.needs_smtputf8()
@@ -221,15 +290,79 @@ impl MailEnvelop {
 
 }
 
+/// The `MAIL` parameters (`SMTPUTF8`, `BODY=`) a mail is send with
+///
+/// `TransferMode::select` picks them based on the envelop addresses,
+/// the content of the mail and the capabilities of the server, it is
+/// used by `send_mail`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TransferMode {
+    smtputf8: bool,
+    body: Option<BodyMode>
+}
+
+impl TransferMode {
+
+    /// selects the mode to send the mail with
+    ///
+    /// - `SMTPUTF8` is used if any envelop address is internationalized, the
+    ///   header contains non us-ascii data or the mail requires it, it fails
+    ///   if the server doesn't support `SMTPUTF8`
+    /// - `BODY=8BITMIME` is used if the mail contains non us-ascii data or
+    ///   requires `8BITMIME`, if the server doesn't support `8BITMIME` this
+    ///   fails (except if `SMTPUTF8` is used) in which case the caller has
+    ///   to encode the mail (e.g. with quoted-printable or base64)
+    /// - for pure us-ascii mails neither is used (i.e. it's send as 7bit)
+    pub fn select(envelop: &MailEnvelop, caps: &Capabilities) -> Result<Self, MissingCapabilities> {
+        let mail = envelop.mail();
+        let detected = EncodingRequirement::detect(mail.raw_data());
+        let declared = mail.encoding_requirement();
+
+        let smtputf8 = envelop.envelop_data.needs_smtputf8()
+            || detected == EncodingRequirement::Smtputf8
+            || declared == EncodingRequirement::Smtputf8;
+        let has_8bit = detected != EncodingRequirement::None
+            || declared == EncodingRequirement::Mime8bit;
+
+        if smtputf8 && !caps.has_smtputf8() {
+            return Err(MissingCapabilities::new_from_unchecked("SMTPUTF8"));
+        }
+
+        let body =
+            if !has_8bit {
+                None
+            } else if caps.has_8bitmime() {
+                Some(BodyMode::EightBitMime)
+            } else if smtputf8 {
+                // SMTPUTF8 already allows 8bit data
+                None
+            } else {
+                return Err(MissingCapabilities::new_from_unchecked("8BITMIME"));
+            };
+
+        Ok(TransferMode { smtputf8, body })
+    }
+
+    /// true if the `SMTPUTF8` parameter is used
+    pub fn smtputf8(&self) -> bool {
+        self.smtputf8
+    }
+
+    /// the value of the `BODY=` parameter, `None` if it's not used
+    pub fn body_mode(&self) -> Option<BodyMode> {
+        self.body
+    }
+}
+
 impl From<(Mail, EnvelopData)> for MailEnvelop {
     fn from((mail, envelop_data): (Mail, EnvelopData)) -> Self {
-        MailEnvelop { envelop_data, mail }
+        MailEnvelop { envelop_data, mail, deliver_by: None }
     }
 }
 
 impl From<MailEnvelop> for (Mail, EnvelopData) {
     fn from(me: MailEnvelop) -> Self {
-        let MailEnvelop { mail, envelop_data } = me;
+        let MailEnvelop { mail, envelop_data, .. } = me;
         (mail, envelop_data)
     }
 }
@@ -337,47 +470,618 @@ pub type MailSendFuture = Box<Future<Item=(Connection, MailSendResult), Error=st
 /// `on_error` is passed to the internally used `chain` and can allow failing
 /// some, but not all, `RCPT TO:` commands. Use `chain::OnError::StopAndReset`
 /// if you are not sure what to use here.
+///
+/// The `SMTPUTF8` and `BODY=` parameters are selected with `TransferMode::select`,
+/// if the server lacks a needed capability this fails with `LogicError::MissingCapabilities`
+/// without sending any command.
+///
+/// If the server advertised a maximal message size (`SIZE`) and the mail is
+/// larger than it, this fails with `LogicError::MessageTooLarge` without
+/// sending any command. The size is the size of the mail as it is send,
+/// see `io::estimate_wire_size`.
 pub fn send_mail<H>(con: Connection, envelop: MailEnvelop, on_error: H)
     -> impl Future<Item=(Connection, MailSendResult), Error=std_io::Error> + Send
     where H: HandleErrorInChain
 {
-    let use_smtputf8 =  envelop.needs_smtputf8();
-    let (mail, EnvelopData { from, to: tos }) = envelop.into();
+    let (mail_cmd, tos, mail) = match prepare_mail(&con, envelop) {
+        Ok(prepared) => prepared,
+        Err(err) => return Either::B(future::ok((con, Err((0, err)))))
+    };
 
-    let check_mime_8bit_support =
-        !use_smtputf8 && mail.encoding_requirement() == EncodingRequirement::Mime8bit;
+    let uses_deliver_by = mail_cmd.deliver_by.is_some();
+    let mut cmd_chain = vec![ mail_cmd.boxed() ];
 
-    if (use_smtputf8 && !con.has_capability("SMTPUTF8"))
-       || (check_mime_8bit_support && !con.has_capability("8BITMIME"))
-    {
-        return Either::B(future::ok(
-            (con, Err((0, MissingCapabilities::new_from_unchecked("SMTPUTF8").into())))
-        ));
+    for to in tos.into_iter() {
+        cmd_chain.push(command::Recipient::new(to.into()).boxed());
+    }
+
+    cmd_chain.push(command::Data::from_buf(mail.into_raw_data()).boxed());
+
+    if !uses_deliver_by {
+        return Either::A(Either::A(chain(con, cmd_chain, on_error)));
+    }
+
+    let fut = chain(con, cmd_chain, on_error)
+        .map(|(con, result)| {
+            let result = result.map_err(|(idx, err)| (idx, DeliverBy::map_failure(err)));
+            (con, result)
+        });
+
+    Either::A(Either::B(fut))
+}
+
+/// does the checks done before sending any command and creates the `MAIL` command
+fn prepare_mail(con: &Connection, envelop: MailEnvelop)
+    -> Result<(command::Mail, Vec1<MailAddress>, Mail), LogicError>
+{
+    let (mode, size_limit) = {
+        let caps = con.caps();
+        (TransferMode::select(&envelop, caps), caps.max_message_size())
+    };
+
+    let mode = mode?;
+
+    let deliver_by = envelop.deliver_by;
+    let (mail, EnvelopData { from, to: tos }) = envelop.into();
+
+    if let Some(limit) = size_limit {
+        let size = estimate_wire_size(mail.raw_data()) as u64;
+        if size > limit {
+            return Err(LogicError::MessageTooLarge { size, limit });
+        }
     }
 
     let reverse_path = from.map(ReversePath::from)
         .unwrap_or_else(|| ReversePath::from_unchecked(""));
 
     let mut mail_params = Default::default();
-    if use_smtputf8 {
+    if mode.smtputf8() {
         mail_params  = params_with_smtputf8(mail_params);
     }
-    let mut cmd_chain = vec![
-        command::Mail {
-            reverse_path,
-            params: mail_params
-        }.boxed()
-    ];
 
-    for to in tos.into_iter() {
-        cmd_chain.push(command::Recipient::new(to.into()).boxed());
+    let mail_cmd = command::Mail {
+        reverse_path,
+        params: mail_params,
+        auth: None,
+        dsn_return: None,
+        envelop_id: None,
+        body: mode.body_mode(),
+        future_release: None,
+        require_tls: false,
+        mt_priority: None,
+        deliver_by
+    };
+
+    Ok((mail_cmd, tos, mail))
+}
+
+/// The outcome of the `RCPT TO:` command for a single recipient
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum RecipientStatus {
+    /// the recipient was accepted by the server
+    Accepted {
+        code: ResponseCode
+    },
+    /// the recipient was rejected, the mail is not send to it
+    Rejected {
+        code: ResponseCode,
+        /// the enhanced status code (RFC 3463), e.g. `"5.1.1"`, if the server send one
+        enhanced: Option<String>,
+        /// the text of the response (without the enhanced status code)
+        text: String
     }
+}
 
-    cmd_chain.push(command::Data::from_buf(mail.into_raw_data()).boxed());
+impl RecipientStatus {
+
+    fn from_response(response: &Response) -> Self {
+        let code = response.code();
+        if !response.is_erroneous() {
+            return RecipientStatus::Accepted { code };
+        }
+
+        let enhanced = response.enhanced_status_code()
+            .map(|enhanced| enhanced.to_owned());
+
+        let text = response.msg().iter()
+            .map(|line| split_enhanced_code(line).1)
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        RecipientStatus::Rejected { code, enhanced, text }
+    }
+
+    pub fn is_accepted(&self) -> bool {
+        match *self {
+            RecipientStatus::Accepted { .. } => true,
+            RecipientStatus::Rejected { .. } => false
+        }
+    }
+}
+
+/// The result of successfully sending a mail with `send_mail_with_report`
+///
+/// Contains the status of every recipient (in the order they were send)
+/// and the queue ids the server assigned to the mail, if they could be
+/// found in the final response to `DATA`. If the recipients were split
+/// across multiple transactions (see `send_mail_with_recipient_limit`)
+/// there is one queue id entry per transaction.
+#[derive(Debug, Clone)]
+pub struct DeliveryReport {
+    recipients: Vec<(MailAddress, RecipientStatus)>,
+    queued_ids: Vec<Option<String>>
+}
+
+impl DeliveryReport {
+
+    /// the recipients with the status the server gave them
+    pub fn recipients(&self) -> &[(MailAddress, RecipientStatus)] {
+        &self.recipients
+    }
+
+    /// returns the status of given recipient (if it was part of the mail)
+    pub fn status_of(&self, recipient: &str) -> Option<&RecipientStatus> {
+        self.recipients.iter()
+            .find(|(addr, _)| addr.as_str() == recipient)
+            .map(|(_, status)| status)
+    }
+
+    /// true if any recipient was rejected
+    pub fn has_rejected_recipients(&self) -> bool {
+        self.recipients.iter().any(|(_, status)| !status.is_accepted())
+    }
+
+    /// the queue id from the response to `DATA` (best-effort)
+    ///
+    /// E.g. `"ABC123"` for `250 2.0.0 Ok: queued as ABC123` or `"1abc-2def"`
+    /// for `250 OK id=1abc-2def`. Many servers don't include a queue id or
+    /// use a format which isn't recognized, in which case this is `None`.
+    ///
+    /// If multiple transactions were used this is the first found queue id.
+    pub fn queued_id(&self) -> Option<&str> {
+        self.queued_ids.iter()
+            .filter_map(|id| id.as_deref())
+            .next()
+    }
+
+    /// the queue id (see `queued_id`) of each transaction, in the order they were send
+    ///
+    /// Failed transactions have a `None` entry.
+    pub fn queued_ids(&self) -> &[Option<String>] {
+        &self.queued_ids
+    }
+
+    /// the number of `MAIL` transactions used to send the mail
+    pub fn transaction_count(&self) -> usize {
+        self.queued_ids.len()
+    }
+}
+
+/// The result of `send_mail_with_report`
+///
+/// The error case is the same as for `MailSendResult`. If no recipient
+/// accepted the mail the error of the last failing command is returned,
+/// e.g. if all recipients are rejected `DATA` is not send and the error of
+/// the last `RCPT TO:` command is returned. With multiple transactions the
+/// index is the index of the command in the failing transaction.
+pub type DeliveryResult = Result<DeliveryReport, (usize, LogicError)>;
+
+type Recipients = Vec<(MailAddress, RecipientStatus)>;
+
+type TransactionResult = Result<Option<String>, (usize, LogicError)>;
+
+type TransactionFuture = Box<
+    Future<Item=(Connection, Recipients, TransactionResult), Error=std_io::Error> + Send
+>;
+
+/// Sends a mail like `send_mail` but reports the status of each recipient
+///
+/// Rejected recipients do not stop sending the mail to the other
+/// recipients. If `MAIL`, `DATA` or all `RCPT TO:` commands fail
+/// `RSET` is send and the failing command is returned like for
+/// `send_mail` with `OnError::StopAndReset`.
+///
+/// If the server advertised a recipient limit the recipients are split
+/// across multiple transactions, see `send_mail_with_recipient_limit`.
+pub fn send_mail_with_report(con: Connection, envelop: MailEnvelop)
+    -> impl Future<Item=(Connection, DeliveryResult), Error=std_io::Error> + Send
+{
+    send_mail_with_recipient_limit(con, envelop, None)
+}
+
+/// Sends a mail like `send_mail_with_report` using at most `max_recipients` per transaction
+///
+/// If there are more recipients then allowed the same mail is send in
+/// multiple `MAIL`/`RCPT TO:`/`DATA` transactions, each with at most
+/// `max_recipients` recipients. If the server advertises a (lower) limit
+/// (see `Capabilities::max_recipients`) it is used instead, a limit of `0`
+/// is treated as `1`.
+///
+/// A transaction failing with an error response doesn't stop the other
+/// transactions, instead all recipients of it which were not already rejected
+/// are marked as rejected with the response of the failing command. Other
+/// errors (e.g. the server closing the connection) stop sending the mail and
+/// are returned as error, even if previous transactions succeeded.
+pub fn send_mail_with_recipient_limit(
+    con: Connection,
+    envelop: MailEnvelop,
+    max_recipients: Option<usize>
+)
+    -> impl Future<Item=(Connection, DeliveryResult), Error=std_io::Error> + Send
+{
+    let limit = match (max_recipients, con.caps().max_recipients()) {
+        (Some(limit), Some(server_limit)) => limit.min(server_limit),
+        (Some(limit), None) | (None, Some(limit)) => limit,
+        (None, None) => usize::MAX
+    }.max(1);
+
+    let (mail_cmd, tos, mail) = match prepare_mail(&con, envelop) {
+        Ok(prepared) => prepared,
+        Err(err) => return Either::B(future::ok((con, Err((0, err)))))
+    };
+
+    let uses_deliver_by = mail_cmd.deliver_by.is_some();
+    let body = mail.into_raw_data();
+    let batches = tos.chunks(limit)
+        .map(|batch| batch.to_vec())
+        .collect::<Vec<_>>()
+        .into_iter();
+
+    let report = DeliveryReport {
+        recipients: Vec::with_capacity(tos.len()),
+        queued_ids: Vec::with_capacity(batches.len())
+    };
+
+    let fut = future::loop_fn(
+        (con, batches, report, None),
+        move |(con, mut batches, mut report, last_err)| {
+            let batch = match batches.next() {
+                Some(batch) => batch,
+                None => {
+                    let any_accepted = report.recipients.iter()
+                        .any(|(_, status)| status.is_accepted());
+
+                    let result = match last_err {
+                        Some(failed) if !any_accepted => Err(failed),
+                        _ => Ok(report)
+                    };
+                    return Either::B(future::ok(Loop::Break((con, result))));
+                }
+            };
+
+            let fut = send_transaction(con, mail_cmd.clone(), batch.clone(), body.clone())
+                .map(move |(con, recipients, result)| match result {
+                    Ok(queued_id) => {
+                        report.recipients.extend(recipients);
+                        report.queued_ids.push(queued_id);
+                        Loop::Continue((con, batches, report, last_err))
+                    },
+                    Err((index, LogicError::Code(response))) => {
+                        let failed_status = RecipientStatus::from_response(&response);
+                        let mut statuses = recipients.into_iter().map(|(_, status)| status);
+                        for to in batch {
+                            let status = match statuses.next() {
+                                Some(status) if !status.is_accepted() => status,
+                                _ => failed_status.clone()
+                            };
+                            report.recipients.push((to, status));
+                        }
+                        report.queued_ids.push(None);
+                        let last_err = Some((index, LogicError::Code(response)));
+                        Loop::Continue((con, batches, report, last_err))
+                    },
+                    Err(failed) => Loop::Break((con, Err(failed)))
+                });
+
+            Either::A(fut)
+        }
+    );
+
+    let fut = fut.map(move |(con, result)| {
+        if !uses_deliver_by {
+            return (con, result);
+        }
+        let result = result.map_err(|(idx, err)| (idx, DeliverBy::map_failure(err)));
+        (con, result)
+    });
+
+    Either::A(fut)
+}
+
+/// sends a single `MAIL`/`RCPT TO:`/`DATA` transaction
+///
+/// The returned recipients are the ones for which `RCPT TO:` was send.
+fn send_transaction(con: Connection, mail_cmd: command::Mail, tos: Vec<MailAddress>, body: Bytes)
+    -> TransactionFuture
+{
+    let fut = con
+        .send(mail_cmd)
+        .and_then(move |(con, result)| -> TransactionFuture {
+            if let Err(err) = result {
+                return reset_and_fail(con, Vec::new(), 0, err);
+            }
+
+            let fut = send_recipients(con, tos)
+                .and_then(move |(con, recipients, failed)| -> TransactionFuture {
+                    if let Some((index, err)) = failed {
+                        return reset_and_fail(con, recipients, index, err);
+                    }
+
+                    let data_index = recipients.len() + 1;
+                    let fut = con
+                        .send(command::Data::from_buf(body))
+                        .and_then(move |(con, result)| -> TransactionFuture {
+                            match result {
+                                Ok(response) => {
+                                    let queued_id = parse_queued_id(&response);
+                                    Box::new(future::ok((con, recipients, Ok(queued_id))))
+                                },
+                                Err(err) => reset_and_fail(con, recipients, data_index, err)
+                            }
+                        });
+
+                    Box::new(fut)
+                });
+
+            Box::new(fut)
+        });
+
+    Box::new(fut)
+}
+
+/// sends `RCPT TO:` for all recipients, fails if none is accepted
+fn send_recipients(con: Connection, tos: Vec<MailAddress>)
+    -> impl Future<Item=(Connection, Recipients, Option<(usize, LogicError)>), Error=std_io::Error> + Send
+{
+    let tos = tos.into_iter();
+    let recipients = Vec::with_capacity(tos.len());
+
+    future::loop_fn((con, tos, recipients, None), |(con, mut tos, mut recipients, last_err)| {
+        let to = match tos.next() {
+            Some(to) => to,
+            None => {
+                let any_accepted = recipients.iter()
+                    .any(|(_, status): &(_, RecipientStatus)| status.is_accepted());
+
+                let failed = if any_accepted { None } else { last_err };
+                return Either::B(future::ok(Loop::Break((con, recipients, failed))));
+            }
+        };
+
+        let index = recipients.len() + 1;
+        let fut = con
+            .send(command::Recipient::new(to.clone().into()))
+            .map(move |(con, result)| match result {
+                Ok(response) => {
+                    recipients.push((to, RecipientStatus::from_response(&response)));
+                    Loop::Continue((con, tos, recipients, last_err))
+                },
+                Err(LogicError::Code(response)) => {
+                    recipients.push((to, RecipientStatus::from_response(&response)));
+                    let last_err = Some((index, LogicError::Code(response)));
+                    Loop::Continue((con, tos, recipients, last_err))
+                },
+                Err(err) => Loop::Break((con, recipients, Some((index, err))))
+            });
+
+        Either::A(fut)
+    })
+}
+
+fn reset_and_fail(con: Connection, recipients: Recipients, index: usize, err: LogicError)
+    -> TransactionFuture
+{
+    if con.is_service_closed() {
+        return Box::new(future::ok((con, recipients, Err((index, err)))));
+    }
+
+    let fut = con
+        .send(command::Reset)
+        .map(move |(con, _)| (con, recipients, Err((index, err))));
+
+    Box::new(fut)
+}
+
+/// searches the response for a queue id, e.g. `queued as ABC123` or `id=ABC123`
+fn parse_queued_id(response: &Response) -> Option<String> {
+    const MARKERS: &[&str] = &["queued as ", " id="];
+
+    for line in response.msg() {
+        let lower = line.to_ascii_lowercase();
+        for marker in MARKERS {
+            if let Some(idx) = lower.find(marker) {
+                let id = line[idx + marker.len()..]
+                    .split(|ch: char| ch.is_whitespace())
+                    .next()
+                    .unwrap_or("")
+                    .trim_matches(|ch: char| !ch.is_ascii_alphanumeric());
+
+                if !id.is_empty() {
+                    return Some(id.to_owned());
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Future of a single delivery attempt of `deliver_direct`
+///
+/// It resolves to an error if the next mail exchanger should be tried and
+/// fails if the mail was rejected permanently.
+#[cfg(feature="resolver")]
+type AttemptFuture = Box<Future<Item=Result<(), GeneralError>, Error=GeneralError> + Send>;
+
+/// Delivers a mail directly to the mail exchangers of the domain of its recipients
+///
+/// The mail exchangers are resolved with `mx::resolve_mx` and tried in the
+/// returned order, for each of them all addresses returned by
+/// `MxResolver::lookup_ip` are tried. `config_for` creates the config used
+/// to connect to a mail exchanger at one of it's addresses, normally it
+/// should use port 25 (`DEFAULT_SMTP_MX_PORT`) and (opportunistic) tls.
+///
+/// The next address/mail exchanger is tried if connecting fails, the
+/// connection is lost or the mail is rejected with a transient failure
+/// (`4xx`). A permanent failure (`5xx`) is returned without trying other
+/// mail exchangers. If all attempts fail the error of the last one is
+/// returned.
+///
+/// All recipients have to be in the same domain, else this fails with an
+/// `std::io::ErrorKind::InvalidInput` error without connecting to any host.
+/// If the domain doesn't accept mail it fails with an `NotFound` error.
+#[cfg(feature="resolver")]
+pub fn deliver_direct<R, F, A, S>(envelop: MailEnvelop, resolver: Arc<R>, config_for: F)
+    -> impl Future<Item=(), Error=GeneralError> + Send
+    where R: MxResolver + ?Sized,
+          F: Fn(&Domain, IpAddr) -> ConnectionConfig<A, S> + Send + Sync + 'static,
+          A: Cmd + Send + 'static,
+          S: SetupTls
+{
+    let domain = match recipient_domain(&envelop) {
+        Ok(domain) => domain,
+        Err(err) => return Either::B(future::err(GeneralError::Io(err)))
+    };
+
+    let config_for = Arc::new(config_for);
+    let fut = resolve_mx(resolver.clone(), domain)
+        .map_err(GeneralError::Io)
+        .and_then(move |hosts| {
+            if hosts.is_empty() {
+                let err = std_io::Error::new(std_io::ErrorKind::NotFound, "domain does not accept mail");
+                return Either::B(future::err(GeneralError::Io(err)));
+            }
 
-    Either::A(chain(con, cmd_chain, on_error))
+            let hosts = hosts.into_iter().map(|(_, host)| host);
+            let fut = future::loop_fn((hosts, None), move |(mut hosts, last_err)| {
+                let host = match hosts.next() {
+                    Some(host) => host,
+                    None => {
+                        //UNWRAP_SAFE: there is at least one host and each failed attempt sets last_err
+                        return Either::B(future::err(last_err.unwrap()));
+                    }
+                };
+
+                let fut = try_mail_exchanger(&*resolver, host, config_for.clone(), envelop.clone())
+                    .map(move |result| match result {
+                        Ok(()) => Loop::Break(()),
+                        Err(err) => Loop::Continue((hosts, Some(err)))
+                    });
+                Either::A(fut)
+            });
+            Either::A(fut)
+        });
+
+    Either::A(fut)
 }
 
+/// returns the domain of the recipients, fails if they are in different domains
+#[cfg(feature="resolver")]
+fn recipient_domain(envelop: &MailEnvelop) -> Result<Domain, std_io::Error> {
+    let domain_of = |address: &MailAddress| {
+        let address = address.as_str();
+        address.rfind('@').map(|idx| address[idx+1..].to_owned())
+    };
+
+    let invalid = |msg| std_io::Error::new(std_io::ErrorKind::InvalidInput, msg);
+
+    let mut tos = envelop.to_address().iter();
+    //UNWRAP_SAFE: Vec1 has at least one element
+    let domain = domain_of(tos.next().unwrap())
+        .ok_or_else(|| invalid("recipient without domain"))?;
+
+    if domain.starts_with('[') {
+        return Err(invalid("recipient domain is an address literal"));
+    }
+
+    for to in tos {
+        match domain_of(to) {
+            Some(ref other) if other.eq_ignore_ascii_case(&domain) => (),
+            _ => return Err(invalid("recipients are in different domains"))
+        }
+    }
+
+    Ok(Domain::new_unchecked(domain))
+}
+
+/// tries to deliver the mail to all addresses of a mail exchanger
+#[cfg(feature="resolver")]
+fn try_mail_exchanger<R, F, A, S>(resolver: &R, host: Domain, config_for: Arc<F>, envelop: MailEnvelop)
+    -> AttemptFuture
+    where R: MxResolver + ?Sized,
+          F: Fn(&Domain, IpAddr) -> ConnectionConfig<A, S> + Send + Sync + 'static,
+          A: Cmd + Send + 'static,
+          S: SetupTls
+{
+    let fut = resolver.lookup_ip(&host)
+        .then(move |result| -> AttemptFuture {
+            let addrs = match result {
+                Ok(addrs) => addrs,
+                Err(err) => return Box::new(future::ok(Err(GeneralError::Io(err))))
+            };
+
+            if addrs.is_empty() {
+                let err = std_io::Error::new(std_io::ErrorKind::NotFound, "mail exchanger has no address");
+                return Box::new(future::ok(Err(GeneralError::Io(err))));
+            }
+
+            let fut = future::loop_fn((addrs.into_iter(), None), move |(mut addrs, last_err)| {
+                let addr = match addrs.next() {
+                    Some(addr) => addr,
+                    None => {
+                        //UNWRAP_SAFE: there is at least one address and each failed attempt sets last_err
+                        return Either::B(future::ok(Loop::Break(Err(last_err.unwrap()))));
+                    }
+                };
+
+                let fut = try_deliver(config_for(&host, addr), envelop.clone())
+                    .map(move |result| match result {
+                        Ok(()) => Loop::Break(Ok(())),
+                        Err(err) => Loop::Continue((addrs, Some(err)))
+                    });
+                Either::A(fut)
+            });
+            Box::new(fut)
+        });
+
+    Box::new(fut)
+}
+
+/// connects using `config`, sends the mail and quits
+#[cfg(feature="resolver")]
+fn try_deliver<A, S>(config: ConnectionConfig<A, S>, envelop: MailEnvelop) -> AttemptFuture
+    where A: Cmd + Send + 'static, S: SetupTls
+{
+    let fut = Connection::connect(config)
+        .then(move |result| -> AttemptFuture {
+            let con = match result {
+                Ok(con) => con,
+                Err(err) => return Box::new(future::ok(Err(GeneralError::Connecting(err))))
+            };
+
+            let fut = con.send_mail(envelop)
+                .then(|result| -> AttemptFuture {
+                    let (con, result) = match result {
+                        Ok(sent) => sent,
+                        Err(err) => return Box::new(future::ok(Err(GeneralError::Io(err))))
+                    };
+
+                    let fut = con.quit().then(move |_| match result {
+                        Ok(()) => Ok(Ok(())),
+                        Err((_, err)) => {
+                            if err.is_transient() {
+                                Ok(Err(GeneralError::Cmd(err)))
+                            } else {
+                                Err(GeneralError::Cmd(err))
+                            }
+                        }
+                    });
+                    Box::new(fut)
+                });
+            Box::new(fut)
+        });
+
+    Box::new(fut)
+}
 
 impl Connection {
 
@@ -394,6 +1098,20 @@ impl Connection {
         send_mail(self, envelop, OnError::StopAndReset)
     }
 
+    /// Sends a mail and reports the status of each recipient, see `send_mail_with_report`.
+    pub fn send_mail_with_report(self, envelop: MailEnvelop)
+        -> impl Future<Item=(Connection, DeliveryResult), Error=std_io::Error> + Send
+    {
+        send_mail_with_report(self, envelop)
+    }
+
+    /// Sends a mail using at most `max_recipients` per transaction, see `send_mail_with_recipient_limit`.
+    pub fn send_mail_with_recipient_limit(self, envelop: MailEnvelop, max_recipients: Option<usize>)
+        -> impl Future<Item=(Connection, DeliveryResult), Error=std_io::Error> + Send
+    {
+        send_mail_with_recipient_limit(self, envelop, max_recipients)
+    }
+
     /// Sends all mails from mails through the connection.
     ///
     /// The connection is moved into the `SendAllMails` adapter
@@ -728,9 +1446,16 @@ impl<S, F, U> Stream for OnCompletion<S, F, U::Future>
 
 #[cfg(test)]
 mod test {
-    use ::{Connection, ConnectionConfig, command};
+    use std::collections::HashMap;
+
+    use ::{
+        Connection, ConnectionConfig, command,
+        Capabilities, Capability, EsmtpKeyword, EhloData, Domain
+    };
     use ::error::GeneralError;
-    use ::send_mail::MailEnvelop;
+    use ::send_mail::{MailEnvelop, MailAddress, Mail, EncodingRequirement, TransferMode};
+    use ::command::BodyMode;
+    use vec1::Vec1;
 
     fn assert_send(_: &impl Send) {}
 
@@ -743,4 +1468,260 @@ mod test {
         assert_send(&fut);
     }
 
-}
\ No newline at end of file
+    fn ehlo_data(caps: &[&str]) -> EhloData {
+        let map = caps.iter()
+            .map(|cap| (Capability::from(EsmtpKeyword::from_unchecked(*cap)), Vec::new()))
+            .collect::<HashMap<_,_>>();
+        EhloData::new(Domain::from_unchecked("test.test"), map)
+    }
+
+    fn select(from: &str, data: &str, caps: &Capabilities) -> Result<TransferMode, String> {
+        let envelop = MailEnvelop::new(
+            MailAddress::from_unchecked(from),
+            Vec1::new(MailAddress::from_unchecked("to@test.test")),
+            Mail::new(EncodingRequirement::None, data.to_owned())
+        );
+        TransferMode::select(&envelop, caps)
+            .map_err(|err| err.capabilities()[0].as_str().to_owned())
+    }
+
+    #[test]
+    fn ascii_mail_is_send_as_7bit() {
+        let all = ehlo_data(&["8BITMIME", "SMTPUTF8"]);
+        let mode = select("a@test.test", "Subject: Hy\r\n\r\nHy\r\n", all.caps()).unwrap();
+        assert!(!mode.smtputf8());
+        assert_eq!(mode.body_mode(), None);
+
+        let none = ehlo_data(&[]);
+        assert!(select("a@test.test", "Subject: Hy\r\n\r\nHy\r\n", none.caps()).is_ok());
+    }
+
+    #[test]
+    fn eight_bit_body_uses_8bitmime() {
+        let data = "Subject: Hy\r\n\r\nGr\u{fc}\u{df}e\r\n";
+        let caps = ehlo_data(&["8BITMIME"]);
+        let mode = select("a@test.test", data, caps.caps()).unwrap();
+        assert!(!mode.smtputf8());
+        assert_eq!(mode.body_mode(), Some(BodyMode::EightBitMime));
+
+        let none = ehlo_data(&[]);
+        assert_eq!(select("a@test.test", data, none.caps()), Err("8BITMIME".to_owned()));
+    }
+
+    #[test]
+    fn utf8_address_uses_smtputf8() {
+        let caps = ehlo_data(&["SMTPUTF8", "8BITMIME"]);
+        let mode = select("\u{fc}@test.test", "Subject: Hy\r\n\r\nHy\r\n", caps.caps()).unwrap();
+        assert!(mode.smtputf8());
+        assert_eq!(mode.body_mode(), None);
+
+        let mode = select("a@test.test", "Subject: Gr\u{fc}\u{df}e\r\n\r\nHy\r\n", caps.caps()).unwrap();
+        assert!(mode.smtputf8());
+        assert_eq!(mode.body_mode(), Some(BodyMode::EightBitMime));
+
+        let no_utf8 = ehlo_data(&["8BITMIME"]);
+        assert_eq!(
+            select("\u{fc}@test.test", "Subject: Hy\r\n\r\nHy\r\n", no_utf8.caps()),
+            Err("SMTPUTF8".to_owned())
+        );
+    }
+
+    mod delivery_report {
+        use ::Response;
+        use ::response::ResponseCode;
+        use super::super::{parse_queued_id, RecipientStatus};
+
+        fn response(code: u16, lines: &[&str]) -> Response {
+            let lines = lines.iter().map(|line| (*line).to_owned()).collect();
+            Response::new(ResponseCode::from_u16(code).unwrap(), lines)
+        }
+
+        #[test]
+        fn parses_queued_id() {
+            let id = parse_queued_id(&response(250, &["2.0.0 Ok: queued as ABC123"]));
+            assert_eq!(id, Some("ABC123".to_owned()));
+
+            let id = parse_queued_id(&response(250, &["OK id=1fQx2a-0003Ab-Kd"]));
+            assert_eq!(id, Some("1fQx2a-0003Ab-Kd".to_owned()));
+
+            let id = parse_queued_id(&response(250, &["2.0.0 OK (queued as <xyz789@host>)"]));
+            assert_eq!(id, Some("xyz789@host".to_owned()));
+        }
+
+        #[test]
+        fn queued_id_is_best_effort() {
+            assert_eq!(parse_queued_id(&response(250, &["Ok"])), None);
+            assert_eq!(parse_queued_id(&response(250, &["2.0.0 OK 1530000000 gsmtp"])), None);
+            assert_eq!(parse_queued_id(&response(250, &["Ok: queued as "])), None);
+        }
+
+        #[test]
+        fn recipient_status_from_response() {
+            let status = RecipientStatus::from_response(&response(250, &["2.1.5 Ok"]));
+            assert_eq!(status, RecipientStatus::Accepted { code: ResponseCode::from_u16(250).unwrap() });
+
+            let status = RecipientStatus::from_response(
+                &response(550, &["5.1.1 <t3@test.test>:", "5.1.1 User unknown"]));
+            assert_eq!(status, RecipientStatus::Rejected {
+                code: ResponseCode::from_u16(550).unwrap(),
+                enhanced: Some("5.1.1".to_owned()),
+                text: "<t3@test.test>: User unknown".to_owned()
+            });
+        }
+    }
+
+    #[cfg(feature="resolver")]
+    mod direct {
+        use std::net::{IpAddr, Ipv4Addr};
+        use std::sync::Arc;
+        use std::thread::JoinHandle;
+        use futures::future::{self, Future};
+
+        use ::{ConnectionConfig, ClientId, Domain};
+        use ::error::{GeneralError, LogicError};
+        use ::mx::{MxResolver, LookupFuture};
+        use ::send_mail::{deliver_direct, MailEnvelop, MailAddress, Mail, EncodingRequirement};
+        use ::stub_server::{self, closed_port, serve_lines};
+        use vec1::Vec1;
+
+        #[derive(Debug)]
+        struct StubResolver(Vec<(u16, Domain)>);
+
+        impl MxResolver for StubResolver {
+            fn lookup_mx(&self, _domain: &Domain) -> LookupFuture<Vec<(u16, Domain)>> {
+                Box::new(future::ok(self.0.clone()))
+            }
+
+            fn lookup_ip(&self, _domain: &Domain) -> LookupFuture<Vec<IpAddr>> {
+                Box::new(future::ok(vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))]))
+            }
+        }
+
+        fn resolver() -> Arc<StubResolver> {
+            Arc::new(StubResolver(vec![
+                (20, Domain::from_unchecked("mx2.test.test")),
+                (10, Domain::from_unchecked("mx1.test.test"))
+            ]))
+        }
+
+        fn envelop() -> MailEnvelop {
+            MailEnvelop::new(
+                MailAddress::from_unchecked("from@sender.test"),
+                Vec1::new(MailAddress::from_unchecked("to@test.test")),
+                Mail::new(EncodingRequirement::None, "Subject: Hy\r\n\r\nHy\r\n".to_owned())
+            )
+        }
+
+        /// runs a server which responds to `MAIL` with `mail_response` and accepts everything else
+        ///
+        /// The server thread returns true if it received the mail data.
+        fn mx_server(mail_response: &'static [u8]) -> (u16, JoinHandle<bool>) {
+            let (addr, server) = stub_server::spawn_one(move |stream| {
+                let mut in_data = false;
+                let mut received_data = false;
+                serve_lines(stream, b"220 mx ready\r\n", |line| Some(
+                    if in_data {
+                        if line == ".\r\n" {
+                            in_data = false;
+                            received_data = true;
+                            b"250 queued as ABC\r\n"
+                        } else { b"" }
+                    }
+                    else if line.starts_with("EHLO") { b"250 mx.test.test\r\n" }
+                    else if line.starts_with("MAIL") { mail_response }
+                    else if line.starts_with("DATA") { in_data = true; b"354 go ahead\r\n" }
+                    else if line.starts_with("QUIT") { b"221 Bye\r\n" }
+                    else { b"250 Ok\r\n" }
+                ));
+                received_data
+            });
+            (addr.port(), server)
+        }
+
+        fn config_for(mx1_port: u16, mx2_port: u16)
+            -> impl Fn(&Domain, IpAddr) -> ConnectionConfig<::command::Noop> + Send + Sync + 'static
+        {
+            move |host, _addr| {
+                let port = if host.as_str() == "mx1.test.test" { mx1_port } else { mx2_port };
+                ConnectionConfig::builder_local_unencrypted()
+                    .port(port)
+                    .client_id(ClientId::Domain(Domain::from_unchecked("client.test")))
+                    .build()
+            }
+        }
+
+        #[test]
+        fn next_mx_is_tried_if_connecting_fails() {
+            let (mx2_port, mx2) = mx_server(b"250 Ok\r\n");
+
+            deliver_direct(envelop(), resolver(), config_for(closed_port(), mx2_port))
+                .wait()
+                .unwrap();
+
+            assert!(mx2.join().unwrap());
+        }
+
+        #[test]
+        fn next_mx_is_tried_on_transient_failure() {
+            let (mx1_port, mx1) = mx_server(b"451 4.3.0 try again later\r\n");
+            let (mx2_port, mx2) = mx_server(b"250 Ok\r\n");
+
+            deliver_direct(envelop(), resolver(), config_for(mx1_port, mx2_port))
+                .wait()
+                .unwrap();
+
+            assert!(!mx1.join().unwrap());
+            assert!(mx2.join().unwrap());
+        }
+
+        #[test]
+        fn permanent_failure_is_returned_directly() {
+            let (mx1_port, mx1) = mx_server(b"550 5.7.1 go away\r\n");
+
+            let err = deliver_direct(envelop(), resolver(), config_for(mx1_port, closed_port()))
+                .wait()
+                .unwrap_err();
+
+            match err {
+                GeneralError::Cmd(LogicError::Code(response)) => {
+                    assert_eq!(response.code().as_byte_string(), *b"550");
+                },
+                other => panic!("unexpected error: {:?}", other)
+            }
+            assert!(!mx1.join().unwrap());
+        }
+
+        #[test]
+        fn last_error_is_returned_if_all_fail() {
+            let err = deliver_direct(envelop(), resolver(), config_for(closed_port(), closed_port()))
+                .wait()
+                .unwrap_err();
+
+            match err {
+                GeneralError::Connecting(_) => (),
+                other => panic!("unexpected error: {:?}", other)
+            }
+        }
+
+        #[test]
+        fn recipients_have_to_be_in_one_domain() {
+            let envelop = MailEnvelop::new(
+                MailAddress::from_unchecked("from@sender.test"),
+                Vec1::from_vec(vec![
+                    MailAddress::from_unchecked("a@test.test"),
+                    MailAddress::from_unchecked("b@other.test")
+                ]).unwrap(),
+                Mail::new(EncodingRequirement::None, "Subject: Hy\r\n\r\nHy\r\n".to_owned())
+            );
+
+            let err = deliver_direct(envelop, resolver(), config_for(closed_port(), closed_port()))
+                .wait()
+                .unwrap_err();
+
+            match err {
+                GeneralError::Io(err) => assert_eq!(err.kind(), ::std::io::ErrorKind::InvalidInput),
+                other => panic!("unexpected error: {:?}", other)
+            }
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! A blocking tcp stub server for tests which need a real connection
+//!
+//! The server runs in it's own thread listening on `127.0.0.1`, tests join
+//! the thread to get what the server received.
+use std::io::{BufRead, BufReader, Read, Write};
+use std::mem::replace;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread::{self, JoinHandle};
+
+/// accepts `connections` connections one after another, each is handled by `handle`
+///
+/// The server thread returns the results of `handle` in the order the
+/// connections were accepted.
+pub(crate) fn spawn<F, T>(connections: usize, mut handle: F) -> (SocketAddr, JoinHandle<Vec<T>>)
+    where F: FnMut(TcpStream) -> T + Send + 'static, T: Send + 'static
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = thread::spawn(move || {
+        (0..connections)
+            .map(|_| handle(listener.accept().unwrap().0))
+            .collect()
+    });
+
+    (addr, server)
+}
+
+/// like `spawn` but accepts a single connection
+pub(crate) fn spawn_one<F, T>(handle: F) -> (SocketAddr, JoinHandle<T>)
+    where F: FnOnce(TcpStream) -> T + Send + 'static, T: Send + 'static
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = thread::spawn(move || handle(listener.accept().unwrap().0));
+
+    (addr, server)
+}
+
+/// sends `greeting` and answers each received line with `respond`, returns the received lines
+///
+/// If `respond` returns `None` the server stops answering and waits until
+/// the client closes the connection.
+pub(crate) fn serve_lines<S, F>(stream: S, greeting: &[u8], mut respond: F) -> Vec<String>
+    where S: Read + Write, F: FnMut(&str) -> Option<&'static [u8]>
+{
+    let mut reader = BufReader::new(stream);
+    reader.get_mut().write_all(greeting).unwrap();
+
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    while reader.read_line(&mut line).unwrap_or(0) > 0 {
+        let response = respond(&line);
+        lines.push(replace(&mut line, String::new()));
+        match response {
+            Some(response) => reader.get_mut().write_all(response).unwrap(),
+            None => {
+                wait_for_close(reader);
+                break;
+            }
+        }
+    }
+    lines
+}
+
+/// the responses of a minimal server, `EHLO` advertises `SIZE 1000`
+pub(crate) fn default_response(line: &str) -> Option<&'static [u8]> {
+    Some(
+        if line.starts_with("EHLO") { b"250-stub.test\r\n250 SIZE 1000\r\n" }
+        else if line.starts_with("QUIT") { b"221 Bye\r\n" }
+        else { b"250 Ok\r\n" }
+    )
+}
+
+/// reads a line and responds to it, returns false if the client hang up
+pub(crate) fn respond<S: Read + Write>(reader: &mut BufReader<S>, response: &[u8]) -> bool {
+    let mut line = String::new();
+    if reader.read_line(&mut line).unwrap_or(0) == 0 {
+        return false;
+    }
+    reader.get_mut().write_all(response).unwrap();
+    true
+}
+
+/// reads (and discards) everything until the client closes the connection
+pub(crate) fn wait_for_close<S: Read>(mut stream: S) {
+    let mut buf = [0u8; 1024];
+    while let Ok(n) = stream.read(&mut buf) {
+        if n == 0 { break }
+    }
+}
+
+/// returns a port on which connections are refused
+pub(crate) fn closed_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// a test ca and a server certificate signed by it, which is only valid for `smtp.example.com`
+#[cfg(not(any(target_os = "windows", target_vendor = "apple")))]
+pub(crate) mod tls {
+    use std::sync::{Arc, Mutex};
+
+    use openssl::pkey::PKey;
+    use openssl::ssl::{NameType, SslAcceptor, SslMethod};
+    use openssl::x509::X509;
+    use native_tls::{self, Certificate, TlsConnectorBuilder, TlsConnector as NativeTlsConnector};
+
+    use ::common::{DomainVerifier, SetupTls, TlsOptions};
+    use ::data_types::Domain;
+
+    pub(crate) static CA_CERT: &[u8] = include_bytes!("io/test_certs/ca.pem");
+    pub(crate) static SERVER_CERT: &[u8] = include_bytes!("io/test_certs/server.pem");
+    pub(crate) static SERVER_KEY: &[u8] = include_bytes!("io/test_certs/server.key");
+
+    /// trusts the test ca, applying the `TlsOptions` on top
+    #[derive(Debug)]
+    pub(crate) struct TrustTestCa(pub(crate) TlsOptions);
+
+    impl SetupTls for TrustTestCa {
+        fn setup(self, mut builder: TlsConnectorBuilder)
+            -> Result<NativeTlsConnector, native_tls::Error>
+        {
+            builder.add_root_certificate(Certificate::from_pem(CA_CERT)?);
+            self.0.setup(builder)
+        }
+
+        fn spki_pins(&self) -> Vec<[u8; 32]> {
+            self.0.spki_pins()
+        }
+
+        fn domain_verifier(&self) -> Option<DomainVerifier> {
+            Some(Box::new(|der: &[u8], domain: &Domain| {
+                let cert = X509::from_der(der).unwrap();
+                cert.subject_alt_names().map(|names| names.iter().any(|name| {
+                    name.dnsname().map(|dns_name| dns_name.eq_ignore_ascii_case(domain.as_str())).unwrap_or(false)
+                })).unwrap_or(false)
+            }))
+        }
+    }
+
+    /// creates a tls acceptor recording the server name (SNI) send by the client
+    pub(crate) fn sni_recording_acceptor() -> (SslAcceptor, Arc<Mutex<Option<String>>>) {
+        let sni = Arc::new(Mutex::new(None));
+        let recorded = sni.clone();
+
+        let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        builder.set_private_key(&PKey::private_key_from_pem(SERVER_KEY).unwrap()).unwrap();
+        builder.set_certificate(&X509::from_pem(SERVER_CERT).unwrap()).unwrap();
+        builder.set_servername_callback(move |ssl, _| {
+            *recorded.lock().unwrap() = ssl.servername(NameType::HOST_NAME).map(str::to_owned);
+            Ok(())
+        });
+
+        (builder.build(), sni)
+    }
+}
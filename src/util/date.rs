@@ -0,0 +1,277 @@
+//! lenient parsing of timestamps contained in free form text (e.g. a greeting banner)
+//!
+//! Recognized are RFC 5322 dates (`Thu, 14 Jun 2018 11:22:18 +0200`, the weekday
+//! and seconds being optional), asctime like dates (`Thu Jun 14 11:22:18 2018`)
+//! and ISO 8601/RFC 3339 timestamps (`2018-06-14T11:22:18Z`). A missing or
+//! unknown time zone is treated as UTC.
+//!
+//! Additionally `format_rfc3339` formats a timestamp e.g. for the
+//! `HOLDUNTIL=` parameter of `MAIL`.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MONTHS: &[&str] = &[
+    "jan", "feb", "mar", "apr", "may", "jun",
+    "jul", "aug", "sep", "oct", "nov", "dec"
+];
+
+/// finds the first timestamp in given text, returns `None` if there is none
+pub fn find_timestamp(text: &str) -> Option<SystemTime> {
+    let tokens = text
+        .split(|ch: char| ch.is_whitespace() || ch == ',' || ch == ';')
+        .filter(|token| !token.is_empty())
+        .collect::<Vec<_>>();
+
+    (0..tokens.len())
+        .filter_map(|idx| {
+            let tokens = &tokens[idx..];
+            parse_rfc5322(tokens)
+                .or_else(|| parse_asctime(tokens))
+                .or_else(|| parse_iso8601(tokens[0]))
+        })
+        .next()
+}
+
+/// `14 Jun 2018 11:22:18 +0200`
+fn parse_rfc5322(tokens: &[&str]) -> Option<SystemTime> {
+    if tokens.len() < 4 {
+        return None;
+    }
+    let day = parse_number(tokens[0], 1, 2)?;
+    let month = parse_month(tokens[1])?;
+    let year = parse_year(tokens[2])?;
+    let (hour, minute, second) = parse_time(tokens[3])?;
+    let offset = tokens.get(4).and_then(|zone| parse_zone(zone)).unwrap_or(0);
+    to_system_time(year, month, day, hour, minute, second, offset)
+}
+
+/// `Jun 14 11:22:18 2018` (the weekday is skipped by the caller)
+fn parse_asctime(tokens: &[&str]) -> Option<SystemTime> {
+    if tokens.len() < 4 {
+        return None;
+    }
+    let month = parse_month(tokens[0])?;
+    let day = parse_number(tokens[1], 1, 2)?;
+    let (hour, minute, second) = parse_time(tokens[2])?;
+    let (year, offset) = match parse_year(tokens[3]) {
+        Some(year) => (year, 0),
+        // e.g. `Jun 14 11:22:18 CEST 2018`
+        None => (parse_year(tokens.get(4)?)?, parse_zone(tokens[3]).unwrap_or(0))
+    };
+    to_system_time(year, month, day, hour, minute, second, offset)
+}
+
+/// `2018-06-14T11:22:18Z`, `2018-06-14T11:22:18.123+02:00`
+fn parse_iso8601(token: &str) -> Option<SystemTime> {
+    let t_idx = token.find(&['T', 't'][..])?;
+    let (date, time) = (&token[..t_idx], &token[t_idx + 1..]);
+
+    let mut date_parts = date.split('-');
+    let year = parse_number(date_parts.next()?, 4, 4)? as i64;
+    let month = parse_number(date_parts.next()?, 2, 2)?;
+    let day = parse_number(date_parts.next()?, 2, 2)?;
+    if date_parts.next().is_some() {
+        return None;
+    }
+
+    let zone_idx = time.find(&['Z', 'z', '+', '-'][..])
+        .unwrap_or(time.len());
+    let (time, zone) = (&time[..zone_idx], &time[zone_idx..]);
+    // fractional seconds are ignored
+    let time = time.split('.').next()?;
+    let (hour, minute, second) = parse_time(time)?;
+    let offset = if zone.is_empty() {
+        0
+    } else {
+        parse_zone(&zone.replace(':', ""))?
+    };
+    to_system_time(year, month, day, hour, minute, second, offset)
+}
+
+fn parse_number(token: &str, min_len: usize, max_len: usize) -> Option<u32> {
+    if token.len() < min_len || token.len() > max_len || !token.bytes().all(|bch| bch.is_ascii_digit()) {
+        return None;
+    }
+    token.parse().ok()
+}
+
+fn parse_month(token: &str) -> Option<u32> {
+    if token.len() < 3 {
+        return None;
+    }
+    let prefix = token.get(..3)?.to_ascii_lowercase();
+    MONTHS.iter()
+        .position(|month| *month == prefix)
+        .map(|idx| idx as u32 + 1)
+}
+
+fn parse_year(token: &str) -> Option<i64> {
+    let year = parse_number(token, 2, 4)? as i64;
+    // obsolete two (or three) digit years as specified in RFC 5322
+    Some(match token.len() {
+        2 if year < 50 => year + 2000,
+        2 | 3 => year + 1900,
+        _ => year
+    })
+}
+
+/// `HH:MM` or `HH:MM:SS`
+fn parse_time(token: &str) -> Option<(u32, u32, u32)> {
+    let mut parts = token.split(':');
+    let hour = parse_number(parts.next()?, 1, 2)?;
+    let minute = parse_number(parts.next()?, 2, 2)?;
+    let second = match parts.next() {
+        Some(second) => parse_number(second, 2, 2)?,
+        None => 0
+    };
+    if parts.next().is_some() || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    Some((hour, minute, second))
+}
+
+/// returns the offset to UTC in seconds
+fn parse_zone(token: &str) -> Option<i64> {
+    let token = token.trim_matches(&['(', ')'][..]);
+    let bytes = token.as_bytes();
+    if bytes.len() == 5 && (bytes[0] == b'+' || bytes[0] == b'-') {
+        // the server controls the token, it might not be ascii
+        let hours = parse_number(token.get(1..3)?, 2, 2)? as i64;
+        let minutes = parse_number(token.get(3..5)?, 2, 2)? as i64;
+        let offset = hours * 3600 + minutes * 60;
+        return Some(if bytes[0] == b'-' { -offset } else { offset });
+    }
+
+    let hours = match &*token.to_ascii_uppercase() {
+        "Z" | "UT" | "UTC" | "GMT" => 0,
+        "EDT" => -4,
+        "EST" | "CDT" => -5,
+        "CST" | "MDT" => -6,
+        "MST" | "PDT" => -7,
+        "PST" => -8,
+        _ => return None
+    };
+    Some(hours * 3600)
+}
+
+fn to_system_time(
+    year: i64, month: u32, day: u32,
+    hour: u32, minute: u32, second: u32,
+    offset: i64
+) -> Option<SystemTime> {
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let days = days_from_civil(year, month, day);
+    let secs = days * 86400
+        + i64::from(hour) * 3600
+        + i64::from(minute) * 60
+        + i64::from(second)
+        - offset;
+
+    if secs < 0 {
+        None
+    } else {
+        Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
+    }
+}
+
+/// formats given time as RFC 3339 timestamp in UTC, e.g. `2018-06-14T11:22:18Z`
+///
+/// Fractions of a second are truncated, times before 1970 are formatted
+/// as `1970-01-01T00:00:00Z`.
+pub fn format_rfc3339(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs_of_day = secs % 86400;
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day,
+        secs_of_day / 3600, secs_of_day % 3600 / 60, secs_of_day % 60)
+}
+
+/// (proleptic gregorian) date of given days since 1970-01-01, inverse of `days_from_civil`
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = if days >= 0 { days } else { days - 146096 } / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// days since 1970-01-01 of given (proleptic gregorian) date
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let month = i64::from(month);
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+    use super::{find_timestamp, format_rfc3339, parse_zone};
+
+    /// 2018-06-14T11:22:18Z
+    const EXPECTED_SECS: u64 = 1528975338;
+
+    fn expected() -> Option<::std::time::SystemTime> {
+        Some(UNIX_EPOCH + Duration::from_secs(EXPECTED_SECS))
+    }
+
+    #[test]
+    fn parses_rfc5322_date_in_banner() {
+        assert_eq!(
+            find_timestamp("mail.test.test ESMTP Postfix; Thu, 14 Jun 2018 13:22:18 +0200"),
+            expected()
+        );
+        assert_eq!(find_timestamp("mx.test ESMTP Exim 4.92 Thu, 14 Jun 2018 11:22:18 GMT"), expected());
+        assert_eq!(find_timestamp("14 Jun 18 07:22:18 EDT"), expected());
+    }
+
+    #[test]
+    fn parses_other_formats() {
+        assert_eq!(find_timestamp("ready at Thu Jun 14 11:22:18 2018"), expected());
+        assert_eq!(find_timestamp("ready at 2018-06-14T11:22:18Z"), expected());
+        assert_eq!(find_timestamp("ready at 2018-06-14T13:22:18.532+02:00"), expected());
+    }
+
+    #[test]
+    fn returns_none_without_timestamp() {
+        assert_eq!(find_timestamp("mail.test.test ESMTP Postfix (Debian/GNU)"), None);
+        assert_eq!(find_timestamp("mx.test ESMTP ready 12 of 40 slots"), None);
+        assert_eq!(find_timestamp(""), None);
+    }
+
+    #[test]
+    fn non_ascii_zone_does_not_panic() {
+        assert_eq!(parse_zone("+1\u{e9}2"), None);
+        assert_eq!(parse_zone("-\u{e9}12"), None);
+        // the zone is ignored, like other unknown zones
+        assert!(find_timestamp("ready; Thu, 14 Jun 2018 13:22:18 +1\u{e9}2").is_some());
+        assert_eq!(find_timestamp("ready at 2018-06-14T11:22:18+1\u{e9}2"), None);
+    }
+
+    #[test]
+    fn formats_rfc3339_timestamps() {
+        assert_eq!(format_rfc3339(expected().unwrap()), "2018-06-14T11:22:18Z");
+        assert_eq!(format_rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        // 2000-02-29T23:59:59Z, a leap day
+        assert_eq!(format_rfc3339(UNIX_EPOCH + Duration::from_secs(951868799)), "2000-02-29T23:59:59Z");
+    }
+
+    #[test]
+    fn formatted_timestamps_can_be_parsed() {
+        let time = UNIX_EPOCH + Duration::from_secs(4102444800 + 12345);
+        assert_eq!(find_timestamp(&format_rfc3339(time)), Some(time));
+    }
+}
@@ -0,0 +1,5 @@
+//! utilities, e.g. encodings used by esmtp parameters
+
+pub mod xtext;
+pub mod date;
+pub(crate) mod random;
@@ -0,0 +1,11 @@
+//! non-cryptographic randomness, e.g. for jitter or load distribution
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// returns a random number, _not_ suitable for anything security related
+///
+/// Each call uses a newly (randomly) seeded hasher, which is random
+/// enough for e.g. jitter and doesn't require a dependency on `rand`.
+pub(crate) fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
@@ -0,0 +1,126 @@
+//! xtext encoding as specified in RFC 3461
+//!
+//! It is used by esmtp parameters like `ENVID`, `ORCPT` (DSN) and
+//! `AUTH=` (RFC 4954). `'+'`, `'='` and all bytes which are not printable
+//! us-ascii are encoded as `"+XX"` with `XX` being the upper case hex value
+//! of the byte (utf-8 is encoded byte wise).
+use std::fmt::{self, Display};
+use std::error::Error;
+
+/// xtext encodes the input
+pub fn encode(inp: &str) -> String {
+    let mut out = String::with_capacity(inp.len());
+    for bch in inp.bytes() {
+        if needs_encoding(bch) {
+            out.push_str(&format!("+{:02X}", bch));
+        } else {
+            out.push(bch as char);
+        }
+    }
+    out
+}
+
+/// decodes xtext
+///
+/// # Error
+///
+/// Fails if a `'+'` is not followed by two upper case hex digits, the
+/// input contains chars which have to be encoded or the decoded
+/// bytes are not valid utf-8.
+pub fn decode(inp: &str) -> Result<String, DecodeError> {
+    let mut out = Vec::with_capacity(inp.len());
+    let mut iter = inp.bytes();
+    while let Some(bch) = iter.next() {
+        if bch == b'+' {
+            let high = iter.next().ok_or(DecodeError::Truncated)?;
+            let low = iter.next().ok_or(DecodeError::Truncated)?;
+            match (hex_value(high), hex_value(low)) {
+                (Some(high), Some(low)) => out.push(high << 4 | low),
+                _ => return Err(DecodeError::InvalidHexChar)
+            }
+        } else if needs_encoding(bch) {
+            return Err(DecodeError::NotEncoded(bch));
+        } else {
+            out.push(bch);
+        }
+    }
+    String::from_utf8(out).map_err(|_| DecodeError::InvalidUtf8)
+}
+
+fn needs_encoding(bch: u8) -> bool {
+    match bch {
+        b'+' | b'=' => true,
+        b'!'..=b'~' => false,
+        _ => true
+    }
+}
+
+fn hex_value(bch: u8) -> Option<u8> {
+    match bch {
+        b'0'..=b'9' => Some(bch - b'0'),
+        b'A'..=b'F' => Some(bch - b'A' + 10),
+        _ => None
+    }
+}
+
+/// error returned by `xtext::decode`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// the input ended in the middle of a `"+XX"` sequence
+    Truncated,
+    /// a `'+'` was not followed by two upper case hex digits
+    InvalidHexChar,
+    /// the input contained a byte which should have been encoded
+    NotEncoded(u8),
+    /// the decoded bytes are not valid utf-8
+    InvalidUtf8
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, fter: &mut fmt::Formatter) -> fmt::Result {
+        use self::DecodeError::*;
+        match *self {
+            Truncated => write!(fter, "xtext ends in a truncated \"+XX\" sequence"),
+            InvalidHexChar => write!(fter, "xtext contains a \"+\" not followed by two upper case hex digits"),
+            NotEncoded(bch) => write!(fter, "xtext contains not encoded byte 0x{:02X}", bch),
+            InvalidUtf8 => write!(fter, "decoded xtext is not valid utf-8")
+        }
+    }
+}
+
+impl Error for DecodeError {
+    fn description(&self) -> &str {
+        "decoding xtext failed"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encodes_special_chars() {
+        assert_eq!(encode("user@example.test"), "user@example.test");
+        assert_eq!(encode("a+b=c d"), "a+2Bb+3Dc+20d");
+        assert_eq!(encode("\r\n"), "+0D+0A");
+        assert_eq!(encode("ä"), "+C3+A4");
+    }
+
+    #[test]
+    fn round_trips() {
+        for inp in &["", "simple", "a+b=c d", "tab\there", "üñí©ödé", "+++==="] {
+            assert_eq!(decode(&encode(inp)).unwrap(), *inp);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        assert_eq!(decode("abc+"), Err(DecodeError::Truncated));
+        assert_eq!(decode("abc+2"), Err(DecodeError::Truncated));
+        assert_eq!(decode("+2G"), Err(DecodeError::InvalidHexChar));
+        assert_eq!(decode("+2b"), Err(DecodeError::InvalidHexChar));
+        assert_eq!(decode("a=b"), Err(DecodeError::NotEncoded(b'=')));
+        assert_eq!(decode("a b"), Err(DecodeError::NotEncoded(b' ')));
+        assert_eq!(decode("+C3"), Err(DecodeError::InvalidUtf8));
+    }
+}
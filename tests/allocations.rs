@@ -0,0 +1,132 @@
+//FIXME see if we can put this into Cargo.toml
+#[cfg(not(feature="mock-impl"))]
+compile_error!("integration tests require \"mock-impl\" feature");
+
+extern crate new_tokio_smtp;
+extern crate futures;
+extern crate tokio;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::{self as std_io, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use futures::{Future, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use new_tokio_smtp::{command, Cmd, Connection, Io};
+use new_tokio_smtp::io::{MockStream, Socket};
+
+/// counts the allocations done through the global allocator
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const REPLY: &[u8] = b"250 Ok\r\n";
+
+/// a socket answering each command line with `REPLY` without allocating
+#[derive(Debug)]
+struct AlwaysOk {
+    pending_replies: usize,
+    reply_offset: usize
+}
+
+impl Read for AlwaysOk {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std_io::Error> {
+        if self.pending_replies == 0 {
+            return Err(std_io::ErrorKind::WouldBlock.into());
+        }
+        let rest = &REPLY[self.reply_offset..];
+        let len = rest.len().min(buf.len());
+        buf[..len].copy_from_slice(&rest[..len]);
+        self.reply_offset += len;
+        if self.reply_offset == REPLY.len() {
+            self.reply_offset = 0;
+            self.pending_replies -= 1;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for AlwaysOk {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std_io::Error> {
+        self.pending_replies += buf.iter().filter(|bch| **bch == b'\n').count();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), std_io::Error> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for AlwaysOk {}
+
+impl AsyncWrite for AlwaysOk {
+    fn shutdown(&mut self) -> Poll<(), std_io::Error> {
+        Ok(().into())
+    }
+}
+
+impl MockStream for AlwaysOk {
+    fn set_is_secure(&mut self, _secure: bool) {}
+}
+
+const COMMANDS: usize = 100;
+
+/// returns the average number of allocations of sending `NOOP` with `send_cmd`
+fn allocations_per_noop<F>(send_cmd: F) -> f64
+    where F: Fn(Connection) -> Connection
+{
+    let socket = AlwaysOk { pending_replies: 0, reply_offset: 0 };
+    let mut con = Connection::from(Io::from(Socket::Mock(Box::new(socket))));
+    // the first command might move buffers from inline storage to the heap
+    con = send_cmd(con);
+
+    let before = ALLOCATIONS.load(Ordering::SeqCst);
+    for _ in 0..COMMANDS {
+        con = send_cmd(con);
+    }
+    let allocations = ALLOCATIONS.load(Ordering::SeqCst) - before;
+
+    con.shutdown().wait().unwrap();
+    allocations as f64 / COMMANDS as f64
+}
+
+#[test]
+fn allocations_per_command() {
+    let plain = allocations_per_noop(|con| {
+        let (con, res) = con.send(command::Noop).wait().unwrap();
+        res.unwrap();
+        con
+    });
+    let boxed = allocations_per_noop(|con| {
+        let (con, res) = con.send(command::Noop.boxed()).wait().unwrap();
+        res.unwrap();
+        con
+    });
+
+    println!("allocations per NOOP: {} (boxed cmd: {})", plain, boxed);
+    // the `ExecFuture` box and the parsed `Response` (the line buffer while
+    // parsing, the message vector and its line), the I/O buffers are reused
+    assert!(plain <= 4.0, "too many allocations per command: {}", plain);
+    // boxing the command adds the box of the command itself
+    assert!(boxed <= plain + 1.0, "too many allocations per boxed command: {}", boxed);
+}
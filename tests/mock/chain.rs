@@ -2,9 +2,9 @@ use std::io as std_io;
 
 use futures::{future, Future};
 
-use new_tokio_smtp::chain::{OnError, HandleErrorInChain};
+use new_tokio_smtp::chain::{OnError, OnScriptError, HandleErrorInChain};
 use new_tokio_smtp::mock::{ ActionData, Actor};
-use new_tokio_smtp::{command, Connection};
+use new_tokio_smtp::{command, Connection, Cmd, ClientId, ReversePath, ForwardPath};
 use new_tokio_smtp::error::LogicError;
 
 
@@ -111,4 +111,78 @@ fn ignores_error_if_requested() {
         });
 
     chain.wait().unwrap();
-}
\ No newline at end of file
+}
+fn ehlo_mail_rcpt_rset_script() -> Vec<::new_tokio_smtp::BoxedCmd> {
+    vec![
+        command::Ehlo::new(ClientId::Domain("me.test".parse().unwrap())).boxed(),
+        command::Mail::new(ReversePath::from_unchecked("t1@test.test")).boxed(),
+        command::Recipient::new(ForwardPath::from_unchecked("t2@test.test")).boxed(),
+        command::Reset.boxed()
+    ]
+}
+
+#[test]
+fn runs_script_collecting_results() {
+    let con = mock(vec![
+        (Client,  Lines(vec!["EHLO me.test"])),
+        (Server,  Lines(vec!["250-they.test greets you", "250 SIZE 1000"])),
+        (Client,  Lines(vec!["MAIL FROM:<t1@test.test>"])),
+        (Server,  Lines(vec!["250 Ok"])),
+        (Client,  Lines(vec!["RCPT TO:<t2@test.test>"])),
+        (Server,  Lines(vec!["251 will forward"])),
+        (Client,  Lines(vec!["RSET"])),
+        (Server,  Lines(vec!["250 reset"])),
+    ]);
+
+    let (con, results) = con.run_script(ehlo_mail_rcpt_rset_script()).wait().unwrap();
+
+    let codes = results.iter()
+        .map(|res| res.as_ref().unwrap().code().as_u16())
+        .collect::<Vec<_>>();
+    assert_eq!(codes, vec![250, 250, 251, 250]);
+    assert!(con.has_capability("SIZE"));
+    con.shutdown().wait().unwrap();
+}
+
+#[test]
+fn script_stops_on_first_error() {
+    let con = mock(vec![
+        (Client,  Lines(vec!["EHLO me.test"])),
+        (Server,  Lines(vec!["250 they.test greets you"])),
+        (Client,  Lines(vec!["MAIL FROM:<t1@test.test>"])),
+        (Server,  Lines(vec!["550 go away"])),
+    ]);
+
+    let (con, results) = con.run_script(ehlo_mail_rcpt_rset_script()).wait().unwrap();
+
+    assert_eq!(results.len(), 2);
+    assert!(results[0].is_ok());
+    match results[1] {
+        Err(LogicError::Code(ref response)) => assert_eq!(response.code().as_u16(), 550),
+        ref other => panic!("unexpected result: {:?}", other)
+    }
+    con.shutdown().wait().unwrap();
+}
+
+#[test]
+fn script_can_continue_after_error() {
+    let con = mock(vec![
+        (Client,  Lines(vec!["EHLO me.test"])),
+        (Server,  Lines(vec!["250 they.test greets you"])),
+        (Client,  Lines(vec!["MAIL FROM:<t1@test.test>"])),
+        (Server,  Lines(vec!["250 Ok"])),
+        (Client,  Lines(vec!["RCPT TO:<t2@test.test>"])),
+        (Server,  Lines(vec!["550 no such user"])),
+        (Client,  Lines(vec!["RSET"])),
+        (Server,  Lines(vec!["250 reset"])),
+    ]);
+
+    let (con, results) = con
+        .run_script_with(ehlo_mail_rcpt_rset_script(), OnScriptError::Continue)
+        .wait()
+        .unwrap();
+
+    let ok = results.iter().map(|res| res.is_ok()).collect::<Vec<_>>();
+    assert_eq!(ok, vec![true, true, false, true]);
+    con.shutdown().wait().unwrap();
+}
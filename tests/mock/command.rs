@@ -13,7 +13,7 @@ use new_tokio_smtp::mock::{
 use self::Actor::*;
 use self::ActionData::*;
 
-use super::{mock, mock_no_shutdown};
+use super::{mock, mock_no_shutdown, secure, with_capability, with_capability_params};
 
 
 //fn server_id() -> ClientId {
@@ -58,6 +58,185 @@ mod Ehlo {
         con.shutdown().wait().unwrap();
     }
 
+    #[test]
+    fn accepts_non_ascii_greeting_text() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["EHLO me.test"])),
+            (Server,  Blob(b"250-they.test gr\xc3\xbc\xc3\x9ft dich \xfc\r\n250-smtpUTF8\r\n250 SIZE 1000\r\n".to_vec())),
+        ]);
+
+        let (con, result) = con.send(command::Ehlo::new(client_id())).wait().unwrap();
+
+        let response = result.unwrap();
+        assert_eq!(response.msg()[0], "they.test gr\u{fc}\u{df}t dich \u{fffd}");
+        assert_eq!(con.ehlo_data().unwrap().domain(), "they.test");
+        assert!(con.has_capability("SMTPUTF8"));
+        assert_eq!(con.caps().max_message_size(), Some(1000));
+
+        con.shutdown().wait().unwrap();
+    }
+
+}
+
+mod Helo {
+    use futures::Future;
+    use super::*;
+
+    #[test]
+    fn stores_ehlo_data_without_capabilities() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["HELO me.test"])),
+            (Server,  Lines(vec!["250 they.test greets you"])),
+        ]);
+        let con = with_capability(con, "SMTPUTF8");
+
+        let (con, result) = con.send(command::Helo::new(client_id())).wait().unwrap();
+
+        assert!(result.is_ok());
+        assert!(!con.has_capability("SMTPUTF8"));
+        assert!(!con.has_capability("PIPELINING"));
+        assert!(!con.caps().has_8bitmime());
+        assert_eq!(con.ehlo_data().unwrap().domain(), "they.test");
+        assert!(con.ehlo_data().unwrap().capability_map().is_empty());
+
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn accepts_reply_without_valid_domain() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["HELO me.test"])),
+            (Server,  Lines(vec!["250 [192.0.2.1]"])),
+            (Client,  Lines(vec!["NOOP"])),
+            (Server,  Lines(vec!["250 Ok"])),
+        ]);
+
+        let (con, result) = con.send(command::Helo::new(client_id())).wait().unwrap();
+
+        assert!(result.is_ok());
+        assert_eq!(con.ehlo_data().unwrap().domain(), "[192.0.2.1]");
+
+        let (con, result) = con.send(command::Noop).wait().unwrap();
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+}
+
+mod StartTls {
+    use std::sync::{Arc, Mutex};
+    use futures::Future;
+    use new_tokio_smtp::{Domain, Capabilities, CapabilitiesObserver};
+    use super::*;
+
+    #[test]
+    fn keeps_cleartext_ehlo_data_if_asked_to() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["EHLO me.test"])),
+            (Server,  Lines(vec!["250-they.test", "250-STARTTLS", "250 SIZE 1000"])),
+            (Client,  Lines(vec!["EHLO me.test"])),
+            (Server,  Lines(vec!["250-they.test", "250-AUTH PLAIN", "250 SIZE 2000"])),
+        ]);
+
+        let (mut con, result) = con.send(command::Ehlo::new(client_id())).wait().unwrap();
+        result.unwrap();
+        con.set_keep_cleartext_ehlo_data(true);
+
+        let (con, result) = con
+            .send(command::StartTls::new(Domain::from_unchecked("they.test")))
+            .and_then(|(con, result)| {
+                result.unwrap();
+                con.send(command::Ehlo::new(client_id()))
+            })
+            .wait().unwrap();
+        result.unwrap();
+
+        let cleartext = con.cleartext_ehlo_data().unwrap();
+        assert!(cleartext.has_capability("STARTTLS"));
+        assert!(!cleartext.has_capability("AUTH"));
+        assert_eq!(cleartext.caps().max_message_size(), Some(1000));
+
+        let authoritative = con.ehlo_data().unwrap();
+        assert!(!authoritative.has_capability("STARTTLS"));
+        assert!(authoritative.has_capability("AUTH"));
+        assert_eq!(con.caps().max_message_size(), Some(2000));
+
+        con.shutdown().wait().unwrap();
+    }
+
+    /// records the `(old, new)` capabilities of each notification
+    #[derive(Debug, Default)]
+    struct RecordingObserver {
+        changes: Mutex<Vec<(Capabilities, Capabilities)>>
+    }
+
+    impl CapabilitiesObserver for RecordingObserver {
+        fn capabilities_changed(&self, old: &Capabilities, new: &Capabilities) {
+            self.changes.lock().unwrap().push((old.clone(), new.clone()));
+        }
+    }
+
+    #[test]
+    fn capabilities_observer_sees_change_through_starttls() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["EHLO me.test"])),
+            (Server,  Lines(vec!["250-they.test", "250-STARTTLS", "250 SIZE 1000"])),
+            (Client,  Lines(vec!["EHLO me.test"])),
+            (Server,  Lines(vec!["250-they.test", "250-AUTH PLAIN", "250 SIZE 2000"])),
+        ]);
+        let observer = Arc::new(RecordingObserver::default());
+        let mut con = con;
+        con.set_capabilities_observer(Some(observer.clone()));
+
+        let (con, result) = con.send(command::Ehlo::new(client_id()))
+            .and_then(|(con, result)| {
+                result.unwrap();
+                con.send(command::StartTls::new(Domain::from_unchecked("they.test")))
+            })
+            .and_then(|(con, result)| {
+                result.unwrap();
+                con.send(command::Ehlo::new(client_id()))
+            })
+            .wait().unwrap();
+        result.unwrap();
+
+        let changes = observer.changes.lock().unwrap();
+        assert_eq!(changes.len(), 2);
+
+        let (ref before_ehlo, ref cleartext) = changes[0];
+        assert!(!before_ehlo.has_starttls());
+        assert!(cleartext.has_starttls());
+        assert_eq!(cleartext.max_message_size(), Some(1000));
+
+        let (ref old, ref new) = changes[1];
+        assert_eq!(old, cleartext);
+        assert!(!new.has_starttls());
+        assert!(new.supports_auth_mechanism("PLAIN"));
+        assert_eq!(new.max_message_size(), Some(2000));
+
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn does_not_keep_cleartext_ehlo_data_by_default() {
+        let con = with_capability(mock(vec![
+            (Client,  Lines(vec!["EHLO me.test"])),
+            (Server,  Lines(vec!["250 they.test"])),
+        ]), "STARTTLS");
+
+        let (con, result) = con
+            .send(command::StartTls::new(Domain::from_unchecked("they.test")))
+            .and_then(|(con, result)| {
+                result.unwrap();
+                con.send(command::Ehlo::new(client_id()))
+            })
+            .wait().unwrap();
+        result.unwrap();
+
+        assert!(con.cleartext_ehlo_data().is_none());
+        assert!(con.ehlo_data().is_some());
+
+        con.shutdown().wait().unwrap();
+    }
 }
 
 mod Reset {
@@ -85,13 +264,1398 @@ mod Reset {
 }
 
 mod Data {
-    //TODO test
+    use std::{io as std_io};
+    use std::time::Duration;
+    use futures::Future;
+    use new_tokio_smtp::io::{LineLengthMode, BareLineEndingMode};
+    use new_tokio_smtp::command::BodyMode;
+    use new_tokio_smtp::error::LogicError;
+    use super::*;
+
+    #[test]
+    fn sends_data_after_intermediate_reply() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["DATA"])),
+            (Server,  Lines(vec!["354 go ahead"])),
+            (Client,  Blob(Vec::from("the data\r\n.\r\n".to_owned()))),
+            (Server,  Lines(vec!["250 Ok"])),
+        ]);
+
+        let (con, result) = con
+            .send(command::Data::from_buf("the data\r\n"))
+            .wait()
+            .unwrap();
+
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn accepts_multi_line_354_reply() {
+        // the mock panics if the data is written before all reply lines are read
+        let con = mock(vec![
+            (Client,  Lines(vec!["DATA"])),
+            (Server,  Lines(vec!["354-Start mail input", "354-some more text", "354 end with <CRLF>.<CRLF>"])),
+            (Client,  Blob(Vec::from("the data\r\n.\r\n".to_owned()))),
+            (Server,  Lines(vec!["250 Ok"])),
+        ]);
+
+        let (con, result) = con
+            .send(command::Data::from_buf("the data\r\n"))
+            .wait()
+            .unwrap();
+
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn accepts_354_reply_with_unusual_text() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["DATA"])),
+            (Server,  Lines(vec!["354 "])),
+            (Client,  Blob(Vec::from("the data\r\n.\r\n".to_owned()))),
+            (Server,  Lines(vec!["250 Ok"])),
+            (Client,  Lines(vec!["DATA"])),
+            (Server,  Lines(vec!["354 Feed me 250 OK, go on"])),
+            (Client,  Blob(Vec::from("more data\r\n.\r\n".to_owned()))),
+            (Server,  Lines(vec!["250 Ok"])),
+        ]);
+
+        let (con, result) = con
+            .send(command::Data::from_buf("the data\r\n"))
+            .wait()
+            .unwrap();
+        assert!(result.is_ok());
+
+        let (con, result) = con
+            .send(command::Data::from_buf("more data\r\n"))
+            .wait()
+            .unwrap();
+        assert!(result.is_ok());
+
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn does_not_send_data_after_other_intermediate_code() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["DATA"])),
+            (Server,  Lines(vec!["334 go ahead"])),
+        ]);
+
+        let (con, result) = con
+            .send(command::Data::from_buf("the data\r\n"))
+            .wait()
+            .unwrap();
+
+        match result {
+            Err(LogicError::UnexpectedCode(response)) => {
+                assert_eq!(response.code().as_byte_string(), *b"334");
+            },
+            other => panic!("unexpected result: {:?}", other)
+        }
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn liveness_check_sends_noop_before_data() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["NOOP"])),
+            (Server,  Lines(vec!["250 Ok"])),
+            (Client,  Lines(vec!["DATA"])),
+            (Server,  Lines(vec!["354 go ahead"])),
+            (Client,  Blob(Vec::from("the data\r\n.\r\n".to_owned()))),
+            (Server,  Lines(vec!["250 Ok"])),
+        ]);
+
+        let data = command::Data::from_buf("the data\r\n")
+            .with_liveness_check(Duration::from_secs(0));
+        let (con, result) = con.send(data).wait().unwrap();
+
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn liveness_check_detects_dead_connection_before_data() {
+        // the mock panics if anything (e.g. `DATA`) is written after the `NOOP`
+        let con = mock_no_shutdown(vec![
+            (Client,  Lines(vec!["NOOP"])),
+            (Server,  Lines(vec!["421 4.4.2 connection timed out"])),
+        ]);
+
+        let data = command::Data::from_buf("the data\r\n")
+            .with_liveness_check(Duration::from_secs(0));
+        let (con, result) = con.send(data).wait().unwrap();
+
+        match result {
+            Err(LogicError::ServiceClosing(_)) => (),
+            other => panic!("unexpected result: {:?}", other)
+        }
+        assert!(con.is_service_closed());
+    }
+
+    #[test]
+    fn liveness_check_is_skipped_after_recent_activity() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["DATA"])),
+            (Server,  Lines(vec!["354 go ahead"])),
+            (Client,  Blob(Vec::from("the data\r\n.\r\n".to_owned()))),
+            (Server,  Lines(vec!["250 Ok"])),
+        ]);
+
+        let data = command::Data::from_buf("the data\r\n")
+            .with_liveness_check(Duration::from_secs(3600));
+        let (con, result) = con.send(data).wait().unwrap();
+
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+
+    fn long_line(length: usize) -> String {
+        (0..length).map(|idx| (b'a' + (idx % 26) as u8) as char).collect()
+    }
+
+    #[test]
+    fn folds_too_long_lines() {
+        let line = long_line(1200);
+        let expected = format!("{}\r\n{}\r\n.\r\n", &line[..998], &line[998..]);
+        let con = mock(vec![
+            (Client,  Lines(vec!["DATA"])),
+            (Server,  Lines(vec!["354 go ahead"])),
+            (Client,  Blob(expected.into_bytes())),
+            (Server,  Lines(vec!["250 Ok"])),
+        ]);
+
+        let data = command::Data::from_buf(format!("{}\r\n", line))
+            .with_line_length_mode(LineLengthMode::Fold);
+        let (con, result) = con.send(data).wait().unwrap();
+
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn rejects_too_long_lines() {
+        let con = mock_no_shutdown(vec![
+            (Client,  Lines(vec!["DATA"])),
+            (Server,  Lines(vec!["354 go ahead"])),
+        ]);
+
+        let data = command::Data::from_buf(format!("{}\r\n", long_line(999)))
+            .with_line_length_mode(LineLengthMode::Reject);
+        let res = con.send(data).wait();
+
+        match res {
+            Err(err) => assert_eq!(err.kind(), std_io::ErrorKind::InvalidData),
+            Ok(_) => panic!("unexpected success")
+        }
+    }
+
+    #[test]
+    fn accepts_lines_with_maximal_length() {
+        let line = long_line(998);
+        let con = mock(vec![
+            (Client,  Lines(vec!["DATA"])),
+            (Server,  Lines(vec!["354 go ahead"])),
+            (Client,  Blob(format!("{}\r\n.\r\n", line).into_bytes())),
+            (Server,  Lines(vec!["250 Ok"])),
+        ]);
+
+        let data = command::Data::from_buf(format!("{}\r\n", line))
+            .with_line_length_mode(LineLengthMode::Reject);
+        let (con, result) = con.send(data).wait().unwrap();
+
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn normalizes_bare_line_endings() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["DATA"])),
+            (Server,  Lines(vec!["354 go ahead"])),
+            (Client,  Blob(Vec::from("bare lf\r\n..cr\r\n..dot\r\nend\r\n.\r\n".to_owned()))),
+            (Server,  Lines(vec!["250 Ok"])),
+        ]);
+
+        let data = command::Data::from_buf("bare lf\n.cr\r.dot\r\nend\r");
+        let (con, result) = con.send(data).wait().unwrap();
+
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+
+    fn assert_rejects_bare_line_ending(data: &'static str, send_before_error: &str) {
+        let mut conversation = vec![
+            (Client,  Lines(vec!["DATA"])),
+            (Server,  Lines(vec!["354 go ahead"])),
+        ];
+        if !send_before_error.is_empty() {
+            conversation.push((Client, Blob(Vec::from(send_before_error.to_owned()))));
+        }
+        let con = mock_no_shutdown(conversation);
+
+        let data = command::Data::from_buf(data)
+            .with_bare_line_ending_mode(BareLineEndingMode::Reject);
+        let res = con.send(data).wait();
+
+        match res {
+            Err(err) => assert_eq!(err.kind(), std_io::ErrorKind::InvalidData),
+            Ok(_) => panic!("unexpected success")
+        }
+    }
+
+    #[test]
+    fn rejects_bare_lf() {
+        assert_rejects_bare_line_ending("bare\nlf\r\n", "");
+    }
+
+    #[test]
+    fn rejects_bare_cr() {
+        assert_rejects_bare_line_ending("bare\rcr\r\n", "");
+    }
+
+    #[test]
+    fn rejects_bare_cr_at_end_of_data() {
+        // the data before the '\r' is already send when the end of the data is reached
+        assert_rejects_bare_line_ending("bare cr\r", "bare cr");
+    }
+
+    #[test]
+    fn reports_progress_of_bytes_stream() {
+        use std::sync::{Arc, Mutex};
+        use bytes::Bytes;
+        use futures::stream;
+
+        let con = mock(vec![
+            (Client,  Lines(vec!["DATA"])),
+            (Server,  Lines(vec!["354 go ahead"])),
+            (Client,  Blob(Vec::from("line one\r\n..dot line\r\nend\r\n.\r\n".to_owned()))),
+            (Server,  Lines(vec!["250 Ok"])),
+        ]);
+
+        // the dot to stash directly follows the chunk boundary
+        let chunks = vec!["line one\r", "\n.dot line\r\n", "end\r\n"];
+        let body_size = chunks.iter().map(|chunk| chunk.len() as u64).sum::<u64>();
+        let source = stream::iter_ok(chunks.into_iter().map(Bytes::from));
+
+        let totals = Arc::new(Mutex::new(Vec::new()));
+        let totals2 = totals.clone();
+        let data = command::Data::from_bytes_stream(source)
+            .with_progress(move |total| totals2.lock().unwrap().push(total));
+        let (con, result) = con.send(data).wait().unwrap();
+
+        assert!(result.is_ok());
+        let totals = totals.lock().unwrap().clone();
+        assert_eq!(totals, vec![9, 21, body_size]);
+        assert!(totals.windows(2).all(|pair| pair[0] < pair[1]));
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn aborting_mid_stream_poisons_connection() {
+        use std::sync::{Arc, Mutex};
+        use bytes::Bytes;
+        use futures::{stream, Async};
+        use new_tokio_smtp::io::DataAbortHandle;
+
+        // no end of mail sequence is send after the first chunk
+        let con = mock(vec![
+            (Client,  Lines(vec!["DATA"])),
+            (Server,  Lines(vec!["354 go ahead"])),
+            (Client,  Blob(Vec::from("first part\r\n".to_owned()))),
+        ]);
+
+        let handle_slot = Arc::new(Mutex::new(None::<DataAbortHandle>));
+        let handle_slot2 = handle_slot.clone();
+        let mut chunks = vec![Bytes::from("first part\r\n")];
+        // the source fails to produce the second chunk and aborts the transfer
+        let source = stream::poll_fn(move || -> Result<_, std_io::Error> {
+            if let Some(chunk) = chunks.pop() {
+                return Ok(Async::Ready(Some(chunk)));
+            }
+            if let Some(handle) = handle_slot2.lock().unwrap().take() {
+                handle.abort();
+            }
+            Ok(Async::NotReady)
+        });
+
+        let (data, handle) = command::Data::from_bytes_stream(source).abortable();
+        *handle_slot.lock().unwrap() = Some(handle);
+        let (con, result) = con.send(data).wait().unwrap();
+
+        match result {
+            Err(LogicError::Aborted) => (),
+            other => panic!("unexpected result: {:?}", other)
+        }
+        assert!(con.is_poisoned());
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn sends_8bit_data_in_8bitmime_mode() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["DATA"])),
+            (Server,  Lines(vec!["354 go ahead"])),
+            (Client,  Blob(Vec::from("gr\u{fc}\u{df}e\r\n.\r\n".to_owned()))),
+            (Server,  Lines(vec!["250 Ok"])),
+        ]);
+        let con = with_capability(con, "8BITMIME");
+
+        let data = command::Data::from_buf("gr\u{fc}\u{df}e\r\n")
+            .with_body_mode(BodyMode::EightBitMime);
+        let (con, result) = con.send(data).wait().unwrap();
+
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn rejects_8bitmime_mode_if_not_supported() {
+        let con = mock(vec![]);
+
+        let data = command::Data::from_buf("gr\u{fc}\u{df}e\r\n")
+            .with_body_mode(BodyMode::EightBitMime);
+        let (con, result) = con.send(data).wait().unwrap();
+
+        match result {
+            Err(LogicError::MissingCapabilities(_)) => (),
+            other => panic!("unexpected result: {:?}", other)
+        }
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn rejects_8bit_data_in_7bit_mode() {
+        let con = mock_no_shutdown(vec![
+            (Client,  Lines(vec!["DATA"])),
+            (Server,  Lines(vec!["354 go ahead"])),
+        ]);
+
+        let data = command::Data::from_buf("gr\u{fc}\u{df}e\r\n")
+            .with_body_mode(BodyMode::SevenBit);
+        let res = con.send(data).wait();
+
+        match res {
+            Err(err) => assert_eq!(err.kind(), std_io::ErrorKind::InvalidData),
+            Ok(_) => panic!("unexpected success")
+        }
+    }
+
+    #[test]
+    fn does_not_send_data_on_non_intermediate_reply() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["DATA"])),
+            (Server,  Lines(vec!["250 Ok"])),
+        ]);
+
+        let (con, result) = con
+            .send(command::Data::from_buf("the data\r\n"))
+            .wait()
+            .unwrap();
+
+        match result {
+            Err(LogicError::UnexpectedCode(response)) => assert_eq!(response.code().as_byte_string(), *b"250"),
+            other => panic!("unexpected result: {:?}", other)
+        }
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn does_not_send_data_on_error_reply() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["DATA"])),
+            (Server,  Lines(vec!["554 no valid recipients"])),
+        ]);
+
+        let (con, result) = con
+            .send(command::Data::from_buf("the data\r\n"))
+            .wait()
+            .unwrap();
+
+        match result {
+            Err(LogicError::Code(response)) => assert_eq!(response.code().as_byte_string(), *b"554"),
+            other => panic!("unexpected result: {:?}", other)
+        }
+        con.shutdown().wait().unwrap();
+    }
 }
 
 mod Mail {
-    //TODO test
-}
+    use std::time::{Duration, UNIX_EPOCH};
+    use futures::Future;
+    use new_tokio_smtp::{Connection, ReversePath};
+    use new_tokio_smtp::command::{MailAuth, DsnReturn, BodyMode, FutureRelease, DeliverBy, DeliverByMode};
+    use new_tokio_smtp::error::LogicError;
+    use super::*;
 
-mod Recipient {
-    //todo test
+    fn mail_from() -> command::Mail {
+        command::Mail::new(ReversePath::from_unchecked("t1@test.test"))
+    }
+
+    #[test]
+    fn sends_xtext_encoded_auth_param() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["MAIL FROM:<t1@test.test> AUTH=us+2Ber+3D1+20@test.test"])),
+            (Server,  Lines(vec!["250 Ok"])),
+        ]);
+        let con = with_capability(con, "AUTH");
+
+        let mail = mail_from().with_auth(MailAuth::Identity("us+er=1 @test.test".to_owned()));
+        let (con, result) = con.send(mail).wait().unwrap();
+
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn sends_unknown_auth_param() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["MAIL FROM:<t1@test.test> AUTH=<>"])),
+            (Server,  Lines(vec!["250 Ok"])),
+        ]);
+        let con = with_capability(con, "AUTH");
+
+        let (con, result) = con.send(mail_from().with_auth(MailAuth::Unknown)).wait().unwrap();
+
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn sends_dsn_params() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["MAIL FROM:<t1@test.test> RET=HDRS ENVID=id+2B1+3Dx+20y"])),
+            (Server,  Lines(vec!["250 Ok"])),
+        ]);
+        let con = with_capability(con, "DSN");
+
+        let mail = mail_from()
+            .with_dsn_return(DsnReturn::Headers)
+            .with_envelop_id("id+1=x y");
+        let (con, result) = con.send(mail).wait().unwrap();
+
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn omits_dsn_params_if_not_supported() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["MAIL FROM:<t1@test.test> AUTH=<>"])),
+            (Server,  Lines(vec!["250 Ok"])),
+        ]);
+        let con = with_capability(con, "AUTH");
+
+        let mail = mail_from()
+            .with_auth(MailAuth::Unknown)
+            .with_dsn_return(DsnReturn::Full)
+            .with_envelop_id("id1");
+        let (con, result) = con.send(mail).wait().unwrap();
+
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn omits_auth_param_if_not_supported() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["MAIL FROM:<t1@test.test>"])),
+            (Server,  Lines(vec!["250 Ok"])),
+        ]);
+
+        let (con, result) = con.send(mail_from().with_auth(MailAuth::Unknown)).wait().unwrap();
+
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn sends_8bitmime_body_param() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["MAIL FROM:<t1@test.test> BODY=8BITMIME"])),
+            (Server,  Lines(vec!["250 Ok"])),
+        ]);
+        let con = with_capability(con, "8BITMIME");
+
+        let (con, result) = con.send(mail_from().with_body(BodyMode::EightBitMime)).wait().unwrap();
+
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn rejects_8bitmime_body_if_not_supported() {
+        let con = mock(vec![]);
+
+        let (con, result) = con.send(mail_from().with_body(BodyMode::EightBitMime)).wait().unwrap();
+
+        match result {
+            Err(LogicError::MissingCapabilities(err)) => {
+                assert_eq!(err.capabilities()[0].as_str(), "8BITMIME");
+            },
+            other => panic!("unexpected result: {:?}", other)
+        }
+        con.shutdown().wait().unwrap();
+    }
+
+    fn with_future_release(con: Connection) -> Connection {
+        with_capability_params(con, "FUTURERELEASE", &["604800", "2030-01-01T00:00:00Z"])
+    }
+
+    #[test]
+    fn sends_hold_for_param() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["MAIL FROM:<t1@test.test> HOLDFOR=3600"])),
+            (Server,  Lines(vec!["250 Ok"])),
+        ]);
+        let con = with_future_release(con);
+        assert_eq!(con.ehlo_data().unwrap().max_future_release_interval(), Some(Duration::from_secs(604800)));
+
+        let mail = mail_from().with_future_release(FutureRelease::HoldFor(Duration::from_secs(3600)));
+        let (con, result) = con.send(mail).wait().unwrap();
+
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn sends_hold_until_param() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["MAIL FROM:<t1@test.test> HOLDUNTIL=2018-06-14T11:22:18Z"])),
+            (Server,  Lines(vec!["250 Ok"])),
+        ]);
+        let con = with_future_release(con);
+
+        let until = UNIX_EPOCH + Duration::from_secs(1528975338);
+        let (con, result) = con.send(mail_from().with_future_release(FutureRelease::HoldUntil(until)))
+            .wait().unwrap();
+
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn rejects_future_release_if_not_supported() {
+        let con = mock(vec![]);
+
+        let mail = mail_from().with_future_release(FutureRelease::HoldFor(Duration::from_secs(60)));
+        let (con, result) = con.send(mail).wait().unwrap();
+
+        match result {
+            Err(LogicError::MissingCapabilities(err)) => {
+                assert_eq!(err.capabilities()[0].as_str(), "FUTURERELEASE");
+            },
+            other => panic!("unexpected result: {:?}", other)
+        }
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn rejects_hold_exceeding_max_hold_time() {
+        let con = with_future_release(mock(vec![]));
+
+        let requested = Duration::from_secs(604801);
+        let mail = mail_from().with_future_release(FutureRelease::HoldFor(requested));
+        let (con, result) = con.send(mail).wait().unwrap();
+
+        match result {
+            Err(LogicError::HoldTooLong { requested: got, limit }) => {
+                assert_eq!(got, requested);
+                assert_eq!(limit, Duration::from_secs(604800));
+            },
+            other => panic!("unexpected result: {:?}", other)
+        }
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn sends_requiretls_param() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["MAIL FROM:<t1@test.test> REQUIRETLS"])),
+            (Server,  Lines(vec!["250 Ok"])),
+        ]);
+        let con = with_capability(secure(con), "REQUIRETLS");
+        assert!(con.ehlo_data().unwrap().supports_requiretls());
+
+        let (con, result) = con.send(mail_from().with_require_tls()).wait().unwrap();
+
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn does_not_send_requiretls_param_if_not_requested() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["MAIL FROM:<t1@test.test>"])),
+            (Server,  Lines(vec!["250 Ok"])),
+        ]);
+        let con = with_capability(con, "REQUIRETLS");
+
+        let (con, result) = con.send(mail_from()).wait().unwrap();
+
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn rejects_requiretls_over_unencrypted_connection() {
+        let con = with_capability(mock(vec![]), "REQUIRETLS");
+        assert!(con.ehlo_data().unwrap().supports_requiretls());
+        assert!(!con.is_secure());
+
+        let (con, result) = con.send(mail_from().with_require_tls()).wait().unwrap();
+
+        match result {
+            Err(LogicError::InsecureRequireTls) => (),
+            other => panic!("unexpected result: {:?}", other)
+        }
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn rejects_requiretls_if_not_supported() {
+        let con = with_capability(mock(vec![]), "8BITMIME");
+        assert!(!con.ehlo_data().unwrap().supports_requiretls());
+
+        let (con, result) = con.send(mail_from().with_require_tls()).wait().unwrap();
+
+        match result {
+            Err(LogicError::MissingCapabilities(err)) => {
+                assert_eq!(err.capabilities()[0].as_str(), "REQUIRETLS");
+            },
+            other => panic!("unexpected result: {:?}", other)
+        }
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn sends_mt_priority_param() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["MAIL FROM:<t1@test.test> MT-PRIORITY=-3"])),
+            (Server,  Lines(vec!["250 Ok"])),
+        ]);
+        let con = with_capability_params(con, "MT-PRIORITY", &["STANAG4406"]);
+        assert_eq!(con.ehlo_data().unwrap().mt_priority_profile(), Some("STANAG4406"));
+
+        let (con, result) = con.send(mail_from().with_mt_priority(-3)).wait().unwrap();
+
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn does_not_send_mt_priority_param_if_not_supported() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["MAIL FROM:<t1@test.test>"])),
+            (Server,  Lines(vec!["250 Ok"])),
+        ]);
+        let con = with_capability(con, "8BITMIME");
+        assert_eq!(con.ehlo_data().unwrap().mt_priority_profile(), None);
+
+        let (con, result) = con.send(mail_from().with_mt_priority(9)).wait().unwrap();
+
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn rejects_out_of_range_mt_priority() {
+        for &priority in &[10, -10, i8::MAX, i8::MIN] {
+            let con = with_capability(mock(vec![]), "MT-PRIORITY");
+            assert_eq!(con.ehlo_data().unwrap().mt_priority_profile(), None);
+
+            let (con, result) = con.send(mail_from().with_mt_priority(priority)).wait().unwrap();
+
+            match result {
+                Err(LogicError::PriorityOutOfRange { priority: got }) => assert_eq!(got, priority),
+                other => panic!("unexpected result: {:?}", other)
+            }
+            con.shutdown().wait().unwrap();
+        }
+    }
+
+    #[test]
+    fn sends_deliver_by_param() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["MAIL FROM:<t1@test.test> BY=3600;R"])),
+            (Server,  Lines(vec!["250 Ok"])),
+            (Client,  Lines(vec!["MAIL FROM:<t1@test.test> BY=-60;NT"])),
+            (Server,  Lines(vec!["250 Ok"])),
+        ]);
+        let con = with_capability_params(con, "DELIVERBY", &["240"]);
+        assert_eq!(con.ehlo_data().unwrap().min_deliver_by_time(), Some(Duration::from_secs(240)));
+
+        let mail = mail_from().with_deliver_by(DeliverBy::new(3600, DeliverByMode::Return));
+        let (con, result) = con.send(mail).wait().unwrap();
+        assert!(result.is_ok());
+
+        let mail = mail_from().with_deliver_by(DeliverBy::new(-60, DeliverByMode::Notify).with_trace());
+        let (con, result) = con.send(mail).wait().unwrap();
+        assert!(result.is_ok());
+
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_deliver_by_time_without_sending() {
+        let con = with_capability_params(mock(vec![]), "DELIVERBY", &["240"]);
+
+        let invalid = vec![
+            DeliverBy::new(0, DeliverByMode::Return),
+            DeliverBy::new(-60, DeliverByMode::Return),
+            DeliverBy::new(120, DeliverByMode::Return),
+            DeliverBy::new(1_000_000_000, DeliverByMode::Return),
+            DeliverBy::new(-1_000_000_000, DeliverByMode::Notify),
+        ];
+
+        let mut con = con;
+        for deliver_by in invalid {
+            let (new_con, result) = con.send(mail_from().with_deliver_by(deliver_by)).wait().unwrap();
+            match result {
+                Err(LogicError::InvalidDeliverBy { by_time }) => assert_eq!(by_time, deliver_by.by_time),
+                other => panic!("unexpected result for {:?}: {:?}", deliver_by, other)
+            }
+            con = new_con;
+        }
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn validates_deliver_by_time() {
+        let min = Some(Duration::from_secs(240));
+        assert!(DeliverBy::new(240, DeliverByMode::Return).is_valid(min));
+        assert!(!DeliverBy::new(239, DeliverByMode::Return).is_valid(min));
+        assert!(DeliverBy::new(1, DeliverByMode::Return).is_valid(None));
+        assert!(!DeliverBy::new(0, DeliverByMode::Return).is_valid(None));
+        assert!(DeliverBy::new(0, DeliverByMode::Notify).is_valid(min));
+        assert!(DeliverBy::new(-999_999_999, DeliverByMode::Notify).is_valid(min));
+        assert!(DeliverBy::new(999_999_999, DeliverByMode::Return).is_valid(min));
+        assert!(!DeliverBy::new(1_000_000_000, DeliverByMode::Notify).is_valid(None));
+    }
+
+    #[test]
+    fn deliver_by_requires_capability() {
+        let con = with_capability(mock(vec![]), "8BITMIME");
+        assert_eq!(con.ehlo_data().unwrap().min_deliver_by_time(), None);
+
+        let mail = mail_from().with_deliver_by(DeliverBy::new(3600, DeliverByMode::Return));
+        let (con, result) = con.send(mail).wait().unwrap();
+
+        match result {
+            Err(LogicError::MissingCapabilities(err)) => {
+                assert_eq!(err.capabilities()[0].as_str(), "DELIVERBY");
+            },
+            other => panic!("unexpected result: {:?}", other)
+        }
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn maps_expired_deliver_by_time_to_error() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["MAIL FROM:<t1@test.test> BY=60;R"])),
+            (Server,  Lines(vec!["554 5.4.7 Delivery time expired"])),
+        ]);
+        let con = with_capability(con, "DELIVERBY");
+
+        let mail = mail_from().with_deliver_by(DeliverBy::new(60, DeliverByMode::Return));
+        let (con, result) = con.send(mail).wait().unwrap();
+
+        match result {
+            Err(LogicError::DeliverByFailed(response)) => {
+                assert_eq!(response.code().as_byte_string(), *b"554");
+                assert_eq!(response.enhanced_status_code(), Some("5.4.7"));
+            },
+            other => panic!("unexpected result: {:?}", other)
+        }
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn other_deliver_by_failures_are_not_mapped() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["MAIL FROM:<t1@test.test> BY=60;R"])),
+            (Server,  Lines(vec!["501 5.5.4 BY time too short"])),
+            (Client,  Lines(vec!["MAIL FROM:<t1@test.test> BY=60;R"])),
+            (Server,  Lines(vec!["454 4.4.7 Delivery time expired"])),
+        ]);
+        let con = with_capability(con, "DELIVERBY");
+
+        let deliver_by = DeliverBy::new(60, DeliverByMode::Return);
+
+        let (con, result) = con.send(mail_from().with_deliver_by(deliver_by)).wait().unwrap();
+        match result {
+            Err(LogicError::Code(response)) => assert_eq!(response.code().as_byte_string(), *b"501"),
+            other => panic!("unexpected result: {:?}", other)
+        }
+
+        let (con, result) = con.send(mail_from().with_deliver_by(deliver_by)).wait().unwrap();
+        match result {
+            Err(LogicError::Code(response)) => assert_eq!(response.code().as_byte_string(), *b"454"),
+            other => panic!("unexpected result: {:?}", other)
+        }
+        con.shutdown().wait().unwrap();
+    }
+}
+
+mod Bdat {
+    use futures::Future;
+    use new_tokio_smtp::error::LogicError;
+    use super::*;
+
+    #[test]
+    fn sends_chunks_at_split_points() {
+        let con = mock(vec![
+            (Client,  Blob(b"BDAT 5\r\npart1".to_vec())),
+            (Server,  Lines(vec!["250 5 octets received"])),
+            (Client,  Blob(b"BDAT 12\r\nsecond part2".to_vec())),
+            (Server,  Lines(vec!["250 12 octets received"])),
+            (Client,  Blob(b"BDAT 3 LAST\r\nend".to_vec())),
+            (Server,  Lines(vec!["250 Message accepted"])),
+        ]);
+        let con = with_capability(con, "CHUNKING");
+
+        let bdat = command::Bdat::new("part1second part2end")
+            .with_split_points(vec![5, 17])
+            .unwrap();
+        assert_eq!(bdat.chunk_sizes(), vec![5, 12, 3]);
+
+        let (con, result) = con.send(bdat).wait().unwrap();
+
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn stops_after_rejected_chunk() {
+        let con = mock(vec![
+            (Client,  Blob(b"BDAT 5\r\npart1".to_vec())),
+            (Server,  Lines(vec!["552 too much mail data"])),
+        ]);
+        let con = with_capability(con, "CHUNKING");
+
+        let bdat = command::Bdat::new("part1part2").with_chunk_size(5);
+        let (con, result) = con.send(bdat).wait().unwrap();
+
+        match result {
+            Err(LogicError::Code(response)) => assert_eq!(response.code().as_byte_string(), *b"552"),
+            other => panic!("unexpected result: {:?}", other)
+        }
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn requires_chunking() {
+        let con = mock(vec![]);
+
+        let (con, result) = con.send(command::Bdat::new("data")).wait().unwrap();
+
+        match result {
+            Err(LogicError::MissingCapabilities(err)) => {
+                assert_eq!(err.capabilities()[0].as_str(), "CHUNKING");
+            },
+            other => panic!("unexpected result: {:?}", other)
+        }
+        con.shutdown().wait().unwrap();
+    }
+}
+
+mod Burl {
+    use futures::Future;
+    use new_tokio_smtp::error::LogicError;
+    use super::*;
+
+    #[test]
+    fn sends_url_with_last() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["BURL imap://joe@example.com/Drafts;UIDVALIDITY=385759045/;UID=20;urlauth=submit+joe:internal:91354a473744909de610943775f92038 LAST"])),
+            (Server,  Lines(vec!["250 2.5.0 Ok"])),
+        ]);
+        let con = with_capability_params(con, "BURL", &["imap"]);
+
+        let cmd = command::Burl::new("imap://joe@example.com/Drafts;UIDVALIDITY=385759045/;UID=20;urlauth=submit+joe:internal:91354a473744909de610943775f92038");
+        let (con, result) = con.send(cmd).wait().unwrap();
+
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn requires_burl() {
+        let con = mock(vec![]);
+
+        let (con, result) = con.send(command::Burl::new("imap://example.com/x")).wait().unwrap();
+
+        match result {
+            Err(LogicError::MissingCapabilities(err)) => {
+                assert_eq!(err.capabilities()[0].as_str(), "BURL");
+            },
+            other => panic!("unexpected result: {:?}", other)
+        }
+        con.shutdown().wait().unwrap();
+    }
+}
+
+mod Recipient {
+    //todo test
+}
+
+mod SendFrom {
+    use futures::Future;
+    use new_tokio_smtp::ReversePath;
+    use new_tokio_smtp::error::LogicError;
+    use new_tokio_smtp::response::codes;
+    use super::*;
+
+    fn send_from() -> command::SendFrom {
+        command::SendFrom::new(ReversePath::from_unchecked("t1@test.test"))
+    }
+
+    #[test]
+    fn sends_send_from() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["SEND FROM:<t1@test.test>"])),
+            (Server,  Lines(vec!["250 Ok"])),
+        ]);
+
+        let (con, result) = con.send(send_from()).wait().unwrap();
+
+        assert_eq!(result.unwrap().code(), codes::OK);
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn reports_not_implemented() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["SEND FROM:<t1@test.test>"])),
+            (Server,  Lines(vec!["502 Command not implemented"])),
+        ]);
+
+        let (con, result) = con.send(send_from()).wait().unwrap();
+
+        match result {
+            Err(LogicError::Code(response)) => assert_eq!(response.code().as_u16(), 502),
+            other => panic!("unexpected result: {:?}", other)
+        }
+        con.shutdown().wait().unwrap();
+    }
+}
+
+mod Plain {
+    use futures::Future;
+    use new_tokio_smtp::command::auth::Plain;
+    use new_tokio_smtp::error::LogicError;
+    use super::*;
+
+    fn plain() -> Plain {
+        Plain::from_username("user", "password").unwrap()
+    }
+
+    #[test]
+    fn refuses_auth_over_plaintext() {
+        let con = mock(vec![]);
+        let con = with_capability_params(con, "AUTH", &["PLAIN"]);
+
+        let (con, result) = con.send(plain()).wait().unwrap();
+
+        match result {
+            Err(LogicError::InsecureAuth) => (),
+            other => panic!("unexpected result: {:?}", other)
+        }
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn sends_over_plaintext_if_allowed() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["AUTH PLAIN dXNlcgB1c2VyAHBhc3N3b3Jk"])),
+            (Server,  Lines(vec!["235 Authentication successful"])),
+        ]);
+        let mut con = with_capability_params(con, "AUTH", &["PLAIN"]);
+        con.set_allow_insecure_auth(true);
+
+        let (con, result) = con.send(plain()).wait().unwrap();
+
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn maps_encryption_required_to_insecure_auth() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["AUTH PLAIN dXNlcgB1c2VyAHBhc3N3b3Jk"])),
+            (Server,  Lines(vec!["538 5.7.11 Encryption required for requested authentication mechanism"])),
+        ]);
+        let mut con = with_capability_params(con, "AUTH", &["PLAIN"]);
+        con.set_allow_insecure_auth(true);
+
+        let (con, result) = con.send(plain()).wait().unwrap();
+
+        match result {
+            Err(LogicError::InsecureAuth) => (),
+            other => panic!("unexpected result: {:?}", other)
+        }
+        con.shutdown().wait().unwrap();
+    }
+}
+
+mod Sasl {
+    use std::error::Error;
+    use futures::Future;
+    use new_tokio_smtp::command::auth::{InvalidMechanismName, Sasl, SaslMechanism};
+    use new_tokio_smtp::error::LogicError;
+    use super::*;
+
+    /// answers each challenge with the reversed challenge, fails on `"bad"`
+    struct Reverse {
+        steps: usize
+    }
+
+    impl SaslMechanism for Reverse {
+        fn name(&self) -> &str {
+            "X-REVERSE"
+        }
+
+        fn initial_response(&mut self) -> Option<Vec<u8>> {
+            Some(b"hello".to_vec())
+        }
+
+        fn step(&mut self, challenge: &[u8]) -> Result<Vec<u8>, Box<Error + Send + Sync>> {
+            self.steps += 1;
+            if challenge == b"bad" {
+                return Err("bad challenge".into());
+            }
+            Ok(challenge.iter().rev().cloned().collect())
+        }
+    }
+
+    fn reverse() -> Sasl<Reverse> {
+        Sasl::new(Reverse { steps: 0 })
+    }
+
+    #[test]
+    fn drives_custom_mechanism_through_challenges() {
+        let con = secure(mock(vec![
+            (Client,  Lines(vec!["AUTH X-REVERSE aGVsbG8="])),
+            (Server,  Lines(vec!["334 YWJj"])),
+            (Client,  Lines(vec!["Y2Jh"])),
+            (Server,  Lines(vec!["334 eHl6"])),
+            (Client,  Lines(vec!["enl4"])),
+            (Server,  Lines(vec!["235 Authentication successful"])),
+        ]));
+        let con = with_capability_params(con, "AUTH", &["PLAIN", "X-REVERSE"]);
+
+        let (con, result) = con.send(reverse()).wait().unwrap();
+
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn cancels_exchange_if_mechanism_fails() {
+        let con = secure(mock(vec![
+            (Client,  Lines(vec!["AUTH X-REVERSE aGVsbG8="])),
+            (Server,  Lines(vec!["334 YmFk"])),
+            (Client,  Lines(vec!["*"])),
+            (Server,  Lines(vec!["501 5.7.0 Authentication canceled"])),
+        ]));
+        let con = with_capability_params(con, "AUTH", &["X-REVERSE"]);
+
+        let (con, result) = con.send(reverse()).wait().unwrap();
+
+        match result {
+            Err(LogicError::Custom(err)) => assert_eq!(err.to_string(), "bad challenge"),
+            other => panic!("unexpected result: {:?}", other)
+        }
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn cancels_exchange_on_malformed_challenge_without_poisoning() {
+        let con = secure(mock(vec![
+            (Client,  Lines(vec!["AUTH X-REVERSE aGVsbG8="])),
+            (Server,  Lines(vec!["334 not base64!"])),
+            (Client,  Lines(vec!["*"])),
+            (Server,  Lines(vec!["501 5.7.0 Authentication canceled"])),
+            (Client,  Lines(vec!["NOOP"])),
+            (Server,  Lines(vec!["250 Ok"])),
+        ]));
+        let con = with_capability_params(con, "AUTH", &["X-REVERSE"]);
+
+        let (con, result) = con.send(reverse()).wait().unwrap();
+
+        match result {
+            Err(LogicError::MalformedChallenge { raw }) => assert_eq!(raw, "334 not base64!"),
+            other => panic!("unexpected result: {:?}", other)
+        }
+        assert!(!con.is_poisoned());
+
+        let (con, result) = con.send(command::Noop).wait().unwrap();
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn requires_advertised_mechanism() {
+        let con = secure(mock_no_shutdown(vec![]));
+        let con = with_capability_params(con, "AUTH", &["PLAIN"]);
+
+        let (_con, result) = con.send(reverse()).wait().unwrap();
+
+        match result {
+            Err(LogicError::MissingCapabilities(_)) => (),
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    /// sends a fixed initial response and doesn't expect any challenge
+    struct Fixed {
+        name: &'static str,
+        initial_response: Vec<u8>
+    }
+
+    impl SaslMechanism for Fixed {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn initial_response(&mut self) -> Option<Vec<u8>> {
+            Some(self.initial_response.clone())
+        }
+
+        fn step(&mut self, _challenge: &[u8]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+            Err("unexpected challenge".into())
+        }
+    }
+
+    #[test]
+    fn sends_auth_line_longer_than_a_command_line() {
+        // 3000 octets are 4000 base64 characters, i.e. the line has 4015 octets
+        let line = format!("AUTH X-FIXED {}", "YWFh".repeat(1000));
+        let line: &'static str = Box::leak(line.into_boxed_str());
+        let con = secure(mock(vec![
+            (Client,  Lines(vec![line])),
+            (Server,  Lines(vec!["235 Authentication successful"])),
+        ]));
+        let con = with_capability_params(con, "AUTH", &["X-FIXED"]);
+        let auth = Sasl::new(Fixed { name: "X-FIXED", initial_response: vec![b'a'; 3000] });
+
+        let (con, result) = con.send(auth).wait().unwrap();
+
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn does_not_send_too_long_auth_line() {
+        let con = secure(mock(vec![]));
+        let con = with_capability_params(con, "AUTH", &["X-FIXED"]);
+        // 9300 octets are 12400 base64 characters
+        let auth = Sasl::new(Fixed { name: "X-FIXED", initial_response: vec![b'a'; 9300] });
+
+        let (con, result) = con.send(auth).wait().unwrap();
+
+        match result {
+            Err(LogicError::LineTooLong { length, limit }) => {
+                assert_eq!(length, 12415);
+                assert_eq!(limit, 12288);
+            },
+            other => panic!("unexpected result: {:?}", other)
+        }
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_mechanism_name() {
+        let con = secure(mock(vec![]));
+        let con = with_capability_params(con, "AUTH", &["x-fixed"]);
+        let auth = Sasl::new(Fixed { name: "x-fixed", initial_response: vec![] });
+
+        let (con, result) = con.send(auth).wait().unwrap();
+
+        match result {
+            Err(LogicError::Custom(err)) => {
+                let err = err.downcast_ref::<InvalidMechanismName>().unwrap();
+                assert_eq!(err.0, "x-fixed");
+            },
+            other => panic!("unexpected result: {:?}", other)
+        }
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn refuses_auth_over_plaintext() {
+        let con = mock(vec![]);
+        let con = with_capability_params(con, "AUTH", &["X-REVERSE"]);
+
+        let (con, result) = con.send(reverse()).wait().unwrap();
+
+        match result {
+            Err(LogicError::InsecureAuth) => (),
+            other => panic!("unexpected result: {:?}", other)
+        }
+        con.shutdown().wait().unwrap();
+    }
+}
+
+mod OAuthBearer {
+    use futures::Future;
+    use new_tokio_smtp::command::auth::{OAuthBearer, OAuthBearerError};
+    use new_tokio_smtp::error::LogicError;
+    use super::*;
+
+    const INITIAL_RESPONSE: &str =
+        "AUTH OAUTHBEARER bixhPXVzZXJAZXhhbXBsZS5jb20sAWF1dGg9QmVhcmVyIHRva2VuAQE=";
+
+    #[test]
+    fn authenticates_with_token() {
+        let con = mock(vec![
+            (Client,  Lines(vec![INITIAL_RESPONSE])),
+            (Server,  Lines(vec!["235 Authentication successful"])),
+        ]);
+        let con = secure(with_capability_params(con, "AUTH", &["OAUTHBEARER"]));
+
+        let (con, result) = con
+            .send(OAuthBearer::new("user@example.com", "token"))
+            .wait()
+            .unwrap();
+
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn completes_exchange_on_error_challenge() {
+        let con = mock(vec![
+            (Client,  Lines(vec![INITIAL_RESPONSE])),
+            (Server,  Lines(vec!["334 eyJzdGF0dXMiOiJpbnZhbGlkX3Rva2VuIiwic2NvcGUiOiJtYWlsIn0="])),
+            (Client,  Lines(vec!["AQ=="])),
+            (Server,  Lines(vec!["535 5.7.8 Authentication credentials invalid"])),
+        ]);
+        let con = secure(with_capability_params(con, "AUTH", &["OAUTHBEARER"]));
+
+        let (con, result) = con
+            .send(OAuthBearer::new("user@example.com", "token"))
+            .wait()
+            .unwrap();
+
+        match result {
+            Err(LogicError::Custom(err)) => {
+                let err = err.downcast_ref::<OAuthBearerError>().expect("unexpected error type");
+                assert_eq!(err.error_json(), r#"{"status":"invalid_token","scope":"mail"}"#);
+                assert_eq!(err.response().code().as_byte_string(), *b"535");
+            },
+            other => panic!("unexpected result: {:?}", other)
+        }
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn does_not_send_too_long_auth_line() {
+        let con = secure(with_capability_params(mock(vec![]), "AUTH", &["OAUTHBEARER"]));
+
+        let (con, result) = con
+            .send(OAuthBearer::new("user@example.com", "t".repeat(10000)))
+            .wait()
+            .unwrap();
+
+        match result {
+            Err(LogicError::LineTooLong { limit, .. }) => assert_eq!(limit, 12288),
+            other => panic!("unexpected result: {:?}", other)
+        }
+        con.shutdown().wait().unwrap();
+    }
+}
+
+mod Anonymous {
+    use futures::Future;
+    use new_tokio_smtp::command::auth::Anonymous;
+    use super::*;
+
+    #[test]
+    fn sends_empty_initial_response_without_trace() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["AUTH ANONYMOUS ="])),
+            (Server,  Lines(vec!["235 Ok"])),
+        ]);
+        let con = with_capability_params(con, "AUTH", &["ANONYMOUS"]);
+
+        let (con, result) = con.send(Anonymous::new()).wait().unwrap();
+
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn sends_trace_token() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["AUTH ANONYMOUS c2lyaGM="])),
+            (Server,  Lines(vec!["235 Ok"])),
+        ]);
+        let con = with_capability_params(con, "AUTH", &["ANONYMOUS"]);
+
+        let anonymous = Anonymous::with_trace("sirhc").unwrap();
+        let (con, result) = con.send(anonymous).wait().unwrap();
+
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+}
+
+mod ExtensionCmd {
+    use futures::Future;
+    use new_tokio_smtp::command::ExtensionCmd;
+    use new_tokio_smtp::error::LogicError;
+    use super::*;
+
+    /// ETRN as specified in RFC 1985
+    struct Etrn {
+        node: String
+    }
+
+    impl ExtensionCmd for Etrn {
+        fn required_capability(&self) -> Option<&str> {
+            Some("ETRN")
+        }
+
+        fn command_line(&self) -> String {
+            format!("ETRN {}", self.node)
+        }
+    }
+
+    fn etrn() -> Etrn {
+        Etrn { node: "@they.test".to_owned() }
+    }
+
+    #[test]
+    fn sends_command_line_if_capability_is_supported() {
+        let con = mock(vec![
+            (Client,  Lines(vec!["ETRN @they.test"])),
+            (Server,  Lines(vec!["250 OK, queuing for node they.test started"])),
+        ]);
+        let con = with_capability(con, "ETRN");
+
+        let (con, result) = con.send(etrn()).wait().unwrap();
+
+        assert!(result.is_ok());
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn is_not_send_if_capability_is_missing() {
+        let con = mock(vec![]);
+
+        let (con, result) = con.send(etrn()).wait().unwrap();
+
+        match result {
+            Err(LogicError::MissingCapabilities(err)) => {
+                assert_eq!(err.capabilities()[0].as_str(), "ETRN");
+            },
+            other => panic!("unexpected result: {:?}", other)
+        }
+        con.shutdown().wait().unwrap();
+    }
 }
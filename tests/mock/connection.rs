@@ -0,0 +1,896 @@
+use std::sync::{Arc, Mutex};
+use std::{io as std_io, thread};
+use std::time::Duration;
+
+use futures::{future, Future, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use new_tokio_smtp::{command, Cmd, Connection, Io, EhloData, ExecFuture, ForwardPath, ReversePath, Domain, ClientId};
+use new_tokio_smtp::error::{LogicError, MissingCapabilities};
+use new_tokio_smtp::io::{Socket, CustomStream};
+use new_tokio_smtp::mock::{ActionData, Actor, MockSocket};
+
+use self::Actor::*;
+use self::ActionData::*;
+
+use super::{mock, mock_no_shutdown, with_capability, with_mock_clock};
+
+/// a command which "leaks" the io and never completes
+struct Leaking(Arc<Mutex<Option<Io>>>);
+
+impl Cmd for Leaking {
+    fn check_cmd_availability(&self, _caps: Option<&EhloData>)
+        -> Result<(), MissingCapabilities>
+    {
+        Ok(())
+    }
+
+    fn exec(self, io: Io) -> ExecFuture {
+        *self.0.lock().unwrap() = Some(io);
+        Box::new(future::empty())
+    }
+}
+
+/// a command sending the given parts as a simple command line
+struct RawLine(&'static [&'static str]);
+
+impl Cmd for RawLine {
+    fn check_cmd_availability(&self, _caps: Option<&EhloData>)
+        -> Result<(), MissingCapabilities>
+    {
+        Ok(())
+    }
+
+    fn exec(self, io: Io) -> ExecFuture {
+        io.exec_simple_cmd(self.0)
+    }
+}
+
+#[test]
+fn refuses_send_after_command_was_dropped() {
+    let con = mock_no_shutdown(vec![]);
+    let slot = Arc::new(Mutex::new(None));
+
+    let mut fut = con.send(Leaking(slot.clone()));
+    assert!(fut.poll().unwrap().is_not_ready());
+    drop(fut);
+
+    let io = slot.lock().unwrap().take().unwrap();
+    let con = Connection::from(io);
+    assert!(con.is_poisoned());
+
+    let res = con.send(command::Noop).wait();
+    assert!(res.is_err());
+}
+
+#[test]
+fn is_secure_after_starttls() {
+    let con = with_capability(mock(vec![]), "STARTTLS");
+    assert!(!con.is_secure());
+    assert!(con.tls_info().is_none());
+
+    let starttls = command::StartTls::new(Domain::from_unchecked("uhmail.test"));
+    let (con, res) = con.send(starttls).wait().unwrap();
+    assert!(res.is_ok());
+    assert!(con.is_secure());
+    assert!(con.tls_info().is_some());
+}
+
+#[test]
+fn shuts_down_socket_on_drop() {
+    // `mock` panics on drop if the socket was not shutdown
+    let con = mock(vec![]);
+    drop(con);
+}
+
+#[test]
+fn resets_idle_timer_on_each_command() {
+    with_mock_clock(|clock| {
+        let mut con = mock(vec![
+            (Client, Lines(vec!["NOOP"])),
+            (Server, Lines(vec!["250 Ok"])),
+            (Client, Lines(vec!["NOOP"])),
+            (Server, Lines(vec!["250 Ok"])),
+        ]);
+        con.set_idle_timeout(Some(Duration::from_secs(200)));
+
+        clock.advance(Duration::from_secs(120));
+        let (con, res) = con.send(command::Noop).wait().unwrap();
+        assert!(res.is_ok());
+
+        clock.advance(Duration::from_secs(120));
+        assert!(!con.is_idle_timed_out());
+        let (con, res) = con.send(command::Noop).wait().unwrap();
+        assert!(res.is_ok());
+
+        con.shutdown().wait().unwrap();
+    })
+}
+
+#[test]
+fn quits_after_idle_timeout() {
+    with_mock_clock(|clock| {
+        // `mock` panics on drop if the socket was not shutdown
+        let mut con = mock(vec![
+            (Client, Lines(vec!["QUIT"])),
+            (Server, Lines(vec!["221 Bye"])),
+        ]);
+        con.set_idle_timeout(Some(Duration::from_secs(10)));
+
+        clock.advance(Duration::from_secs(9));
+        assert!(!con.is_idle_timed_out());
+
+        clock.advance(Duration::from_secs(1));
+        assert!(con.is_idle_timed_out());
+
+        let err = con.send(command::Noop).wait().unwrap_err();
+        assert_eq!(err.kind(), std_io::ErrorKind::TimedOut);
+    })
+}
+
+#[test]
+fn rcpt_many_continues_after_rejected_recipient() {
+    let con = mock(vec![
+        (Client, Lines(vec!["RCPT TO:<t1@test.test>"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["RCPT TO:<t2@test.test>"])),
+        (Server, Lines(vec!["550 No such user"])),
+        (Client, Lines(vec!["RCPT TO:<t3@test.test>"])),
+        (Server, Lines(vec!["250 Ok"])),
+    ]);
+
+    let addrs = vec![
+        ForwardPath::from_unchecked("t1@test.test"),
+        ForwardPath::from_unchecked("t2@test.test"),
+        ForwardPath::from_unchecked("t3@test.test"),
+    ];
+
+    let (con, results) = con.rcpt_many(&addrs).wait().unwrap();
+
+    assert_eq!(results.len(), 3);
+    for ((addr, _), expected) in results.iter().zip(addrs.iter()) {
+        assert_eq!(addr, expected);
+    }
+    assert!(results[0].1.is_ok());
+    assert!(results[1].1.is_err());
+    assert!(results[2].1.is_ok());
+
+    con.shutdown().wait().unwrap();
+}
+
+#[test]
+fn reset_makes_connection_reusable_mid_transaction() {
+    let con = mock(vec![
+        (Client, Lines(vec!["MAIL FROM:<t1@test.test>"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["RSET"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["MAIL FROM:<t2@test.test>"])),
+        (Server, Lines(vec!["250 Ok"])),
+    ]);
+
+    let mail = command::Mail::new(ReversePath::from_unchecked("t1@test.test"));
+    let (con, result) = con.send(mail).wait().unwrap();
+    assert!(result.is_ok());
+
+    let con = con.reset().wait().unwrap();
+
+    let mail = command::Mail::new(ReversePath::from_unchecked("t2@test.test"));
+    let (con, result) = con.send(mail).wait().unwrap();
+    assert!(result.is_ok());
+
+    con.shutdown().wait().unwrap();
+}
+
+#[test]
+fn reset_fails_on_non_250_reply() {
+    let con = mock_no_shutdown(vec![
+        (Client, Lines(vec!["RSET"])),
+        (Server, Lines(vec!["421 closing"])),
+    ]);
+
+    let err = con.reset().wait().unwrap_err();
+    assert_eq!(err.kind(), std_io::ErrorKind::Other);
+}
+
+#[test]
+fn rehello_updates_the_capabilities() {
+    let con = mock(vec![
+        (Client, Lines(vec!["EHLO me.test"])),
+        (Server, Lines(vec!["250-they.test greets me.test", "250 SMTPUTF8"])),
+        (Client, Lines(vec!["EHLO other.test"])),
+        (Server, Lines(vec!["250-they.test greets other.test", "250-8BITMIME", "250 SIZE 1000"])),
+    ]);
+    let me = ClientId::Domain(Domain::from_unchecked("me.test"));
+    let other = ClientId::Domain(Domain::from_unchecked("other.test"));
+
+    let (con, result) = con.rehello(me).wait().unwrap();
+    assert!(result.is_ok());
+    assert!(con.caps().has_smtputf8());
+
+    let (con, result) = con.rehello(other).wait().unwrap();
+    assert!(result.is_ok());
+    assert!(!con.caps().has_smtputf8());
+    assert!(con.caps().has_8bitmime());
+    assert_eq!(con.ehlo_data().unwrap().max_message_size(), Some(1000));
+
+    con.shutdown().wait().unwrap();
+}
+
+#[test]
+fn capability_view_stays_valid_after_the_connection_is_used() {
+    let con = mock(vec![
+        (Client, Lines(vec!["EHLO me.test"])),
+        (Server, Lines(vec!["250-they.test greets me.test", "250 SMTPUTF8"])),
+        (Client, Lines(vec!["EHLO other.test"])),
+        (Server, Lines(vec!["250-they.test greets other.test", "250 8BITMIME"])),
+    ]);
+    let me = ClientId::Domain(Domain::from_unchecked("me.test"));
+    let other = ClientId::Domain(Domain::from_unchecked("other.test"));
+
+    fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+    let (con, result) = con.rehello(me).wait().unwrap();
+    assert!(result.is_ok());
+    let view = con.capability_view();
+    assert_send_sync(&view);
+
+    let (con, result) = con.rehello(other).wait().unwrap();
+    assert!(result.is_ok());
+    assert!(con.caps().has_8bitmime());
+    con.shutdown().wait().unwrap();
+
+    let view = thread::spawn(move || {
+        assert!(view.caps().has_smtputf8());
+        assert!(view.has_capability("smtputf8"));
+        assert!(!view.has_capability("8BITMIME"));
+        assert!(view.is_esmtp());
+        assert!(!view.is_secure());
+        view
+    }).join().unwrap();
+    assert_eq!(view.ehlo_data().unwrap().domain().as_str(), "they.test");
+}
+
+#[test]
+fn rehello_keeps_the_capabilities_on_failure() {
+    let con = mock(vec![
+        (Client, Lines(vec!["EHLO other.test"])),
+        (Server, Lines(vec!["550 go away"])),
+    ]);
+    let con = with_capability(con, "SMTPUTF8");
+    let other = ClientId::Domain(Domain::from_unchecked("other.test"));
+
+    let (con, result) = con.rehello(other).wait().unwrap();
+    assert!(result.is_err());
+    assert!(con.caps().has_smtputf8());
+
+    con.shutdown().wait().unwrap();
+}
+
+#[test]
+fn is_esmtp_after_ehlo() {
+    let con = mock(vec![
+        (Client, Lines(vec!["EHLO me.test"])),
+        (Server, Lines(vec!["250-they.test greets you", "250 CHUNKING"])),
+    ]);
+    assert!(!con.is_esmtp());
+
+    let (con, result) = con.send(command::Ehlo::new(ClientId::Domain(Domain::from_unchecked("me.test")))).wait().unwrap();
+
+    assert!(result.is_ok());
+    assert!(con.is_esmtp());
+    con.shutdown().wait().unwrap();
+}
+
+#[test]
+fn capability_gated_commands_fail_after_helo_fallback() {
+    let con = mock(vec![
+        (Client, Lines(vec!["HELO me.test"])),
+        (Server, Lines(vec!["250 they.test greets you"])),
+    ]);
+
+    let (con, result) = con.send(command::Helo::new(ClientId::Domain(Domain::from_unchecked("me.test")))).wait().unwrap();
+    assert!(result.is_ok());
+    assert!(!con.is_esmtp());
+
+    let (con, result) = con.send(command::Bdat::new("data")).wait().unwrap();
+
+    match result {
+        Err(LogicError::MissingCapabilities(err)) =>
+            assert_eq!(err.to_string(), "missing capabilities: CHUNKING"),
+        other => panic!("unexpected result: {:?}", other)
+    }
+    con.shutdown().wait().unwrap();
+}
+
+#[test]
+fn response_interceptor_observes_every_reply_in_order() {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let seen2 = seen.clone();
+    let con = mock(vec![
+        (Client, Lines(vec!["NOOP"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["VRFY t@test.test"])),
+        (Server, Lines(vec!["550-5.1.1 no such", "550 5.1.1 user"])),
+        (Client, Lines(vec!["RSET"])),
+        (Server, Lines(vec!["250 Reset"])),
+    ]);
+    let con = con.with_response_interceptor(move |response| {
+        seen2.lock().unwrap().push((response.code().as_u16(), response.msg().to_vec()));
+    });
+
+    let (con, _) = con.send(command::Noop).wait().unwrap();
+    let (con, result) = con.send(command::Verify { query: "t@test.test".to_owned() }).wait().unwrap();
+    assert!(result.is_err());
+    let (mut con, _) = con.send(command::Reset).wait().unwrap();
+
+    assert_eq!(*seen.lock().unwrap(), vec![
+        (250, vec!["Ok".to_owned()]),
+        (550, vec!["5.1.1 no such".to_owned(), "5.1.1 user".to_owned()]),
+        (250, vec!["Reset".to_owned()]),
+    ]);
+
+    con.remove_response_interceptor();
+    con.shutdown().wait().unwrap();
+}
+
+#[test]
+fn ping_measures_the_round_trip_time() {
+    let mut socket = MockSocket::new(vec![
+        (Client, Lines(vec!["NOOP"])),
+        (Server, Lines(vec!["250 Ok"])),
+    ]);
+    socket.set_reply_delay(Some(Duration::from_millis(50)));
+    let con = Connection::from(Io::from(socket));
+
+    let (con, result) = con.ping().wait().unwrap();
+
+    assert!(result.unwrap() >= Duration::from_millis(50));
+    con.shutdown().wait().unwrap();
+}
+
+#[test]
+fn ping_returns_error_replies() {
+    let con = mock(vec![
+        (Client, Lines(vec!["NOOP"])),
+        (Server, Lines(vec!["500 unknown command"])),
+    ]);
+
+    let (con, result) = con.ping().wait().unwrap();
+
+    match result {
+        Err(LogicError::Code(response)) => assert_eq!(response.code().as_byte_string(), *b"500"),
+        other => panic!("unexpected result: {:?}", other)
+    }
+    con.shutdown().wait().unwrap();
+}
+
+#[test]
+fn keep_alive_skips_recently_active_connections() {
+    let con = mock(vec![
+        (Client, Lines(vec!["RSET"])),
+        (Server, Lines(vec!["250 Ok"])),
+    ]);
+
+    let (con, result) = con.send(command::Reset).wait().unwrap();
+    assert!(result.is_ok());
+    assert!(!con.needs_keep_alive(Duration::from_secs(60)));
+
+    let (con, result) = con.keep_alive(Duration::from_secs(60)).wait().unwrap();
+    assert!(result.is_none());
+    con.shutdown().wait().unwrap();
+}
+
+#[test]
+fn keep_alive_pings_idle_connections() {
+    with_mock_clock(|clock| {
+        let con = mock(vec![
+            (Client, Lines(vec!["NOOP"])),
+            (Server, Lines(vec!["250 Ok"])),
+        ]);
+
+        clock.advance(Duration::from_secs(10));
+        assert!(con.needs_keep_alive(Duration::from_secs(10)));
+
+        let (con, result) = con.keep_alive(Duration::from_secs(10)).wait().unwrap();
+        assert!(result.unwrap().is_ok());
+        assert!(!con.needs_keep_alive(Duration::from_secs(10)));
+        con.shutdown().wait().unwrap();
+    })
+}
+
+#[test]
+fn help_collects_multi_line_help_text() {
+    let con = mock(vec![
+        (Client, Lines(vec!["HELP MAIL"])),
+        (Server, Lines(vec![
+            "214-MAIL FROM:<sender> [parameters]",
+            "214-  starts a mail transaction",
+            "214 End of HELP info"
+        ])),
+    ]);
+
+    let (con, result) = con.help(Some("MAIL".to_owned())).wait().unwrap();
+
+    assert_eq!(result.unwrap(), vec![
+        "MAIL FROM:<sender> [parameters]".to_owned(),
+        "  starts a mail transaction".to_owned(),
+        "End of HELP info".to_owned()
+    ]);
+    con.shutdown().wait().unwrap();
+}
+
+#[test]
+fn help_treats_not_implemented_as_empty_help() {
+    let con = mock(vec![
+        (Client, Lines(vec!["HELP"])),
+        (Server, Lines(vec!["502 5.5.1 Command not implemented"])),
+    ]);
+
+    let (con, result) = con.help(None).wait().unwrap();
+
+    assert_eq!(result.unwrap(), Vec::<String>::new());
+    con.shutdown().wait().unwrap();
+}
+
+#[test]
+fn rejects_commands_after_service_closing() {
+    let con = mock_no_shutdown(vec![
+        (Client, Lines(vec!["NOOP"])),
+        (Server, Lines(vec!["421 4.3.2 Service shutting down"])),
+    ]);
+
+    let (con, result) = con.send(command::Noop).wait().unwrap();
+
+    match result {
+        Err(LogicError::ServiceClosing(response)) => {
+            assert_eq!(response.code().as_byte_string(), *b"421");
+        },
+        other => panic!("unexpected result: {:?}", other)
+    }
+    assert!(con.is_service_closed());
+
+    let err = con.send(command::Noop).wait().err().expect("send succeeded after 421");
+    assert_eq!(err.kind(), std_io::ErrorKind::NotConnected);
+}
+
+#[test]
+fn quit_does_not_send_quit_after_service_closing() {
+    let con = mock(vec![
+        (Client, Lines(vec!["NOOP"])),
+        (Server, Lines(vec!["421 Service not available"])),
+    ]);
+
+    let (con, _result) = con.send(command::Noop).wait().unwrap();
+
+    con.quit().wait().unwrap();
+}
+
+#[test]
+fn close_sends_quit_and_shuts_down_the_socket() {
+    // `mock` panics on drop if the socket was not shutdown
+    let con = mock(vec![
+        (Client, Lines(vec!["QUIT"])),
+        (Server, Lines(vec!["221 Bye"])),
+    ]);
+
+    con.close().wait().unwrap();
+}
+
+#[test]
+fn close_only_shuts_down_after_service_closing() {
+    let con = mock(vec![
+        (Client, Lines(vec!["NOOP"])),
+        (Server, Lines(vec!["421 Service not available"])),
+    ]);
+
+    let (con, _result) = con.send(command::Noop).wait().unwrap();
+
+    con.close().wait().unwrap();
+}
+
+#[test]
+fn quit_with_response_returns_the_221_reply() {
+    let con = mock(vec![
+        (Client, Lines(vec!["QUIT"])),
+        (Server, Lines(vec!["221-2.0.0 Bye", "221 2.0.0 closing connection"])),
+    ]);
+
+    let (socket, result) = con.quit_with_response().wait().unwrap();
+
+    let response = result.unwrap().unwrap();
+    assert_eq!(response.code().as_byte_string(), *b"221");
+    assert_eq!(response.msg(), &["2.0.0 Bye".to_owned(), "2.0.0 closing connection".to_owned()]);
+    // `mock` panics on drop if the socket was not shutdown
+    drop(socket);
+}
+
+#[test]
+fn quit_with_response_reports_unexpected_code() {
+    let con = mock(vec![
+        (Client, Lines(vec!["QUIT"])),
+        (Server, Lines(vec!["250 Ok"])),
+    ]);
+
+    let (socket, result) = con.quit_with_response().wait().unwrap();
+
+    match result {
+        Some(Err(LogicError::UnexpectedCode(response))) =>
+            assert_eq!(response.code().as_byte_string(), *b"250"),
+        other => panic!("unexpected result: {:?}", other)
+    }
+    // `mock` panics on drop if the socket was not shutdown
+    drop(socket);
+}
+
+#[test]
+fn quit_with_response_does_not_send_quit_after_service_closing() {
+    let con = mock(vec![
+        (Client, Lines(vec!["NOOP"])),
+        (Server, Lines(vec!["421 Service not available"])),
+    ]);
+
+    let (con, _result) = con.send(command::Noop).wait().unwrap();
+    let (_socket, result) = con.quit_with_response().wait().unwrap();
+
+    assert!(result.is_none());
+}
+
+#[cfg(feature="metrics")]
+#[test]
+fn metrics_count_scripted_session() {
+    let con = mock(vec![
+        (Client, Lines(vec!["NOOP"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["RCPT TO:<t1@test.test>"])),
+        (Server, Lines(vec!["450 Mailbox busy"])),
+        (Client, Lines(vec!["RCPT TO:<t2@test.test>"])),
+        (Server, Lines(vec!["550 No such user"])),
+    ]);
+    assert_eq!(con.metrics().commands_sent(), 0);
+
+    let (con, _) = con.send(command::Noop).wait().unwrap();
+    let metrics = con.metrics().clone();
+    assert_eq!(metrics.commands_sent(), 1);
+    assert_eq!(metrics.bytes_written(), "NOOP\r\n".len() as u64);
+    assert_eq!(metrics.bytes_read(), "250 Ok\r\n".len() as u64);
+    assert_eq!(metrics.transient_failures(), 0);
+    assert_eq!(metrics.permanent_failures(), 0);
+
+    let addrs = vec![
+        ForwardPath::from_unchecked("t1@test.test"),
+        ForwardPath::from_unchecked("t2@test.test"),
+    ];
+    let (con, _) = con.rcpt_many(&addrs).wait().unwrap();
+    let metrics = con.metrics().clone();
+    assert_eq!(metrics.commands_sent(), 3);
+    assert_eq!(metrics.bytes_written(), 6 + 2 * "RCPT TO:<t1@test.test>\r\n".len() as u64);
+    assert_eq!(metrics.bytes_read(), 8 + 18 + 18);
+    assert_eq!(metrics.transient_failures(), 1);
+    assert_eq!(metrics.permanent_failures(), 1);
+    assert_eq!(metrics.tls_handshake_duration(), None);
+
+    con.shutdown().wait().unwrap();
+}
+
+#[cfg(feature="transcript")]
+#[test]
+fn transcript_records_session_with_auth_redacted() {
+    use new_tokio_smtp::command::auth::Plain;
+    use new_tokio_smtp::io::{Direction, TranscriptEntry};
+    use super::{secure, with_capability_params};
+
+    let con = mock(vec![
+        (Client, Lines(vec!["NOOP"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["AUTH PLAIN dXNlcgB1c2VyAHBhc3N3b3Jk"])),
+        (Server, Lines(vec!["235 Authentication successful"])),
+        (Client, Lines(vec!["RSET"])),
+        (Server, Lines(vec!["250 Ok"])),
+    ]);
+    let mut con = secure(with_capability_params(con, "AUTH", &["PLAIN"]));
+    assert!(con.transcript().is_none());
+    con.enable_transcript(10);
+
+    let (con, _) = con.send(command::Noop).wait().unwrap();
+    let plain = Plain::from_username("user", "password").unwrap();
+    let (con, result) = con.send(plain).wait().unwrap();
+    assert!(result.is_ok());
+    let (con, _) = con.send(command::Reset).wait().unwrap();
+
+    let sent = |line| TranscriptEntry::new(Direction::Sent, line);
+    let received = |line| TranscriptEntry::new(Direction::Received, line);
+    assert_eq!(con.transcript().unwrap().entries(), vec![
+        sent("NOOP"),
+        received("250 Ok"),
+        sent("AUTH PLAIN [redacted]"),
+        received("235 Authentication successful"),
+        sent("RSET"),
+        received("250 Ok"),
+    ]);
+
+    con.shutdown().wait().unwrap();
+}
+
+#[cfg(feature="transcript")]
+#[test]
+fn error_log_keeps_the_last_error_responses_in_order() {
+    let mut con = mock(vec![
+        (Client, Lines(vec!["VRFY a"])),
+        (Server, Lines(vec!["550 5.1.1 a unknown"])),
+        (Client, Lines(vec!["NOOP"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["VRFY b"])),
+        (Server, Lines(vec!["451-4.3.0 b is", "451 4.3.0 temporary unavailable"])),
+        (Client, Lines(vec!["VRFY c"])),
+        (Server, Lines(vec!["502 5.5.1 VRFY not implemented"])),
+    ]);
+    assert!(con.error_log().is_none());
+    con.enable_error_log(2);
+
+    let verify = |query: &str| command::Verify { query: query.to_owned() };
+    let (con, _) = con.send(verify("a")).wait().unwrap();
+    let (con, _) = con.send(command::Noop).wait().unwrap();
+    assert_eq!(con.error_log().unwrap().entries().len(), 1);
+    let (con, _) = con.send(verify("b")).wait().unwrap();
+    let (con, _) = con.send(verify("c")).wait().unwrap();
+
+    let entries = con.error_log().unwrap().entries();
+    let entries = entries.iter()
+        .map(|response| (response.code().as_u16(), response.msg().to_vec()))
+        .collect::<Vec<_>>();
+    assert_eq!(entries, vec![
+        (451, vec!["4.3.0 b is".to_owned(), "4.3.0 temporary unavailable".to_owned()]),
+        (502, vec!["5.5.1 VRFY not implemented".to_owned()]),
+    ]);
+
+    con.shutdown().wait().unwrap();
+}
+
+fn with_greeting(con: Connection, banner: &str) -> Connection {
+    use new_tokio_smtp::response::{codes, Response};
+
+    let mut io = Io::from(con);
+    io.set_greeting(Response::new(codes::READY, vec![banner.to_owned()]));
+    Connection::from(io)
+}
+
+#[test]
+fn estimates_server_time_from_greeting_with_date() {
+    use std::time::{UNIX_EPOCH, Duration};
+
+    let con = with_greeting(mock(vec![]), "mail.test.test ESMTP Postfix; Thu, 14 Jun 2018 13:22:18 +0200");
+
+    assert_eq!(con.estimated_server_time(), Some(UNIX_EPOCH + Duration::from_secs(1528975338)));
+    con.shutdown().wait().unwrap();
+}
+
+#[test]
+fn estimated_server_time_is_none_without_date() {
+    let con = mock(vec![]);
+    assert_eq!(con.estimated_server_time(), None);
+
+    let con = with_greeting(con, "mail.test.test ESMTP Postfix (Debian/GNU)");
+    assert!(con.greeting().is_some());
+    assert_eq!(con.estimated_server_time(), None);
+    con.shutdown().wait().unwrap();
+}
+
+#[test]
+fn keeps_capabilities_after_split_and_rejoin() {
+    let con = mock(vec![
+        (Client,  Lines(vec!["NOOP"])),
+        (Server,  Lines(vec!["250 Ok"])),
+    ]);
+    let mut con = with_capability(con, "SMTPUTF8");
+    con.set_idle_timeout(Some(Duration::from_secs(60)));
+
+    let (socket, state) = con.into_parts();
+    assert!(state.ehlo_data().unwrap().has_capability("SMTPUTF8"));
+    let con = Connection::from_parts(socket, state);
+
+    assert!(con.has_capability("SMTPUTF8"));
+    assert!(!con.has_capability("8BITMIME"));
+    let (con, result) = con.send(command::Noop).wait().unwrap();
+    assert!(result.is_ok());
+    con.shutdown().wait().unwrap();
+}
+
+/// middleware counting the bytes written to the wrapped socket
+#[derive(Debug)]
+struct CountWritten {
+    socket: Socket,
+    written: Arc<Mutex<usize>>
+}
+
+impl std_io::Read for CountWritten {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std_io::Error> {
+        self.socket.read(buf)
+    }
+}
+
+impl std_io::Write for CountWritten {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std_io::Error> {
+        let n = self.socket.write(buf)?;
+        *self.written.lock().unwrap() += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), std_io::Error> {
+        self.socket.flush()
+    }
+}
+
+impl AsyncRead for CountWritten {}
+
+impl AsyncWrite for CountWritten {
+    fn shutdown(&mut self) -> Poll<(), std_io::Error> {
+        AsyncWrite::shutdown(&mut self.socket)
+    }
+}
+
+impl CustomStream for CountWritten {
+    fn is_secure(&self) -> bool {
+        CustomStream::is_secure(&self.socket)
+    }
+}
+
+#[test]
+fn socket_can_be_wrapped_by_middleware() {
+    let con = mock(vec![
+        (Client,  Lines(vec!["NOOP"])),
+        (Server,  Lines(vec!["250 Ok"])),
+    ]);
+    let con = with_capability(con, "SMTPUTF8");
+
+    let written = Arc::new(Mutex::new(0));
+    let (socket, state) = con.into_parts();
+    let socket = Socket::Custom(Box::new(CountWritten { socket, written: written.clone() }));
+    let con = Connection::from_parts(socket, state);
+
+    assert!(con.has_capability("SMTPUTF8"));
+    assert!(!con.is_secure());
+    let (con, result) = con.send(command::Noop).wait().unwrap();
+    assert!(result.is_ok());
+    assert_eq!(*written.lock().unwrap(), "NOOP\r\n".len());
+    con.shutdown().wait().unwrap();
+}
+
+#[test]
+fn read_timeout_fails_stalled_read() {
+    use tokio::runtime::current_thread::Runtime;
+
+    let mut socket = MockSocket::new_no_check_shutdown(vec![
+        (Client, Lines(vec!["NOOP"])),
+        (Server, Lines(vec!["250 Ok"])),
+    ]);
+    socket.set_reply_delay(Some(Duration::from_millis(500)));
+    let mut con = Connection::from(Io::from(socket));
+    con.set_read_timeout(Some(Duration::from_millis(50)));
+    assert_eq!(con.read_timeout(), Some(Duration::from_millis(50)));
+    assert_eq!(con.write_timeout(), None);
+
+    let mut runtime = Runtime::new().unwrap();
+    let err = runtime.block_on(con.send(command::Noop)).err().expect("stalled read succeeded");
+
+    assert_eq!(err.kind(), std_io::ErrorKind::TimedOut);
+}
+
+#[test]
+fn read_timeout_allows_slow_but_progressing_reads() {
+    use tokio::runtime::current_thread::Runtime;
+
+    let mut socket = MockSocket::new(vec![
+        (Client, Lines(vec!["NOOP"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["NOOP"])),
+        (Server, Lines(vec!["250 Ok"])),
+    ]);
+    socket.set_reply_delay(Some(Duration::from_millis(20)));
+    let mut con = Connection::from(Io::from(socket));
+    con.set_read_timeout(Some(Duration::from_millis(200)));
+
+    let fut = con.send(command::Noop)
+        .and_then(|(con, _)| con.send(command::Noop))
+        .and_then(|(con, res)| {
+            assert!(res.is_ok());
+            con.shutdown()
+        });
+
+    let mut runtime = Runtime::new().unwrap();
+    runtime.block_on(fut).unwrap();
+}
+
+#[test]
+fn rate_limit_delays_burst_of_commands() {
+    use std::time::Instant;
+    use tokio::runtime::current_thread::Runtime;
+    use new_tokio_smtp::io::RateLimit;
+
+    let mut con = mock(vec![
+        (Client, Lines(vec!["NOOP"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["NOOP"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["NOOP"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["NOOP"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["NOOP"])),
+        (Server, Lines(vec!["250 Ok"])),
+    ]);
+    con.set_rate_limit(Some(RateLimit::new(2, Duration::from_millis(200))));
+
+    let start = Instant::now();
+    let fut = con.send(command::Noop)
+        .and_then(|(con, _)| con.send(command::Noop))
+        .and_then(|(con, _)| {
+            // the first two commands are not delayed
+            assert!(start.elapsed() < Duration::from_millis(150));
+            con.send(command::Noop)
+        })
+        .and_then(|(con, _)| con.send(command::Noop))
+        .and_then(|(con, _)| con.send(command::Noop))
+        .and_then(|(con, res)| {
+            assert!(res.is_ok());
+            con.shutdown()
+        });
+
+    let mut runtime = Runtime::new().unwrap();
+    runtime.block_on(fut).unwrap();
+
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(400), "elapsed: {:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(2000), "elapsed: {:?}", elapsed);
+}
+
+#[test]
+fn rate_limit_uses_tokio_clock() {
+    use new_tokio_smtp::io::RateLimit;
+
+    with_mock_clock(|clock| {
+        let mut con = mock(vec![
+            (Client, Lines(vec!["NOOP"])),
+            (Server, Lines(vec!["250 Ok"])),
+            (Client, Lines(vec!["NOOP"])),
+            (Server, Lines(vec!["250 Ok"])),
+        ]);
+        con.set_rate_limit(Some(RateLimit::new(1, Duration::from_secs(60))));
+
+        let (con, res) = con.send(command::Noop).wait().unwrap();
+        assert!(res.is_ok());
+
+        // the window passed on the mocked clock, so there is nothing to wait for
+        clock.advance(Duration::from_secs(60));
+        let (con, res) = con.send(command::Noop).wait().unwrap();
+        assert!(res.is_ok());
+
+        con.shutdown().wait().unwrap();
+    })
+}
+
+#[test]
+fn rejects_simple_cmd_with_embedded_line_break() {
+    // nothing is send, the mock would fail on any write
+    let con = mock(vec![
+        (Client, Lines(vec!["NOOP"])),
+        (Server, Lines(vec!["250 Ok"])),
+    ]);
+
+    let (con, res) = con.send(RawLine(&["NOOP\r\nRSET"])).wait().unwrap();
+    match res {
+        Err(LogicError::LineBreakInCommand) => (),
+        other => panic!("unexpected result: {:?}", other)
+    }
+
+    let (con, res) = con.send(RawLine(&["NOOP ", "bare\nlf"])).wait().unwrap();
+    match res {
+        Err(LogicError::LineBreakInCommand) => (),
+        other => panic!("unexpected result: {:?}", other)
+    }
+
+    // the connection is still usable
+    let (con, res) = con.send(RawLine(&["NOOP"])).wait().unwrap();
+    assert!(res.is_ok());
+    con.shutdown().wait().unwrap();
+}
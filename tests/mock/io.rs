@@ -0,0 +1,579 @@
+use std::io::{self as std_io, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bytes::BufMut;
+use futures::{Future, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use new_tokio_smtp::{command, Connection, Io};
+use new_tokio_smtp::io::{
+    MockStream, Socket, SmtpResult, DataWriteOutcome,
+    DEFAULT_MAX_RESPONSE_SIZE, DEFAULT_MAX_RESPONSE_LINES
+};
+use new_tokio_smtp::error::LogicError;
+use new_tokio_smtp::response::codes;
+use new_tokio_smtp::mock::{MockSocket, ActionData, Actor};
+
+use self::Actor::*;
+use self::ActionData::*;
+
+#[test]
+fn reuses_output_buffer_across_commands() {
+    let query = "x".repeat(200);
+    let line: &'static str = Box::leak(format!("VRFY {}", query).into_boxed_str());
+
+    let mut conv = vec![];
+    for _ in 0..50 {
+        conv.push((Client, Lines(vec![line])));
+        conv.push((Server, Lines(vec!["252 Ok"])));
+    }
+
+    let mut con = Connection::from(Io::from(MockSocket::new(conv)));
+    // the end of the allocation the output buffer is a view into
+    let mut allocation_ends = Vec::new();
+    for _ in 0..50 {
+        let (new_con, res) = con
+            .send(command::Verify { query: query.clone() })
+            .wait()
+            .unwrap();
+        res.unwrap();
+
+        let mut io = Io::from(new_con);
+        {
+            let buffer = io.out_buffer(0);
+            assert!(buffer.is_empty());
+            allocation_ends.push(buffer.as_ptr() as usize + buffer.capacity());
+        }
+        con = Connection::from(io);
+    }
+
+    // the first command might move the buffer from inline storage to the heap
+    let first = allocation_ends[1];
+    assert!(allocation_ends[1..].iter().all(|end| *end == first));
+
+    con.shutdown().wait().unwrap();
+}
+
+/// a socket accepting at most one byte per write, every other write is not ready
+#[derive(Debug)]
+struct OneBytePerPoll {
+    received: Arc<Mutex<Vec<u8>>>,
+    ready: bool
+}
+
+impl Read for OneBytePerPoll {
+    fn read(&mut self, _buf: &mut [u8]) -> Result<usize, std_io::Error> {
+        Ok(0)
+    }
+}
+
+impl Write for OneBytePerPoll {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std_io::Error> {
+        self.ready = !self.ready;
+        if !self.ready {
+            futures::task::current().notify();
+            return Err(std_io::ErrorKind::WouldBlock.into());
+        }
+        self.received.lock().unwrap().push(buf[0]);
+        Ok(1)
+    }
+
+    fn flush(&mut self) -> Result<(), std_io::Error> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for OneBytePerPoll {}
+
+impl AsyncWrite for OneBytePerPoll {
+    fn shutdown(&mut self) -> Poll<(), std_io::Error> {
+        Ok(().into())
+    }
+}
+
+impl MockStream for OneBytePerPoll {
+    fn set_is_secure(&mut self, _secure: bool) {}
+}
+
+#[test]
+fn flushes_large_output_one_byte_per_poll() {
+    let body = (0..4 * 1024 * 1024).map(|idx| b'a' + (idx % 26) as u8).collect::<Vec<_>>();
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let socket = OneBytePerPoll { received: received.clone(), ready: false };
+    let mut io = Io::from(Socket::Mock(Box::new(socket)));
+    io.out_buffer(body.len()).put_slice(&body);
+
+    let io = io.flush().wait().unwrap();
+    let (_, buffers, _) = io.split();
+    assert!(buffers.output.is_empty());
+
+    let received = received.lock().unwrap();
+    assert_eq!(received.len(), body.len());
+    assert!(*received == body);
+}
+
+#[test]
+fn streams_lines_of_huge_response() {
+    let mut response = String::new();
+    for idx in 0..999 {
+        response.push_str(&format!("250-member{}@test.test\r\n", idx));
+    }
+    response.push_str("250 member999@test.test\r\n");
+
+    let io = Io::from(MockSocket::new(vec![
+        (Client, Lines(vec!["EXPN list"])),
+        (Server, Blob(response.into_bytes())),
+    ]));
+
+    let fut = io
+        .flush_line_from_parts(&["EXPN list"])
+        .and_then(|io| {
+            // only the number of lines is kept, not the lines themselves
+            io.parse_response_lines().fold_lines(0, |count, line| {
+                assert_eq!(line.code.as_u16(), 250);
+                assert_eq!(line.msg, format!("member{}@test.test", count));
+                assert_eq!(line.last_line, count == 999);
+                count + 1
+            })
+        });
+
+    let (io, count) = fut.wait().unwrap();
+    assert_eq!(count, 1000);
+
+    let (mut socket, _, _) = io.split();
+    socket.shutdown_now().unwrap();
+}
+
+#[test]
+fn streaming_lines_fails_on_mismatching_codes() {
+    let io = Io::from(MockSocket::new_no_check_shutdown(vec![
+        (Client, Lines(vec!["EXPN list"])),
+        (Server, Lines(vec!["250-member1@test.test", "251 member2@test.test"])),
+    ]));
+
+    let res = io
+        .flush_line_from_parts(&["EXPN list"])
+        .and_then(|io| io.parse_response_lines().fold_lines(0, |count, _| count + 1))
+        .wait();
+
+    match res {
+        Err(err) => assert_eq!(err.kind(), std_io::ErrorKind::InvalidData),
+        Ok(_) => panic!("unexpected success")
+    }
+}
+
+fn vrfy_line_of_length(length: usize) -> String {
+    // "VRFY " + query + "\r\n"
+    format!("VRFY {}", "x".repeat(length - 7))
+}
+
+#[test]
+fn sends_command_lines_up_to_the_maximal_length() {
+    for &length in &[999, 1000] {
+        let line: &'static str = Box::leak(vrfy_line_of_length(length).into_boxed_str());
+        let con = Connection::from(Io::from(MockSocket::new(vec![
+            (Client, Lines(vec![line])),
+            (Server, Lines(vec!["252 Ok"])),
+        ])));
+
+        let (con, res) = con
+            .send(command::Verify { query: line[5..].to_owned() })
+            .wait()
+            .unwrap();
+
+        res.unwrap();
+        con.shutdown().wait().unwrap();
+    }
+}
+
+#[test]
+fn rejects_too_long_command_line_without_sending_it() {
+    let con = Connection::from(Io::from(MockSocket::new(vec![])));
+    let line = vrfy_line_of_length(1001);
+
+    let (con, res) = con
+        .send(command::Verify { query: line[5..].to_owned() })
+        .wait()
+        .unwrap();
+
+    match res {
+        Err(LogicError::LineTooLong { length, limit }) => {
+            assert_eq!(length, 1001);
+            assert_eq!(limit, 1000);
+        },
+        other => panic!("unexpected result: {:?}", other)
+    }
+    con.shutdown().wait().unwrap();
+}
+
+fn read_huge_response_with_buffer_size(read_buffer_size: usize) {
+    let mut response = String::new();
+    for idx in 0..999 {
+        response.push_str(&format!("250-member{}@test.test\r\n", idx));
+    }
+    response.push_str("250 member999@test.test\r\n");
+
+    let mut io = Io::from(MockSocket::new(vec![
+        (Client, Lines(vec!["EXPN list"])),
+        (Server, Blob(response.into_bytes())),
+    ]));
+    io.set_read_buffer_size(read_buffer_size);
+
+    let (io, result) = io
+        .flush_line_from_parts(&["EXPN list"])
+        .and_then(Io::parse_response)
+        .wait()
+        .unwrap();
+
+    let response = result.unwrap();
+    assert_eq!(response.msg().len(), 1000);
+    for (idx, line) in response.msg().iter().enumerate() {
+        assert_eq!(line, &format!("member{}@test.test", idx));
+    }
+
+    let (mut socket, _, _) = io.split();
+    socket.shutdown_now().unwrap();
+}
+
+#[test]
+fn reads_huge_response_with_tiny_read_buffer() {
+    read_huge_response_with_buffer_size(1);
+}
+
+#[test]
+fn reads_huge_response_with_large_read_buffer() {
+    read_huge_response_with_buffer_size(64 * 1024);
+}
+
+fn parse_with_max_response_size(response: Vec<u8>, limit: usize) -> (Io, SmtpResult) {
+    let mut io = Io::from(MockSocket::new_no_check_shutdown(vec![
+        (Client, Lines(vec!["EXPN list"])),
+        (Server, Blob(response)),
+    ]));
+    assert_eq!(io.max_response_size(), DEFAULT_MAX_RESPONSE_SIZE);
+    io.set_max_response_size(limit);
+
+    io.flush_line_from_parts(&["EXPN list"])
+        .and_then(Io::parse_response)
+        .wait()
+        .unwrap()
+}
+
+#[test]
+fn accepts_response_up_to_the_maximal_size() {
+    let (_io, result) = parse_with_max_response_size(b"250-a\r\n250 b\r\n".to_vec(), 14);
+    assert_eq!(result.unwrap().msg(), &["a".to_owned(), "b".to_owned()]);
+}
+
+#[test]
+fn rejects_response_exceeding_the_maximal_size() {
+    let mut response = String::new();
+    for idx in 0..99 {
+        response.push_str(&format!("250-member{}@test.test\r\n", idx));
+    }
+    response.push_str("250 member99@test.test\r\n");
+
+    let (_io, result) = parse_with_max_response_size(response.into_bytes(), 1024);
+    match result {
+        Err(LogicError::ResponseTooLarge { limit }) => assert_eq!(limit, 1024),
+        other => panic!("unexpected result: {:?}", other)
+    }
+
+    let (_io, result) = parse_with_max_response_size(b"250-a\r\n250 b\r\n".to_vec(), 13);
+    match result {
+        Err(LogicError::ResponseTooLarge { limit }) => assert_eq!(limit, 13),
+        other => panic!("unexpected result: {:?}", other)
+    }
+}
+
+#[test]
+fn rejects_endless_line_exceeding_the_maximal_size() {
+    let (_io, result) = parse_with_max_response_size(vec![b'x'; 4096], 1024);
+    match result {
+        Err(LogicError::ResponseTooLarge { limit }) => assert_eq!(limit, 1024),
+        other => panic!("unexpected result: {:?}", other)
+    }
+}
+
+/// a socket accepting all writes and reading an endless line without `"\r\n"`
+#[derive(Debug)]
+struct EndlessLine;
+
+impl Read for EndlessLine {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std_io::Error> {
+        for bch in buf.iter_mut() {
+            *bch = b'x';
+        }
+        Ok(buf.len())
+    }
+}
+
+impl Write for EndlessLine {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std_io::Error> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), std_io::Error> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for EndlessLine {}
+
+impl AsyncWrite for EndlessLine {
+    fn shutdown(&mut self) -> Poll<(), std_io::Error> {
+        Ok(().into())
+    }
+}
+
+impl MockStream for EndlessLine {
+    fn set_is_secure(&mut self, _secure: bool) {}
+}
+
+#[test]
+fn stops_reading_endless_line_at_the_maximal_size() {
+    let mut io = Io::from(Socket::Mock(Box::new(EndlessLine)));
+    io.set_max_response_size(1024);
+
+    let (mut io, result) = io.flush_line_from_parts(&["EXPN list"])
+        .and_then(Io::parse_response)
+        .wait()
+        .unwrap();
+
+    match result {
+        Err(LogicError::ResponseTooLarge { limit }) => assert_eq!(limit, 1024),
+        other => panic!("unexpected result: {:?}", other)
+    }
+    let read_buffer_size = io.read_buffer_size();
+    assert!(io.in_buffer().len() <= 1024 + read_buffer_size);
+}
+
+#[test]
+fn response_lines_reject_endless_line() {
+    let mut io = Io::from(Socket::Mock(Box::new(EndlessLine)));
+    io.set_max_response_size(1024);
+
+    let err = io.flush_line_from_parts(&["EXPN list"])
+        .and_then(|io| io.parse_response_lines().fold_lines((), |(), _line| ()))
+        .wait()
+        .map(|_| ())
+        .unwrap_err();
+
+    assert_eq!(err.kind(), std_io::ErrorKind::InvalidData);
+    let inner = err.into_inner().unwrap();
+    match inner.downcast_ref::<LogicError>() {
+        Some(&LogicError::ResponseTooLarge { limit }) => assert_eq!(limit, 1024),
+        other => panic!("unexpected error: {:?}", other)
+    }
+}
+
+#[test]
+fn rejects_response_with_too_many_lines() {
+    let response = "250-x\r\n".repeat(DEFAULT_MAX_RESPONSE_LINES) + "250 x\r\n";
+
+    // well below the size limit
+    let (_io, result) = parse_with_max_response_size(response.into_bytes(), DEFAULT_MAX_RESPONSE_SIZE);
+
+    match result {
+        Err(LogicError::TooManyResponseLines { limit }) => assert_eq!(limit, DEFAULT_MAX_RESPONSE_LINES),
+        other => panic!("unexpected result: {:?}", other)
+    }
+}
+
+#[test]
+fn line_limit_is_configurable() {
+    let mut io = Io::from(MockSocket::new_no_check_shutdown(vec![
+        (Client, Lines(vec!["EXPN list"])),
+        (Server, Lines(vec!["250-a", "250-b", "250 c"])),
+    ]));
+    io.set_max_response_lines(2);
+    assert_eq!(io.max_response_lines(), 2);
+
+    let (_io, result) = io.flush_line_from_parts(&["EXPN list"])
+        .and_then(Io::parse_response)
+        .wait()
+        .unwrap();
+    match result {
+        Err(LogicError::TooManyResponseLines { limit }) => assert_eq!(limit, 2),
+        other => panic!("unexpected result: {:?}", other)
+    }
+
+    let mut io = Io::from(MockSocket::new_no_check_shutdown(vec![
+        (Client, Lines(vec!["EXPN list"])),
+        (Server, Lines(vec!["250-a", "250-b", "250 c"])),
+    ]));
+    io.set_max_response_lines(3);
+    let (_io, result) = io.flush_line_from_parts(&["EXPN list"])
+        .and_then(Io::parse_response)
+        .wait()
+        .unwrap();
+    assert_eq!(result.unwrap().msg().len(), 3);
+}
+
+#[test]
+fn too_large_response_poisons_the_connection() {
+    let mut con = Connection::from(Io::from(MockSocket::new_no_check_shutdown(vec![
+        (Client, Lines(vec!["NOOP"])),
+        (Server, Lines(vec!["250-this is a longer response", "250 Ok"])),
+    ])));
+    con.set_max_response_size(16);
+
+    let (con, result) = con.send(command::Noop).wait().unwrap();
+
+    match result {
+        Err(LogicError::ResponseTooLarge { limit }) => assert_eq!(limit, 16),
+        other => panic!("unexpected result: {:?}", other)
+    }
+    assert!(con.is_poisoned());
+}
+
+#[test]
+fn drains_stale_lines_before_next_command() {
+    let conv = vec![
+        (Client, Lines(vec!["NOOP"])),
+        (Server, Lines(vec!["250 Ok"])),
+    ];
+    let mut io = Io::from(MockSocket::new(conv));
+    // e.g. the remainder of a reply abandoned because of an error
+    io.in_buffer().extend_from_slice(b"250-stale\r\n250 stale end\r\n");
+
+    assert_eq!(io.drain_pending(), vec!["250-stale".to_owned(), "250 stale end".to_owned()]);
+    assert!(io.drain_pending().is_empty());
+
+    let (con, res) = Connection::from(io).send(command::Noop).wait().unwrap();
+    assert_eq!(res.unwrap().msg(), &["Ok".to_owned()]);
+
+    con.shutdown().wait().unwrap();
+}
+
+#[test]
+fn drain_keeps_incomplete_line() {
+    let mut io = Io::from(MockSocket::new(vec![]));
+    io.in_buffer().extend_from_slice(b"220 info\r\n250 par");
+
+    assert_eq!(io.drain_pending(), vec!["220 info".to_owned()]);
+    assert_eq!(&io.in_buffer()[..], b"250 par");
+
+    Connection::from(io).shutdown().wait().unwrap();
+}
+
+fn assert_malformed_response(reply: &'static str) {
+    let con = Connection::from(Io::from(MockSocket::new_no_check_shutdown(vec![
+        (Client, Lines(vec!["NOOP"])),
+        (Server, Blob(format!("{}\r\n", reply).into_bytes())),
+    ])));
+
+    let (con, result) = con.send(command::Noop).wait().unwrap();
+
+    match result {
+        Err(LogicError::MalformedResponse { raw }) => assert_eq!(raw, reply),
+        other => panic!("unexpected result for {:?}: {:?}", reply, other)
+    }
+    assert!(con.is_poisoned());
+}
+
+#[test]
+fn empty_reply_line_is_malformed() {
+    assert_malformed_response("");
+}
+
+#[test]
+fn non_numeric_reply_code_is_malformed() {
+    assert_malformed_response("2x0 Ok");
+}
+
+#[test]
+fn reply_without_separator_is_malformed() {
+    assert_malformed_response("250Ok");
+}
+
+#[test]
+fn continuation_with_different_code_is_malformed() {
+    let con = Connection::from(Io::from(MockSocket::new_no_check_shutdown(vec![
+        (Client, Lines(vec!["NOOP"])),
+        (Server, Lines(vec!["250-first", "251 second"])),
+    ])));
+
+    let (con, result) = con.send(command::Noop).wait().unwrap();
+
+    match result {
+        Err(LogicError::MalformedResponse { raw }) => assert_eq!(raw, "251 second"),
+        other => panic!("unexpected result: {:?}", other)
+    }
+    assert!(con.is_poisoned());
+}
+
+fn send_with_intermediate(conv: Vec<(Actor, ActionData)>, abort: bool) -> (Io, SmtpResult, bool) {
+    let io = Io::from(MockSocket::new(conv));
+    let called = Arc::new(AtomicUsize::new(0));
+    let called2 = called.clone();
+
+    let (io, result) = io
+        .send_expecting_intermediate(&["X-UPLOAD"], codes::START_MAIL_DATA, move |io, response| {
+            assert_eq!(response.code(), codes::START_MAIL_DATA);
+            called2.fetch_add(1, Ordering::SeqCst);
+            io.flush_line_from_parts(&["payload"])
+                .map(move |io| if abort {
+                    DataWriteOutcome::Aborted(io)
+                } else {
+                    DataWriteOutcome::Completed(io)
+                })
+        })
+        .wait()
+        .unwrap();
+
+    (io, result, called.load(Ordering::SeqCst) == 1)
+}
+
+#[test]
+fn sends_payload_after_expected_intermediate_reply() {
+    let (io, result, called) = send_with_intermediate(vec![
+        (Client, Lines(vec!["X-UPLOAD"])),
+        (Server, Lines(vec!["354-go", "354 ahead"])),
+        (Client, Lines(vec!["payload"])),
+        (Server, Lines(vec!["250 Ok"])),
+    ], false);
+
+    assert!(called);
+    assert_eq!(result.unwrap().msg(), &["Ok".to_owned()]);
+    let (mut socket, _, _) = io.split();
+    socket.shutdown_now().unwrap();
+}
+
+#[test]
+fn does_not_send_payload_after_other_reply() {
+    for &(reply, error) in &[("250 Ok", false), ("334 ", false), ("554 No", true)] {
+        let (io, result, called) = send_with_intermediate(vec![
+            (Client, Lines(vec!["X-UPLOAD"])),
+            (Server, Lines(vec![reply])),
+        ], false);
+
+        assert!(!called);
+        match result {
+            Err(LogicError::UnexpectedCode(_)) if !error => (),
+            Err(LogicError::Code(_)) if error => (),
+            other => panic!("unexpected result for {:?}: {:?}", reply, other)
+        }
+        let (mut socket, _, _) = io.split();
+        socket.shutdown_now().unwrap();
+    }
+}
+
+#[test]
+fn does_not_read_final_reply_if_payload_was_aborted() {
+    let (io, result, called) = send_with_intermediate(vec![
+        (Client, Lines(vec!["X-UPLOAD"])),
+        (Server, Lines(vec!["354 go ahead"])),
+        (Client, Lines(vec!["payload"])),
+    ], true);
+
+    assert!(called);
+    match result {
+        Err(LogicError::Aborted) => (),
+        other => panic!("unexpected result: {:?}", other)
+    }
+    let (mut socket, _, _) = io.split();
+    socket.shutdown_now().unwrap();
+}
@@ -5,6 +5,10 @@ compile_error!("integration tests require \"mock-impl\" feature");
 #[macro_use]
 extern crate new_tokio_smtp;
 extern crate futures;
+extern crate bytes;
+extern crate tokio;
+extern crate tokio_timer;
+extern crate tokio_executor;
 
 #[cfg(feature="send-mail")]
 #[macro_use]
@@ -12,16 +16,50 @@ extern crate vec1;
 
 use std::collections::HashMap;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use new_tokio_smtp::{Connection, Io, EhloData, Domain, Capability, EsmtpKeyword};
+use tokio_timer::clock::{self, Clock, Now};
+
+use new_tokio_smtp::{Connection, Io, EhloData, EhloParam, Domain, Capability, EsmtpKeyword};
+use new_tokio_smtp::io::Socket;
 use new_tokio_smtp::mock::{MockSocket, Actor, ActionData};
 
 mod command;
 mod chain;
+mod connection;
+mod io;
+mod pool;
 #[cfg(feature="send-mail")]
 mod send_mail;
 
 
+/// a time source for `tokio::clock` which only moves when advanced
+#[derive(Debug, Clone)]
+struct MockClock(Arc<Mutex<Instant>>);
+
+impl MockClock {
+    fn advance(&self, duration: Duration) {
+        *self.0.lock().unwrap() += duration;
+    }
+}
+
+impl Now for MockClock {
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// runs `test` with `tokio::clock::now` using a `MockClock`
+fn with_mock_clock<F, R>(test: F) -> R
+    where F: FnOnce(&MockClock) -> R
+{
+    let mock = MockClock(Arc::new(Mutex::new(Instant::now())));
+    let clock = Clock::new_with_now(mock.clone());
+    let mut enter = tokio_executor::enter().unwrap();
+    clock::with_default(&clock, &mut enter, |_| test(&mock))
+}
+
 fn mock(conv: Vec<(Actor, ActionData)>) -> Connection {
     let io: Io = MockSocket::new(conv).into();
     Connection::from(io)
@@ -32,8 +70,24 @@ fn mock_no_shutdown(conv: Vec<(Actor, ActionData)>) -> Connection {
     Connection::from(io)
 }
 
+/// marks the mock socket as secure (as if STARTTLS had been used)
+fn secure(con: Connection) -> Connection {
+    let mut io = Io::from(con);
+    if let Socket::Mock(ref mut mock) = *io.socket_mut() {
+        mock.set_is_secure(true);
+    }
+    Connection::from(io)
+}
+
 fn with_capability(con: Connection, cap: &str) -> Connection {
+    with_capability_params(con, cap, &[])
+}
+
+fn with_capability_params(con: Connection, cap: &str, params: &[&str]) -> Connection {
     let capability = Capability::from(EsmtpKeyword::from_str(cap).unwrap());
+    let params = params.iter()
+        .map(|param| EhloParam::from_str(param).unwrap())
+        .collect();
 
     let (socket, buffer, opt_ehlo_data) = Io::from(con).split();
 
@@ -41,9 +95,9 @@ fn with_capability(con: Connection, cap: &str) -> Connection {
         .map(|ehlo_data|ehlo_data.into())
         .unwrap_or_else(|| (Domain::from_unchecked("uhmail.test"), HashMap::new()));
 
-    ehlo_map.insert(capability, Vec::new());
+    ehlo_map.insert(capability, params);
 
     let ehlo_data = EhloData::from((domain, ehlo_map));
 
     Connection::from(Io::from((socket, buffer, ehlo_data)))
-}
\ No newline at end of file
+}
@@ -0,0 +1,187 @@
+use std::time::Duration;
+
+use futures::{future, Async, Future};
+use tokio::runtime::current_thread::Runtime;
+
+use new_tokio_smtp::{ClientId, Connection, ConnectionConfig, Domain};
+use new_tokio_smtp::command::auth;
+use new_tokio_smtp::pool::{Pool, Acquire, PoolError, PoolKey};
+
+use new_tokio_smtp::mock::{ActionData, Actor};
+
+use self::Actor::*;
+use self::ActionData::*;
+
+use super::{mock_no_shutdown, with_capability, with_mock_clock};
+
+fn named(name: &str) -> Connection {
+    with_capability(mock_no_shutdown(vec![]), name)
+}
+
+/// polls the future once (in a task context), returning the connection if it is ready
+fn poll_ready(fut: &mut Acquire) -> Option<Connection> {
+    match future::lazy(|| Ok::<_, PoolError>(fut.poll())).wait().unwrap().unwrap() {
+        Async::Ready(con) => Some(con),
+        Async::NotReady => None
+    }
+}
+
+#[test]
+fn wakes_waiters_in_fifo_order() {
+    let pool = Pool::with_connections(vec![named("X-ONE"), named("X-TWO")]);
+
+    let first = pool.acquire().wait().unwrap();
+    let second = pool.acquire().wait().unwrap();
+    assert_eq!(pool.idle_count(), 0);
+
+    let mut waiters = vec![pool.acquire(), pool.acquire(), pool.acquire()];
+    assert_eq!(pool.waiting_count(), 3);
+    for waiter in waiters.iter_mut() {
+        assert!(poll_ready(waiter).is_none());
+    }
+
+    pool.release(second);
+    assert!(poll_ready(&mut waiters[2]).is_none());
+    assert!(poll_ready(&mut waiters[1]).is_none());
+    let con = poll_ready(&mut waiters[0]).unwrap();
+    assert!(con.has_capability("X-TWO"));
+
+    pool.release(first);
+    assert!(poll_ready(&mut waiters[2]).is_none());
+    let con2 = poll_ready(&mut waiters[1]).unwrap();
+    assert!(con2.has_capability("X-ONE"));
+
+    pool.release(con);
+    let con3 = poll_ready(&mut waiters[2]).unwrap();
+    assert!(con3.has_capability("X-TWO"));
+    assert_eq!(pool.waiting_count(), 0);
+    assert_eq!(pool.idle_count(), 0);
+
+    pool.release(con2);
+    pool.release(con3);
+    assert_eq!(pool.idle_count(), 2);
+}
+
+#[test]
+fn acquire_times_out_without_losing_connections() {
+    let pool = Pool::new();
+    let mut runtime = Runtime::new().unwrap();
+
+    let res = runtime.block_on(pool.acquire_timeout(Duration::from_millis(50)));
+    match res {
+        Err(PoolError::Timeout) => (),
+        other => panic!("unexpected result: {:?}", other.map(|_| ()))
+    }
+
+    // the timed out waiter is skipped
+    let mut waiter = pool.acquire();
+    pool.release(named("X-ONE"));
+    assert_eq!(pool.waiting_count(), 0);
+    let con = poll_ready(&mut waiter).unwrap();
+    assert!(con.has_capability("X-ONE"));
+
+    // dropping a waiter which already got a connection puts it back
+    let waiter = pool.acquire_timeout(Duration::from_millis(50));
+    pool.release(con);
+    drop(waiter);
+    assert_eq!(pool.idle_count(), 1);
+}
+
+#[test]
+fn acquire_for_never_mixes_destinations() {
+    let config_a = ConnectionConfig::builder_local_unencrypted().port(2525).build();
+    let config_b = ConnectionConfig::builder_local_unencrypted().port(2526).build();
+    assert_ne!(PoolKey::from_config(&config_a), PoolKey::from_config(&config_b));
+
+    let pool = Pool::new();
+    pool.release_for(&config_a, named("X-SERVER-A"));
+    pool.release_for(&config_b, named("X-SERVER-B"));
+    pool.release_for(&config_a, named("X-SERVER-A"));
+    assert_eq!(pool.idle_count_for(&config_a), 2);
+    assert_eq!(pool.idle_count_for(&config_b), 1);
+    // not shared with the unkeyed connections
+    assert_eq!(pool.idle_count(), 0);
+
+    let con_b = pool.acquire_for(&config_b).wait().unwrap();
+    assert!(con_b.has_capability("X-SERVER-B"));
+    let con_a = pool.acquire_for(&config_a).wait().unwrap();
+    assert!(con_a.has_capability("X-SERVER-A"));
+    let con_a2 = pool.acquire_for(&config_a).wait().unwrap();
+    assert!(con_a2.has_capability("X-SERVER-A"));
+
+    assert_eq!(pool.idle_count_for(&config_a), 0);
+    assert_eq!(pool.idle_count_for(&config_b), 0);
+}
+
+#[test]
+fn pool_key_includes_the_security_mode() {
+    let addr = "127.0.0.1:2525".parse().unwrap();
+    let client_id = ClientId::Domain(Domain::from_unchecked("client.example"));
+    let plaintext = ConnectionConfig::builder_local_unencrypted()
+        .port(2525)
+        .client_id(client_id.clone())
+        .build();
+    let direct_tls = ConnectionConfig::builder_with_addr(addr, Domain::from_unchecked("localhost"))
+        .use_direct_tls()
+        .client_id(client_id)
+        .build();
+
+    assert_eq!(plaintext.addr, direct_tls.addr);
+    assert_ne!(PoolKey::from_config(&plaintext), PoolKey::from_config(&direct_tls));
+
+    let pool = Pool::new();
+    pool.release_for(&plaintext, named("X-PLAINTEXT"));
+    assert_eq!(pool.idle_count_for(&direct_tls), 0);
+    assert_eq!(pool.idle_count_for(&plaintext), 1);
+}
+
+#[test]
+fn pool_key_includes_the_auth_identity() {
+    let addr = "127.0.0.1:25".parse().unwrap();
+    let domain = Domain::from_unchecked("localhost");
+    let alice = ConnectionConfig::builder_with_addr(addr, domain.clone())
+        .auth(auth::Login::new("alice", "secret"))
+        .build();
+    let bob = ConnectionConfig::builder_with_addr(addr, domain.clone())
+        .auth(auth::Login::new("bob", "secret"))
+        .build();
+    let no_auth = ConnectionConfig::builder_with_addr(addr, domain)
+        .build();
+
+    let alice_key = PoolKey::from_config(&alice);
+    assert_ne!(alice_key, PoolKey::from_config(&bob));
+    assert_ne!(alice_key, PoolKey::from_config(&no_auth));
+    assert_eq!(alice_key, PoolKey::from_config(&alice.clone()));
+    // only the identity is part of the key, not the credentials
+    assert!(!alice_key.auth_identity().unwrap().contains("secret"));
+
+    let pool = Pool::new();
+    pool.release_for(&alice, named("X-ALICE"));
+    assert_eq!(pool.idle_count_for(&bob), 0);
+    assert_eq!(pool.idle_count_for(&no_auth), 0);
+    let con = pool.acquire_for(&alice).wait().unwrap();
+    assert!(con.has_capability("X-ALICE"));
+}
+
+#[test]
+fn keep_alive_only_pings_idle_connections() {
+    with_mock_clock(|clock| {
+        let idle = with_capability(mock_no_shutdown(vec![
+            (Client, Lines(vec!["NOOP"])),
+            (Server, Lines(vec!["250 Ok"])),
+        ]), "X-IDLE");
+        clock.advance(Duration::from_secs(100));
+        // any NOOP send through this would fail as the conversation is empty
+        let active = named("X-ACTIVE");
+
+        let pool = Pool::with_connections(vec![active, idle]);
+        let pinged = pool.keep_alive(Duration::from_secs(100)).wait().unwrap();
+
+        assert_eq!(pinged, 1);
+        assert_eq!(pool.idle_count(), 2);
+        let first = pool.acquire().wait().unwrap();
+        assert!(first.has_capability("X-ACTIVE"));
+        let second = pool.acquire().wait().unwrap();
+        assert!(second.has_capability("X-IDLE"));
+    })
+}
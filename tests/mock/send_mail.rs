@@ -2,15 +2,17 @@ use futures::Future;
 
 use new_tokio_smtp::send_mail::{
     Mail, MailAddress, MailEnvelop,
-    EncodingRequirement,
+    EncodingRequirement, RecipientStatus
 };
 use new_tokio_smtp::mock::{ ActionData, Actor};
+use new_tokio_smtp::command::{DeliverBy, DeliverByMode};
+use new_tokio_smtp::error::LogicError;
 
 
 use self::Actor::*;
 use self::ActionData::*;
 
-use super::{mock, with_capability};
+use super::{mock, mock_no_shutdown, with_capability, with_capability_params};
 
 #[test]
 fn creates_the_right_chain() {
@@ -71,4 +73,415 @@ fn uses_smtputf8_for_internationalized_mail_addresses() {
     con.send_mail(envelop)
         .and_then(|(con, _)| con.quit())
         .wait().unwrap();
-}
\ No newline at end of file
+}
+fn simple_envelop() -> MailEnvelop {
+    MailEnvelop::new(
+        MailAddress::from_unchecked("t1@test.test"),
+        vec1![
+            MailAddress::from_unchecked("t2@test.test"),
+        ],
+        Mail::new(EncodingRequirement::None, Vec::from("the data\r\n"))
+    )
+}
+
+#[test]
+fn sends_mail_under_size_limit() {
+    let con = mock(vec![
+        (Client,  Lines(vec!["MAIL FROM:<t1@test.test>"])),
+        (Server,  Lines(vec!["250 Ok"])),
+        (Client,  Lines(vec!["RCPT TO:<t2@test.test>"])),
+        (Server,  Lines(vec!["250 Ok"])),
+        (Client,  Lines(vec!["DATA"])),
+        (Server,  Lines(vec!["354 ..."])),
+        (Client,  Blob(Vec::from("the data\r\n.\r\n".to_owned()))),
+        (Server,  Lines(vec!["250 Ok"])),
+        (Client,  Lines(vec!["QUIT"])),
+        (Server,  Lines(vec!["250 Ok"])),
+    ]);
+
+    let con = with_capability_params(con, "SIZE", &["10"]);
+
+    con.send_mail(simple_envelop())
+        .and_then(|(con, res)| {
+            assert!(res.is_ok());
+            con.quit()
+        })
+        .wait().unwrap();
+}
+
+#[test]
+fn rejects_mail_over_size_limit_without_sending() {
+    let con = mock_no_shutdown(vec![]);
+    let con = with_capability_params(con, "SIZE", &["5"]);
+
+    let (_con, res) = con.send_mail(simple_envelop()).wait().unwrap();
+
+    match res {
+        Err((0, LogicError::MessageTooLarge { size: 10, limit: 5 })) => (),
+        other => panic!("unexpected result: {:?}", other)
+    }
+}
+
+#[test]
+fn size_check_uses_the_size_as_send() {
+    let con = mock_no_shutdown(vec![]);
+    let con = with_capability_params(con, "SIZE", &["12"]);
+
+    let envelop = MailEnvelop::new(
+        MailAddress::from_unchecked("t1@test.test"),
+        vec1![ MailAddress::from_unchecked("t2@test.test") ],
+        Mail::new(EncodingRequirement::None, Vec::from("line1\nline2"))
+    );
+
+    let (_con, res) = con.send_mail(envelop).wait().unwrap();
+
+    match res {
+        Err((0, LogicError::MessageTooLarge { size: 14, limit: 12 })) => (),
+        other => panic!("unexpected result: {:?}", other)
+    }
+}
+
+#[test]
+fn skips_size_check_without_advertised_limit() {
+    let con = mock(vec![
+        (Client,  Lines(vec!["MAIL FROM:<t1@test.test>"])),
+        (Server,  Lines(vec!["250 Ok"])),
+        (Client,  Lines(vec!["RCPT TO:<t2@test.test>"])),
+        (Server,  Lines(vec!["250 Ok"])),
+        (Client,  Lines(vec!["DATA"])),
+        (Server,  Lines(vec!["354 ..."])),
+        (Client,  Blob(Vec::from("the data\r\n.\r\n".to_owned()))),
+        (Server,  Lines(vec!["250 Ok"])),
+        (Client,  Lines(vec!["QUIT"])),
+        (Server,  Lines(vec!["250 Ok"])),
+    ]);
+
+    // SIZE without a parameter (or with 0) means no fixed limit
+    let con = with_capability(con, "SIZE");
+
+    con.send_mail(simple_envelop())
+        .and_then(|(con, res)| {
+            assert!(res.is_ok());
+            con.quit()
+        })
+        .wait().unwrap();
+}
+
+#[test]
+fn sends_deliver_by_from_envelop_and_maps_failure_of_any_reply() {
+    let con = mock(vec![
+        (Client,  Lines(vec!["MAIL FROM:<t1@test.test> BY=120;R"])),
+        (Server,  Lines(vec!["250 Ok"])),
+        (Client,  Lines(vec!["RCPT TO:<t2@test.test>"])),
+        (Server,  Lines(vec!["553 5.4.7 Can not deliver in time"])),
+        (Client,  Lines(vec!["RSET"])),
+        (Server,  Lines(vec!["250 Ok"])),
+        (Client,  Lines(vec!["MAIL FROM:<t1@test.test> BY=120;R"])),
+        (Server,  Lines(vec!["250 Ok"])),
+        (Client,  Lines(vec!["RCPT TO:<t2@test.test>"])),
+        (Server,  Lines(vec!["250 Ok"])),
+        (Client,  Lines(vec!["DATA"])),
+        (Server,  Lines(vec!["354 ..."])),
+        (Client,  Blob(Vec::from("the data\r\n.\r\n".to_owned()))),
+        (Server,  Lines(vec!["554 5.4.7 Delivery time expired"])),
+        (Client,  Lines(vec!["RSET"])),
+        (Server,  Lines(vec!["250 Ok"])),
+    ]);
+    let con = with_capability(con, "DELIVERBY");
+
+    let envelop = simple_envelop()
+        .with_deliver_by(DeliverBy::new(120, DeliverByMode::Return));
+
+    let (con, res) = con.send_mail(envelop.clone()).wait().unwrap();
+    match res {
+        Err((1, LogicError::DeliverByFailed(response))) => assert_eq!(response.code().as_u16(), 553),
+        other => panic!("unexpected result: {:?}", other)
+    }
+
+    let (con, res) = con.send_mail(envelop).wait().unwrap();
+    match res {
+        Err((2, LogicError::DeliverByFailed(response))) => assert_eq!(response.code().as_u16(), 554),
+        other => panic!("unexpected result: {:?}", other)
+    }
+
+    con.shutdown().wait().unwrap();
+}
+
+#[test]
+fn does_not_map_delivery_time_expired_without_deliver_by() {
+    let con = mock(vec![
+        (Client,  Lines(vec!["MAIL FROM:<t1@test.test>"])),
+        (Server,  Lines(vec!["250 Ok"])),
+        (Client,  Lines(vec!["RCPT TO:<t2@test.test>"])),
+        (Server,  Lines(vec!["553 5.4.7 Can not deliver in time"])),
+        (Client,  Lines(vec!["RSET"])),
+        (Server,  Lines(vec!["250 Ok"])),
+    ]);
+
+    let (con, res) = con.send_mail(simple_envelop()).wait().unwrap();
+    match res {
+        Err((1, LogicError::Code(response))) => assert_eq!(response.code().as_u16(), 553),
+        other => panic!("unexpected result: {:?}", other)
+    }
+
+    con.shutdown().wait().unwrap();
+}
+
+#[test]
+fn report_maps_deliver_by_failure_of_rejected_recipients() {
+    let con = mock(vec![
+        (Client,  Lines(vec!["MAIL FROM:<t1@test.test> BY=120;R"])),
+        (Server,  Lines(vec!["250 Ok"])),
+        (Client,  Lines(vec!["RCPT TO:<t2@test.test>"])),
+        (Server,  Lines(vec!["553 5.4.7 Can not deliver in time"])),
+        (Client,  Lines(vec!["RSET"])),
+        (Server,  Lines(vec!["250 Ok"])),
+    ]);
+    let con = with_capability(con, "DELIVERBY");
+
+    let envelop = simple_envelop()
+        .with_deliver_by(DeliverBy::new(120, DeliverByMode::Return));
+
+    let (con, res) = con.send_mail_with_report(envelop).wait().unwrap();
+    match res {
+        Err((1, LogicError::DeliverByFailed(response))) => assert_eq!(response.code().as_u16(), 553),
+        other => panic!("unexpected result: {:?}", other)
+    }
+
+    con.shutdown().wait().unwrap();
+}
+
+fn two_recipient_envelop() -> MailEnvelop {
+    MailEnvelop::new(
+        MailAddress::from_unchecked("t1@test.test"),
+        vec1![
+            MailAddress::from_unchecked("t2@test.test"),
+            MailAddress::from_unchecked("t3@test.test"),
+        ],
+        Mail::new(EncodingRequirement::None, Vec::from("the data\r\n"))
+    )
+}
+
+#[test]
+fn reports_recipient_status_and_queued_id() {
+    let con = mock(vec![
+        (Client,  Lines(vec!["MAIL FROM:<t1@test.test>"])),
+        (Server,  Lines(vec!["250 2.1.0 Ok"])),
+        (Client,  Lines(vec!["RCPT TO:<t2@test.test>"])),
+        (Server,  Lines(vec!["250 2.1.5 Ok"])),
+        (Client,  Lines(vec!["RCPT TO:<t3@test.test>"])),
+        (Server,  Lines(vec!["550 5.1.1 <t3@test.test>: Recipient address rejected"])),
+        (Client,  Lines(vec!["DATA"])),
+        (Server,  Lines(vec!["354 ..."])),
+        (Client,  Blob(Vec::from("the data\r\n.\r\n".to_owned()))),
+        (Server,  Lines(vec!["250 2.0.0 Ok: queued as ABC123"])),
+    ]);
+
+    let (con, res) = con.send_mail_with_report(two_recipient_envelop()).wait().unwrap();
+
+    let report = res.unwrap();
+    assert_eq!(report.queued_id(), Some("ABC123"));
+    assert!(report.has_rejected_recipients());
+    assert_eq!(report.recipients().len(), 2);
+
+    match report.status_of("t2@test.test") {
+        Some(&RecipientStatus::Accepted { code }) => assert_eq!(code.as_u16(), 250),
+        other => panic!("unexpected status: {:?}", other)
+    }
+    match report.status_of("t3@test.test") {
+        Some(&RecipientStatus::Rejected { code, ref enhanced, ref text }) => {
+            assert_eq!(code.as_u16(), 550);
+            assert_eq!(enhanced.as_ref().map(|code| &**code), Some("5.1.1"));
+            assert_eq!(text, "<t3@test.test>: Recipient address rejected");
+        },
+        other => panic!("unexpected status: {:?}", other)
+    }
+
+    con.shutdown().wait().unwrap();
+}
+
+#[test]
+fn fails_and_resets_if_all_recipients_are_rejected() {
+    let con = mock(vec![
+        (Client,  Lines(vec!["MAIL FROM:<t1@test.test>"])),
+        (Server,  Lines(vec!["250 Ok"])),
+        (Client,  Lines(vec!["RCPT TO:<t2@test.test>"])),
+        (Server,  Lines(vec!["550 5.1.1 User unknown"])),
+        (Client,  Lines(vec!["RCPT TO:<t3@test.test>"])),
+        (Server,  Lines(vec!["450 4.2.0 Mailbox busy"])),
+        (Client,  Lines(vec!["RSET"])),
+        (Server,  Lines(vec!["250 Ok"])),
+    ]);
+
+    let (con, res) = con.send_mail_with_report(two_recipient_envelop()).wait().unwrap();
+
+    match res {
+        Err((2, LogicError::Code(response))) => assert_eq!(response.code().as_u16(), 450),
+        other => panic!("unexpected result: {:?}", other)
+    }
+
+    con.shutdown().wait().unwrap();
+}
+
+fn five_recipient_envelop() -> MailEnvelop {
+    MailEnvelop::new(
+        MailAddress::from_unchecked("t1@test.test"),
+        vec1![
+            MailAddress::from_unchecked("r1@test.test"),
+            MailAddress::from_unchecked("r2@test.test"),
+            MailAddress::from_unchecked("r3@test.test"),
+            MailAddress::from_unchecked("r4@test.test"),
+            MailAddress::from_unchecked("r5@test.test"),
+        ],
+        Mail::new(EncodingRequirement::None, Vec::from("the data\r\n"))
+    )
+}
+
+#[test]
+fn splits_recipients_across_transactions() {
+    let con = mock(vec![
+        (Client,  Lines(vec!["MAIL FROM:<t1@test.test>"])),
+        (Server,  Lines(vec!["250 Ok"])),
+        (Client,  Lines(vec!["RCPT TO:<r1@test.test>"])),
+        (Server,  Lines(vec!["250 Ok"])),
+        (Client,  Lines(vec!["RCPT TO:<r2@test.test>"])),
+        (Server,  Lines(vec!["250 Ok"])),
+        (Client,  Lines(vec!["DATA"])),
+        (Server,  Lines(vec!["354 ..."])),
+        (Client,  Blob(Vec::from("the data\r\n.\r\n".to_owned()))),
+        (Server,  Lines(vec!["250 2.0.0 Ok: queued as Q0"])),
+        (Client,  Lines(vec!["MAIL FROM:<t1@test.test>"])),
+        (Server,  Lines(vec!["250 Ok"])),
+        (Client,  Lines(vec!["RCPT TO:<r3@test.test>"])),
+        (Server,  Lines(vec!["250 Ok"])),
+        (Client,  Lines(vec!["RCPT TO:<r4@test.test>"])),
+        (Server,  Lines(vec!["550 5.1.1 User unknown"])),
+        (Client,  Lines(vec!["DATA"])),
+        (Server,  Lines(vec!["354 ..."])),
+        (Client,  Blob(Vec::from("the data\r\n.\r\n".to_owned()))),
+        (Server,  Lines(vec!["250 2.0.0 Ok: queued as Q1"])),
+        (Client,  Lines(vec!["MAIL FROM:<t1@test.test>"])),
+        (Server,  Lines(vec!["250 Ok"])),
+        (Client,  Lines(vec!["RCPT TO:<r5@test.test>"])),
+        (Server,  Lines(vec!["250 Ok"])),
+        (Client,  Lines(vec!["DATA"])),
+        (Server,  Lines(vec!["354 ..."])),
+        (Client,  Blob(Vec::from("the data\r\n.\r\n".to_owned()))),
+        (Server,  Lines(vec!["250 2.0.0 Ok: queued as Q2"])),
+    ]);
+
+    let (con, res) = con.send_mail_with_recipient_limit(five_recipient_envelop(), Some(2))
+        .wait().unwrap();
+
+    let report = res.unwrap();
+    assert_eq!(report.transaction_count(), 3);
+    assert_eq!(report.queued_ids(), &[
+        Some("Q0".to_owned()), Some("Q1".to_owned()), Some("Q2".to_owned())
+    ]);
+    assert_eq!(report.queued_id(), Some("Q0"));
+
+    let accepted = report.recipients().iter()
+        .map(|(addr, status)| (addr.as_str(), status.is_accepted()))
+        .collect::<Vec<_>>();
+    assert_eq!(accepted, vec![
+        ("r1@test.test", true), ("r2@test.test", true), ("r3@test.test", true),
+        ("r4@test.test", false), ("r5@test.test", true)
+    ]);
+
+    con.shutdown().wait().unwrap();
+}
+
+#[test]
+fn uses_server_advertised_recipient_limit() {
+    let con = mock(vec![
+        (Client,  Lines(vec!["MAIL FROM:<t1@test.test>"])),
+        (Server,  Lines(vec!["250 Ok"])),
+        (Client,  Lines(vec!["RCPT TO:<t2@test.test>"])),
+        (Server,  Lines(vec!["250 Ok"])),
+        (Client,  Lines(vec!["DATA"])),
+        (Server,  Lines(vec!["354 ..."])),
+        (Client,  Blob(Vec::from("the data\r\n.\r\n".to_owned()))),
+        (Server,  Lines(vec!["250 Ok"])),
+        (Client,  Lines(vec!["MAIL FROM:<t1@test.test>"])),
+        (Server,  Lines(vec!["451 4.3.0 Try again later"])),
+        (Client,  Lines(vec!["RSET"])),
+        (Server,  Lines(vec!["250 Ok"])),
+    ]);
+    let con = with_capability_params(con, "LIMITS", &["RCPTMAX=1"]);
+
+    let (con, res) = con.send_mail_with_recipient_limit(two_recipient_envelop(), Some(10))
+        .wait().unwrap();
+
+    let report = res.unwrap();
+    assert_eq!(report.transaction_count(), 2);
+    assert_eq!(report.queued_ids(), &[None, None]);
+    assert!(report.status_of("t2@test.test").unwrap().is_accepted());
+    match report.status_of("t3@test.test") {
+        Some(&RecipientStatus::Rejected { code, .. }) => assert_eq!(code.as_u16(), 451),
+        other => panic!("unexpected status: {:?}", other)
+    }
+
+    con.shutdown().wait().unwrap();
+}
+
+#[cfg(feature="mail")]
+const MESSAGE_8BIT: &str = concat!(
+    "From: T1 <t1@test.test>\r\n",
+    "To: t2@test.test\r\n",
+    "Bcc: t3@test.test\r\n",
+    "Subject: Hy\r\n",
+    "\r\n",
+    "Gr\u{fc}\u{df}e\r\n"
+);
+
+#[cfg(feature="mail")]
+#[test]
+fn sends_encoded_message_with_derived_envelop() {
+    let con = mock(vec![
+        (Client,  Lines(vec!["MAIL FROM:<t1@test.test> BODY=8BITMIME"])),
+        (Server,  Lines(vec!["250 Ok"])),
+        (Client,  Lines(vec!["RCPT TO:<t2@test.test>"])),
+        (Server,  Lines(vec!["250 Ok"])),
+        (Client,  Lines(vec!["RCPT TO:<t3@test.test>"])),
+        (Server,  Lines(vec!["250 Ok"])),
+        (Client,  Lines(vec!["DATA"])),
+        (Server,  Lines(vec!["354 ..."])),
+        (Client,  Blob(Vec::from(concat!(
+            "From: T1 <t1@test.test>\r\n",
+            "To: t2@test.test\r\n",
+            "Subject: Hy\r\n",
+            "\r\n",
+            "Gr\u{fc}\u{df}e\r\n",
+            ".\r\n"
+        ).to_owned()))),
+        (Server,  Lines(vec!["250 Ok"])),
+        (Client,  Lines(vec!["QUIT"])),
+        (Server,  Lines(vec!["250 Ok"])),
+    ]);
+    let con = with_capability(con, "8BITMIME");
+
+    let envelop = MailEnvelop::from_message(MESSAGE_8BIT).unwrap();
+
+    con.send_mail(envelop)
+        .and_then(|(con, res)| {
+            assert!(res.is_ok());
+            con.quit()
+        })
+        .wait().unwrap();
+}
+
+#[cfg(feature="mail")]
+#[test]
+fn rejects_8bit_message_if_8bitmime_is_not_supported() {
+    let con = mock_no_shutdown(vec![]);
+
+    let envelop = MailEnvelop::from_message(MESSAGE_8BIT).unwrap();
+    let (_con, res) = con.send_mail(envelop).wait().unwrap();
+
+    match res {
+        Err((0, LogicError::MissingCapabilities(err))) => {
+            assert_eq!(err.capabilities()[0].as_str(), "8BITMIME");
+        },
+        other => panic!("unexpected result: {:?}", other)
+    }
+}